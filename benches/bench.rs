@@ -1,9 +1,29 @@
 use criterion::{criterion_group, criterion_main, Criterion, Throughput};
 use rand::prelude::*;
-use smol::{net::UdpSocket, prelude::*};
-use std::{fs::File, sync::Arc};
+use smol::{
+    channel::{bounded, Receiver, Sender},
+    net::UdpSocket,
+    prelude::*,
+    Timer,
+};
+use std::{
+    fs::File,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 pub const DATA_SIZE: usize = 0x1000000 * 4; // 64 MB
+pub const PACKETS: usize = 0x100000;
+pub const ROUND_TRIPS: usize = 1000;
+pub const MESSAGE_SIZE: usize = 64;
+pub const LOSSY_DATA_SIZE: usize = 0x400000; // 4 MB
+
+/// (name, one-way delay in milliseconds, packet loss)
+pub const LOSSY_SCENARIOS: [(&str, u64, f64); 3] = [
+    ("rtt-50ms-loss-2%", 25, 0.02),
+    ("rtt-100ms-loss-5%", 50, 0.05),
+    ("rtt-200ms-loss-1%", 100, 0.01),
+];
 
 pub async fn get_udp_pair() -> (UdpSocket, UdpSocket) {
     let io1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
@@ -20,6 +40,63 @@ fn random_data() -> Arc<Vec<u8>> {
     Arc::new(buf)
 }
 
+/// An in-memory link, every packet is delayed and may be lost
+pub struct LossyIo {
+    loss: f64,
+    delay: Duration,
+    tx: Sender<bytes::Bytes>,
+    rx: Receiver<bytes::Bytes>,
+}
+
+impl LossyIo {
+    pub fn pair(loss: f64, delay: Duration) -> (Self, Self) {
+        let (tx1, rx1) = bounded(0x1000);
+        let (tx2, rx2) = bounded(0x1000);
+        let io1 = Self {
+            loss,
+            delay,
+            tx: tx1,
+            rx: rx2,
+        };
+        let io2 = Self {
+            loss,
+            delay,
+            tx: tx2,
+            rx: rx1,
+        };
+        (io1, io2)
+    }
+}
+
+#[async_trait::async_trait]
+impl ap_kcp::KcpIo for LossyIo {
+    async fn send_packet(&self, buf: &[u8]) -> std::io::Result<()> {
+        if rand::thread_rng().gen_bool(self.loss) {
+            return Ok(());
+        }
+        let tx = self.tx.clone();
+        let delay = self.delay;
+        let packet = bytes::Bytes::copy_from_slice(buf);
+        smol::spawn(async move {
+            Timer::after(delay).await;
+            // A full queue drops it, like a router
+            let _ = tx.try_send(packet);
+        })
+        .detach();
+        Ok(())
+    }
+
+    async fn recv_packet(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let packet = self
+            .rx
+            .recv()
+            .await
+            .map_err(|_| std::io::ErrorKind::ConnectionReset)?;
+        buf[..packet.len()].copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+}
+
 fn init() {
     std::env::set_var("SMOL_THREADS", "8");
 }
@@ -64,10 +141,161 @@ pub fn xmit_benchmark(c: &mut Criterion) {
     group.finish();
 }
 
+/// The RTT of every round trip of a small message, echoed back by the peer
+fn ping_pong(config: ap_kcp::KcpConfig, round_trips: usize) -> Vec<Duration> {
+    smol::block_on(async move {
+        let (io1, io2) = get_udp_pair().await;
+        let handle1 = ap_kcp::KcpHandle::new(io1, config.clone());
+        let t = smol::spawn(async move {
+            let handle2 = ap_kcp::KcpHandle::new(io2, config);
+            let mut stream2 = handle2.accept().await.unwrap();
+            let mut buf = [0u8; MESSAGE_SIZE];
+            for _ in 0..round_trips {
+                stream2.read_exact(&mut buf).await.unwrap();
+                stream2.write_all(&buf).await.unwrap();
+            }
+            stream2.close().await.unwrap();
+        });
+        let mut stream1 = handle1.connect().await.unwrap();
+        let message = [0x42u8; MESSAGE_SIZE];
+        let mut buf = [0u8; MESSAGE_SIZE];
+        let mut rtts = Vec::with_capacity(round_trips);
+        for _ in 0..round_trips {
+            let start = Instant::now();
+            stream1.write_all(&message).await.unwrap();
+            stream1.read_exact(&mut buf).await.unwrap();
+            rtts.push(start.elapsed());
+        }
+        stream1.close().await.unwrap();
+        t.await;
+        rtts
+    })
+}
+
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    sorted[(sorted.len() - 1) * percent / 100]
+}
+
+/// Round trips of small messages, the distribution guides the nodelay and interval tuning
+pub fn latency_benchmark(c: &mut Criterion) {
+    init();
+    let mut nodelay = ap_kcp::KcpConfig::default();
+    nodelay.nodelay = true;
+    nodelay.max_interval = 10;
+    let configs = [
+        ("default", ap_kcp::KcpConfig::default()),
+        ("nodelay", nodelay),
+    ];
+
+    for (name, config) in configs.iter() {
+        let mut rtts = ping_pong(config.clone(), ROUND_TRIPS);
+        rtts.sort();
+        println!(
+            "latency/{}: p50 {:?}, p90 {:?}, p99 {:?}",
+            name,
+            percentile(&rtts, 50),
+            percentile(&rtts, 90),
+            percentile(&rtts, 99)
+        );
+    }
+
+    let mut group = c.benchmark_group("latency");
+    for (name, config) in configs.iter() {
+        group.bench_function(*name, |b| {
+            b.iter_custom(|iters| ping_pong(config.clone(), iters as usize).iter().sum())
+        });
+    }
+    group.finish();
+}
+
+fn lossy(data: Arc<Vec<u8>>, delay: u64, loss: f64, congestion: ap_kcp::Congestion) {
+    smol::block_on(async move {
+        let (io1, io2) = LossyIo::pair(loss, Duration::from_millis(delay));
+        let mut config = ap_kcp::KcpConfig::default();
+        config.congestion = congestion;
+        let handle1 = ap_kcp::KcpHandle::new(io1, config.clone());
+        let data1 = data.clone();
+        let t = smol::spawn(async move {
+            let handle2 = ap_kcp::KcpHandle::new(io2, config);
+            let mut stream2 = handle2.accept().await.unwrap();
+            let mut buf = Vec::new();
+            buf.resize(data1.len(), 0);
+            stream2.read_exact(&mut buf).await.unwrap();
+        });
+        let mut stream1 = handle1.connect().await.unwrap();
+        stream1.write_all(&data).await.unwrap();
+        t.await;
+    });
+}
+
+/// Bulk transfers over delayed and lossy links, one group per scenario, to compare the
+/// congestion controllers
+pub fn lossy_benchmark(c: &mut Criterion) {
+    init();
+    let mut data = Vec::new();
+    data.resize(LOSSY_DATA_SIZE, 0);
+    rand::thread_rng().fill_bytes(&mut data);
+    let data = Arc::new(data);
+    let controllers = [
+        ("none", ap_kcp::Congestion::None),
+        ("kcp-reno", ap_kcp::Congestion::KcpReno),
+        ("loss-tolerance", ap_kcp::Congestion::LossTolerance),
+    ];
+
+    for (scenario, delay, loss) in LOSSY_SCENARIOS.iter() {
+        let mut group = c.benchmark_group(format!("lossy/{}", scenario));
+        group.throughput(Throughput::Bytes(LOSSY_DATA_SIZE as u64));
+        for (name, congestion) in controllers.iter() {
+            group.bench_function(*name, |b| {
+                b.iter(|| lossy(data.clone(), *delay, *loss, congestion.clone()))
+            });
+        }
+        group.finish();
+    }
+}
+
+fn handoff_channel() {
+    smol::block_on(async move {
+        let (tx, rx) = smol::channel::bounded(0x100);
+        let consumer = smol::spawn(async move { while rx.recv().await.is_ok() {} });
+        let packet = bytes::Bytes::from_static(&[0u8; 1350]);
+        for _ in 0..PACKETS {
+            tx.send(packet.clone()).await.unwrap();
+        }
+        drop(tx);
+        consumer.await;
+    });
+}
+
+fn handoff_spsc() {
+    smol::block_on(async move {
+        let (mut tx, mut rx) = ap_kcp::spsc::bounded(0x100);
+        let consumer = smol::spawn(async move { while rx.recv().await.is_ok() {} });
+        let packet = bytes::Bytes::from_static(&[0u8; 1350]);
+        for _ in 0..PACKETS {
+            while tx.try_send(packet.clone()).is_err() {
+                smol::future::yield_now().await;
+            }
+        }
+        drop(tx);
+        consumer.await;
+    });
+}
+
+/// Packets per second from the udp demux task to a session
+pub fn handoff_benchmark(c: &mut Criterion) {
+    init();
+    let mut group = c.benchmark_group("handoff");
+    group.throughput(Throughput::Elements(PACKETS as u64));
+    group.bench_function("channel", |b| b.iter(handoff_channel));
+    group.bench_function("spsc", |b| b.iter(handoff_spsc));
+    group.finish();
+}
+
 criterion_group! {
     name = handshake_benches;
     config = Criterion::default().sample_size(10);
-    targets = xmit_benchmark
+    targets = xmit_benchmark, latency_benchmark, lossy_benchmark, handoff_benchmark
 }
 
 criterion_main!(handshake_benches);
@@ -0,0 +1,7 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Malformed packets must come back as errors, never as panics
+    let _ = ap_kcp::fuzz::decode_packet(data);
+});
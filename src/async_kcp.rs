@@ -1,15 +1,22 @@
 use std::{
+    cmp,
     collections::HashMap,
     collections::VecDeque,
+    fmt,
+    io::IoSlice,
+    net::SocketAddr,
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, RwLock,
+    },
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 
-use bytes::{Buf, Bytes};
-use futures::{ready, AsyncRead, AsyncWrite, Future};
-use futures_timer::Delay;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use event_listener::Event;
+use futures::{ready, AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt, Future, Stream};
 use smol::{
     channel::{bounded, Receiver, Sender},
     future::FutureExt,
@@ -18,37 +25,102 @@ use smol::{
 };
 
 use crate::{
-    core::{KcpConfig, KcpCore, KcpIo},
+    core::{
+        i32diff, CongestionState, Features, KcpConfig, KcpCore, KcpIo, KcpStats, RateLimiter,
+        SharedCongestion, SharedRateLimiter, UNKNOWN_STREAM_RESET_CODE,
+    },
     error::{KcpError, KcpResult},
-    segment::{KcpSegment, CMD_PING, CMD_PUSH, HEADER_SIZE},
+    segment::{
+        KcpSegment, CMD_DATAGRAM, CMD_FRAGMENT, CMD_HALF_CLOSE, CMD_OPEN, CMD_PUSH, CMD_RESET,
+        CMD_SKIP, KCP_HEADER_LEN,
+    },
+    spans::{self, Instrument, Span},
 };
 
+pub const MAX_LABEL_LEN: usize = 0x100;
+pub const MAX_RESET_REASON_LEN: usize = 0x80;
+
+// The `conn_id` of the spans
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+
 type LockCoreFuture = Pin<Box<dyn Future<Output = MutexGuardArc<KcpCore>> + Send>>;
 
 pub struct KcpStream {
     core: Arc<Mutex<KcpCore>>,
-    read_buffer: Option<VecDeque<Bytes>>,
+    stream_id: u16,
+    label: Bytes,
+    read_buffer: VecDeque<Bytes>,
     recv_lock_future: Option<LockCoreFuture>,
     send_lock_future: Option<LockCoreFuture>,
     flush_lock_future: Option<LockCoreFuture>,
     close_lock_future: Option<LockCoreFuture>,
+    // False for the halves of a split stream, they close it themselves
+    close_on_drop: bool,
 }
 
 impl Drop for KcpStream {
     fn drop(&mut self) {
-        smol::block_on(async {
-            let _ = self.core.lock().await.try_close();
-        });
+        if self.close_on_drop {
+            smol::block_on(async {
+                let _ = self.core.lock().await.try_close();
+            });
+        }
         log::trace!("kcp stream dropped");
     }
 }
 
 impl KcpStream {
+    fn new(core: Arc<Mutex<KcpCore>>, stream_id: u16, label: Bytes) -> Self {
+        Self {
+            core,
+            stream_id,
+            label,
+            read_buffer: VecDeque::new(),
+            recv_lock_future: None,
+            send_lock_future: None,
+            flush_lock_future: None,
+            close_lock_future: None,
+            close_on_drop: true,
+        }
+    }
+
+    /// Splits the stream into a read half and a write half, owned by different tasks.
+    ///
+    /// Closing the write half is a half-close: the peer reads EOF, but may keep writing, and
+    /// the read half receives until the peer closes too. Closing the whole stream is the
+    /// same FIN on the wire, but a peer getting it closes its own side as well. A peer
+    /// without `Features::HALF_CLOSE` always does.
+    ///
+    /// Dropping the write half half-closes the stream too, unlike dropping a `KcpStream`
+    /// which closes it. Join the halves with `KcpReadHalf::reunite` first to close or reset
+    /// the whole stream instead.
+    pub fn split(mut self) -> (KcpReadHalf, KcpWriteHalf) {
+        self.close_on_drop = false;
+        let mut write = Self::new(self.core.clone(), self.stream_id, self.label.clone());
+        write.close_on_drop = false;
+        let write = KcpWriteHalf {
+            stream: write,
+            close_on_drop: true,
+        };
+        (KcpReadHalf { stream: self }, write)
+    }
+
+    #[inline]
+    pub fn get_stream_id(&self) -> u16 {
+        self.stream_id
+    }
+
+    /// The label given by the connecting side, empty if there is none
+    #[inline]
+    pub fn label(&self) -> &[u8] {
+        &self.label
+    }
+
     #[inline]
     fn lock_core(
         cx: &mut Context<'_>,
         core: Arc<Mutex<KcpCore>>,
-        future_storage: &mut Option<Pin<Box<dyn Future<Output = MutexGuardArc<KcpCore>> + Send>>>,
+        future_storage: &mut Option<LockCoreFuture>,
     ) -> Poll<MutexGuardArc<KcpCore>> {
         if future_storage.is_none() {
             if let Some(core) = core.try_lock_arc() {
@@ -65,40 +137,241 @@ impl KcpStream {
         *future_storage = None;
         Poll::Ready(core)
     }
-}
 
-impl AsyncRead for KcpStream {
-    fn poll_read(
-        mut self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        buf: &mut [u8],
-    ) -> Poll<std::io::Result<usize>> {
-        loop {
-            if self.read_buffer.is_some() {
-                if self.read_buffer.as_mut().unwrap().is_empty() {
-                    self.read_buffer = None;
-                } else {
-                    let queue = self.read_buffer.as_mut().unwrap();
-                    let payload = queue.front_mut().unwrap();
-                    if payload.remaining() > buf.len() {
-                        let buf_len = buf.len();
-                        buf.copy_from_slice(&payload[..buf_len]);
-                        payload.advance(buf_len);
-                        return Poll::Ready(Ok(buf_len));
-                    }
-                    let len = payload.remaining();
-                    payload.copy_to_slice(&mut buf[..len]);
-                    queue.pop_front();
-                    return Poll::Ready(Ok(len));
-                }
+    /// Closes the stream without sending the data still waiting in the send queue.
+    ///
+    /// Only the data already moved into the sending window reaches the peer, so the
+    /// peer may see a truncated stream. `close()` is the default way to close a stream,
+    /// it delivers everything written before sending FIN.
+    pub async fn close_immediate(&mut self) -> std::io::Result<()> {
+        self.core.lock().await.close_immediate()?;
+        self.close().await
+    }
+
+    /// Aborts the stream instead of closing it, the peer's reads and writes fail with
+    /// `KcpError::PeerReset` carrying `code` and `reason`. Data not delivered yet is dropped
+    /// on both sides. Like a TCP RST the reset is sent once, if it's lost the peer times out.
+    pub async fn reset_with(&mut self, code: u32, reason: &str) -> KcpResult<()> {
+        if reason.len() > MAX_RESET_REASON_LEN {
+            return Err(KcpError::ReasonTooLong(reason.len()));
+        }
+        self.core.lock().await.reset(code, reason);
+        Ok(())
+    }
+
+    /// Waits until everything written so far is acknowledged by the peer,
+    /// not merely handed to the io layer.
+    ///
+    /// It's the same as `flush()`, spelled out for request/response patterns.
+    pub async fn flush_and_wait_acked(&mut self) -> std::io::Result<()> {
+        self.flush().await
+    }
+
+    /// Writes all the slices in order, they are segmented without being joined first
+    pub async fn write_all_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<()> {
+        // poll_write_vectored takes either all the slices or none of them
+        let written = self.write_vectored(bufs).await?;
+        debug_assert_eq!(written, bufs.iter().map(|buf| buf.len()).sum::<usize>());
+        Ok(())
+    }
+
+    pub async fn get_stats(&self) -> KcpStats {
+        self.core.lock().await.get_stats()
+    }
+
+    /// The mtu left for KCP after the overhead of the io layers, lower once large packets
+    /// seemed to be dropped, see `KcpConfig::min_mtu`
+    pub async fn effective_mtu(&self) -> usize {
+        self.core.lock().await.get_mtu()
+    }
+
+    /// Lower the mtu of the stream, in the units of `effective_mtu`, when the path MTU is known
+    /// to have shrunk. Data already queued or in flight is cut to the new size, see
+    /// `KcpCore::lower_mtu`.
+    pub async fn lower_mtu(&self, mtu: usize) -> KcpResult<()> {
+        self.core.lock().await.lower_mtu(mtu)
+    }
+
+    /// Segments allowed in flight, clamped by the peer's window and the congestion window
+    pub async fn effective_send_window(&self) -> u32 {
+        self.core.lock().await.get_send_window()
+    }
+
+    /// Resend the data in flight now rather than at the RTO, e.g. when the application
+    /// detects a stall of critical data. It's rate limited, false when ignored, see
+    /// `KcpCore::request_retransmit`.
+    pub async fn request_retransmit(&self) -> bool {
+        self.core.lock().await.request_retransmit()
+    }
+
+    /// Whether the last flush was capped by the congestion window or the peer's window,
+    /// rather than by the application writing too little. An adaptive encoder should not
+    /// raise its bitrate while it's true.
+    pub async fn is_congestion_limited(&self) -> bool {
+        self.core.lock().await.is_window_limited()
+    }
+
+    /// How this stream is served under `max_send_bps`, see `SchedulingPolicy`
+    pub async fn set_priority(&self, priority: u8) {
+        self.core.lock().await.set_priority(priority);
+    }
+
+    pub async fn get_priority(&self) -> u8 {
+        self.core.lock().await.get_priority()
+    }
+
+    /// Features supported by both sides, known once the peer's OPEN arrives
+    pub async fn get_features(&self) -> Features {
+        self.core.lock().await.get_features()
+    }
+
+    /// What was negotiated, read under a single lock. The future doesn't borrow the stream,
+    /// so a spawned task may await it without holding a `&KcpStream` across the await.
+    pub fn parameters(&self) -> impl Future<Output = StreamParameters> + Send + 'static {
+        let core = self.core.clone();
+        let stream_id = self.stream_id;
+        async move {
+            let core = core.lock_arc().await;
+            StreamParameters {
+                stream_id,
+                mtu: core.get_mtu(),
+                send_window: core.get_send_window(),
+                peer_window: core.get_remote_window(),
+                features: core.get_features(),
             }
+        }
+    }
+
+    /// The receive window most recently advertised by the peer
+    pub async fn peer_recv_window(&self) -> u32 {
+        self.core.lock().await.get_remote_window()
+    }
+
+    /// Cap the receive window advertised to the peer, which throttles its sending.
+    /// Pass `recv_window_size` of the config to lift the cap.
+    pub async fn set_local_recv_window(&self, window: u32) {
+        self.core.lock().await.set_local_recv_window(window)
+    }
+
+    /// Make sure there is something in the read buffer, returns false on EOF
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        while self.read_buffer.is_empty() {
             let mut core = ready!(Self::lock_core(
                 cx,
                 self.core.clone(),
                 &mut self.recv_lock_future
             ));
             let payload = ready!(core.poll_recv(cx))?;
-            self.read_buffer = Some(payload);
+            if payload.is_empty() {
+                return Poll::Ready(Ok(false));
+            }
+            self.read_buffer = payload;
+        }
+        Poll::Ready(Ok(true))
+    }
+
+    fn poll_peek(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+        if !ready!(self.poll_fill(cx))? {
+            return Poll::Ready(Ok(0));
+        }
+        let mut len = 0;
+        for payload in &self.read_buffer {
+            if len == buf.len() {
+                break;
+            }
+            let size = cmp::min(buf.len() - len, payload.len());
+            buf[len..len + size].copy_from_slice(&payload[..size]);
+            len += size;
+        }
+        Poll::Ready(Ok(len))
+    }
+
+    /// Takes all the data received but not read yet without waiting, e.g. to save it before
+    /// dropping the stream. Data behind a missing segment is not included.
+    pub async fn take_buffered(&mut self) -> Bytes {
+        let mut buffered = std::mem::take(&mut self.read_buffer);
+        buffered.extend(self.core.lock().await.take_recv_queue());
+        let len = buffered.iter().map(|payload| payload.len()).sum();
+        let mut data = BytesMut::with_capacity(len);
+        for payload in buffered {
+            data.extend_from_slice(&payload);
+        }
+        data.freeze()
+    }
+
+    /// Reads until the peer closes its write side, appending to `buf`, for at most `timeout`.
+    /// On expiry it fails with `KcpError::Timeout`, and `buf` keeps what was read so far.
+    pub async fn read_to_end_timeout(
+        &mut self,
+        buf: &mut Vec<u8>,
+        timeout: Duration,
+    ) -> KcpResult<usize> {
+        let clock = self.core.lock().await.config.clock.clone();
+        let start = buf.len();
+        let read = async {
+            loop {
+                // Filled and drained at once, so nothing is lost when the timeout wins
+                if !futures::future::poll_fn(|cx| self.poll_fill(cx)).await? {
+                    break;
+                }
+                for payload in self.read_buffer.drain(..) {
+                    buf.extend_from_slice(&payload);
+                }
+            }
+            Ok::<_, KcpError>(())
+        };
+        let timeout = async {
+            clock.sleep(timeout).await;
+            Err(KcpError::Timeout)
+        };
+        read.or(timeout).await?;
+        Ok(buf.len() - start)
+    }
+
+    /// Reads the received data without consuming it, the next read returns the same bytes.
+    /// Like `read`, it waits until some data arrives and returns `Ok(0)` on EOF.
+    pub async fn peek(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        futures::future::poll_fn(|cx| self.poll_peek(cx, buf)).await
+    }
+}
+
+impl AsyncRead for KcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if !ready!(this.poll_fill(cx))? {
+            return Poll::Ready(Ok(0));
+        }
+        let payload = this.read_buffer.front_mut().unwrap();
+        let len = cmp::min(payload.remaining(), buf.len());
+        payload.copy_to_slice(&mut buf[..len]);
+        if !payload.has_remaining() {
+            this.read_buffer.pop_front();
+        }
+        Poll::Ready(Ok(len))
+    }
+}
+
+/// Serves the received payloads in place, so `read_until` and `read_line` need no extra buffer
+impl AsyncBufRead for KcpStream {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if !ready!(this.poll_fill(cx))? {
+            return Poll::Ready(Ok(&[]));
+        }
+        Poll::Ready(Ok(&this.read_buffer.front().unwrap()[..]))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        if let Some(payload) = this.read_buffer.front_mut() {
+            payload.advance(amt);
+            if !payload.has_remaining() {
+                this.read_buffer.pop_front();
+            }
         }
     }
 }
@@ -122,6 +395,25 @@ impl AsyncWrite for KcpStream {
         Poll::Ready(Ok(buf.len()))
     }
 
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        if len == 0 {
+            // Never send an empty packet
+            return Poll::Ready(Ok(0));
+        }
+        let mut core = ready!(Self::lock_core(
+            cx,
+            self.core.clone(),
+            &mut self.send_lock_future,
+        ));
+        ready!(core.poll_send_vectored(cx, bufs))?;
+        Poll::Ready(Ok(len))
+    }
+
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
         let mut core = ready!(Self::lock_core(
             cx,
@@ -143,17 +435,409 @@ impl AsyncWrite for KcpStream {
     }
 }
 
+/// The read half of a split `KcpStream`. Dropping it doesn't close the stream.
+pub struct KcpReadHalf {
+    stream: KcpStream,
+}
+
+impl KcpReadHalf {
+    /// Joins the halves back into a stream, or gives them back if they come from different
+    /// streams
+    pub fn reunite(
+        self,
+        mut write: KcpWriteHalf,
+    ) -> Result<KcpStream, (KcpReadHalf, KcpWriteHalf)> {
+        if !Arc::ptr_eq(&self.stream.core, &write.stream.core) {
+            return Err((self, write));
+        }
+        write.close_on_drop = false;
+        let mut stream = self.stream;
+        stream.close_on_drop = true;
+        Ok(stream)
+    }
+
+    #[inline]
+    pub fn get_stream_id(&self) -> u16 {
+        self.stream.stream_id
+    }
+
+    /// See `KcpStream::take_buffered`
+    pub async fn take_buffered(&mut self) -> Bytes {
+        self.stream.take_buffered().await
+    }
+
+    /// See `KcpStream::read_to_end_timeout`
+    pub async fn read_to_end_timeout(
+        &mut self,
+        buf: &mut Vec<u8>,
+        timeout: Duration,
+    ) -> KcpResult<usize> {
+        self.stream.read_to_end_timeout(buf, timeout).await
+    }
+}
+
+impl fmt::Debug for KcpReadHalf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KcpReadHalf")
+            .field("stream_id", &self.stream.stream_id)
+            .finish()
+    }
+}
+
+impl AsyncRead for KcpReadHalf {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncBufRead for KcpReadHalf {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().stream).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.stream).consume(amt)
+    }
+}
+
+/// The write half of a split `KcpStream`. `close()` and dropping it half-close the stream.
+pub struct KcpWriteHalf {
+    stream: KcpStream,
+    close_on_drop: bool,
+}
+
+impl KcpWriteHalf {
+    #[inline]
+    pub fn get_stream_id(&self) -> u16 {
+        self.stream.stream_id
+    }
+
+    pub async fn request_retransmit(&self) -> bool {
+        self.stream.request_retransmit().await
+    }
+}
+
+impl fmt::Debug for KcpWriteHalf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KcpWriteHalf")
+            .field("stream_id", &self.stream.stream_id)
+            .finish()
+    }
+}
+
+impl Drop for KcpWriteHalf {
+    fn drop(&mut self) {
+        if !self.close_on_drop {
+            return;
+        }
+        smol::block_on(async {
+            let _ = self.stream.core.lock().await.try_close_write();
+        });
+    }
+}
+
+impl AsyncWrite for KcpWriteHalf {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    /// Sends FIN and waits until the peer acknowledged everything, the read half goes on
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let stream = &mut self.stream;
+        let mut core = ready!(KcpStream::lock_core(
+            cx,
+            stream.core.clone(),
+            &mut stream.close_lock_future,
+        ));
+        ready!(core.poll_close_write(cx))?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// What a handle carried over its life, for billing and auditing. `stats` includes the
+/// closed streams, see `KcpHandle::get_stats`.
+#[derive(Clone, Debug)]
+pub struct SessionSummary {
+    pub duration: Duration,
+    pub stats: KcpStats,
+}
+
+impl fmt::Display for SessionSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "duration {}ms, {} bytes in, {} bytes out, {} retransmits, peak rtt {}ms, peak cwnd {}",
+            self.duration.as_millis(),
+            self.stats.bytes_received,
+            self.stats.bytes_sent,
+            self.stats.segments_retransmitted,
+            self.stats.peak_rtt,
+            self.stats.peak_congestion_window
+        )
+    }
+}
+
+/// See `KcpStream::parameters`
+#[derive(Clone, Debug)]
+pub struct StreamParameters {
+    pub stream_id: u16,
+    /// As `KcpStream::effective_mtu`
+    pub mtu: usize,
+    pub send_window: u32,
+    /// The receive window most recently advertised by the peer
+    pub peer_window: u32,
+    /// Features supported by both sides
+    pub features: Features,
+}
+
+/// A stream accepted from the peer, along with how it was established
+pub struct AcceptedStream {
+    pub stream: KcpStream,
+    pub established_at: SystemTime,
+    /// Features supported by both sides
+    pub features: Features,
+    /// None if the io has no notion of addresses
+    pub peer_addr: Option<SocketAddr>,
+    pub label: Bytes,
+}
+
+/// Unreliable datagrams over a handle. They are encrypted and multiplexed like the streams,
+/// but never retransmitted or ordered. All `KcpDatagram`s of a handle share one incoming queue.
+pub struct KcpDatagram<IO> {
+    io: Arc<IO>,
+    rx: Receiver<(Bytes, Instant)>,
+    max_len: usize,
+    gate: Arc<FlowGate>,
+    single_stream: bool,
+}
+
+impl<IO: KcpIo + Send + Sync> KcpDatagram<IO> {
+    #[inline]
+    pub fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    pub async fn send(&self, data: &[u8]) -> KcpResult<()> {
+        if data.len() > self.max_len {
+            return Err(KcpError::DatagramTooLong(data.len()));
+        }
+        let segment = KcpSegment {
+            stream_id: 0,
+            command: CMD_DATAGRAM,
+            recv_window_size: 0,
+            timestamp: 0,
+            sequence: 0,
+            recv_next: 0,
+            data: Bytes::copy_from_slice(data),
+        };
+        let mut buf = BytesMut::with_capacity(segment.framed_len(self.single_stream));
+        segment.encode_framed(&mut buf, self.single_stream);
+        self.gate.wait_output().await;
+        self.io.send_packet(&buf).await?;
+        Ok(())
+    }
+
+    pub async fn recv(&self) -> KcpResult<Bytes> {
+        Ok(self.recv_timed().await?.0)
+    }
+
+    /// The next datagram and when its packet arrived, taken before decoding, so the time spent
+    /// in the queue doesn't count, e.g. for a jitter buffer
+    pub async fn recv_timed(&self) -> KcpResult<(Bytes, Instant)> {
+        self.rx.recv().await.map_err(|_| {
+            KcpError::Shutdown("receiving datagram but kcp handle is closed".to_string())
+        })
+    }
+
+    /// The datagrams with their arrival times like `recv_timed`, ending when the handle closes
+    pub fn incoming(&self) -> impl Stream<Item = (Bytes, Instant)> + Unpin {
+        self.rx.clone()
+    }
+}
+
+/// The delivery of a channel opened with `KcpHandle::connect_with_kind`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamKind {
+    /// An ordered stream, retransmitted until acked
+    Reliable,
+    /// Datagrams, neither retransmitted nor ordered
+    Datagram,
+}
+
+impl Default for StreamKind {
+    fn default() -> Self {
+        StreamKind::Reliable
+    }
+}
+
+/// A channel of either kind, see `StreamKind`
+pub enum KcpChannel<IO> {
+    Stream(KcpStream),
+    Datagram(KcpDatagram<IO>),
+}
+
+impl<IO> KcpChannel<IO> {
+    pub fn kind(&self) -> StreamKind {
+        match self {
+            KcpChannel::Stream(_) => StreamKind::Reliable,
+            KcpChannel::Datagram(_) => StreamKind::Datagram,
+        }
+    }
+
+    pub fn into_stream(self) -> Option<KcpStream> {
+        match self {
+            KcpChannel::Stream(stream) => Some(stream),
+            KcpChannel::Datagram(_) => None,
+        }
+    }
+
+    pub fn into_datagram(self) -> Option<KcpDatagram<IO>> {
+        match self {
+            KcpChannel::Stream(_) => None,
+            KcpChannel::Datagram(datagram) => Some(datagram),
+        }
+    }
+}
+
 struct KcpSession {
     core: Arc<Mutex<KcpCore>>,
+    // Where the stream's packets go and come from, when the io has several peers
+    peer: Option<SocketAddr>,
     _update_task: Task<KcpResult<()>>,
 }
 
-pub struct KcpHandle<T> {
+/// The io of one stream, sending to its own peer on a handle with several, see
+/// `KcpHandle::connect_to`. Only the handle receives.
+struct PeerIo<IO> {
+    io: Arc<IO>,
+    peer: Option<SocketAddr>,
+}
+
+#[async_trait::async_trait]
+impl<IO: KcpIo + Send + Sync> KcpIo for PeerIo<IO> {
+    async fn send_packet(&self, buf: &[u8]) -> std::io::Result<()> {
+        match self.peer {
+            Some(peer) => self.io.send_packet_to(buf, peer).await,
+            None => self.io.send_packet(buf).await,
+        }
+    }
+
+    async fn recv_packet(&self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        unreachable!("streams don't receive from the io")
+    }
+
+    fn overhead(&self) -> usize {
+        self.io.overhead()
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer.or_else(|| self.io.peer_addr())
+    }
+}
+
+/// Holds the sending and the receiving of a handle, see `KcpHandle::pause_output`
+#[derive(Default)]
+struct FlowGate {
+    output_paused: AtomicBool,
+    input_paused: AtomicBool,
+    resumed: Event,
+}
+
+impl FlowGate {
+    async fn wait(&self, paused: &AtomicBool) {
+        loop {
+            if !paused.load(Ordering::Acquire) {
+                return;
+            }
+            let listener = self.resumed.listen();
+            // Resumed before listening
+            if !paused.load(Ordering::Acquire) {
+                return;
+            }
+            listener.await;
+        }
+    }
+
+    async fn wait_output(&self) {
+        self.wait(&self.output_paused).await
+    }
+
+    async fn wait_input(&self) {
+        self.wait(&self.input_paused).await
+    }
+
+    fn set(&self, paused: &AtomicBool, value: bool) {
+        paused.store(value, Ordering::Release);
+        if !value {
+            self.resumed.notify(usize::MAX);
+        }
+    }
+}
+
+/// What the task reading the socket shares with its handle, see `KcpHandle::feed_packet`
+struct FeedContext<IO> {
     sessions: Arc<Mutex<HashMap<u16, KcpSession>>>,
     config: Arc<KcpConfig>,
-    accept_rx: Receiver<KcpStream>,
+    accept_config: Arc<Mutex<Arc<KcpConfig>>>,
+    session_deadline: Option<u32>,
+    io: Arc<IO>,
+    accept_tx: Sender<AcceptedStream>,
+    datagram_tx: Sender<(Bytes, Instant)>,
+    dead_tx: Sender<u16>,
+    congestion: SharedCongestion,
+    rate_limiter: Option<SharedRateLimiter>,
+    idle_event: Arc<Event>,
+    gate: Arc<FlowGate>,
+    draining: Arc<AtomicBool>,
+    unknown_stream_segments: Arc<AtomicU64>,
+    conn_id: u64,
+    span: Span,
+}
+
+pub struct KcpHandle<T> {
+    sessions: Arc<Mutex<HashMap<u16, KcpSession>>>,
+    // Replaced by `reconfigure`
+    config: RwLock<Arc<KcpConfig>>,
+    created_at: u32,
+    // The config of new streams, they change with `reconfigure`
+    connect_config: Mutex<Arc<KcpConfig>>,
+    accept_config: Arc<Mutex<Arc<KcpConfig>>>,
+    session_deadline: Option<u32>,
+    accept_rx: Receiver<AcceptedStream>,
+    datagram_rx: Receiver<(Bytes, Instant)>,
     dead_tx: Sender<u16>,
     io: Arc<T>,
+    congestion: SharedCongestion,
+    rate_limiter: Option<SharedRateLimiter>,
+    closed_stats: Arc<Mutex<KcpStats>>,
+    // Counted by the task reading the socket, which holds the sessions lock meanwhile
+    unknown_stream_segments: Arc<AtomicU64>,
+    idle_event: Arc<Event>,
+    gate: Arc<FlowGate>,
+    draining: Arc<AtomicBool>,
+    conn_id: u64,
+    // The `session` span of the `tracing` feature, the parent of the stream spans
+    span: Span,
     _feed_packet_task: Task<KcpResult<()>>,
     _clean_task: Task<KcpResult<()>>,
 }
@@ -162,6 +846,7 @@ impl<T> Drop for KcpHandle<T> {
     fn drop(&mut self) {
         smol::block_on(async move {
             self.accept_rx.close();
+            self.datagram_rx.close();
             let sessions = self.sessions.lock().await;
             for (_, session) in sessions.iter() {
                 let _ = session.core.lock().await.force_close();
@@ -172,12 +857,250 @@ impl<T> Drop for KcpHandle<T> {
 }
 
 impl<IO: KcpIo + Send + Sync + 'static> KcpHandle<IO> {
+    /// The address of the peer, if the io knows it
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.io.peer_addr()
+    }
+
+    /// The handle's config, the latest one passed to `reconfigure`
+    pub fn config(&self) -> Arc<KcpConfig> {
+        self.config.read().unwrap().clone()
+    }
+
     pub async fn get_stream_count(&self) -> usize {
         self.sessions.lock().await.len()
     }
 
-    async fn find_new_stream_id(&self) -> KcpResult<u16> {
+    /// Statistics of all streams on this handle, including the closed ones.
+    pub async fn get_stats(&self) -> KcpStats {
+        // A stream is moved to `closed_stats` under the sessions lock, so it's counted once
         let sessions = self.sessions.lock().await;
+        let mut stats = self.closed_stats.lock().await.clone();
+        stats.unknown_stream_segments += self.unknown_stream_segments.load(Ordering::Relaxed);
+        for session in sessions.values() {
+            stats.accumulate(&session.core.lock().await.get_stats());
+        }
+        stats
+    }
+
+    /// The statistics so far and how long the handle has lived. `shutdown` logs it.
+    pub async fn session_summary(&self) -> SessionSummary {
+        let now = self.config().clock.now_millis();
+        SessionSummary {
+            duration: Duration::from_millis(now.wrapping_sub(self.created_at) as u64),
+            stats: self.get_stats().await,
+        }
+    }
+
+    /// Evict a peer, e.g. to ban it: every stream of `peer` is reset with `code` and `reason`
+    /// and removed from the handle, so the peer's streams fail with `KcpError::PeerReset`
+    /// rather than by timeout. The streams are matched by their peer address, see
+    /// `connect_to`, on an io without addresses None matches all of them. Returns how many
+    /// streams were closed.
+    pub async fn close_session(
+        &self,
+        peer: Option<SocketAddr>,
+        code: u32,
+        reason: &str,
+    ) -> KcpResult<usize> {
+        if reason.len() > MAX_RESET_REASON_LEN {
+            return Err(KcpError::ReasonTooLong(reason.len()));
+        }
+        let evicted: Vec<KcpSession> = {
+            let mut sessions = self.sessions.lock().await;
+            let stream_ids: Vec<u16> = sessions
+                .iter()
+                .filter(|(_, session)| session.peer.or_else(|| self.io.peer_addr()) == peer)
+                .map(|(stream_id, _)| *stream_id)
+                .collect();
+            let evicted: Vec<_> = stream_ids
+                .iter()
+                .filter_map(|stream_id| sessions.remove(stream_id))
+                .collect();
+            // Counted under the sessions lock like in `clean`, the queues go with the streams
+            for session in evicted.iter() {
+                let stats = session.core.lock().await.get_stats();
+                self.closed_stats.lock().await.accumulate(&stats.retired());
+            }
+            if !evicted.is_empty() && sessions.is_empty() {
+                self.idle_event.notify(usize::MAX);
+            }
+            evicted
+        };
+        for session in evicted.iter() {
+            let mut core = session.core.lock().await;
+            core.reset(code, reason);
+            // The update task goes with the session, so the RESET is sent right here
+            let io = PeerIo {
+                io: self.io.clone(),
+                peer: session.peer,
+            };
+            let _ = core.flush(&io).await;
+        }
+        log::info!("evicted {} streams of {:?}", evicted.len(), peer);
+        Ok(evicted.len())
+    }
+
+    /// Refuse the streams the peer opens from now on, the open ones go on. Unlike
+    /// `shutdown`, streams already accepted are still returned by `accept`.
+    pub fn stop_accepting(&self) {
+        self.draining.store(true, Ordering::Release);
+    }
+
+    /// Reset every open stream, see `KcpStream::reset_with`
+    pub async fn reset_all(&self, code: u32, reason: &str) -> KcpResult<()> {
+        if reason.len() > MAX_RESET_REASON_LEN {
+            return Err(KcpError::ReasonTooLong(reason.len()));
+        }
+        for session in self.sessions.lock().await.values() {
+            session.core.lock().await.reset(code, reason);
+        }
+        Ok(())
+    }
+
+    /// Stop accepting new streams and close all streams gracefully.
+    /// Streams still open after `config.timeout` are force closed.
+    pub async fn shutdown(&self) {
+        self.accept_rx.close();
+        for session in self.sessions.lock().await.values() {
+            let _ = session.core.lock().await.try_close();
+        }
+        let config = self.config();
+        let timeout = async {
+            config
+                .clock
+                .sleep(Duration::from_millis(config.timeout as u64))
+                .await;
+        };
+        self.wait_idle(None).or(timeout).await;
+        for session in self.sessions.lock().await.values() {
+            session.core.lock().await.force_close();
+        }
+        log::info!("kcp session ended: {}", self.session_summary().await);
+    }
+
+    /// Resolves once no stream is left on the handle.
+    /// With a grace period, the handle must also stay idle that long, a stream opened
+    /// meanwhile (by either side) restarts the wait.
+    pub async fn wait_idle(&self, grace: Option<Duration>) {
+        loop {
+            // Listen before checking, so that a removal in between is not missed
+            let listener = self.idle_event.listen();
+            if self.get_stream_count().await > 0 {
+                listener.await;
+                continue;
+            }
+            match grace {
+                None => return,
+                Some(grace) => {
+                    self.config().clock.sleep(grace).await;
+                    if self.get_stream_count().await == 0 {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    fn remove_session(
+        sessions: &mut HashMap<u16, KcpSession>,
+        idle_event: &Event,
+        stream_id: u16,
+    ) -> Option<KcpSession> {
+        let session = sessions.remove(&stream_id);
+        if session.is_some() && sessions.is_empty() {
+            idle_event.notify(usize::MAX);
+        }
+        session
+    }
+
+    fn stream_congestion(
+        config: &KcpConfig,
+        congestion: &SharedCongestion,
+    ) -> Option<SharedCongestion> {
+        if config.per_stream_cc {
+            None
+        } else {
+            Some(congestion.clone())
+        }
+    }
+
+    /// Checks a per-stream override against the handle's config
+    fn check_stream_config(&self, config: &KcpConfig) -> KcpResult<()> {
+        config.validate()?;
+        let handle_config = self.config();
+        if config.mtu > handle_config.mtu {
+            return Err(KcpError::InvalidConfig(format!(
+                "stream mtu {} exceeds the handle mtu {}",
+                config.mtu, handle_config.mtu
+            )));
+        }
+        if config.mtu <= KCP_HEADER_LEN + self.io.overhead() {
+            return Err(KcpError::InvalidConfig(format!(
+                "mtu {} is too small for the io overhead {}",
+                config.mtu,
+                self.io.overhead()
+            )));
+        }
+        if config.single_stream != handle_config.single_stream {
+            return Err(KcpError::InvalidConfig(
+                "single_stream of a stream must be the handle's".to_string(),
+            ));
+        }
+        if config.per_stream_keys != handle_config.per_stream_keys {
+            return Err(KcpError::InvalidConfig(
+                "per_stream_keys of a stream must be the handle's".to_string(),
+            ));
+        }
+        if config.initial_sequence != handle_config.initial_sequence {
+            return Err(KcpError::InvalidConfig(
+                "initial_sequence of a stream must be the handle's".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Use `config` instead of the handle's config for the streams accepted from now on.
+    /// See `KcpConfig` for the parameters which may differ per stream.
+    pub async fn set_accept_config(&self, config: KcpConfig) -> KcpResult<()> {
+        self.check_stream_config(&config)?;
+        *self.accept_config.lock().await = Arc::new(config);
+        Ok(())
+    }
+
+    /// Applies `config` to the running handle: to the open streams, including the ones which
+    /// had their own config, and to the streams opened or accepted from now on. Sessions are
+    /// kept. Intervals, windows, timeouts, the RTO bounds and the `max_send_bps` cap change
+    /// live, `max_stream_lifetime` only for new streams.
+    ///
+    /// What the segments or the peer depend on is rejected, and nothing is applied: the mtu
+    /// and segment sizes, `per_stream_cc`, the features, ECN, the session lifetime, and
+    /// turning the cap on or off. The crypto of the io layers is not part of the config,
+    /// it never changes.
+    pub async fn reconfigure(&self, config: KcpConfig) -> KcpResult<()> {
+        self.config().check_reconfigure(&config)?;
+        let config = Arc::new(config);
+        *self.config.write().unwrap() = config.clone();
+        if let Some(limiter) = &self.rate_limiter {
+            limiter.lock().unwrap().reconfigure(&config);
+        }
+        *self.connect_config.lock().await = config.clone();
+        *self.accept_config.lock().await = config.clone();
+        for session in self.sessions.lock().await.values() {
+            session.core.lock().await.reconfigure(config.clone());
+        }
+        Ok(())
+    }
+
+    fn find_new_stream_id(&self, sessions: &HashMap<u16, KcpSession>) -> KcpResult<u16> {
+        if self.config().single_stream {
+            // The header has no stream id, every segment is of stream 0
+            return if sessions.is_empty() {
+                Ok(0)
+            } else {
+                Err(KcpError::TooManyStreams)
+            };
+        }
         if sessions.len() == 0xffff {
             return Err(KcpError::TooManyStreams);
         }
@@ -193,35 +1116,165 @@ impl<IO: KcpIo + Send + Sync + 'static> KcpHandle<IO> {
         Err(KcpError::TooManyStreams)
     }
 
+    pub fn open_datagram(&self) -> KcpDatagram<IO> {
+        let config = self.config();
+        KcpDatagram {
+            io: self.io.clone(),
+            rx: self.datagram_rx.clone(),
+            max_len: config.mtu - self.io.overhead() - KcpSegment::header_len(config.single_stream),
+            gate: self.gate.clone(),
+            single_stream: config.single_stream,
+        }
+    }
+
+    /// Stops sending packets, as if the link went down. Streams keep accepting writes until
+    /// their queues are full, nothing is lost, and the timers only run again once resumed.
+    pub fn pause_output(&self) {
+        self.gate.set(&self.gate.output_paused, true);
+    }
+
+    /// Sends the backlog queued while paused
+    pub fn resume_output(&self) {
+        self.gate.set(&self.gate.output_paused, false);
+    }
+
+    /// Stops reading packets from the io, they wait in its buffers or get dropped there
+    pub fn pause_input(&self) {
+        self.gate.set(&self.gate.input_paused, true);
+    }
+
+    pub fn resume_input(&self) {
+        self.gate.set(&self.gate.input_paused, false);
+    }
+
+    /// Open a channel of the given kind. `connect` is the same for a reliable stream.
+    /// Datagrams need `Features::DATAGRAM` in the handle's config.
+    pub async fn connect_with_kind(&self, kind: StreamKind) -> KcpResult<KcpChannel<IO>> {
+        match kind {
+            StreamKind::Reliable => self.connect().await.map(KcpChannel::Stream),
+            StreamKind::Datagram => {
+                if !self.config().features.contains(Features::DATAGRAM) {
+                    return Err(KcpError::InvalidConfig(
+                        "datagrams are not in the features, the handle is reliable-only"
+                            .to_string(),
+                    ));
+                }
+                Ok(KcpChannel::Datagram(self.open_datagram()))
+            }
+        }
+    }
+
+    /// Open a stream. There is no handshake: the OPEN segment goes out along with the first
+    /// data, and the keys of the crypto layer are pre-shared, so every connect is already 0-RTT.
+    /// Until the peer acks it, the OPEN is retransmitted every `KcpConfig::handshake_interval`,
+    /// and the stream fails with `KcpError::HandshakeTimeout` after `handshake_retries`.
     pub async fn connect(&self) -> KcpResult<KcpStream> {
-        let stream_id = self.find_new_stream_id().await?;
+        self.connect_with_label(&[]).await
+    }
+
+    /// Open a stream carrying a label, the peer reads it from the accepted stream.
+    /// The label travels within the first segment, no extra round trip is needed.
+    pub async fn connect_with_label(&self, label: &[u8]) -> KcpResult<KcpStream> {
+        if label.len() > MAX_LABEL_LEN {
+            return Err(KcpError::LabelTooLong(label.len()));
+        }
+        let config = self.connect_config.lock().await.clone();
+        self.connect_stream(Bytes::copy_from_slice(label), config, None)
+            .await
+    }
+
+    /// Open a stream with its own config instead of the handle's.
+    /// See `KcpConfig` for the parameters which may differ per stream.
+    pub async fn connect_with_config(&self, config: KcpConfig) -> KcpResult<KcpStream> {
+        self.check_stream_config(&config)?;
+        self.connect_stream(Bytes::new(), Arc::new(config), None)
+            .await
+    }
+
+    /// Open a stream to `addr`, for a handle over an unconnected socket talking to several
+    /// peers, e.g. a mesh. The packets of every stream are matched by their source address,
+    /// and the streams accepted from a peer answer to it. Stream ids are random and shared
+    /// by all peers, the OPEN of a peer colliding with a live stream is dropped, and retried
+    /// by the peer. The io must know the source addresses, so `KcpConfig::ecn` can't be used,
+    /// and `open_datagram` only works on a connected io.
+    pub async fn connect_to(&self, addr: SocketAddr) -> KcpResult<KcpStream> {
+        let config = self.connect_config.lock().await.clone();
+        self.connect_stream(Bytes::new(), config, Some(addr)).await
+    }
+
+    async fn connect_stream(
+        &self,
+        label: Bytes,
+        config: Arc<KcpConfig>,
+        peer: Option<SocketAddr>,
+    ) -> KcpResult<KcpStream> {
+        if let Some(deadline) = self.session_deadline {
+            if i32diff(self.config().clock.now_millis(), deadline) >= 0 {
+                return Err(KcpError::LifetimeExpired);
+            }
+        }
+        // The id is taken and its session inserted under one lock, or concurrent connects
+        // could pick the same id
+        let mut sessions = self.sessions.lock().await;
+        let stream_id = self.find_new_stream_id(&sessions)?;
         let (tx, rx) = bounded(1);
-        let core = Arc::new(Mutex::new(KcpCore::new(stream_id, self.config.clone(), tx)));
-        let stream = KcpStream {
-            core: core.clone(),
-            read_buffer: None,
-            recv_lock_future: None,
-            send_lock_future: None,
-            flush_lock_future: None,
-            close_lock_future: None,
+        let mut core = KcpCore::new(
+            stream_id,
+            config,
+            tx,
+            Self::stream_congestion(&self.config(), &self.congestion),
+            self.io.overhead(),
+        );
+        if let Some(deadline) = self.session_deadline {
+            core.limit_lifetime(deadline);
+        }
+        if let Some(limiter) = &self.rate_limiter {
+            core.limit_rate(limiter.clone());
+        }
+        core.open(label.clone());
+        let core = Arc::new(Mutex::new(core));
+        let stream = KcpStream::new(core.clone(), stream_id, label);
+        let span = spans::stream(
+            &self.span,
+            self.conn_id,
+            stream_id,
+            peer.or_else(|| self.io.peer_addr()),
+        );
+        let io = PeerIo {
+            io: self.io.clone(),
+            peer,
         };
-        let _update_task = smol::spawn(Self::update(
-            core.clone(),
-            self.io.clone(),
-            rx,
-            self.dead_tx.clone(),
-        ));
-        self.sessions
-            .lock()
-            .await
-            .insert(stream_id, KcpSession { core, _update_task });
+        let _update_task = smol::spawn(
+            Self::update(
+                core.clone(),
+                io,
+                rx,
+                self.dead_tx.clone(),
+                self.gate.clone(),
+            )
+            .instrument(span),
+        );
+        sessions.insert(
+            stream_id,
+            KcpSession {
+                core,
+                peer,
+                _update_task,
+            },
+        );
         Ok(stream)
     }
 
     pub async fn accept(&self) -> KcpResult<KcpStream> {
+        self.accept_with_metadata()
+            .await
+            .map(|accepted| accepted.stream)
+    }
+
+    pub async fn accept_with_metadata(&self) -> KcpResult<AcceptedStream> {
         match self.accept_rx.recv().await {
-            Ok(stream) => {
-                return Ok(stream);
+            Ok(accepted) => {
+                return Ok(accepted);
             }
             Err(_) => {
                 return Err(KcpError::Shutdown(
@@ -233,6 +1286,8 @@ impl<IO: KcpIo + Send + Sync + 'static> KcpHandle<IO> {
 
     async fn clean(
         sessions: Arc<Mutex<HashMap<u16, KcpSession>>>,
+        closed_stats: Arc<Mutex<KcpStats>>,
+        idle_event: Arc<Event>,
         dead_rx: Receiver<u16>,
     ) -> KcpResult<()> {
         loop {
@@ -240,21 +1295,30 @@ impl<IO: KcpIo + Send + Sync + 'static> KcpHandle<IO> {
                 .recv()
                 .await
                 .map_err(|_| KcpError::Shutdown("cleaning but kcp handle is closed".to_string()))?;
-            sessions.lock().await.remove(&stream_id);
+            // Counted before the sessions lock is released, or the summary taken once the
+            // handle is idle could miss the stream
+            let mut sessions = sessions.lock().await;
+            if let Some(session) = Self::remove_session(&mut sessions, &idle_event, stream_id) {
+                let stats = session.core.lock().await.get_stats();
+                closed_stats.lock().await.accumulate(&stats.retired());
+            }
             log::trace!("cleaning {}", stream_id);
         }
     }
 
     async fn update(
         core: Arc<Mutex<KcpCore>>,
-        io: Arc<IO>,
+        io: PeerIo<IO>,
         flush_notify_rx: Receiver<()>,
         dead_tx: Sender<u16>,
+        gate: Arc<FlowGate>,
     ) -> KcpResult<()> {
+        let clock = core.lock().await.config.clock.clone();
         loop {
+            gate.wait_output().await;
             let interval = {
                 let mut core = core.lock().await;
-                if let Err(e) = core.flush(&*io).await {
+                if let Err(e) = core.flush(&io).await {
                     log::error!("flush error: {}", e);
                     let _ = dead_tx.send(core.get_stream_id()).await;
                     return Err(KcpError::Shutdown(
@@ -263,56 +1327,126 @@ impl<IO: KcpIo + Send + Sync + 'static> KcpHandle<IO> {
                 }
                 core.get_interval()
             };
-            let delay = Delay::new(Duration::from_millis(interval as u64));
             let notify = async {
                 let _ = flush_notify_rx.recv().await;
                 log::trace!("wake up now!");
             };
-            let tick = async move {
-                delay.await;
-            };
+            let tick = clock.sleep(Duration::from_millis(interval as u64));
 
             notify.race(tick).await;
         }
     }
 
-    async fn feed_packet(
-        sessions: Arc<Mutex<HashMap<u16, KcpSession>>>,
-        config: Arc<KcpConfig>,
-        io: Arc<IO>,
-        accept_tx: Sender<KcpStream>,
-        dead_tx: Sender<u16>,
-    ) -> KcpResult<()> {
+    /// Tells the peer to give up `stream_id`, which the handle doesn't know
+    async fn reset_unknown(io: &IO, config: &KcpConfig, stream_id: u16, peer: Option<SocketAddr>) {
+        let reason = b"unknown stream";
+        let mut data = BytesMut::with_capacity(4 + reason.len());
+        data.put_u32_le(UNKNOWN_STREAM_RESET_CODE);
+        data.put_slice(reason);
+        let segment = KcpSegment {
+            stream_id,
+            command: CMD_RESET,
+            recv_window_size: 0,
+            recv_next: 0,
+            sequence: 0,
+            timestamp: config.clock.now_millis(),
+            data: data.freeze(),
+        };
+        let mut buffer = BytesMut::with_capacity(segment.framed_len(config.single_stream));
+        segment.encode_framed(&mut buffer, config.single_stream);
+        let sent = match peer {
+            Some(peer) => io.send_packet_to(&buffer, peer).await,
+            None => io.send_packet(&buffer).await,
+        };
+        if let Err(e) = sent {
+            log::debug!("failed to reset unknown stream {}: {}", stream_id, e);
+        }
+    }
+
+    async fn feed_packet(context: FeedContext<IO>) -> KcpResult<()> {
+        let FeedContext {
+            sessions,
+            config,
+            accept_config,
+            session_deadline,
+            io,
+            accept_tx,
+            datagram_tx,
+            dead_tx,
+            congestion,
+            rate_limiter,
+            idle_event,
+            gate,
+            draining,
+            unknown_stream_segments,
+            conn_id,
+            span,
+        } = context;
         let mut buf = Vec::new();
         buf.resize(2 * config.mtu, 0);
         loop {
-            let size = io.recv_packet(&mut buf).await?;
-            if size < HEADER_SIZE {
+            gate.wait_input().await;
+            let received = if config.ecn {
+                io.recv_packet_ecn(&mut buf)
+                    .await
+                    .map(|(size, ce)| (size, ce, None))
+            } else {
+                io.recv_packet_from(&mut buf)
+                    .await
+                    .map(|(size, source)| (size, false, source))
+            };
+            let arrived = Instant::now();
+            let (size, ce, source) = match received {
+                Ok(received) => received,
+                Err(e) => {
+                    // No more packets, all streams are dead
+                    let e = KcpError::from(e);
+                    for session in sessions.lock().await.values() {
+                        let mut core = session.core.lock().await;
+                        match e {
+                            KcpError::DecryptFailureThreshold(failures) => {
+                                core.fail_decrypt(failures)
+                            }
+                            _ => core.force_close(),
+                        }
+                    }
+                    return Err(e);
+                }
+            };
+            if size == 0 {
+                // Dropped by a layer below, e.g. failed to decrypt
+                continue;
+            }
+            if size < KcpSegment::header_len(config.single_stream) {
                 log::error!("short packet length {}", size);
                 continue;
             }
 
-            let stream_id = KcpSegment::peek_stream_id(&buf[..size]);
+            let stream_id = if config.single_stream {
+                0
+            } else {
+                KcpSegment::peek_stream_id(&buf[..size])
+            };
             let mut packet = &buf[..size];
             let mut segments = Vec::new();
             let mut is_invalid_packet = false;
             let mut new_stream = false;
 
             while packet.has_remaining() {
-                match KcpSegment::decode(&packet) {
+                match KcpSegment::decode_framed(&packet, config.single_stream) {
                     Ok(segment) => {
                         if segment.stream_id != stream_id {
                             is_invalid_packet = true;
                             log::error!("invalid packet format");
                             break;
                         }
-                        // First push or ping
-                        if (segment.command == CMD_PUSH || segment.command == CMD_PING)
-                            && segment.sequence == 0
+                        // The answering OPEN already acks the connecting one
+                        if segment.command == CMD_OPEN
+                            && segment.recv_next == config.initial_sequence
                         {
                             new_stream = true;
                         }
-                        packet.advance(segment.encoded_len());
+                        packet.advance(segment.framed_len(config.single_stream));
                         segments.push(segment);
                     }
                     Err(e) => {
@@ -327,27 +1461,85 @@ impl<IO: KcpIo + Send + Sync + 'static> KcpHandle<IO> {
                 continue;
             }
 
+            // Datagrams belong to no stream
+            segments.retain(|segment| {
+                if segment.command != CMD_DATAGRAM {
+                    return true;
+                }
+                if !config.features.contains(Features::DATAGRAM) {
+                    log::trace!("reliable-only handle, dropping datagram");
+                } else if datagram_tx
+                    .try_send((segment.data.clone(), arrived))
+                    .is_err()
+                {
+                    log::trace!("datagram queue is full, dropping");
+                }
+                false
+            });
+            if segments.is_empty() {
+                continue;
+            }
+
             let mut is_new_stream = false;
 
             let core = {
                 let mut sessions = sessions.lock().await;
 
                 if let Some(session) = sessions.get_mut(&stream_id) {
+                    if let (Some(peer), Some(source)) = (session.peer, source) {
+                        if peer != source {
+                            log::trace!("stream {} is not of {}, dropping", stream_id, source);
+                            continue;
+                        }
+                    }
                     session.core.clone()
                 } else {
+                    if new_stream && draining.load(Ordering::Acquire) {
+                        // The peer's stream times out, like a connect to a closed port
+                        log::info!("draining, refusing stream {}", stream_id);
+                        continue;
+                    }
                     if new_stream {
                         let (tx, rx) = bounded(1);
-                        let core =
-                            Arc::new(Mutex::new(KcpCore::new(stream_id, config.clone(), tx)));
+                        let stream_config = accept_config.lock().await.clone();
+                        let mut core = KcpCore::new(
+                            stream_id,
+                            stream_config,
+                            tx,
+                            Self::stream_congestion(&config, &congestion),
+                            io.overhead(),
+                        );
+                        if let Some(deadline) = session_deadline {
+                            core.limit_lifetime(deadline);
+                        }
+                        if let Some(limiter) = &rate_limiter {
+                            core.limit_rate(limiter.clone());
+                        }
+                        let core = Arc::new(Mutex::new(core));
+                        // Answer to the source, unless the io is connected to it anyway
+                        let peer = source.filter(|source| io.peer_addr() != Some(*source));
                         let update_task = {
                             let core = core.clone();
-                            let io = io.clone();
-                            smol::spawn(Self::update(core, io, rx, dead_tx.clone()))
+                            let stream_span = spans::stream(
+                                &span,
+                                conn_id,
+                                stream_id,
+                                source.or_else(|| io.peer_addr()),
+                            );
+                            let io = PeerIo {
+                                io: io.clone(),
+                                peer,
+                            };
+                            smol::spawn(
+                                Self::update(core, io, rx, dead_tx.clone(), gate.clone())
+                                    .instrument(stream_span),
+                            )
                         };
                         sessions.insert(
                             stream_id,
                             KcpSession {
                                 core: core.clone(),
+                                peer,
                                 _update_task: update_task,
                             },
                         );
@@ -355,58 +1547,152 @@ impl<IO: KcpIo + Send + Sync + 'static> KcpHandle<IO> {
                         log::trace!("new kcp stream");
                         core
                     } else {
-                        log::error!("unknown stream_id {}", stream_id);
+                        log::debug!("unknown stream_id {}, dropping", stream_id);
+                        unknown_stream_segments.fetch_add(segments.len() as u64, Ordering::Relaxed);
+                        // Only what the peer retransmits is answered, never a reset
+                        let retransmitted = segments.iter().any(|segment| {
+                            [CMD_PUSH, CMD_SKIP, CMD_HALF_CLOSE, CMD_FRAGMENT]
+                                .contains(&segment.command)
+                        });
+                        if config.reset_unknown_streams && retransmitted {
+                            let peer = source.filter(|source| io.peer_addr() != Some(*source));
+                            Self::reset_unknown(&io, &config, stream_id, peer).await;
+                        }
                         continue;
                     }
                 }
             };
 
+            {
+                let mut core = core.lock().await;
+                if core.input(segments).is_err() {
+                    drop(core);
+                    Self::remove_session(&mut *sessions.lock().await, &idle_event, stream_id);
+                    log::trace!("removing dead link")
+                } else if ce {
+                    core.input_ce();
+                }
+            }
+
             if is_new_stream {
-                let stream = KcpStream {
-                    core: core.clone(),
-                    read_buffer: None,
-                    recv_lock_future: None,
-                    send_lock_future: None,
-                    flush_lock_future: None,
-                    close_lock_future: None,
+                // The OPEN segment has been handled, so the label is ready
+                let (label, features) = {
+                    let mut core = core.lock().await;
+                    if core.is_auth_failed() {
+                        // The reset is on its way, then the update task removes the stream
+                        log::warn!("refusing stream {}, peer authentication failed", stream_id);
+                        continue;
+                    }
+                    let label = core.get_label();
+                    // Answer with our own OPEN, so that the peer learns our features
+                    core.open(label.clone());
+                    (label, core.get_features())
+                };
+                let accepted = AcceptedStream {
+                    stream: KcpStream::new(core.clone(), stream_id, label.clone()),
+                    established_at: SystemTime::now(),
+                    features,
+                    peer_addr: source.or_else(|| io.peer_addr()),
+                    label,
                 };
-                if accept_tx.send(stream).await.is_err() {
+                if accept_tx.send(accepted).await.is_err() {
                     log::error!("kcp handle closed");
                     return Ok(());
                 };
             }
-
-            if core.lock().await.input(segments).is_err() {
-                sessions.lock().await.remove(&stream_id);
-                log::trace!("removing dead link")
-            };
         }
     }
 
     pub fn new(io: IO, config: KcpConfig) -> Self {
+        config.validate().expect("invalid kcp config");
+        assert!(
+            config.mtu > KCP_HEADER_LEN + io.overhead(),
+            "mtu {} is too small for the io overhead {}",
+            config.mtu,
+            io.overhead()
+        );
+        assert!(
+            !config.per_stream_keys || io.set_per_stream_keys(true),
+            "the io doesn't support per_stream_keys"
+        );
+        let conn_id = NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed);
+        let span = spans::session(conn_id, io.peer_addr());
         let io = Arc::new(io);
+        let created_at = config.clock.now_millis();
         let config = Arc::new(config);
+        let connect_config = Mutex::new(config.clone());
+        let accept_config = Arc::new(Mutex::new(config.clone()));
+        let session_deadline = config.max_session_lifetime.map(|lifetime| {
+            config
+                .clock
+                .now_millis()
+                .wrapping_add(lifetime.as_millis() as u32)
+        });
         let sessions = Arc::new(Mutex::new(HashMap::<u16, KcpSession>::new()));
+        let congestion = CongestionState::shared(&config);
+        let rate_limiter = RateLimiter::shared(&config);
+        let closed_stats = Arc::new(Mutex::new(KcpStats::default()));
+        let unknown_stream_segments = Arc::new(AtomicU64::new(0));
+        let idle_event = Arc::new(Event::new());
+        let gate = Arc::new(FlowGate::default());
+        let draining = Arc::new(AtomicBool::new(false));
 
         let (accept_tx, accept_rx) = bounded(0x10);
+        let (datagram_tx, datagram_rx) = bounded(0x100);
         let (dead_tx, dead_rx) = bounded(0x10);
 
         // The only task reading the socket
-        let _feed_packet_task = smol::spawn(Self::feed_packet(
-            sessions.clone(),
-            config.clone(),
-            io.clone(),
-            accept_tx,
-            dead_tx.clone(),
-        ));
+        let _feed_packet_task = smol::spawn(
+            Self::feed_packet(FeedContext {
+                sessions: sessions.clone(),
+                config: config.clone(),
+                accept_config: accept_config.clone(),
+                session_deadline,
+                io: io.clone(),
+                accept_tx,
+                datagram_tx,
+                dead_tx: dead_tx.clone(),
+                congestion: congestion.clone(),
+                rate_limiter: rate_limiter.clone(),
+                idle_event: idle_event.clone(),
+                gate: gate.clone(),
+                draining: draining.clone(),
+                unknown_stream_segments: unknown_stream_segments.clone(),
+                conn_id,
+                span: span.clone(),
+            })
+            .instrument(span.clone()),
+        );
 
-        let _clean_task = smol::spawn(Self::clean(sessions.clone(), dead_rx.clone()));
+        let _clean_task = smol::spawn(
+            Self::clean(
+                sessions.clone(),
+                closed_stats.clone(),
+                idle_event.clone(),
+                dead_rx.clone(),
+            )
+            .instrument(span.clone()),
+        );
 
         Self {
             sessions,
-            config,
+            config: RwLock::new(config),
+            created_at,
+            connect_config,
+            accept_config,
+            session_deadline,
             accept_rx,
+            datagram_rx,
             io,
+            congestion,
+            rate_limiter,
+            closed_stats,
+            unknown_stream_segments,
+            idle_event,
+            gate,
+            draining,
+            conn_id,
+            span,
             _feed_packet_task,
             _clean_task,
             dead_tx,
@@ -0,0 +1,120 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{ready, AsyncRead, AsyncWrite};
+use tokio::io::ReadBuf;
+
+use crate::KcpStream;
+
+/// Makes a `KcpStream` usable with tokio's io traits.
+///
+/// Only the io traits are bridged. The timers and tasks driving the stream still run on
+/// the crate's own smol runtime, which works regardless of the runtime polling the adapter.
+pub struct TokioKcpStream(KcpStream);
+
+impl TokioKcpStream {
+    pub fn new(stream: KcpStream) -> Self {
+        Self(stream)
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &KcpStream {
+        &self.0
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut KcpStream {
+        &mut self.0
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> KcpStream {
+        self.0
+    }
+}
+
+impl From<KcpStream> for TokioKcpStream {
+    fn from(stream: KcpStream) -> Self {
+        Self(stream)
+    }
+}
+
+impl tokio::io::AsyncRead for TokioKcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let stream = &mut self.get_mut().0;
+        let len = ready!(Pin::new(stream).poll_read(cx, buf.initialize_unfilled()))?;
+        buf.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl tokio::io::AsyncWrite for TokioKcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().0).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::RngCore;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::{test::get_udp_pair, KcpConfig, KcpHandle};
+
+    #[test]
+    fn tokio_copy() {
+        smol::block_on(async {
+            let (io1, io2) = get_udp_pair().await;
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+
+            let mut data = vec![0u8; 0x10000];
+            rand::thread_rng().fill_bytes(&mut data);
+            let sent = data.clone();
+            let sender = smol::spawn(async move {
+                let mut stream1 = TokioKcpStream::from(kcp1.connect().await.unwrap());
+                tokio::io::copy(&mut &sent[..], &mut stream1).await.unwrap();
+                stream1.shutdown().await.unwrap();
+            });
+
+            let mut stream2 = TokioKcpStream::from(kcp2.accept().await.unwrap());
+            let mut received = Vec::new();
+            tokio::io::copy(&mut stream2, &mut received).await.unwrap();
+            stream2.shutdown().await.unwrap();
+            sender.await;
+            assert_eq!(received, data);
+        });
+    }
+}
@@ -0,0 +1,233 @@
+use std::{io::Write, net::SocketAddr};
+
+use bytes::{BufMut, BytesMut};
+use flate2::{write::DeflateEncoder, Compression, Decompress, FlushDecompress, Status};
+
+use crate::core::KcpIo;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    None = 0,
+    Deflate = 1,
+    Zstd = 2,
+}
+
+impl Codec {
+    fn from_u8(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Codec::None),
+            1 => Some(Codec::Deflate),
+            2 => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+
+    fn compress(self, buf: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Codec::None => None,
+            Codec::Deflate => {
+                let mut encoder =
+                    DeflateEncoder::new(Vec::with_capacity(buf.len()), Compression::fast());
+                encoder.write_all(buf).ok()?;
+                encoder.finish().ok()
+            }
+            Codec::Zstd => zstd::block::compress(buf, 1).ok(),
+        }
+    }
+
+    fn decompress(self, input: &[u8], output: &mut [u8]) -> Option<usize> {
+        match self {
+            Codec::None => {
+                if input.len() > output.len() {
+                    return None;
+                }
+                output[..input.len()].copy_from_slice(input);
+                Some(input.len())
+            }
+            Codec::Deflate => {
+                let mut decompress = Decompress::new(false);
+                match decompress.decompress(input, output, FlushDecompress::Finish) {
+                    Ok(Status::StreamEnd) => Some(decompress.total_out() as usize),
+                    _ => None,
+                }
+            }
+            Codec::Zstd => zstd::block::decompress_to_buffer(input, output).ok(),
+        }
+    }
+}
+
+/// Compresses every packet with the codec. Packets which do not get smaller are sent as they are.
+/// Wrap it outside of `CryptoLayer`, so the payload gets compressed before the encryption.
+///
+/// | CODEC | PAYLOAD |
+///
+/// With `Codec::None` there's no codec header, the packets pass through unchanged, so that
+/// peers without compression still understand them. Both sides must therefore agree on
+/// whether to compress, though not on the codec.
+pub struct CompressionLayer<IO> {
+    io: IO,
+    codec: Codec,
+}
+
+impl<IO: KcpIo + Send + Sync> CompressionLayer<IO> {
+    pub fn wrap(io: IO, codec: Codec) -> Self {
+        Self { io, codec }
+    }
+}
+
+#[async_trait::async_trait]
+impl<IO: KcpIo + Send + Sync> KcpIo for CompressionLayer<IO> {
+    async fn send_packet(&self, buf: &[u8]) -> std::io::Result<()> {
+        self.io.send_packet(&self.pack(buf)).await
+    }
+
+    async fn recv_packet(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.io.recv_packet(buf).await?;
+        Ok(self.unpack(buf, len))
+    }
+
+    async fn recv_packet_ecn(&self, buf: &mut [u8]) -> std::io::Result<(usize, bool)> {
+        let (len, ce) = self.io.recv_packet_ecn(buf).await?;
+        Ok((self.unpack(buf, len), ce))
+    }
+
+    fn overhead(&self) -> usize {
+        match self.codec {
+            Codec::None => self.io.overhead(),
+            // The codec header
+            _ => self.io.overhead() + 1,
+        }
+    }
+
+    fn set_per_stream_keys(&self, enabled: bool) -> bool {
+        // The crypto below reads the stream id off the front of the packet, where a codec
+        // header and compressed data would be. Only the plain packets of `Codec::None` keep it.
+        self.codec == Codec::None && self.io.set_per_stream_keys(enabled)
+    }
+
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        self.io.peer_addr()
+    }
+
+    async fn send_packet_to(&self, buf: &[u8], addr: SocketAddr) -> std::io::Result<()> {
+        self.io.send_packet_to(&self.pack(buf), addr).await
+    }
+
+    async fn recv_packet_from(
+        &self,
+        buf: &mut [u8],
+    ) -> std::io::Result<(usize, Option<SocketAddr>)> {
+        let (len, addr) = self.io.recv_packet_from(buf).await?;
+        Ok((self.unpack(buf, len), addr))
+    }
+}
+
+impl<IO> CompressionLayer<IO> {
+    fn pack(&self, buf: &[u8]) -> BytesMut {
+        if self.codec == Codec::None {
+            return BytesMut::from(buf);
+        }
+        let mut packet = BytesMut::with_capacity(1 + buf.len());
+        match self.codec.compress(buf) {
+            Some(compressed) if compressed.len() < buf.len() => {
+                packet.put_u8(self.codec as u8);
+                packet.put_slice(&compressed);
+            }
+            _ => {
+                packet.put_u8(Codec::None as u8);
+                packet.put_slice(buf);
+            }
+        }
+        packet
+    }
+
+    /// Decompresses the packet of `len` bytes in place, 0 if it's malformed
+    fn unpack(&self, buf: &mut [u8], len: usize) -> usize {
+        if self.codec == Codec::None || len == 0 {
+            return len;
+        }
+        let size = match Codec::from_u8(buf[0]) {
+            Some(Codec::None) => {
+                buf.copy_within(1..len, 0);
+                Some(len - 1)
+            }
+            Some(codec) => {
+                let payload = buf[1..len].to_vec();
+                codec.decompress(&payload, buf)
+            }
+            None => None,
+        };
+        match size {
+            Some(size) => size,
+            None => {
+                log::error!("failed to decompress packet");
+                0
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::VecDeque, sync::Mutex};
+
+    use rand::RngCore;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct LoopbackIo {
+        packets: Mutex<VecDeque<Vec<u8>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl KcpIo for LoopbackIo {
+        async fn send_packet(&self, buf: &[u8]) -> std::io::Result<()> {
+            self.packets.lock().unwrap().push_back(buf.to_vec());
+            Ok(())
+        }
+
+        async fn recv_packet(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let packet = self.packets.lock().unwrap().pop_front().unwrap();
+            buf[..packet.len()].copy_from_slice(&packet);
+            Ok(packet.len())
+        }
+    }
+
+    #[test]
+    fn compression() {
+        smol::block_on(async {
+            let text = b"hello world! ".repeat(100);
+            let mut random = vec![0u8; text.len()];
+            rand::thread_rng().fill_bytes(&mut random);
+
+            for codec in [Codec::None, Codec::Deflate, Codec::Zstd].iter() {
+                let layer = CompressionLayer::wrap(LoopbackIo::default(), *codec);
+                let mut buf = vec![0u8; 0x1000];
+
+                // No header without a codec, the packets are what a peer without the
+                // layer sends
+                let header = if *codec == Codec::None { 0 } else { 1 };
+                assert_eq!(layer.overhead(), header);
+
+                layer.send_packet(&text).await.unwrap();
+                let sent = layer.io.packets.lock().unwrap()[0].clone();
+                if *codec == Codec::None {
+                    assert_eq!(sent, text);
+                } else {
+                    assert!(sent.len() < text.len() / 4);
+                }
+                let len = layer.recv_packet(&mut buf).await.unwrap();
+                assert_eq!(&buf[..len], &text[..]);
+
+                // Incompressible
+                layer.send_packet(&random).await.unwrap();
+                let sent = layer.io.packets.lock().unwrap()[0].len();
+                assert_eq!(sent, random.len() + header);
+                let len = layer.recv_packet(&mut buf).await.unwrap();
+                assert_eq!(&buf[..len], &random[..]);
+            }
+        });
+    }
+}
@@ -1,40 +1,156 @@
 use std::{
     cmp,
-    collections::{HashMap, VecDeque},
-    sync::Arc,
+    collections::{BTreeMap, HashMap, VecDeque},
+    io::IoSlice,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
     task::{Context, Poll, Waker},
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
 
 use bitflags::bitflags;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_timer::Delay;
+use ring::{constant_time, hmac};
 use smol::channel::Sender;
 
 use crate::{
     error::{KcpError, KcpResult},
-    segment::{KcpSegment, CMD_ACK, CMD_PING, CMD_PUSH, HEADER_SIZE},
+    segment::{
+        KcpSegment, CMD_ACK, CMD_ACK_DELAY, CMD_ECN_ECHO, CMD_FRAGMENT, CMD_HALF_CLOSE, CMD_OPEN,
+        CMD_PING, CMD_PUSH, CMD_RESET, CMD_SKIP, CMD_WINDOW_PROBE, FRAGMENT_HEADER_LEN,
+        KCP_HEADER_LEN,
+    },
+    spans,
 };
 
+/// The mtu of `KcpConfig::default()`, which leaves room for common tunnel overheads
+pub const DEFAULT_MTU: usize = 1350;
+/// The largest packet the crate reads, receive buffers of this size hold any packet of an
+/// mtu within the bound
+pub const MAX_DATAGRAM: usize = 0x1000;
 pub const RTO_INIT: u32 = 200;
-pub const SSTHRESH_MIN: u16 = 2;
+pub const SSTHRESH_MIN: u32 = 2;
+/// The largest window shift, a scaled window still fits in 30 bits like in TCP
+pub const MAX_WINDOW_SHIFT: u8 = 14;
+/// Timeouts of every large segment in flight before the path is taken for dropping large packets
+const BLACK_HOLE_REXMITS: u32 = 3;
+/// The reset code of a stream whose peer failed `KcpConfig::peer_auth_key`, reserved
+pub const AUTH_FAILED_RESET_CODE: u32 = 0xffff_ffff;
+/// The reset code answering segments for a stream the handle doesn't know, see
+/// `KcpConfig::reset_unknown_streams`, reserved
+pub const UNKNOWN_STREAM_RESET_CODE: u32 = 0xffff_fffe;
+const AUTH_NONCE_LEN: usize = 16;
+const AUTH_PROOF_LEN: usize = 16;
 
 #[async_trait::async_trait]
 pub trait KcpIo {
     async fn send_packet(&self, buf: &[u8]) -> std::io::Result<()>;
     async fn recv_packet(&self, buf: &mut [u8]) -> std::io::Result<usize>;
+
+    /// Like `recv_packet`, also telling whether the packet was marked Congestion Experienced.
+    /// Only used with `KcpConfig::ecn`, ios which can't read the ECN bits never report a mark.
+    async fn recv_packet_ecn(&self, buf: &mut [u8]) -> std::io::Result<(usize, bool)> {
+        Ok((self.recv_packet(buf).await?, false))
+    }
+
+    /// Bytes added to every packet by this io, e.g. the nonce and tag of encryption.
+    fn overhead(&self) -> usize {
+        0
+    }
+
+    /// Encrypt every stream with its own key, see `KcpConfig::per_stream_keys`. Called by
+    /// the handle before any packet, returns false when the io can't, e.g. has no crypto.
+    fn set_per_stream_keys(&self, _enabled: bool) -> bool {
+        false
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Send to `addr` rather than to the peer, for handles over an unconnected socket, see
+    /// `KcpHandle::connect_to`. An io with a single peer sends it there anyway.
+    async fn send_packet_to(&self, buf: &[u8], _addr: SocketAddr) -> std::io::Result<()> {
+        self.send_packet(buf).await
+    }
+
+    /// Like `recv_packet`, also telling where the packet came from, if the io knows it
+    async fn recv_packet_from(
+        &self,
+        buf: &mut [u8],
+    ) -> std::io::Result<(usize, Option<SocketAddr>)> {
+        Ok((self.recv_packet(buf).await?, self.peer_addr()))
+    }
 }
 
-#[inline(always)]
-fn now_millis() -> u32 {
-    SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u32
+/// A boxed io, e.g. `Box<dyn KcpIo + Send + Sync>` for a transport chosen at runtime, works
+/// wherever a concrete one does
+#[async_trait::async_trait]
+impl<T: KcpIo + Send + Sync + ?Sized> KcpIo for Box<T> {
+    async fn send_packet(&self, buf: &[u8]) -> std::io::Result<()> {
+        (**self).send_packet(buf).await
+    }
+
+    async fn recv_packet(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        (**self).recv_packet(buf).await
+    }
+
+    async fn recv_packet_ecn(&self, buf: &mut [u8]) -> std::io::Result<(usize, bool)> {
+        (**self).recv_packet_ecn(buf).await
+    }
+
+    fn overhead(&self) -> usize {
+        (**self).overhead()
+    }
+
+    fn set_per_stream_keys(&self, enabled: bool) -> bool {
+        (**self).set_per_stream_keys(enabled)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        (**self).peer_addr()
+    }
+
+    async fn send_packet_to(&self, buf: &[u8], addr: SocketAddr) -> std::io::Result<()> {
+        (**self).send_packet_to(buf, addr).await
+    }
+
+    async fn recv_packet_from(
+        &self,
+        buf: &mut [u8],
+    ) -> std::io::Result<(usize, Option<SocketAddr>)> {
+        (**self).recv_packet_from(buf).await
+    }
+}
+
+/// Source of time for all timers, so tests can inject a manually advanced clock.
+#[async_trait::async_trait]
+pub trait Clock: Send + Sync {
+    fn now_millis(&self) -> u32;
+    async fn sleep(&self, duration: Duration);
+}
+
+pub struct SystemClock;
+
+#[async_trait::async_trait]
+impl Clock for SystemClock {
+    #[inline(always)]
+    fn now_millis(&self) -> u32 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u32
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        Delay::new(duration).await;
+    }
 }
 
 #[inline(always)]
-fn i32diff(a: u32, b: u32) -> i32 {
-    a as i32 - b as i32
+pub(crate) fn i32diff(a: u32, b: u32) -> i32 {
+    a.wrapping_sub(b) as i32
 }
 
 #[inline(always)]
@@ -42,6 +158,92 @@ fn bound<T: Ord>(lower: T, v: T, upper: T) -> T {
     cmp::min(cmp::max(lower, v), upper)
 }
 
+/// The smallest shift fitting `window` in the 16 bits of the segment header
+fn window_shift(window: u32) -> u8 {
+    (0..MAX_WINDOW_SHIFT)
+        .find(|shift| window >> shift <= 0xffff)
+        .unwrap_or(MAX_WINDOW_SHIFT)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceDirection {
+    Sent,
+    Received,
+}
+
+/// A segment as seen on the wire, reported with the `trace_segments` feature
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SegmentTrace {
+    pub direction: TraceDirection,
+    pub stream_id: u16,
+    pub command: u8,
+    pub sequence: u32,
+    pub timestamp: u32,
+    pub recv_next: u32,
+    pub recv_window_size: u16,
+    pub len: usize,
+}
+
+pub type SegmentTracer = Arc<dyn Fn(&SegmentTrace) + Send + Sync>;
+
+#[cfg(feature = "trace_segments")]
+fn trace_segment(config: &KcpConfig, direction: TraceDirection, segment: &KcpSegment) {
+    let trace = SegmentTrace {
+        direction,
+        stream_id: segment.stream_id,
+        command: segment.command,
+        sequence: segment.sequence,
+        timestamp: segment.timestamp,
+        recv_next: segment.recv_next,
+        recv_window_size: segment.recv_window_size,
+        len: segment.data.len(),
+    };
+    match &config.segment_tracer {
+        Some(tracer) => tracer(&trace),
+        None => log::trace!(
+            target: "ap_kcp::segments",
+            "{:?} stream={} cmd={} seq={} ts={} una={} wnd={} len={}",
+            trace.direction,
+            trace.stream_id,
+            trace.command,
+            trace.sequence,
+            trace.timestamp,
+            trace.recv_next,
+            trace.recv_window_size,
+            trace.len
+        ),
+    }
+}
+
+#[cfg(not(feature = "trace_segments"))]
+#[inline(always)]
+fn trace_segment(_config: &KcpConfig, _direction: TraceDirection, _segment: &KcpSegment) {}
+
+/// How the streams of a handle share the `max_send_bps` budget.
+/// Priorities are set with `KcpStream::set_priority`, streams start at 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchedulingPolicy {
+    /// Equal shares for the streams with data to send, priorities are ignored
+    RoundRobin,
+    /// Shares proportional to `priority + 1`
+    WeightedByPriority,
+    /// A stream sends only while no stream of a higher priority has data to send
+    StrictPriority,
+}
+
+/// What a write does when the stream already queues `send_window_size` segments not sent yet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendOverflowPolicy {
+    /// Wait until the window drains, e.g. for relays which must not lose data
+    Backpressure,
+    /// Drop the oldest queued segments to make room, so that a real-time sender doesn't
+    /// build up latency. The dropped data never reaches the peer, which reads on past the
+    /// gap; they're counted in `KcpStats::send_queue_dropped`.
+    DropOldest,
+    /// Fail the write with `KcpError::SendQueueFull`, the queue is left as it was
+    Error,
+}
+
 #[derive(Clone)]
 pub enum Congestion {
     None,
@@ -49,15 +251,55 @@ pub enum Congestion {
     LossTolerance,
 }
 
+bitflags! {
+    /// Optional protocol features, advertised in the OPEN segment
+    pub struct Features: u8 {
+        /// Unreliable datagrams, see `StreamKind`. A handle without it is reliable-only, it
+        /// drops the datagrams it receives.
+        const DATAGRAM = 0b00000001;
+        /// ACK entries carry how long the receiver held them
+        const ACK_DELAY = 0b00000010;
+        /// Congestion Experienced marks are echoed back to the sender
+        const ECN = 0b00000100;
+        /// A FIN may end only the sender's direction, see `KcpWriteHalf`
+        const HALF_CLOSE = 0b00001000;
+        /// Advertised windows are shifted by a factor sent in the OPEN segment, so they may
+        /// exceed 0xffff segments
+        const WINDOW_SCALE = 0b00010000;
+        /// The OPEN segment proves the knowledge of `KcpConfig::peer_auth_key`. Only advertised
+        /// with a key.
+        const AUTH = 0b00100000;
+        /// Segments sent before the mtu was lowered are resent in FRAGMENTs of the new size
+        const FRAGMENT = 0b01000000;
+    }
+}
+
 bitflags! {
     struct CloseFlags: u8 {
         const TX_CLOSING = 0b00000001;
         const TX_CLOSED = 0b00000011;
         const RX_CLOSED = 0b00000100;
+        const RX_EOF = 0b00001100;
         const CLOSED = Self::TX_CLOSED.bits | Self::RX_CLOSED.bits;
     }
 }
 
+/// Parameters of the KCP state machine.
+///
+/// A handle applies its config to every stream, but a stream may be given its own one with
+/// `KcpHandle::connect_with_config` or `KcpHandle::set_accept_config`. Then
+///
+/// * The intervals, thresholds, rto bounds, windows, congestion control, `timeout`,
+///   `max_segment_size`, `max_segments_per_tick`, `max_inflight_bytes`, `min_mtu`,
+///   `recv_reorder_window`, `features`, `segment_ttl`, `max_stream_lifetime`,
+///   `stream_idle_timeout` and `send_overflow_policy` are per stream, and may differ freely
+///   from the peer.
+/// * `mtu` may not exceed the handle's, which sizes the receive buffer, nor the peer handle's.
+/// * `keep_alive_interval` should stay well below the peer's `timeout`, or idle streams die.
+/// * `per_stream_cc`, `max_session_lifetime`, `max_send_bps`, `scheduling`, `ecn` and
+///   `reset_unknown_streams` are decided by the handle, they're ignored in stream configs.
+/// * `single_stream`, `per_stream_keys` and `initial_sequence` must be the handle's.
+/// * `peer_auth_key` is per stream, and must be the peer's, as must `initial_sequence`.
 #[derive(Clone)]
 pub struct KcpConfig {
     pub max_interval: u32,
@@ -69,11 +311,108 @@ pub struct KcpConfig {
     pub fast_ack_thresh: u32,
     pub congestion: Congestion,
     pub max_rexmit_time: u32,
-    pub min_rto: u32,
-    pub send_window_size: u16,
-    pub recv_window_size: u16,
+    /// Lower bound of the RTO in milliseconds. Raise it on satellite links, lower it on LANs.
+    pub rto_min: u32,
+    /// Upper bound of the RTO in milliseconds
+    pub rto_max: u32,
+    /// In segments, the most sent and not acked yet, however large the peer's window. It's
+    /// independent of `recv_window_size`, e.g. large on the fast direction of an asymmetric link.
+    pub send_window_size: u32,
+    /// In segments, the most received and not read by the application yet. The unused part
+    /// is advertised to the peer, and segments beyond it are dropped unacked. Windows beyond
+    /// 0xffff are advertised scaled when both sides support `Features::WINDOW_SCALE`, and
+    /// capped at 0xffff otherwise.
+    pub recv_window_size: u32,
     pub timeout: u32,
     pub keep_alive_interval: u32,
+    /// Milliseconds between retransmissions of the OPEN segment while it's not acked. No
+    /// RTT is measured yet, so it doesn't back off like the RTO of data.
+    pub handshake_interval: u32,
+    /// Retransmissions of the OPEN segment before the stream fails with
+    /// `KcpError::HandshakeTimeout`
+    pub handshake_retries: u32,
+    /// Milliseconds before probing a peer advertising a zero window while data waits. The
+    /// wait doubles after every probe, up to `keep_alive_interval`.
+    pub window_probe_interval: u32,
+    /// Keep an independent congestion window for every stream. Each stream then competes
+    /// like a separate flow, which is fairer to other traffic on the link. When disabled,
+    /// all streams of a handle share one window, so a loss on any stream slows down all of them.
+    pub per_stream_cc: bool,
+    /// One stream per handle and no stream id in the segment header, which saves 2 bytes per
+    /// segment. Both sides must agree on it, like on the crypto. `connect` fails while a
+    /// stream is open, and the peer's streams are accepted one at a time.
+    pub single_stream: bool,
+    /// Cap the payload of emitted segments below `mss`, for links which mishandle near-MTU datagrams.
+    pub max_segment_size: Option<usize>,
+    /// Cap the data segments emitted by one flush of a stream, retransmissions included,
+    /// however large the window. The rest waits for the following flushes, a coarse pacing
+    /// which bounds the bursts. None sends whatever the window allows.
+    pub max_segments_per_tick: Option<u32>,
+    /// Cap the payload bytes sent and not acked yet, whatever the windows allow, a safety
+    /// valve for links with unusual buffering. One segment is always let out, even a larger
+    /// one. None leaves it to the windows.
+    pub max_inflight_bytes: Option<usize>,
+    /// When large segments keep timing out while the peer's packets still arrive, the path
+    /// is taken for dropping large packets, and the mtu of the stream is lowered by a quarter
+    /// at a time, down to this. A `min_mtu` of at least `mtu` keeps the mtu fixed.
+    pub min_mtu: usize,
+    /// How many out-of-order segments are buffered while waiting for a gap to fill.
+    /// Segments arriving beyond it are dropped unacked, and the sender retransmits them later.
+    pub recv_reorder_window: u16,
+    /// ACK entries sent in one packet. More are split across several packets, as are the
+    /// ones which don't fit in the mtu.
+    pub max_acks_per_packet: usize,
+    pub clock: Arc<dyn Clock>,
+    /// Features this side supports, the ones supported by both sides are used
+    pub features: Features,
+    /// Abandon a segment still unacked after this long since its first transmission.
+    /// The peer is told to skip it, so later data is still delivered in order. Its reader
+    /// gets `KcpError::DataSkipped` where the data is missing. None retransmits forever.
+    pub segment_ttl: Option<Duration>,
+    /// Close a stream gracefully once it has lived this long, however busy it is
+    pub max_stream_lifetime: Option<Duration>,
+    /// Close a stream gracefully once the application has neither written to it nor read
+    /// anything from it for this long, even if the peer keeps the session alive
+    pub stream_idle_timeout: Option<Duration>,
+    /// Close all streams of a handle gracefully once the handle has lived this long,
+    /// new streams are refused from then on
+    pub max_session_lifetime: Option<Duration>,
+    /// Cap the bits per second sent by a handle, all streams included, whatever the
+    /// congestion window allows. Only data segments are held back, ACKs and pings never wait.
+    pub max_send_bps: Option<u64>,
+    /// Which stream gets the budget of `max_send_bps` when several have data to send.
+    /// Without a cap each stream sends on its own, and the policy does nothing.
+    pub scheduling: SchedulingPolicy,
+    /// What writes do once the send queue reaches `send_window_size` segments
+    pub send_overflow_policy: SendOverflowPolicy,
+    /// Receives every segment sent or received, instead of the TRACE log of the
+    /// `ap_kcp::segments` target. Both need the `trace_segments` feature.
+    pub segment_tracer: Option<SegmentTracer>,
+    /// Read the ECN bits of received packets, and echo Congestion Experienced marks to the
+    /// peer, which backs off as if a packet was lost. The io must report the marks, see
+    /// `KcpIo::recv_packet_ecn`, and the sending side should mark its packets ECN-capable,
+    /// see `UdpOptions::ecn`.
+    pub ecn: bool,
+    /// Verify that the peer holds the same pre-shared key, e.g. the password of the crypto.
+    /// The OPEN segments carry a random challenge and a fingerprint of the key, the accepting
+    /// side's answering the connecting side's challenge. A peer with another key or none is
+    /// reset, and both sides fail with `KcpError::PeerAuthFailed`. It catches misconfigured
+    /// peers, the stream itself is only protected by the crypto.
+    pub peer_auth_key: Option<Bytes>,
+    /// Encrypt every stream with its own key, derived from the key of the crypto and the
+    /// stream id. The stream id then travels in the clear, authenticated with the packet.
+    /// Both sides must agree on it, like on the crypto, and the io must support it, see
+    /// `KcpIo::set_per_stream_keys`. Not available with `single_stream`.
+    pub per_stream_keys: bool,
+    /// Answer data for a stream the handle doesn't know, closed long ago or never opened,
+    /// with a reset, so that the peer stops retransmitting it. Otherwise it's only dropped,
+    /// and the peer's stream times out. Either way it's counted in
+    /// `KcpStats::unknown_stream_segments`.
+    pub reset_unknown_streams: bool,
+    /// The sequence number of the OPEN segment, the first one of both directions of every
+    /// stream. The peer must use the same, peers without the option start at 0. Starting
+    /// close to `u32::MAX` tests the wraparound, a secret one hides where streams start.
+    pub initial_sequence: u32,
 }
 
 impl Default for KcpConfig {
@@ -82,27 +421,458 @@ impl Default for KcpConfig {
             min_interval: 10,
             max_interval: 100,
             nodelay: false,
-            mtu: 1350,
-            mss: 1350 - HEADER_SIZE,
+            mtu: DEFAULT_MTU,
+            mss: DEFAULT_MTU - KCP_HEADER_LEN,
             fast_rexmit_thresh: 3,
             fast_ack_thresh: 32,
             congestion: Congestion::LossTolerance,
             max_rexmit_time: 32,
-            min_rto: 20,
+            rto_min: 20,
+            rto_max: 5000,
             send_window_size: 0x800,
             recv_window_size: 0x800,
             timeout: 5000,
             keep_alive_interval: 1500,
+            handshake_interval: RTO_INIT,
+            handshake_retries: 16,
+            window_probe_interval: 100,
+            per_stream_cc: true,
+            single_stream: false,
+            max_segment_size: None,
+            max_segments_per_tick: None,
+            max_inflight_bytes: None,
+            min_mtu: 576,
+            recv_reorder_window: 0x800,
+            max_acks_per_packet: 128,
+            per_stream_keys: false,
+            reset_unknown_streams: false,
+            initial_sequence: 0,
+            clock: Arc::new(SystemClock),
+            features: Features::all(),
+            segment_ttl: None,
+            max_stream_lifetime: None,
+            stream_idle_timeout: None,
+            max_session_lifetime: None,
+            max_send_bps: None,
+            scheduling: SchedulingPolicy::RoundRobin,
+            send_overflow_policy: SendOverflowPolicy::Backpressure,
+            segment_tracer: None,
+            ecn: false,
+            peer_auth_key: None,
+        }
+    }
+}
+
+impl KcpConfig {
+    /// The payload size of the segments actually emitted
+    pub fn segment_size(&self) -> usize {
+        match self.max_segment_size {
+            Some(size) => cmp::min(size, self.mss),
+            None => self.mss,
+        }
+    }
+
+    pub fn validate(&self) -> KcpResult<()> {
+        if self.mtu > MAX_DATAGRAM {
+            return Err(KcpError::InvalidConfig(format!(
+                "mtu {} exceeds MAX_DATAGRAM {}",
+                self.mtu, MAX_DATAGRAM
+            )));
+        }
+        if self.mtu <= KCP_HEADER_LEN || self.mss == 0 || self.mss > self.mtu - KCP_HEADER_LEN {
+            return Err(KcpError::InvalidConfig(format!(
+                "mss {} does not fit in mtu {}",
+                self.mss, self.mtu
+            )));
+        }
+        if let Some(size) = self.max_segment_size {
+            if size == 0 || size > self.mtu - KCP_HEADER_LEN {
+                return Err(KcpError::InvalidConfig(format!(
+                    "max_segment_size {} does not fit in mtu {}",
+                    size, self.mtu
+                )));
+            }
+        }
+        if self.rto_min > self.rto_max {
+            return Err(KcpError::InvalidConfig(format!(
+                "rto_min {} is larger than rto_max {}",
+                self.rto_min, self.rto_max
+            )));
+        }
+        if self.max_send_bps == Some(0) {
+            return Err(KcpError::InvalidConfig(
+                "max_send_bps should be at least 1".to_string(),
+            ));
+        }
+        if self.send_window_size == 0 {
+            return Err(KcpError::InvalidConfig(
+                "send_window_size should be at least 1".to_string(),
+            ));
+        }
+        if self.recv_reorder_window == 0 {
+            return Err(KcpError::InvalidConfig(
+                "recv_reorder_window should be at least 1".to_string(),
+            ));
+        }
+        if self.min_mtu <= KCP_HEADER_LEN {
+            return Err(KcpError::InvalidConfig(format!(
+                "min_mtu {} leaves no room for a segment",
+                self.min_mtu
+            )));
+        }
+        if self.handshake_interval == 0 {
+            return Err(KcpError::InvalidConfig(
+                "handshake_interval should be at least 1".to_string(),
+            ));
+        }
+        if self.window_probe_interval == 0 {
+            return Err(KcpError::InvalidConfig(
+                "window_probe_interval should be at least 1".to_string(),
+            ));
+        }
+        if self.recv_window_size > 0xffff << MAX_WINDOW_SHIFT {
+            return Err(KcpError::InvalidConfig(format!(
+                "recv_window_size {} exceeds the largest scaled window",
+                self.recv_window_size
+            )));
+        }
+        if self.max_acks_per_packet == 0 {
+            return Err(KcpError::InvalidConfig(
+                "max_acks_per_packet should be at least 1".to_string(),
+            ));
+        }
+        if self.max_inflight_bytes == Some(0) {
+            return Err(KcpError::InvalidConfig(
+                "max_inflight_bytes should be at least 1".to_string(),
+            ));
+        }
+        if self.max_segments_per_tick == Some(0) {
+            return Err(KcpError::InvalidConfig(
+                "max_segments_per_tick should be at least 1".to_string(),
+            ));
+        }
+        if self.per_stream_keys && self.single_stream {
+            return Err(KcpError::InvalidConfig(
+                "per_stream_keys needs the stream id, which single_stream leaves out".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks that `config` only changes what may change on a running handle, see
+    /// `KcpHandle::reconfigure`
+    pub fn check_reconfigure(&self, config: &KcpConfig) -> KcpResult<()> {
+        config.validate()?;
+        let fixed = [
+            ("mtu", self.mtu == config.mtu),
+            ("mss", self.mss == config.mss),
+            (
+                "max_segment_size",
+                self.max_segment_size == config.max_segment_size,
+            ),
+            ("per_stream_cc", self.per_stream_cc == config.per_stream_cc),
+            ("single_stream", self.single_stream == config.single_stream),
+            (
+                "per_stream_keys",
+                self.per_stream_keys == config.per_stream_keys,
+            ),
+            ("features", self.features == config.features),
+            ("ecn", self.ecn == config.ecn),
+            (
+                "reset_unknown_streams",
+                self.reset_unknown_streams == config.reset_unknown_streams,
+            ),
+            (
+                "max_session_lifetime",
+                self.max_session_lifetime == config.max_session_lifetime,
+            ),
+            (
+                "initial_sequence",
+                self.initial_sequence == config.initial_sequence,
+            ),
+        ];
+        if let Some((name, _)) = fixed.iter().find(|(_, same)| !same) {
+            return Err(KcpError::InvalidConfig(format!(
+                "{} can't change on a running handle",
+                name
+            )));
+        }
+        if self.max_send_bps.is_some() != config.max_send_bps.is_some() {
+            return Err(KcpError::InvalidConfig(
+                "max_send_bps can't be turned on or off on a running handle".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct KcpStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub segments_sent: u64,
+    pub segments_retransmitted: u64,
+    /// Payload bytes sent again, the waste caused by loss
+    pub bytes_retransmitted: u64,
+    /// The current RTO in milliseconds, the largest one when stats of several streams are added up
+    pub rto: u32,
+    /// Segments abandoned because of `segment_ttl`
+    pub segments_expired: u64,
+    /// Congestion Experienced marks echoed by the peer
+    pub ecn_echoes: u64,
+    /// Flushes, every one is a chance to send new data
+    pub flushes: u64,
+    /// Flushes leaving data queued because the congestion window was full
+    pub window_limited_flushes: u64,
+    /// The largest RTT sample in milliseconds
+    pub peak_rtt: u32,
+    /// The RTT of the OPEN segment in milliseconds, 0 until it's acked. The path is often
+    /// slower to set up than to use, compare with `srtt`. The largest one when stats of
+    /// several streams are added up.
+    pub handshake_rtt: u32,
+    /// The smoothed RTT in milliseconds, the largest one when stats of several streams
+    /// are added up
+    pub srtt: u32,
+    /// The largest congestion window in segments
+    pub peak_congestion_window: u32,
+    /// Times the mtu was lowered because large packets seemed to be dropped, see `min_mtu`
+    pub mtu_reductions: u64,
+    /// Segments written by the application and not sent yet. A queue which keeps growing
+    /// means a stuck peer or a slow path.
+    pub send_queue_len: u64,
+    pub send_queue_bytes: u64,
+    /// Queued segments dropped unsent to make room for newer writes, see
+    /// `SendOverflowPolicy::DropOldest`
+    pub send_queue_dropped: u64,
+    /// Segments received in order and not read by the application yet. A queue which keeps
+    /// growing means a slow reader.
+    pub recv_queue_len: u64,
+    pub recv_queue_bytes: u64,
+    /// Segments dropped because their stream is unknown to the handle, late retransmits
+    /// of a closed stream or garbage. Only counted in the stats of the handle.
+    pub unknown_stream_segments: u64,
+}
+
+impl KcpStats {
+    pub(crate) fn accumulate(&mut self, other: &KcpStats) {
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.segments_sent += other.segments_sent;
+        self.segments_retransmitted += other.segments_retransmitted;
+        self.bytes_retransmitted += other.bytes_retransmitted;
+        self.rto = cmp::max(self.rto, other.rto);
+        self.segments_expired += other.segments_expired;
+        self.ecn_echoes += other.ecn_echoes;
+        self.flushes += other.flushes;
+        self.window_limited_flushes += other.window_limited_flushes;
+        self.peak_rtt = cmp::max(self.peak_rtt, other.peak_rtt);
+        self.handshake_rtt = cmp::max(self.handshake_rtt, other.handshake_rtt);
+        self.srtt = cmp::max(self.srtt, other.srtt);
+        self.peak_congestion_window =
+            cmp::max(self.peak_congestion_window, other.peak_congestion_window);
+        self.mtu_reductions += other.mtu_reductions;
+        self.send_queue_len += other.send_queue_len;
+        self.send_queue_bytes += other.send_queue_bytes;
+        self.send_queue_dropped += other.send_queue_dropped;
+        self.recv_queue_len += other.recv_queue_len;
+        self.recv_queue_bytes += other.recv_queue_bytes;
+        self.unknown_stream_segments += other.unknown_stream_segments;
+    }
+
+    /// The counters only, for stats kept after their stream or handle is gone
+    pub(crate) fn retired(&self) -> KcpStats {
+        KcpStats {
+            send_queue_len: 0,
+            send_queue_bytes: 0,
+            recv_queue_len: 0,
+            recv_queue_bytes: 0,
+            ..self.clone()
+        }
+    }
+
+    /// The fraction of flushes where the congestion window held data back. Near 1 means a
+    /// larger window would help, near 0 means the sending is application-limited.
+    pub fn window_limited_ratio(&self) -> f64 {
+        if self.flushes == 0 {
+            0.0
+        } else {
+            self.window_limited_flushes as f64 / self.flushes as f64
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct CongestionState {
+    window_size: u32,
+    window_bytes: usize,
+    slow_start_thresh: u32,
+}
+
+pub(crate) type SharedCongestion = Arc<Mutex<CongestionState>>;
+
+impl CongestionState {
+    pub fn shared(config: &KcpConfig) -> SharedCongestion {
+        Arc::new(Mutex::new(Self {
+            window_size: 16,
+            window_bytes: config.segment_size(),
+            slow_start_thresh: SSTHRESH_MIN,
+        }))
+    }
+
+    /// Move the shared state by what a stream changed from `loaded` to `current`. The other
+    /// streams may have changed it meanwhile, their changes are kept.
+    fn merge(&mut self, loaded: &Self, current: &Self, mss: usize) {
+        fn shift(shared: u64, loaded: u64, current: u64, min: u64) -> u64 {
+            cmp::max((shared + current).saturating_sub(loaded), min)
+        }
+        self.window_size = shift(
+            self.window_size as u64,
+            loaded.window_size as u64,
+            current.window_size as u64,
+            1,
+        ) as u32;
+        self.window_bytes = shift(
+            self.window_bytes as u64,
+            loaded.window_bytes as u64,
+            current.window_bytes as u64,
+            mss as u64,
+        ) as usize;
+        self.slow_start_thresh = shift(
+            self.slow_start_thresh as u64,
+            loaded.slow_start_thresh as u64,
+            current.slow_start_thresh as u64,
+            SSTHRESH_MIN as u64,
+        ) as u32;
+    }
+}
+
+/// A stream competing for the budget
+struct Flow {
+    priority: u8,
+    // Bytes served, scaled down by the weight. The lowest one goes next.
+    virtual_time: u64,
+    seen_ts: u32,
+}
+
+/// Token bucket enforcing `max_send_bps`, shared by all streams of a handle
+pub(crate) struct RateLimiter {
+    bytes_per_sec: u64,
+    burst: i64,
+    // May go below zero, a segment is sent whole once any token is left
+    tokens: i64,
+    refill_ts: u32,
+    policy: SchedulingPolicy,
+    flows: HashMap<u16, Flow>,
+    // A flow not asking for this long has nothing to send
+    flow_timeout: u32,
+}
+
+pub(crate) type SharedRateLimiter = Arc<Mutex<RateLimiter>>;
+
+impl RateLimiter {
+    // Bytes per second and the burst
+    fn rate(bps: u64, config: &KcpConfig) -> (u64, i64) {
+        let bytes_per_sec = cmp::max(bps / 8, 1);
+        // Enough for the longest flush interval, and at least one full packet
+        let burst = cmp::max(
+            bytes_per_sec * config.max_interval as u64 / 1000,
+            config.mtu as u64,
+        ) as i64;
+        (bytes_per_sec, burst)
+    }
+
+    pub fn shared(config: &KcpConfig) -> Option<SharedRateLimiter> {
+        config.max_send_bps.map(|bps| {
+            let (bytes_per_sec, burst) = Self::rate(bps, config);
+            Arc::new(Mutex::new(Self {
+                bytes_per_sec,
+                burst,
+                tokens: burst,
+                refill_ts: config.clock.now_millis(),
+                policy: config.scheduling,
+                flows: HashMap::new(),
+                flow_timeout: config.max_interval * 2,
+            }))
+        })
+    }
+
+    /// Takes the new cap and policy, the tokens and the flows are kept
+    pub fn reconfigure(&mut self, config: &KcpConfig) {
+        if let Some(bps) = config.max_send_bps {
+            let (bytes_per_sec, burst) = Self::rate(bps, config);
+            self.bytes_per_sec = bytes_per_sec;
+            self.burst = burst;
+            self.tokens = cmp::min(self.tokens, burst);
+        }
+        self.policy = config.scheduling;
+        self.flow_timeout = config.max_interval * 2;
+    }
+
+    fn weight(&self, priority: u8) -> u64 {
+        match self.policy {
+            SchedulingPolicy::WeightedByPriority => priority as u64 + 1,
+            _ => 1,
+        }
+    }
+
+    /// Whether the stream may send a segment now
+    fn available(&mut self, now: u32, stream_id: u16, priority: u8) -> bool {
+        let elapsed = i32diff(now, self.refill_ts);
+        if elapsed > 0 {
+            let refill = (elapsed as u64 * self.bytes_per_sec / 1000) as i64;
+            // Wait for at least one whole token, or the fraction is lost
+            if refill > 0 {
+                self.tokens = cmp::min(self.tokens + refill, self.burst);
+                self.refill_ts = now;
+            }
+        }
+
+        let flow_timeout = self.flow_timeout as i32;
+        self.flows
+            .retain(|_, flow| i32diff(now, flow.seen_ts) <= flow_timeout);
+        // A returning flow gets no credit for the time it had nothing to send
+        let min_virtual_time = self.flows.values().map(|flow| flow.virtual_time).min();
+        let flow = self.flows.entry(stream_id).or_insert(Flow {
+            priority,
+            virtual_time: min_virtual_time.unwrap_or(0),
+            seen_ts: now,
+        });
+        flow.priority = priority;
+        flow.seen_ts = now;
+        let virtual_time = flow.virtual_time;
+
+        if self.tokens <= 0 {
+            return false;
+        }
+        match self.policy {
+            SchedulingPolicy::StrictPriority => {
+                !self.flows.values().any(|flow| flow.priority > priority)
+            }
+            SchedulingPolicy::RoundRobin | SchedulingPolicy::WeightedByPriority => self
+                .flows
+                .values()
+                .all(|flow| flow.virtual_time >= virtual_time),
+        }
+    }
+
+    fn consume(&mut self, stream_id: u16, bytes: usize) {
+        self.tokens -= bytes as i64;
+        if let Some(flow) = self.flows.get(&stream_id) {
+            let served = bytes as u64 * 0x100 / self.weight(flow.priority);
+            self.flows.get_mut(&stream_id).unwrap().virtual_time += served;
         }
     }
 }
 
 struct SendingKcpSegment {
     segment: KcpSegment,
+    sent_timestamp: u32,
     rexmit_timestamp: u32,
     rto: u32,
     fast_rexmit_counter: u32,
     rexmit_counter: u32,
+    /// Resent on the next flush without the congestion reaction of a fast rexmit
+    rexmit_requested: bool,
 }
 
 pub(crate) struct KcpCore {
@@ -111,16 +881,28 @@ pub(crate) struct KcpCore {
     send_window: VecDeque<SendingKcpSegment>,
     recv_queue: VecDeque<Bytes>,
     recv_window: HashMap<u32, KcpSegment>,
-    ack_list: VecDeque<(u32, u32)>,
+    /// Pieces of the segments arriving in FRAGMENTs, with the length of the whole segment
+    recv_fragments: HashMap<u32, (usize, BTreeMap<usize, Bytes>)>,
+    /// Segments the peer abandoned which the reader hasn't been told about yet. The data
+    /// past them waits in `recv_window` until `poll_recv` reports the gap.
+    recv_skipped: u32,
+    // (timestamp, sequence, arrival)
+    ack_list: VecDeque<(u32, u32, u32)>,
 
     send_unack: u32,
     send_next: u32,
     recv_next: u32,
 
-    remote_window_size: u16,
-    congestion_window_size: u16,
+    remote_window_size: u32,
+    local_recv_window: u32,
+    congestion_window_size: u32,
     congestion_window_bytes: usize,
-    slow_start_thresh: u16,
+    slow_start_thresh: u32,
+    // The shared congestion state as last loaded, see `store_congestion`
+    loaded_congestion: CongestionState,
+    // Advertised windows are shifted right by ours, the peer's ones left by its own
+    local_window_shift: u8,
+    remote_window_shift: u8,
 
     srtt: u32,
     rttval: u32,
@@ -128,11 +910,17 @@ pub(crate) struct KcpCore {
 
     now: u32,
     ping_ts: u32,
+    // The persist timer, probe_wait is 0 while the peer's window is open
+    probe_ts: u32,
+    probe_wait: u32,
 
     close_state: CloseFlags,
     close_ts: u32,
 
     buffer: BytesMut,
+    mtu: usize,
+    mss: usize,
+    min_mtu: usize,
 
     pub config: Arc<KcpConfig>,
 
@@ -140,10 +928,54 @@ pub(crate) struct KcpCore {
     recv_waker: Option<Waker>,
     flush_waker: Option<Waker>,
     close_waker: Option<Waker>,
+    // The FIN closes only our write side, the peer keeps its own open
+    half_close: bool,
 
     flush_notify_tx: Sender<()>,
 
     last_active: u32,
+    // When a packet of the peer last arrived
+    last_input: u32,
+    // The last write or read of the application
+    last_app_active: u32,
+    idle_expired: bool,
+
+    shared_congestion: Option<SharedCongestion>,
+    rate_limiter: Option<SharedRateLimiter>,
+    priority: u8,
+
+    stats: KcpStats,
+
+    open_data: Option<Bytes>,
+    label: Bytes,
+    remote_features: Features,
+
+    deadline: Option<u32>,
+    lifetime_expired: bool,
+
+    // | CODE | REASON |, sent by the next flush, which then shuts the core down
+    reset_data: Option<Bytes>,
+    peer_reset: Option<(u32, String)>,
+
+    ecn_echo_pending: bool,
+    // The last flush left data queued behind a full window
+    window_limited: bool,
+    // When the application last asked for a retransmit, see `request_retransmit`
+    rexmit_request_ts: Option<u32>,
+    // The update loop sleeps until the next timer instead of ticking, see `get_interval`
+    sleeping: bool,
+    // No more backing off for CE marks until then, once per rtt like a loss
+    ecn_reaction_ts: u32,
+
+    // The challenge sent in our OPEN, and the one in the peer's
+    auth_nonce: [u8; AUTH_NONCE_LEN],
+    remote_auth_nonce: Option<[u8; AUTH_NONCE_LEN]>,
+    // Our OPEN went out before the peer's arrived, so the peer's answers our challenge
+    auth_initiator: bool,
+    auth_failed: bool,
+    decrypt_failures: Option<u32>,
+    // The OPEN was retransmitted `handshake_retries` times in vain
+    handshake_failed: bool,
 }
 
 impl Drop for KcpCore {
@@ -158,6 +990,182 @@ impl KcpCore {
         self.stream_id
     }
 
+    #[inline]
+    pub fn get_label(&self) -> Bytes {
+        self.label.clone()
+    }
+
+    /// Features supported by both sides
+    #[inline]
+    pub fn get_features(&self) -> Features {
+        self.local_features() & self.remote_features
+    }
+
+    #[inline]
+    fn local_features(&self) -> Features {
+        let mut features = self.config.features;
+        features.set(Features::AUTH, self.config.peer_auth_key.is_some());
+        features
+    }
+
+    /// HMAC of the challenges, the answering side covers the challenge of the initiator too
+    fn auth_proof(key: &[u8], nonce: &[u8], answered: Option<&[u8]>) -> [u8; AUTH_PROOF_LEN] {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+        let mut context = hmac::Context::with_key(&key);
+        context.update(b"ap-kcp-peer-auth");
+        context.update(nonce);
+        if let Some(answered) = answered {
+            context.update(answered);
+        }
+        let mut proof = [0u8; AUTH_PROOF_LEN];
+        proof.copy_from_slice(&context.sign().as_ref()[..AUTH_PROOF_LEN]);
+        proof
+    }
+
+    /// Queue the OPEN segment, it always takes the first sequence number
+    pub fn open(&mut self, label: Bytes) {
+        // | FEATURES | SHIFT, with WINDOW_SCALE | NONCE PROOF, with AUTH | LABEL |
+        let features = self.local_features();
+        let mut data = BytesMut::with_capacity(2 + AUTH_NONCE_LEN + AUTH_PROOF_LEN + label.len());
+        data.put_u8(features.bits());
+        if features.contains(Features::WINDOW_SCALE) {
+            data.put_u8(self.local_window_shift);
+        }
+        if let Some(key) = &self.config.peer_auth_key {
+            let answered = self.remote_auth_nonce;
+            self.auth_initiator = answered.is_none();
+            data.put_slice(&self.auth_nonce);
+            data.put_slice(&Self::auth_proof(
+                key,
+                &self.auth_nonce,
+                answered.as_ref().map(|nonce| &nonce[..]),
+            ));
+        }
+        data.put_slice(&label);
+        self.open_data = Some(data.freeze());
+        self.label = label;
+        self.wake_if_sleeping();
+    }
+
+    #[inline]
+    pub fn get_stats(&self) -> KcpStats {
+        KcpStats {
+            rto: self.rto,
+            srtt: self.srtt,
+            send_queue_len: self.send_queue.len() as u64,
+            send_queue_bytes: self.send_queue.iter().map(|data| data.len() as u64).sum(),
+            recv_queue_len: self.recv_queue.len() as u64,
+            recv_queue_bytes: self.recv_queue.iter().map(|data| data.len() as u64).sum(),
+            ..self.stats.clone()
+        }
+    }
+
+    /// Whether the last flush left data queued behind a full window, rather than sending
+    /// everything the application wrote
+    #[inline]
+    pub fn is_window_limited(&self) -> bool {
+        self.window_limited
+    }
+
+    #[inline]
+    pub fn get_mtu(&self) -> usize {
+        self.mtu
+    }
+
+    #[inline]
+    pub fn get_remote_window(&self) -> u32 {
+        self.remote_window_size
+    }
+
+    /// Advertise a smaller receive window to throttle the peer.
+    /// It's kept within 1 and `recv_window_size`, so the peer never stalls completely.
+    pub fn set_local_recv_window(&mut self, window: u32) {
+        self.local_recv_window = bound(1, window, self.config.recv_window_size);
+    }
+
+    /// How many segments may be in flight now
+    pub fn get_send_window(&self) -> u32 {
+        let window = cmp::min(self.config.send_window_size, self.remote_window_size);
+        match self.config.congestion {
+            Congestion::None => window,
+            _ => cmp::min(window, self.congestion_window_size),
+        }
+    }
+
+    /// Close the stream at `deadline` at the latest, e.g. when its session expires
+    pub fn limit_lifetime(&mut self, deadline: u32) {
+        self.deadline = match self.deadline {
+            Some(current) if i32diff(current, deadline) < 0 => Some(current),
+            _ => Some(deadline),
+        };
+        self.wake_if_sleeping();
+    }
+
+    /// Switch to `config` mid-stream, the handle made sure the segment size stays the same
+    pub fn reconfigure(&mut self, config: Arc<KcpConfig>) {
+        // A window not capped by set_local_recv_window follows the new size
+        self.local_recv_window = if self.local_recv_window == self.config.recv_window_size {
+            config.recv_window_size
+        } else {
+            bound(1, self.local_recv_window, config.recv_window_size)
+        };
+        self.rto = bound(config.rto_min, self.rto, config.rto_max);
+        self.config = config;
+        self.wake_if_sleeping();
+    }
+
+    /// Hold data segments back when the handle exceeds its `max_send_bps`
+    pub fn limit_rate(&mut self, limiter: SharedRateLimiter) {
+        self.rate_limiter = Some(limiter);
+    }
+
+    /// See `SchedulingPolicy`
+    pub fn set_priority(&mut self, priority: u8) {
+        self.priority = priority;
+    }
+
+    #[inline]
+    pub fn get_priority(&self) -> u8 {
+        self.priority
+    }
+
+    /// The last packet input was marked Congestion Experienced by the network
+    pub fn input_ce(&mut self) {
+        if self.get_features().contains(Features::ECN) {
+            self.ecn_echo_pending = true;
+        }
+    }
+
+    fn closing_error(&self, operation: &str) -> KcpError {
+        if self.auth_failed {
+            KcpError::PeerAuthFailed
+        } else if let Some(failures) = self.decrypt_failures {
+            KcpError::DecryptFailureThreshold(failures)
+        } else if let Some((code, reason)) = &self.peer_reset {
+            KcpError::PeerReset {
+                code: *code,
+                reason: reason.clone(),
+            }
+        } else if self.handshake_failed {
+            KcpError::HandshakeTimeout
+        } else if self.lifetime_expired {
+            KcpError::LifetimeExpired
+        } else if self.idle_expired {
+            KcpError::IdleTimeout
+        } else {
+            KcpError::Shutdown(format!(
+                "{} on a closing kcp core: {}",
+                operation, self.close_state.bits,
+            ))
+        }
+    }
+
+    /// Closed because `failures` packets in a row failed to decrypt
+    pub fn fail_decrypt(&mut self, failures: u32) {
+        self.decrypt_failures = Some(failures);
+        self.force_close();
+    }
+
     pub fn force_close(&mut self) {
         self.close_state.set(CloseFlags::CLOSED, true);
         if let Some(waker) = self.send_waker.take() {
@@ -174,6 +1182,34 @@ impl KcpCore {
         }
     }
 
+    fn load_congestion(&mut self) {
+        if let Some(shared) = &self.shared_congestion {
+            let state = *shared.lock().unwrap();
+            self.congestion_window_size = state.window_size;
+            self.congestion_window_bytes = state.window_bytes;
+            self.slow_start_thresh = state.slow_start_thresh;
+            self.loaded_congestion = state;
+        }
+    }
+
+    /// Only the changes since `load_congestion` are applied, rather than the whole state, so
+    /// the streams updating it concurrently don't overwrite each other
+    fn store_congestion(&mut self) {
+        if let Some(shared) = &self.shared_congestion {
+            let current = CongestionState {
+                window_size: self.congestion_window_size,
+                window_bytes: self.congestion_window_bytes,
+                slow_start_thresh: self.slow_start_thresh,
+            };
+            let mut state = shared.lock().unwrap();
+            state.merge(&self.loaded_congestion, &current, self.mss);
+            self.congestion_window_size = state.window_size;
+            self.congestion_window_bytes = state.window_bytes;
+            self.slow_start_thresh = state.slow_start_thresh;
+            self.loaded_congestion = *state;
+        }
+    }
+
     fn remove_send_window_until(&mut self, sequence: u32) {
         while self.send_window.len() != 0 {
             if i32diff(sequence, self.send_window.front().unwrap().segment.sequence) > 0 {
@@ -192,6 +1228,7 @@ impl KcpCore {
     }
 
     fn update_rtt(&mut self, rtt: u32) {
+        self.stats.peak_rtt = cmp::max(self.stats.peak_rtt, rtt);
         if self.srtt == 0 {
             self.srtt = rtt;
             self.rttval = rtt / 2;
@@ -208,10 +1245,140 @@ impl KcpCore {
             }
         }
         let rto = self.srtt + cmp::max(self.config.max_interval, 4 * self.rttval);
-        self.rto = bound(self.config.min_rto, rto, self.config.timeout);
+        self.rto = bound(self.config.rto_min, rto, self.config.rto_max);
         log::trace!("update srtt = {}, rto = {}", self.srtt, rto);
     }
 
+    /// Large segments timing out again and again while the peer's packets, ACKs included,
+    /// still arrive look like a path dropping large packets. Then the mtu is lowered, see
+    /// `refragment`: the segments already sent only get through in FRAGMENTs, so without
+    /// `Features::FRAGMENT` only the data not sent yet benefits.
+    fn check_black_hole(&mut self) {
+        let reduced = cmp::max(self.mtu * 3 / 4, self.min_mtu);
+        if reduced >= self.mtu || reduced <= KCP_HEADER_LEN + FRAGMENT_HEADER_LEN {
+            return;
+        }
+        let mtu = self.mtu;
+        let fragment = self.get_features().contains(Features::FRAGMENT);
+        if !fragment
+            && self
+                .send_window
+                .iter()
+                .any(|sending_segment| sending_segment.segment.encoded_len() > mtu)
+        {
+            // Sent before the last reduction, a lower mtu can't help them
+            return;
+        }
+        // Segments which fit in the reduced mtu stay as they are, the others are measured
+        // as they're sent, in FRAGMENTs of the mtu
+        let mut large = self
+            .send_window
+            .iter()
+            .filter(|sending_segment| {
+                cmp::min(sending_segment.segment.encoded_len(), mtu) > reduced
+            })
+            .peekable();
+        if large.peek().is_none() {
+            return;
+        }
+        let last_input = self.last_input;
+        let black_holed = large.all(|sending_segment| {
+            sending_segment.segment.command == CMD_PUSH
+                && sending_segment.rexmit_counter > BLACK_HOLE_REXMITS
+                && i32diff(last_input, sending_segment.sent_timestamp) > 0
+        });
+        if !black_holed {
+            return;
+        }
+        log::info!(
+            "large packets seem to be dropped, mtu {} -> {}",
+            self.mtu,
+            reduced
+        );
+        self.reduce_mtu(reduced);
+    }
+
+    fn reduce_mtu(&mut self, mtu: usize) {
+        self.mtu = mtu;
+        self.mss = cmp::min(self.mss, mtu - KCP_HEADER_LEN);
+        self.stats.mtu_reductions += 1;
+        // Sent segments too large now go out in FRAGMENTs at once, the backoff of the old
+        // size tells nothing about the new one
+        for sending_segment in &mut self.send_window {
+            if sending_segment.rexmit_counter > 0 && sending_segment.segment.encoded_len() > mtu {
+                sending_segment.rexmit_counter = 1;
+                sending_segment.rto = self.rto;
+                sending_segment.rexmit_timestamp = self.now;
+            }
+        }
+    }
+
+    /// Lower the mtu mid-stream, e.g. when the path MTU shrinks. Queued data and unacked
+    /// segments which don't fit anymore are cut to the new size by the next flush, see
+    /// `refragment`. An mtu which isn't lower than the current one is ignored.
+    pub fn lower_mtu(&mut self, mtu: usize) -> KcpResult<()> {
+        if mtu <= KCP_HEADER_LEN {
+            return Err(KcpError::InvalidConfig(format!(
+                "mtu {} leaves no room for a segment",
+                mtu
+            )));
+        }
+        if mtu < self.mtu {
+            self.now = self.config.clock.now_millis();
+            log::info!(
+                "mtu of stream {} lowered {} -> {}",
+                self.stream_id,
+                self.mtu,
+                mtu
+            );
+            self.reduce_mtu(mtu);
+            self.wake_if_sleeping();
+        }
+        Ok(())
+    }
+
+    /// Segments exceeding the mtu since it was lowered can't be split in place, a sequence
+    /// number holds one segment. Those never sent are taken back, and their data is sent
+    /// anew in segments of the new size. Sent ones keep their sequence numbers, the peer may
+    /// hold them already with only the ACKs lost, they're resent in FRAGMENTs instead.
+    fn refragment(&mut self) {
+        let mtu = self.mtu;
+        // Sent in order, so what was never sent is the tail of the window
+        let first_unsent = self
+            .send_window
+            .iter()
+            .position(|sending_segment| sending_segment.rexmit_counter == 0)
+            .filter(|first_unsent| {
+                self.send_window
+                    .iter()
+                    .skip(*first_unsent)
+                    .any(|sending_segment| sending_segment.segment.encoded_len() > mtu)
+            });
+        if let Some(first_unsent) = first_unsent {
+            let unsent = self.send_window.split_off(first_unsent);
+            self.send_next = unsent[0].segment.sequence;
+            let mut queue = VecDeque::with_capacity(unsent.len() + self.send_queue.len());
+            for sending_segment in unsent {
+                let segment = sending_segment.segment;
+                match segment.command {
+                    CMD_OPEN => self.open_data = Some(segment.data),
+                    _ => queue.push_back(BytesMut::from(&segment.data[..])),
+                }
+            }
+            queue.extend(self.send_queue.drain(..));
+            self.send_queue = queue;
+        } else if self.send_queue.iter().all(|data| data.len() <= self.mss) {
+            return;
+        }
+        let queue = std::mem::take(&mut self.send_queue);
+        for mut data in queue {
+            while data.len() > self.mss {
+                self.send_queue.push_back(data.split_to(self.mss));
+            }
+            self.send_queue.push_back(data);
+        }
+    }
+
     fn remove_from_send_window(&mut self, sequence: u32) {
         // Make sure send_una <= seq < send_next
         if i32diff(sequence, self.send_unack) < 0 || i32diff(sequence, self.send_next) >= 0 {
@@ -223,7 +1390,7 @@ impl KcpCore {
             if sequence == segment_seq {
                 self.send_window.remove(i);
                 break;
-            } else if sequence < segment_seq {
+            } else if i32diff(sequence, segment_seq) < 0 {
                 break;
             }
         }
@@ -244,30 +1411,67 @@ impl KcpCore {
         }
     }
 
-    fn handle_ack(&mut self, segment: &KcpSegment) {
+    fn handle_ecn_echo(&mut self) {
+        self.stats.ecn_echoes += 1;
+        if i32diff(self.now, self.ecn_reaction_ts) < 0 {
+            return;
+        }
+        self.ecn_reaction_ts = self
+            .now
+            .wrapping_add(cmp::max(self.srtt, self.config.min_interval));
+        match self.config.congestion {
+            Congestion::None => {}
+            Congestion::KcpReno => {
+                // Like a fast retransmission, but nothing needs to be resent
+                self.slow_start_thresh = cmp::max(self.congestion_window_size / 2, SSTHRESH_MIN);
+                self.congestion_window_size = self.slow_start_thresh;
+                self.congestion_window_bytes = self.congestion_window_size as usize * self.mss;
+            }
+            Congestion::LossTolerance => {
+                self.congestion_window_size -= self.congestion_window_size / 4;
+            }
+        }
+        log::trace!("ecn echo, cwnd = {}", self.congestion_window_size);
+    }
+
+    fn handle_ack(&mut self, segment: &KcpSegment) {
+        // | TIMESTAMP | SEQUENCE | (DELAY) |
+        let with_delay = segment.command == CMD_ACK_DELAY;
+        let entry_len = if with_delay { 12 } else { 8 };
         let mut cursor = &segment.data[..];
-        let mut max_ack = 0;
+        let mut max_ack = None;
         let mut ack_num = 0;
         let old_send_unack = self.send_unack;
 
-        while cursor.remaining() >= 8 {
+        while cursor.remaining() >= entry_len {
             let timestamp = cursor.get_u32_le();
             let sequence = cursor.get_u32_le();
+            let delay = if with_delay { cursor.get_u32_le() } else { 0 };
 
             if timestamp < self.now {
-                self.update_rtt(self.now - timestamp);
+                // The time the ack was held by the peer is not part of the path
+                let rtt = cmp::max((self.now - timestamp).saturating_sub(delay), 1);
+                // The OPEN takes the first sequence. It may be gone from the send window
+                // already, acked by the recv_next of a segment ahead of this ack.
+                let open_acked = sequence == self.config.initial_sequence;
+                if open_acked && self.stats.handshake_rtt == 0 {
+                    self.stats.handshake_rtt = rtt;
+                }
+                self.update_rtt(rtt);
             }
             self.remove_from_send_window(sequence);
-            if sequence > max_ack {
-                max_ack = sequence;
+            if max_ack.map_or(true, |max_ack| i32diff(sequence, max_ack) > 0) {
+                max_ack = Some(sequence);
             }
             ack_num += 1;
         }
 
         self.update_unack();
-        self.update_fast_rexmit(max_ack);
+        if let Some(max_ack) = max_ack {
+            self.update_fast_rexmit(max_ack);
+        }
 
-        if self.send_unack > old_send_unack {
+        if i32diff(self.send_unack, old_send_unack) > 0 {
             // Some packets were sent and acked successfully
             // It's time to update cwnd
             match self.config.congestion {
@@ -275,7 +1479,7 @@ impl KcpCore {
                 Congestion::KcpReno => {
                     for _ in 0..ack_num {
                         if self.congestion_window_size < self.remote_window_size {
-                            let mss = self.config.mss;
+                            let mss = self.mss;
                             if self.congestion_window_size < self.slow_start_thresh {
                                 // Slow start
                                 self.congestion_window_size += 1;
@@ -312,14 +1516,90 @@ impl KcpCore {
         log::trace!("input ack");
     }
 
+    fn handle_reset(&mut self, segment: &KcpSegment) {
+        let mut data = &segment.data[..];
+        if data.len() < 4 {
+            log::trace!("malformed reset");
+            return;
+        }
+        let code = data.get_u32_le();
+        let reason = String::from_utf8_lossy(data).into_owned();
+        log::trace!("reset by peer: {} {}", code, reason);
+        self.auth_failed |= code == AUTH_FAILED_RESET_CODE;
+        self.peer_reset = Some((code, reason));
+        // Aborted, what's not read yet is dropped
+        self.recv_queue.clear();
+        self.recv_window.clear();
+        self.recv_fragments.clear();
+        self.recv_skipped = 0;
+        // The peer forgot the stream, retransmitting while lingering for the ACKs would only
+        // time out and shrink the congestion window shared with the other streams
+        self.send_queue.clear();
+        self.send_window.clear();
+        self.update_unack();
+        self.force_close();
+    }
+
+    /// Checks the NONCE PROOF of the peer's OPEN against our key, anything goes without one
+    fn verify_peer(&mut self, auth: Option<Bytes>) -> bool {
+        let key = match &self.config.peer_auth_key {
+            Some(key) => key,
+            None => return true,
+        };
+        let auth = match auth {
+            Some(auth) => auth,
+            None => {
+                log::warn!("the peer of stream {} sent no fingerprint", self.stream_id);
+                return false;
+            }
+        };
+        let mut nonce = [0u8; AUTH_NONCE_LEN];
+        nonce.copy_from_slice(&auth[..AUTH_NONCE_LEN]);
+        self.remote_auth_nonce = Some(nonce);
+        let answered = if self.auth_initiator {
+            Some(&self.auth_nonce[..])
+        } else {
+            None
+        };
+        let expected = Self::auth_proof(key, &nonce, answered);
+        if constant_time::verify_slices_are_equal(&expected, &auth[AUTH_NONCE_LEN..]).is_err() {
+            log::warn!("the peer of stream {} holds another key", self.stream_id);
+            return false;
+        }
+        true
+    }
+
+    fn fail_auth(&mut self) {
+        self.auth_failed = true;
+        self.recv_queue.clear();
+        self.recv_window.clear();
+        self.recv_fragments.clear();
+        self.recv_skipped = 0;
+        self.reset(AUTH_FAILED_RESET_CODE, "peer authentication failed");
+    }
+
+    /// The peer failed `KcpConfig::peer_auth_key`, or we failed the peer's
+    #[inline]
+    pub fn is_auth_failed(&self) -> bool {
+        self.auth_failed
+    }
+
     fn handle_push(&mut self, segment: &KcpSegment) {
-        if i32diff(
-            segment.sequence,
-            self.recv_next + self.config.recv_window_size as u32,
-        ) < 0
-        {
+        if self.auth_failed {
+            return;
+        }
+        // Only what fits in the window advertised, even if the peer ignores it
+        let window_end = self.recv_next.wrapping_add(self.recv_window_unused());
+        if i32diff(segment.sequence, window_end) < 0 {
+            if i32diff(segment.sequence, self.recv_next) > 0
+                && !self.recv_window.contains_key(&segment.sequence)
+                && self.recv_window.len() >= self.config.recv_reorder_window as usize
+            {
+                log::trace!("reorder window is full, dropping {}", segment.sequence);
+                return;
+            }
             self.ack_list
-                .push_back((segment.timestamp, segment.sequence));
+                .push_back((segment.timestamp, segment.sequence, self.now));
             if self.ack_list.len() >= self.config.fast_ack_thresh as usize {
                 let _ = self.flush_notify_tx.try_send(());
             }
@@ -327,51 +1607,179 @@ impl KcpCore {
                 if !self.recv_window.contains_key(&segment.sequence) {
                     self.recv_window.insert(segment.sequence, segment.clone());
                 }
-                while self.recv_window.contains_key(&self.recv_next) {
-                    let segment = self.recv_window.remove(&self.recv_next).unwrap();
-                    // Empty payload, closing
-                    log::trace!("empty payload, closing");
-                    if segment.data.len() == 0 {
-                        // No more data from the peer
-                        // This is the last segment moved into send_queue
-                        self.close_state.set(CloseFlags::RX_CLOSED, true);
-                        // Try to close local tx
-                        if !self.close_state.contains(CloseFlags::TX_CLOSING) {
-                            self.close_state.set(CloseFlags::TX_CLOSING, true);
-                            self.send_queue.push_back(BytesMut::new());
+                self.deliver();
+            }
+        }
+
+        log::trace!("input push");
+    }
+
+    /// Moves the segments in order from `recv_window` to `recv_queue`
+    fn deliver(&mut self) {
+        while let Some(segment) = self.recv_window.get(&self.recv_next) {
+            if self.recv_skipped > 0 && segment.command != CMD_SKIP {
+                // The reader hears about the gap before the data past it
+                break;
+            }
+            let segment = self.recv_window.remove(&self.recv_next).unwrap();
+            if segment.command == CMD_SKIP {
+                // Abandoned by the peer
+                self.recv_next = self.recv_next.wrapping_add(1);
+                self.recv_skipped += 1;
+                continue;
+            }
+            if segment.command == CMD_OPEN {
+                if let Some(features) = segment.data.first() {
+                    self.remote_features = Features::from_bits_truncate(*features);
+                    let mut label = segment.data.slice(1..);
+                    if self.remote_features.contains(Features::WINDOW_SCALE) {
+                        if let Some(shift) = label.first() {
+                            self.remote_window_shift = cmp::min(*shift, MAX_WINDOW_SHIFT);
+                            label = label.slice(1..);
                         }
-                        break;
                     }
-                    self.recv_queue.push_back(segment.data);
-                    self.recv_next += 1;
+                    let mut auth = None;
+                    if self.remote_features.contains(Features::AUTH)
+                        && label.len() >= AUTH_NONCE_LEN + AUTH_PROOF_LEN
+                    {
+                        auth = Some(label.slice(..AUTH_NONCE_LEN + AUTH_PROOF_LEN));
+                        label = label.slice(AUTH_NONCE_LEN + AUTH_PROOF_LEN..);
+                    }
+                    self.label = label;
+                    if !self.verify_peer(auth) {
+                        self.fail_auth();
+                        return;
+                    }
+                }
+                self.recv_next = self.recv_next.wrapping_add(1);
+                continue;
+            }
+            // Empty payload, closing
+            log::trace!("empty payload, closing");
+            if segment.data.len() == 0 {
+                // No more data from the peer
+                // This is the last segment moved into send_queue
+                self.close_state.set(CloseFlags::RX_EOF, true);
+                // Try to close local tx, unless the peer only closed its own
+                if segment.command != CMD_HALF_CLOSE
+                    && !self.close_state.contains(CloseFlags::TX_CLOSING)
+                {
+                    self.close_state.set(CloseFlags::TX_CLOSING, true);
+                    self.send_queue.push_back(BytesMut::new());
                 }
+                break;
             }
+            self.stats.bytes_received += segment.data.len() as u64;
+            self.recv_queue.push_back(segment.data);
+            self.recv_next = self.recv_next.wrapping_add(1);
         }
+    }
 
-        log::trace!("input push");
+    /// The pieces are kept until the segment is whole, then it's acked and handled as the
+    /// PUSH it was. A piece of a segment received already only answers the lost ACK.
+    fn handle_fragment(&mut self, segment: &KcpSegment) {
+        if self.auth_failed || segment.data.len() <= FRAGMENT_HEADER_LEN {
+            return;
+        }
+        let mut data = segment.data.clone();
+        let total = data.get_u16_le() as usize;
+        let offset = data.get_u16_le() as usize;
+        let window_end = self.recv_next.wrapping_add(self.recv_window_unused());
+        if offset + data.len() > total || i32diff(segment.sequence, window_end) >= 0 {
+            return;
+        }
+        if i32diff(segment.sequence, self.recv_next) < 0
+            || self.recv_window.contains_key(&segment.sequence)
+        {
+            self.ack_list
+                .push_back((segment.timestamp, segment.sequence, self.now));
+            return;
+        }
+        let recv_next = self.recv_next;
+        self.recv_fragments
+            .retain(|sequence, _| i32diff(*sequence, recv_next) >= 0);
+        if !self.recv_fragments.contains_key(&segment.sequence)
+            && self.recv_fragments.len() >= self.config.recv_reorder_window as usize
+        {
+            log::trace!(
+                "reorder window is full, dropping fragment of {}",
+                segment.sequence
+            );
+            return;
+        }
+        let (expected, pieces) = self
+            .recv_fragments
+            .entry(segment.sequence)
+            .or_insert_with(|| (total, BTreeMap::new()));
+        if *expected != total {
+            return;
+        }
+        pieces.insert(offset, data);
+        // The pieces of different mtus may overlap
+        let mut covered = 0;
+        for (offset, piece) in pieces.iter() {
+            if *offset > covered {
+                return;
+            }
+            covered = cmp::max(covered, offset + piece.len());
+        }
+        if covered < total {
+            return;
+        }
+        let (_, pieces) = self.recv_fragments.remove(&segment.sequence).unwrap();
+        let mut whole = BytesMut::with_capacity(total);
+        for (offset, piece) in pieces {
+            if offset + piece.len() > whole.len() {
+                let skipped = whole.len() - offset;
+                whole.extend_from_slice(&piece[skipped..]);
+            }
+        }
+        self.handle_push(&KcpSegment {
+            command: CMD_PUSH,
+            data: whole.freeze(),
+            ..segment.clone()
+        });
     }
 
     pub fn input(&mut self, segments: Vec<KcpSegment>) -> KcpResult<()> {
-        self.now = now_millis();
+        self.now = self.config.clock.now_millis();
         self.last_active = self.now;
+        self.last_input = self.now;
+        self.load_congestion();
 
         for segment in &segments {
             assert_eq!(segment.stream_id, self.stream_id);
             log::trace!("input segment: {:?}", segment);
-            self.remote_window_size = segment.recv_window_size;
+            trace_segment(&self.config, TraceDirection::Received, segment);
+            self.remote_window_size = self.remote_window(segment);
             self.remove_send_window_until(segment.recv_next);
             self.update_unack();
 
             match segment.command {
-                CMD_ACK => {
+                CMD_ACK | CMD_ACK_DELAY => {
                     self.handle_ack(segment);
                 }
-                CMD_PUSH => {
+                CMD_PUSH | CMD_OPEN | CMD_SKIP | CMD_HALF_CLOSE => {
                     self.handle_push(segment);
                 }
+                CMD_FRAGMENT => {
+                    self.handle_fragment(segment);
+                }
                 CMD_PING => {
                     log::trace!("input ping");
                 }
+                CMD_WINDOW_PROBE => {
+                    log::trace!("input window probe");
+                    // The next flush pings, telling our window
+                    self.ping_ts = self.now;
+                    let _ = self.flush_notify_tx.try_send(());
+                }
+                CMD_ECN_ECHO => {
+                    self.handle_ecn_echo();
+                }
+                CMD_RESET => {
+                    self.handle_reset(segment);
+                }
                 _ => unreachable!(),
             }
         }
@@ -383,9 +1791,19 @@ impl KcpCore {
             // The last empty packet was sent and acked by the peer
             log::trace!("TX_CLOSING to TX_CLOSED");
             self.close_state.set(CloseFlags::TX_CLOSED, true);
+            if let Some(waker) = self.close_waker.take() {
+                waker.wake();
+            }
         }
 
+        self.stats.peak_congestion_window = cmp::max(
+            self.stats.peak_congestion_window,
+            self.congestion_window_size,
+        );
+        self.store_congestion();
         self.try_wake_stream();
+        // Acks and window updates are due
+        self.wake_if_sleeping();
         Ok(())
     }
 
@@ -397,7 +1815,11 @@ impl KcpCore {
             waker.wake();
         }
 
-        if self.recv_ready() && self.recv_waker.is_some() {
+        // The EOF of a HALF_CLOSE comes alone, nothing else wakes the reader then
+        let recv_news = self.recv_ready()
+            || self.recv_skipped > 0
+            || self.close_state.contains(CloseFlags::RX_CLOSED);
+        if recv_news && self.recv_waker.is_some() {
             let waker = self.recv_waker.take().unwrap();
             log::trace!("waking recv task");
             waker.wake();
@@ -422,39 +1844,63 @@ impl KcpCore {
 
     #[inline]
     fn flush_ready(&self) -> bool {
-        self.send_queue.is_empty() && self.send_window.is_empty()
+        // Everything is sent and acked by the peer
+        self.open_data.is_none() && self.send_queue.is_empty() && self.send_window.is_empty()
     }
 
     pub fn poll_send(&mut self, cx: &Context, payload: &[u8]) -> Poll<KcpResult<()>> {
+        self.poll_send_vectored(cx, &[IoSlice::new(payload)])
+    }
+
+    /// Segments the slices one after another, without joining them first
+    pub fn poll_send_vectored(
+        &mut self,
+        cx: &Context,
+        payloads: &[IoSlice<'_>],
+    ) -> Poll<KcpResult<()>> {
         if self.close_state.contains(CloseFlags::TX_CLOSING) {
-            return Poll::Ready(Err(KcpError::Shutdown(format!(
-                "poll_send on a closing kcp core: {}",
-                self.close_state.bits,
-            ))));
+            return Poll::Ready(Err(self.closing_error("poll_send")));
         }
 
-        self.now = now_millis();
+        self.now = self.config.clock.now_millis();
         self.last_active = self.now;
+        self.last_app_active = self.now;
+
+        if !self.send_ready() {
+            match self.config.send_overflow_policy {
+                SendOverflowPolicy::Backpressure => {}
+                SendOverflowPolicy::DropOldest => {
+                    while !self.send_ready() {
+                        self.send_queue.pop_front();
+                        self.stats.send_queue_dropped += 1;
+                    }
+                }
+                SendOverflowPolicy::Error => return Poll::Ready(Err(KcpError::SendQueueFull)),
+            }
+        }
 
         if self.send_ready() {
-            let mss = self.config.mss;
+            let mss = self.mss;
             if self.send_queue.is_empty() {
                 self.send_queue.push_back(BytesMut::with_capacity(mss));
             }
 
-            let mut cursor = payload;
+            for payload in payloads {
+                let mut cursor: &[u8] = payload;
 
-            while cursor.has_remaining() {
-                if self.send_queue.back_mut().unwrap().len() < mss {
-                    let back = self.send_queue.back_mut().unwrap();
-                    let len = cmp::min(cursor.remaining(), mss - back.len());
-                    back.extend_from_slice(&cursor[..len]);
-                    cursor.advance(len);
-                } else {
-                    self.send_queue.push_back(BytesMut::with_capacity(mss));
+                while cursor.has_remaining() {
+                    if self.send_queue.back_mut().unwrap().len() < mss {
+                        let back = self.send_queue.back_mut().unwrap();
+                        let len = cmp::min(cursor.remaining(), mss - back.len());
+                        back.extend_from_slice(&cursor[..len]);
+                        cursor.advance(len);
+                    } else {
+                        self.send_queue.push_back(BytesMut::with_capacity(mss));
+                    }
                 }
             }
 
+            self.wake_if_sleeping();
             Poll::Ready(Ok(()))
         } else {
             let _ = self.flush_notify_tx.try_send(());
@@ -465,19 +1911,27 @@ impl KcpCore {
     }
 
     pub fn poll_recv(&mut self, cx: &Context) -> Poll<KcpResult<VecDeque<Bytes>>> {
-        self.now = now_millis();
+        self.now = self.config.clock.now_millis();
         self.last_active = self.now;
 
         if self.recv_ready() {
+            self.last_app_active = self.now;
             let queue = self.recv_queue.clone();
             self.recv_queue.clear();
             return Poll::Ready(Ok(queue));
         } else {
+            if self.recv_skipped > 0 {
+                // Everything before the gap is read, the data past it follows the error
+                let skipped = std::mem::take(&mut self.recv_skipped);
+                self.deliver();
+                return Poll::Ready(Err(KcpError::DataSkipped(skipped)));
+            }
+            if self.close_state.contains(CloseFlags::RX_EOF) {
+                // The peer closed its write side, an empty queue means EOF
+                return Poll::Ready(Ok(VecDeque::new()));
+            }
             if self.close_state.contains(CloseFlags::RX_CLOSED) {
-                return Poll::Ready(Err(KcpError::Shutdown(format!(
-                    "poll_recv on a closing kcp core: {}",
-                    self.close_state.bits,
-                ))));
+                return Poll::Ready(Err(self.closing_error("poll_recv")));
             }
             log::trace!("poll_recv pending");
             self.recv_waker = Some(cx.waker().clone());
@@ -485,20 +1939,28 @@ impl KcpCore {
         }
     }
 
+    /// The payloads delivered in order but not received by the application yet, without
+    /// waiting. Segments held back by a gap are not included.
+    pub fn take_recv_queue(&mut self) -> VecDeque<Bytes> {
+        self.now = self.config.clock.now_millis();
+        self.last_active = self.now;
+        self.last_app_active = self.now;
+        std::mem::take(&mut self.recv_queue)
+    }
+
     pub fn poll_flush(&mut self, cx: &Context) -> Poll<KcpResult<()>> {
         if self.close_state.contains(CloseFlags::TX_CLOSING) {
-            return Poll::Ready(Err(KcpError::Shutdown(format!(
-                "poll_recv on a closing kcp core: {}",
-                self.close_state.bits,
-            ))));
+            return Poll::Ready(Err(self.closing_error("poll_flush")));
         }
 
-        self.now = now_millis();
+        self.now = self.config.clock.now_millis();
         self.last_active = self.now;
 
         if self.flush_ready() {
             Poll::Ready(Ok(()))
         } else {
+            // Don't wait for the next interval
+            let _ = self.flush_notify_tx.try_send(());
             self.flush_waker = Some(cx.waker().clone());
             Poll::Pending
         }
@@ -510,10 +1972,77 @@ impl KcpCore {
         } else {
             self.close_state.set(CloseFlags::TX_CLOSING, true);
             self.send_queue.push_back(BytesMut::new());
+            self.wake_if_sleeping();
             Ok(())
         }
     }
 
+    /// Like `try_close`, but the peer may keep writing after it reads EOF
+    pub fn try_close_write(&mut self) -> KcpResult<()> {
+        self.try_close()?;
+        self.half_close = true;
+        Ok(())
+    }
+
+    /// Half-close, ready once everything written including the FIN is acknowledged. Receiving
+    /// goes on until the peer closes.
+    pub fn poll_close_write(&mut self, cx: &Context) -> Poll<KcpResult<()>> {
+        if !self.close_state.contains(CloseFlags::TX_CLOSING) {
+            let _ = self.try_close_write();
+        }
+        if self.close_state.contains(CloseFlags::TX_CLOSED) {
+            Poll::Ready(Ok(()))
+        } else {
+            self.close_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    /// Aborts the stream at once, the peer gets `KcpError::PeerReset` with `code` and
+    /// `reason`. Unsent and unacked data is dropped.
+    pub fn reset(&mut self, code: u32, reason: &str) {
+        let mut data = BytesMut::with_capacity(4 + reason.len());
+        data.put_u32_le(code);
+        data.put_slice(reason.as_bytes());
+        self.reset_data = Some(data.freeze());
+        self.send_queue.clear();
+        self.force_close();
+        let _ = self.flush_notify_tx.try_send(());
+    }
+
+    /// Resend every segment in flight with the next flush, like a fast retransmit, instead of
+    /// waiting for the RTO. Honored at most once per smoothed RTT, and at least `rto_min`
+    /// apart. False when it's too soon or nothing is in flight.
+    pub fn request_retransmit(&mut self) -> bool {
+        self.now = self.config.clock.now_millis();
+        if let Some(ts) = self.rexmit_request_ts {
+            if i32diff(self.now, ts) < cmp::max(self.srtt, self.config.rto_min) as i32 {
+                return false;
+            }
+        }
+        let mut requested = false;
+        for sending_segment in &mut self.send_window {
+            if sending_segment.rexmit_counter > 0 {
+                sending_segment.rexmit_requested = true;
+                requested = true;
+            }
+        }
+        if requested {
+            self.rexmit_request_ts = Some(self.now);
+            let _ = self.flush_notify_tx.try_send(());
+        }
+        requested
+    }
+
+    pub fn close_immediate(&mut self) -> KcpResult<()> {
+        if self.close_state.contains(CloseFlags::TX_CLOSING) {
+            return Err(KcpError::Shutdown("kcp core is shutting down".to_string()));
+        }
+        // Segments in the sending window are kept, the peer still expects them
+        self.send_queue.clear();
+        self.try_close()
+    }
+
     pub fn poll_close(&mut self, cx: &Context) -> Poll<KcpResult<()>> {
         if !self.close_state.contains(CloseFlags::TX_CLOSING) {
             self.close_state.set(CloseFlags::TX_CLOSING, true);
@@ -544,13 +2073,46 @@ impl KcpCore {
         segment: &KcpSegment,
         buffer: &mut BytesMut,
         io: &IO,
+        config: &KcpConfig,
         mtu: usize,
     ) -> KcpResult<()> {
-        if buffer.len() + segment.encoded_len() > mtu {
+        if buffer.len() + segment.framed_len(config.single_stream) > mtu {
             io.send_packet(buffer).await?;
             buffer.clear();
         }
-        segment.encode(buffer);
+        trace_segment(config, TraceDirection::Sent, segment);
+        segment.encode_framed(buffer, config.single_stream);
+        Ok(())
+    }
+
+    /// A sent segment exceeding the mtu lowered since, cut into FRAGMENTs of its sequence
+    async fn encode_fragments<IO: KcpIo>(
+        segment: &KcpSegment,
+        buffer: &mut BytesMut,
+        io: &IO,
+        config: &KcpConfig,
+        mtu: usize,
+    ) -> KcpResult<()> {
+        let room = cmp::max(
+            1,
+            mtu.saturating_sub(KcpSegment::header_len(config.single_stream) + FRAGMENT_HEADER_LEN),
+        );
+        let total = segment.data.len();
+        let mut offset = 0;
+        while offset < total {
+            let len = cmp::min(room, total - offset);
+            let mut data = BytesMut::with_capacity(FRAGMENT_HEADER_LEN + len);
+            data.put_u16_le(total as u16);
+            data.put_u16_le(offset as u16);
+            data.extend_from_slice(&segment.data[offset..offset + len]);
+            let fragment = KcpSegment {
+                command: CMD_FRAGMENT,
+                data: data.freeze(),
+                ..segment.clone()
+            };
+            Self::encode_segment(&fragment, buffer, io, config, mtu).await?;
+            offset += len;
+        }
         Ok(())
     }
 
@@ -558,28 +2120,68 @@ impl KcpCore {
         if self.ack_list.is_empty() {
             return Ok(());
         }
-        let mut data = BytesMut::new();
-        data.resize(4 * 2 * self.ack_list.len(), 0);
+        let with_delay = self.get_features().contains(Features::ACK_DELAY);
+        let entry_len = if with_delay { 4 * 3 } else { 4 * 2 };
+        let per_packet = cmp::max(
+            1,
+            cmp::min(
+                self.config.max_acks_per_packet,
+                self.mtu.saturating_sub(KCP_HEADER_LEN) / entry_len,
+            ),
+        );
+        let recv_window_unused = self.advertised_window();
+        let acks: Vec<_> = self.ack_list.drain(..).collect();
+
+        for (i, chunk) in acks.chunks(per_packet).enumerate() {
+            if i > 0 {
+                // One ACK segment per packet, so the cap holds whatever else is in the buffer
+                writer.send_packet(&self.buffer).await?;
+                self.buffer.clear();
+            }
+            let mut data = BytesMut::new();
+            data.resize(entry_len * chunk.len(), 0);
 
-        let mut cursor = &mut data[..];
+            let mut cursor = &mut data[..];
 
-        for (timestamp, sequence) in &self.ack_list {
-            cursor.put_u32_le(*timestamp);
-            cursor.put_u32_le(*sequence);
+            for (timestamp, sequence, arrival) in chunk {
+                cursor.put_u32_le(*timestamp);
+                cursor.put_u32_le(*sequence);
+                if with_delay {
+                    cursor.put_u32_le(self.now.wrapping_sub(*arrival));
+                }
+            }
+
+            let segment = KcpSegment {
+                stream_id: self.stream_id,
+                command: if with_delay { CMD_ACK_DELAY } else { CMD_ACK },
+                recv_window_size: recv_window_unused,
+                recv_next: self.recv_next,
+                sequence: 0,
+                timestamp: 0,
+                data: data.freeze(),
+            };
+            Self::encode_segment(&segment, &mut self.buffer, writer, &self.config, self.mtu)
+                .await?;
         }
+        Ok(())
+    }
 
-        let segment = KcpSegment {
-            stream_id: self.stream_id,
-            command: CMD_ACK,
-            recv_window_size: self.recv_window_unused(),
-            recv_next: self.recv_next,
-            sequence: 0,
-            timestamp: 0,
-            data: data.freeze(),
+    /// Called after sending data, whose recv_next already acks every in-order segment.
+    /// The ack entries ride in the last packet if they fit, otherwise only the out-of-order
+    /// ones are sent.
+    async fn piggyback_ack<IO: KcpIo>(&mut self, writer: &IO) -> KcpResult<()> {
+        let entry_len = if self.get_features().contains(Features::ACK_DELAY) {
+            4 * 3
+        } else {
+            4 * 2
         };
-        Self::encode_segment(&segment, &mut self.buffer, writer, self.config.mtu).await?;
-        self.ack_list.clear();
-        Ok(())
+        let len = KCP_HEADER_LEN + entry_len * self.ack_list.len();
+        if self.buffer.len() + len > self.mtu {
+            let recv_next = self.recv_next;
+            self.ack_list
+                .retain(|(_, sequence, _)| i32diff(*sequence, recv_next) >= 0);
+        }
+        self.flush_ack(writer).await
     }
 
     async fn flush_ping<IO: KcpIo>(&mut self, writer: &IO) -> KcpResult<()> {
@@ -589,28 +2191,119 @@ impl KcpCore {
             let segment = KcpSegment {
                 stream_id: self.stream_id,
                 command: CMD_PING,
-                recv_window_size: self.recv_window_unused(),
+                recv_window_size: self.advertised_window(),
                 recv_next: self.recv_next,
                 sequence: self.send_next,
                 timestamp: self.now,
                 data: Bytes::new(),
             };
-            Self::encode_segment(&segment, &mut self.buffer, writer, self.config.mtu).await?;
+            Self::encode_segment(&segment, &mut self.buffer, writer, &self.config, self.mtu)
+                .await?;
         }
         Ok(())
     }
 
+    /// The persist timer. Without it, a lost window update leaves both sides waiting until
+    /// the peer's next keep-alive.
+    async fn flush_window_probe<IO: KcpIo>(&mut self, writer: &IO) -> KcpResult<()> {
+        if self.remote_window_size > 0 || self.send_queue.is_empty() || !self.send_window.is_empty()
+        {
+            self.probe_wait = 0;
+            return Ok(());
+        }
+        if self.probe_wait == 0 {
+            self.probe_wait = self.config.window_probe_interval;
+            self.probe_ts = self.now + self.probe_wait;
+            return Ok(());
+        }
+        if i32diff(self.now, self.probe_ts) < 0 {
+            return Ok(());
+        }
+        log::trace!("probing zero window");
+        self.probe_wait = cmp::min(self.probe_wait * 2, self.config.keep_alive_interval);
+        self.probe_ts = self.now + self.probe_wait;
+        let segment = KcpSegment {
+            stream_id: self.stream_id,
+            command: CMD_WINDOW_PROBE,
+            recv_window_size: self.advertised_window(),
+            recv_next: self.recv_next,
+            sequence: self.send_next,
+            timestamp: self.now,
+            data: Bytes::new(),
+        };
+        Self::encode_segment(&segment, &mut self.buffer, writer, &self.config, self.mtu).await
+    }
+
+    async fn flush_ecn_echo<IO: KcpIo>(&mut self, writer: &IO) -> KcpResult<()> {
+        if !self.ecn_echo_pending {
+            return Ok(());
+        }
+        self.ecn_echo_pending = false;
+        let segment = KcpSegment {
+            stream_id: self.stream_id,
+            command: CMD_ECN_ECHO,
+            recv_window_size: self.advertised_window(),
+            recv_next: self.recv_next,
+            sequence: 0,
+            timestamp: self.now,
+            data: Bytes::new(),
+        };
+        Self::encode_segment(&segment, &mut self.buffer, writer, &self.config, self.mtu).await
+    }
+
     #[inline]
-    fn recv_window_unused(&self) -> u16 {
-        if self.recv_queue.len() < self.config.recv_window_size as usize {
-            self.config.recv_window_size - self.recv_queue.len() as u16
+    /// The window field of our segments. The peer scales it once it has our OPEN, which
+    /// it tells by acking it, like we know its shift once we have delivered its own.
+    fn advertised_window(&self) -> u16 {
+        let unused = self.recv_window_unused();
+        let window = if self.recv_next != self.config.initial_sequence
+            && self.get_features().contains(Features::WINDOW_SCALE)
+        {
+            unused >> self.local_window_shift
+        } else {
+            unused
+        };
+        cmp::min(window, 0xffff) as u16
+    }
+
+    /// The peer's window in segments, see `advertised_window`
+    fn remote_window(&self, segment: &KcpSegment) -> u32 {
+        let window = segment.recv_window_size as u32;
+        if segment.recv_next != self.config.initial_sequence
+            && self.get_features().contains(Features::WINDOW_SCALE)
+        {
+            window << self.remote_window_shift
+        } else {
+            window
+        }
+    }
+
+    fn recv_window_unused(&self) -> u32 {
+        if self.recv_queue.len() < self.local_recv_window as usize {
+            self.local_recv_window - self.recv_queue.len() as u32
         } else {
             0
         }
     }
 
     pub async fn flush<IO: KcpIo>(&mut self, io: &IO) -> KcpResult<()> {
-        self.now = now_millis();
+        self.now = self.config.clock.now_millis();
+
+        if let Some(data) = self.reset_data.take() {
+            let segment = KcpSegment {
+                stream_id: self.stream_id,
+                command: CMD_RESET,
+                recv_window_size: 0,
+                recv_next: self.recv_next,
+                sequence: 0,
+                timestamp: self.now,
+                data,
+            };
+            Self::encode_segment(&segment, &mut self.buffer, io, &self.config, self.mtu).await?;
+            io.send_packet(&self.buffer).await?;
+            self.buffer.clear();
+            return Err(KcpError::Shutdown("kcp core is reset".to_string()));
+        }
 
         // Keep working until the core is fully closed
         if self.close_state.contains(CloseFlags::CLOSED) {
@@ -633,46 +2326,139 @@ impl KcpCore {
             ));
         }
 
-        self.flush_ack(io).await?;
-        self.flush_ping(io).await?;
+        if let Some(deadline) = self.deadline {
+            if i32diff(self.now, deadline) >= 0 {
+                if !self.lifetime_expired {
+                    log::trace!("lifetime expired, closing");
+                    self.lifetime_expired = true;
+                    // Everything written so far is still delivered
+                    let _ = self.try_close();
+                } else if i32diff(self.now, deadline.wrapping_add(self.config.timeout)) >= 0 {
+                    // The peer didn't finish closing in time
+                    self.force_close();
+                    return Err(KcpError::LifetimeExpired);
+                }
+            }
+        }
 
-        let mut final_window_size = cmp::min(self.config.send_window_size, self.remote_window_size);
-        match self.config.congestion {
-            Congestion::None => {}
-            _ => {
-                final_window_size = cmp::min(final_window_size, self.congestion_window_size);
+        if let Some(idle_timeout) = self.config.stream_idle_timeout {
+            if !self.idle_expired
+                && !self.close_state.contains(CloseFlags::TX_CLOSING)
+                && i32diff(self.now, self.last_app_active) >= idle_timeout.as_millis() as i32
+            {
+                log::trace!("stream idle, closing");
+                self.idle_expired = true;
+                let _ = self.try_close();
             }
         }
 
-        let recv_window_unused = self.recv_window_unused();
+        self.load_congestion();
+
+        let final_window_size = self.get_send_window();
+
+        // Pending acks wait for the new data, and ride on it
+        let piggyback = (self.open_data.is_some() || !self.send_queue.is_empty())
+            && i32diff(
+                self.send_next,
+                self.send_unack.wrapping_add(final_window_size),
+            ) < 0;
+        if !piggyback {
+            self.flush_ack(io).await?;
+        }
+        self.flush_ping(io).await?;
+        self.flush_window_probe(io).await?;
+        self.flush_ecn_echo(io).await?;
+        self.check_black_hole();
+        self.refragment();
+
+        let recv_window_unused = self.advertised_window();
+        let mut inflight_bytes: usize = match self.config.max_inflight_bytes {
+            Some(_) => self
+                .send_window
+                .iter()
+                .map(|sending_segment| sending_segment.segment.data.len())
+                .sum(),
+            None => 0,
+        };
 
         // Push data into sending window
-        while i32diff(self.send_next, self.send_unack + final_window_size as u32) < 0 {
-            match self.send_queue.pop_front() {
-                Some(data) => {
-                    let segment = KcpSegment {
-                        stream_id: self.stream_id,
-                        command: CMD_PUSH,
-                        sequence: self.send_next,
-                        timestamp: self.now,
-                        recv_window_size: recv_window_unused,
-                        recv_next: self.recv_next,
-                        data: data.freeze(),
-                    };
-                    let sending_segment = SendingKcpSegment {
-                        segment,
-                        rexmit_timestamp: self.now,
-                        rto: self.rto,
-                        fast_rexmit_counter: 0,
-                        rexmit_counter: 0,
-                    };
-                    self.send_next += 1;
-                    self.send_window.push_back(sending_segment);
-                }
-                None => {
+        while i32diff(
+            self.send_next,
+            self.send_unack.wrapping_add(final_window_size),
+        ) < 0
+        {
+            if let Some(max_inflight) = self.config.max_inflight_bytes {
+                let next_len = match (&self.open_data, self.send_queue.front()) {
+                    (Some(data), _) => data.len(),
+                    (None, Some(data)) => data.len(),
+                    (None, None) => 0,
+                };
+                if !self.send_window.is_empty() && inflight_bytes + next_len > max_inflight {
                     break;
                 }
+                inflight_bytes += next_len;
             }
+            let (command, data) = match self.open_data.take() {
+                Some(data) => (CMD_OPEN, data),
+                None => match self.send_queue.pop_front() {
+                    Some(data)
+                        if data.is_empty()
+                            && self.half_close
+                            && self.recv_next == self.config.initial_sequence =>
+                    {
+                        // Whether the peer supports half-close is only known from its OPEN
+                        self.send_queue.push_front(data);
+                        break;
+                    }
+                    Some(data) => {
+                        let command = if data.is_empty()
+                            && self.half_close
+                            && self.get_features().contains(Features::HALF_CLOSE)
+                        {
+                            CMD_HALF_CLOSE
+                        } else {
+                            CMD_PUSH
+                        };
+                        (command, data.freeze())
+                    }
+                    None => {
+                        break;
+                    }
+                },
+            };
+            let segment = KcpSegment {
+                stream_id: self.stream_id,
+                command,
+                sequence: self.send_next,
+                timestamp: self.now,
+                recv_window_size: recv_window_unused,
+                recv_next: self.recv_next,
+                data,
+            };
+            let sending_segment = SendingKcpSegment {
+                segment,
+                sent_timestamp: self.now,
+                rexmit_timestamp: self.now,
+                rto: self.rto,
+                fast_rexmit_counter: 0,
+                rexmit_counter: 0,
+                rexmit_requested: false,
+            };
+            self.send_next = self.send_next.wrapping_add(1);
+            self.send_window.push_back(sending_segment);
+        }
+
+        // Data left behind a full window, which only the congestion window made that small
+        self.stats.flushes += 1;
+        self.window_limited = !self.send_queue.is_empty()
+            && i32diff(
+                self.send_next,
+                self.send_unack.wrapping_add(final_window_size),
+            ) >= 0;
+        if self.window_limited
+            && final_window_size < cmp::min(self.config.send_window_size, self.remote_window_size)
+        {
+            self.stats.window_limited_flushes += 1;
         }
 
         let fast_rexmit_thresh = self.config.fast_rexmit_thresh;
@@ -685,19 +2471,72 @@ impl KcpCore {
 
         let mut rexmit = 0;
         let mut fast_rexmit = 0;
+        let segment_ttl = self.config.segment_ttl.map(|ttl| ttl.as_millis() as i32);
+        let rate_limiter = self.rate_limiter.clone();
+        let mut segments_left = self.config.max_segments_per_tick;
+        let fragment = self.get_features().contains(Features::FRAGMENT);
 
         for sending_segment in &mut self.send_window {
             let mut need_send = false;
-            if sending_segment.rexmit_counter == 0 {
+            let expired = match segment_ttl {
+                Some(ttl) => {
+                    sending_segment.rexmit_counter > 0
+                        && sending_segment.segment.command == CMD_PUSH
+                        // Never drop FIN
+                        && !sending_segment.segment.data.is_empty()
+                        && i32diff(self.now, sending_segment.sent_timestamp) > ttl
+                }
+                None => false,
+            };
+            let due = expired
+                || sending_segment.rexmit_counter == 0
+                || i32diff(self.now, sending_segment.rexmit_timestamp) >= 0
+                || sending_segment.fast_rexmit_counter > fast_rexmit_thresh
+                || sending_segment.rexmit_requested;
+            if due && segments_left == Some(0) {
+                // The cap of this tick is reached, the coming flushes send the rest
+                break;
+            }
+            if let Some(limiter) = &rate_limiter {
+                if due
+                    && !limiter
+                        .lock()
+                        .unwrap()
+                        .available(self.now, self.stream_id, self.priority)
+                {
+                    // Over the cap or another stream's turn, the coming flushes send the rest
+                    break;
+                }
+            }
+            if expired {
+                // Keep the sequence number but drop the stale payload
+                sending_segment.segment.command = CMD_SKIP;
+                sending_segment.segment.data = Bytes::new();
+                self.stats.segments_expired += 1;
+                need_send = true;
+            } else if sending_segment.rexmit_counter == 0 {
                 // First time
-                sending_segment.rto = self.rto;
-                sending_segment.rexmit_timestamp = self.now + self.rto + rexmit_delay;
+                if sending_segment.segment.command == CMD_OPEN {
+                    sending_segment.rto = self.config.handshake_interval;
+                    sending_segment.rexmit_timestamp = self.now + sending_segment.rto;
+                } else {
+                    sending_segment.rto = self.rto;
+                    sending_segment.rexmit_timestamp = self.now + self.rto + rexmit_delay;
+                }
                 need_send = true;
             } else if i32diff(self.now, sending_segment.rexmit_timestamp) >= 0 {
                 // Timeout, rexmit
                 need_send = true;
                 rexmit += 1;
-                if self.config.nodelay {
+                if sending_segment.segment.command == CMD_OPEN {
+                    // The handshake keeps its own pace
+                    if sending_segment.rexmit_counter > self.config.handshake_retries {
+                        log::trace!("handshake timed out, closed");
+                        self.handshake_failed = true;
+                        self.force_close();
+                        return Err(KcpError::HandshakeTimeout);
+                    }
+                } else if self.config.nodelay {
                     // ~ 1.5x rto
                     sending_segment.rto += self.rto / 2;
                 } else {
@@ -710,19 +2549,58 @@ impl KcpCore {
                 need_send = true;
                 fast_rexmit += 1;
                 sending_segment.fast_rexmit_counter = 0;
+            } else if sending_segment.rexmit_requested {
+                // Asked for by the application, the window is left alone
+                need_send = true;
             }
 
             if need_send {
+                if let Some(left) = &mut segments_left {
+                    *left -= 1;
+                }
+                sending_segment.rexmit_requested = false;
                 sending_segment.rexmit_counter += 1;
+                self.stats.segments_sent += 1;
+                if sending_segment.rexmit_counter == 1 {
+                    self.stats.bytes_sent += sending_segment.segment.data.len() as u64;
+                } else {
+                    self.stats.segments_retransmitted += 1;
+                    self.stats.bytes_retransmitted += sending_segment.segment.data.len() as u64;
+                }
                 sending_segment.segment.timestamp = self.now;
                 sending_segment.segment.recv_window_size = recv_window_unused;
-                Self::encode_segment(
-                    &sending_segment.segment,
-                    &mut self.buffer,
-                    io,
-                    self.config.mtu,
-                )
-                .await?;
+                sending_segment.segment.recv_next = self.recv_next;
+                if fragment
+                    && sending_segment.segment.command == CMD_PUSH
+                    && sending_segment
+                        .segment
+                        .framed_len(self.config.single_stream)
+                        > self.mtu
+                {
+                    Self::encode_fragments(
+                        &sending_segment.segment,
+                        &mut self.buffer,
+                        io,
+                        &self.config,
+                        self.mtu,
+                    )
+                    .await?;
+                } else {
+                    Self::encode_segment(
+                        &sending_segment.segment,
+                        &mut self.buffer,
+                        io,
+                        &self.config,
+                        self.mtu,
+                    )
+                    .await?;
+                }
+                if let Some(limiter) = &rate_limiter {
+                    limiter
+                        .lock()
+                        .unwrap()
+                        .consume(self.stream_id, sending_segment.segment.encoded_len());
+                }
                 if sending_segment.rexmit_counter >= self.config.max_rexmit_time {
                     log::trace!("retransmitted for too many times, closed");
                     self.force_close();
@@ -731,6 +2609,10 @@ impl KcpCore {
             }
         }
 
+        if piggyback {
+            self.piggyback_ack(io).await?;
+        }
+
         if !self.buffer.is_empty() {
             io.send_packet(&mut self.buffer).await?;
             self.buffer.clear();
@@ -739,13 +2621,13 @@ impl KcpCore {
         match self.config.congestion {
             Congestion::None => {}
             Congestion::KcpReno => {
-                let mss = self.config.mss;
+                let mss = self.mss;
                 if fast_rexmit > 0 {
                     // Some ack packets was skipped
-                    let inflight_packet = (self.send_next - self.send_unack) as u16;
+                    let inflight_packet = self.send_next.wrapping_sub(self.send_unack);
                     self.slow_start_thresh = cmp::max(inflight_packet / 2, SSTHRESH_MIN);
                     self.congestion_window_size =
-                        self.slow_start_thresh + self.config.fast_rexmit_thresh as u16;
+                        self.slow_start_thresh + self.config.fast_rexmit_thresh;
                     self.congestion_window_bytes = self.congestion_window_size as usize * mss;
                     log::trace!(
                         "fast resent, cwnd = {}, incr = {}",
@@ -786,17 +2668,87 @@ impl KcpCore {
             }
         }
 
+        self.stats.peak_congestion_window = cmp::max(
+            self.stats.peak_congestion_window,
+            self.congestion_window_size,
+        );
+        self.store_congestion();
         self.try_wake_stream();
+        spans::record_sequences(self.send_unack, self.send_next, self.recv_next);
         Ok(())
     }
 
-    #[inline]
-    pub fn get_interval(&self) -> u32 {
-        let mut interval = self.config.max_interval;
-        for i in &self.send_window {
-            let delta = i32diff(self.now, i.rexmit_timestamp);
-            if delta < 0 {
-                return self.config.min_interval;
+    /// Nothing to send, ack or retransmit, only the timers are left
+    fn is_idle(&self) -> bool {
+        self.send_queue.is_empty()
+            && self.send_window.is_empty()
+            && self.ack_list.is_empty()
+            && self.open_data.is_none()
+            && self.reset_data.is_none()
+            && !self.ecn_echo_pending
+            && !self.close_state.contains(CloseFlags::CLOSED)
+            && i32diff(self.now, self.last_input) >= self.config.max_interval as i32
+    }
+
+    /// The earliest of the keep-alive, timeout, persist, lifetime and idle timers
+    fn next_timer(&self) -> u32 {
+        let mut timers = vec![
+            self.ping_ts,
+            self.last_active
+                .wrapping_add(self.config.timeout)
+                .wrapping_add(1),
+        ];
+        if self.probe_wait > 0 {
+            timers.push(self.probe_ts);
+        }
+        if self.close_ts != 0 {
+            timers.push(self.close_ts);
+        }
+        if let Some(deadline) = self.deadline {
+            if self.lifetime_expired {
+                timers.push(deadline.wrapping_add(self.config.timeout));
+            } else {
+                timers.push(deadline);
+            }
+        }
+        if let Some(idle_timeout) = self.config.stream_idle_timeout {
+            if !self.idle_expired {
+                timers.push(
+                    self.last_app_active
+                        .wrapping_add(idle_timeout.as_millis() as u32),
+                );
+            }
+        }
+        let mut delta = i32::MAX;
+        for timer in timers {
+            delta = cmp::min(delta, i32diff(timer, self.now));
+        }
+        cmp::max(delta, self.config.min_interval as i32) as u32
+    }
+
+    /// Flush now if the update loop is sleeping until the next timer
+    #[inline]
+    fn wake_if_sleeping(&mut self) {
+        if self.sleeping {
+            self.sleeping = false;
+            let _ = self.flush_notify_tx.try_send(());
+        }
+    }
+
+    /// How long the update loop may wait before the next flush. An idle core sleeps until its
+    /// next timer, waking early when the application or the peer gives it something to do.
+    pub fn get_interval(&mut self) -> u32 {
+        self.sleeping = self.is_idle();
+        if self.sleeping {
+            let interval = self.next_timer();
+            log::trace!("idle, sleeping for {}", interval);
+            return interval;
+        }
+        let mut interval = self.config.max_interval;
+        for i in &self.send_window {
+            let delta = i32diff(self.now, i.rexmit_timestamp);
+            if delta < 0 {
+                return self.config.min_interval;
             }
             interval = cmp::min(delta as u32, interval);
         }
@@ -805,8 +2757,17 @@ impl KcpCore {
         interval
     }
 
-    pub fn new(stream_id: u16, config: Arc<KcpConfig>, flush_notify_tx: Sender<()>) -> Self {
-        let now = now_millis();
+    pub fn new(
+        stream_id: u16,
+        config: Arc<KcpConfig>,
+        flush_notify_tx: Sender<()>,
+        shared_congestion: Option<SharedCongestion>,
+        overhead: usize,
+    ) -> Self {
+        let now = config.clock.now_millis();
+        // The io layers take their share of the mtu
+        let mtu = config.mtu - overhead;
+        let mss = cmp::min(config.segment_size(), mtu - KCP_HEADER_LEN);
         KcpCore {
             stream_id,
             config: config.clone(),
@@ -814,24 +2775,39 @@ impl KcpCore {
             send_window: VecDeque::with_capacity(config.send_window_size as usize),
             recv_queue: VecDeque::with_capacity(config.recv_window_size as usize),
             recv_window: HashMap::with_capacity(config.recv_window_size as usize),
+            recv_fragments: HashMap::new(),
+            recv_skipped: 0,
             ack_list: VecDeque::with_capacity(config.recv_window_size as usize),
-            send_unack: 0,
-            send_next: 0,
-            recv_next: 0,
+            send_unack: config.initial_sequence,
+            send_next: config.initial_sequence,
+            recv_next: config.initial_sequence,
 
             remote_window_size: 16,
+            local_recv_window: config.recv_window_size,
             congestion_window_size: 16,
-            congestion_window_bytes: config.mss,
+            congestion_window_bytes: mss,
             slow_start_thresh: SSTHRESH_MIN,
+            loaded_congestion: CongestionState {
+                window_size: 16,
+                window_bytes: mss,
+                slow_start_thresh: SSTHRESH_MIN,
+            },
+            local_window_shift: window_shift(config.recv_window_size),
+            remote_window_shift: 0,
 
-            rto: RTO_INIT,
+            rto: bound(config.rto_min, RTO_INIT, config.rto_max),
             srtt: 0,
             rttval: 0,
 
             now: now,
             ping_ts: 0,
+            probe_ts: 0,
+            probe_wait: 0,
 
-            buffer: BytesMut::with_capacity(config.mtu),
+            buffer: BytesMut::with_capacity(mtu),
+            mtu,
+            mss,
+            min_mtu: cmp::min(config.min_mtu.saturating_sub(overhead), mtu),
 
             send_waker: None,
             recv_waker: None,
@@ -840,8 +2816,1394 @@ impl KcpCore {
             close_state: CloseFlags::empty(),
             close_ts: 0,
             close_waker: None,
+            half_close: false,
 
             last_active: now,
+            last_input: now,
+            last_app_active: now,
+            idle_expired: false,
+
+            shared_congestion,
+            rate_limiter: None,
+            priority: 0,
+
+            stats: KcpStats::default(),
+
+            open_data: None,
+            label: Bytes::new(),
+            remote_features: Features::empty(),
+
+            deadline: config
+                .max_stream_lifetime
+                .map(|lifetime| now.wrapping_add(lifetime.as_millis() as u32)),
+            lifetime_expired: false,
+
+            reset_data: None,
+            peer_reset: None,
+
+            ecn_echo_pending: false,
+            window_limited: false,
+            rexmit_request_ts: None,
+            sleeping: false,
+            ecn_reaction_ts: now,
+            auth_nonce: rand::random(),
+            remote_auth_nonce: None,
+            auth_initiator: false,
+            auth_failed: false,
+            decrypt_failures: None,
+            handshake_failed: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use futures::task::noop_waker_ref;
+    use smol::channel::bounded;
+
+    use super::*;
+
+    struct NullIo;
+
+    #[async_trait::async_trait]
+    impl KcpIo for NullIo {
+        async fn send_packet(&self, _buf: &[u8]) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        async fn recv_packet(&self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            futures::future::pending().await
+        }
+    }
+
+    #[derive(Default)]
+    struct ManualClock {
+        now: AtomicU32,
+    }
+
+    impl ManualClock {
+        fn advance(&self, millis: u32) {
+            self.now.fetch_add(millis, Ordering::SeqCst);
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Clock for ManualClock {
+        fn now_millis(&self) -> u32 {
+            self.now.load(Ordering::SeqCst)
+        }
+
+        async fn sleep(&self, duration: Duration) {
+            let deadline = self.now_millis() + duration.as_millis() as u32;
+            while i32diff(self.now_millis(), deadline) < 0 {
+                smol::future::yield_now().await;
+            }
+        }
+    }
+
+    fn new_core(config: &Arc<KcpConfig>, shared_congestion: Option<SharedCongestion>) -> KcpCore {
+        let (tx, _) = bounded(1);
+        KcpCore::new(0, config.clone(), tx, shared_congestion, 0)
+    }
+
+    #[derive(Default)]
+    struct RecordIo {
+        packets: Mutex<Vec<Vec<u8>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl KcpIo for RecordIo {
+        async fn send_packet(&self, buf: &[u8]) -> std::io::Result<()> {
+            self.packets.lock().unwrap().push(buf.to_vec());
+            Ok(())
+        }
+
+        async fn recv_packet(&self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            futures::future::pending().await
+        }
+    }
+
+    impl RecordIo {
+        fn segments(&self) -> Vec<KcpSegment> {
+            let mut segments = Vec::new();
+            for packet in self.packets.lock().unwrap().iter() {
+                let mut packet = &packet[..];
+                while packet.has_remaining() {
+                    let segment = KcpSegment::decode(packet).unwrap();
+                    packet.advance(segment.encoded_len());
+                    segments.push(segment);
+                }
+            }
+            segments
+        }
+    }
+
+    #[test]
+    fn max_segment_size() {
+        let mut config = KcpConfig::default();
+        config.max_segment_size = Some(config.mtu);
+        assert!(config.validate().is_err());
+        config.max_segment_size = Some(100);
+        assert!(config.validate().is_ok());
+        let config = Arc::new(config);
+
+        smol::block_on(async {
+            let mut core = new_core(&config, None);
+            let io = RecordIo::default();
+            let cx = Context::from_waker(noop_waker_ref());
+            assert!(core.poll_send(&cx, &[0u8; 1000]).is_ready());
+            core.flush(&io).await.unwrap();
+
+            let segments = io.segments();
+            let pushed: Vec<_> = segments
+                .iter()
+                .filter(|segment| segment.command == CMD_PUSH)
+                .collect();
+            assert_eq!(pushed.len(), 10);
+            assert!(pushed.iter().all(|segment| segment.data.len() <= 100));
+            // Several segments still share one datagram
+            assert!(io.packets.lock().unwrap().len() < pushed.len());
+        });
+    }
+
+    #[test]
+    fn manual_clock_rexmit() {
+        let clock = Arc::new(ManualClock::default());
+        let mut config = KcpConfig::default();
+        config.clock = clock.clone();
+        let config = Arc::new(config);
+
+        smol::block_on(async {
+            let mut core = new_core(&config, None);
+            let io = RecordIo::default();
+            let cx = Context::from_waker(noop_waker_ref());
+            assert!(core.poll_send(&cx, b"hello").is_ready());
+            core.flush(&io).await.unwrap();
+            assert_eq!(core.get_stats().segments_sent, 1);
+
+            // rto + rto / 8
+            clock.advance(RTO_INIT + RTO_INIT / 8 - 1);
+            core.flush(&io).await.unwrap();
+            assert_eq!(core.get_stats().segments_retransmitted, 0);
+
+            clock.advance(1);
+            core.flush(&io).await.unwrap();
+            assert_eq!(core.get_stats().segments_retransmitted, 1);
+            let pushed = io
+                .segments()
+                .iter()
+                .filter(|segment| segment.command == CMD_PUSH)
+                .count();
+            assert_eq!(pushed, 2);
+        });
+    }
+
+    #[test]
+    fn rto_bounds() {
+        // Satellite-like rtt of 2s, then a LAN-like one of 1ms
+        for (rtt, rto_min, rto_max, expected) in
+            [(2000, 20, 3000, 3000), (1, 500, 5000, 500)].iter()
+        {
+            let clock = Arc::new(ManualClock::default());
+            let mut config = KcpConfig::default();
+            config.rto_min = *rto_min;
+            config.rto_max = *rto_max;
+            config.clock = clock.clone();
+            let config = Arc::new(config);
+
+            smol::block_on(async {
+                let mut core = new_core(&config, None);
+                let cx = Context::from_waker(noop_waker_ref());
+                assert!(core.poll_send(&cx, b"hello").is_ready());
+                core.flush(&NullIo).await.unwrap();
+
+                clock.advance(*rtt);
+                let mut data = BytesMut::new();
+                data.put_u32_le(0);
+                data.put_u32_le(0);
+                let ack = KcpSegment {
+                    stream_id: 0,
+                    command: CMD_ACK,
+                    recv_window_size: 16,
+                    timestamp: 0,
+                    sequence: 0,
+                    recv_next: 1,
+                    data: data.freeze(),
+                };
+                core.input(vec![ack]).unwrap();
+                assert_eq!(core.get_stats().rto, *expected);
+            });
+        }
+
+        let mut config = KcpConfig::default();
+        config.rto_min = 1000;
+        config.rto_max = 100;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn segment_ttl() {
+        let clock = Arc::new(ManualClock::default());
+        let mut config = KcpConfig::default();
+        config.segment_ttl = Some(Duration::from_millis(100));
+        config.clock = clock.clone();
+        let config = Arc::new(config);
+
+        smol::block_on(async {
+            let mut sender = new_core(&config, None);
+            let mut receiver = new_core(&config, None);
+            let cx = Context::from_waker(noop_waker_ref());
+
+            // Lost on the slow link
+            assert!(sender.poll_send(&cx, b"old").is_ready());
+            sender.flush(&NullIo).await.unwrap();
+
+            clock.advance(150);
+            assert!(sender.poll_send(&cx, b"new").is_ready());
+            let io = RecordIo::default();
+            sender.flush(&io).await.unwrap();
+            assert_eq!(sender.get_stats().segments_expired, 1);
+
+            let segments = io.segments();
+            assert!(segments
+                .iter()
+                .any(|segment| segment.command == CMD_SKIP && segment.sequence == 0));
+            receiver.input(segments).unwrap();
+            // The reader is told about the gap first
+            assert!(receiver.recv_queue.is_empty());
+            assert!(matches!(
+                receiver.poll_recv(&cx),
+                Poll::Ready(Err(KcpError::DataSkipped(1)))
+            ));
+            assert_eq!(receiver.recv_next, 2);
+            match receiver.poll_recv(&cx) {
+                Poll::Ready(Ok(received)) => {
+                    assert_eq!(received, vec![Bytes::from_static(b"new")]);
+                }
+                _ => panic!("the data past the gap is not delivered"),
+            }
+        });
+    }
+
+    fn push_segment(sequence: u32) -> KcpSegment {
+        KcpSegment {
+            stream_id: 0,
+            command: CMD_PUSH,
+            recv_window_size: 16,
+            timestamp: 0,
+            sequence,
+            recv_next: 0,
+            data: Bytes::copy_from_slice(&sequence.to_le_bytes()),
+        }
+    }
+
+    #[test]
+    fn recv_reorder_window() {
+        let mut config = KcpConfig::default();
+        config.recv_reorder_window = 2;
+        let config = Arc::new(config);
+        let mut core = new_core(&config, None);
+
+        // Segment 0 is delayed, only two of the later ones fit in the reorder window
+        core.input((1..5).map(push_segment).collect()).unwrap();
+        assert_eq!(core.recv_window.len(), 2);
+        let acked: Vec<_> = core
+            .ack_list
+            .iter()
+            .map(|(_, sequence, _)| *sequence)
+            .collect();
+        assert_eq!(acked, vec![1, 2]);
+
+        core.input(vec![push_segment(0)]).unwrap();
+        assert_eq!(core.recv_next, 3);
+        assert!(core.recv_window.is_empty());
+
+        // The dropped ones get retransmitted
+        core.input((3..5).map(push_segment).collect()).unwrap();
+        assert_eq!(core.recv_next, 5);
+        let received: Vec<_> = core.recv_queue.iter().cloned().collect();
+        let expected: Vec<_> = (0..5).map(|i| push_segment(i).data).collect();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn per_stream_congestion() {
+        smol::block_on(async {
+            for per_stream_cc in [true, false].iter() {
+                let clock = Arc::new(ManualClock::default());
+                let mut config = KcpConfig::default();
+                config.congestion = Congestion::KcpReno;
+                config.per_stream_cc = *per_stream_cc;
+                config.clock = clock.clone();
+                let config = Arc::new(config);
+                let shared = if config.per_stream_cc {
+                    None
+                } else {
+                    Some(CongestionState::shared(&config))
+                };
+                let mut lossy = new_core(&config, shared.clone());
+                let mut healthy = new_core(&config, shared.clone());
+
+                let cx = Context::from_waker(noop_waker_ref());
+                assert!(lossy.poll_send(&cx, b"lossy").is_ready());
+                assert!(healthy.poll_send(&cx, b"healthy").is_ready());
+                lossy.flush(&NullIo).await.unwrap();
+                healthy.flush(&NullIo).await.unwrap();
+
+                // Nothing gets acked, so the lossy stream hits its rto
+                clock.advance(RTO_INIT * 2);
+                lossy.flush(&NullIo).await.unwrap();
+                assert_eq!(lossy.congestion_window_size, 1);
+
+                healthy.load_congestion();
+                if config.per_stream_cc {
+                    assert_eq!(healthy.congestion_window_size, 16);
+                } else {
+                    assert_eq!(healthy.congestion_window_size, 1);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn shared_congestion_merge() {
+        let config = Arc::new(KcpConfig::default());
+        let shared = CongestionState::shared(&config);
+        let mut shrinking = new_core(&config, Some(shared.clone()));
+        let mut growing = new_core(&config, Some(shared.clone()));
+
+        // Both work on the state loaded before either stores
+        shrinking.load_congestion();
+        growing.load_congestion();
+        shrinking.congestion_window_size -= 8;
+        growing.congestion_window_size += 2;
+        shrinking.store_congestion();
+        growing.store_congestion();
+        assert_eq!(shared.lock().unwrap().window_size, 16 - 8 + 2);
+        assert_eq!(growing.congestion_window_size, 16 - 8 + 2);
+
+        // Never below one segment
+        shrinking.load_congestion();
+        growing.load_congestion();
+        shrinking.congestion_window_size = 1;
+        growing.congestion_window_size = 1;
+        shrinking.store_congestion();
+        growing.store_congestion();
+        assert_eq!(shared.lock().unwrap().window_size, 1);
+    }
+
+    #[test]
+    fn ack_delay() {
+        for negotiated in [true, false].iter() {
+            let clock = Arc::new(ManualClock::default());
+            let mut config = KcpConfig::default();
+            config.clock = clock.clone();
+            let config = Arc::new(config);
+
+            smol::block_on(async {
+                let mut sender = new_core(&config, None);
+                let mut receiver = new_core(&config, None);
+                if *negotiated {
+                    sender.remote_features = Features::all();
+                    receiver.remote_features = Features::all();
+                }
+                let cx = Context::from_waker(noop_waker_ref());
+                assert!(sender.poll_send(&cx, b"hello").is_ready());
+                let io = RecordIo::default();
+                sender.flush(&io).await.unwrap();
+
+                // 10ms each way, but the ack is held for 90ms
+                clock.advance(10);
+                receiver.input(io.segments()).unwrap();
+                clock.advance(90);
+                let io = RecordIo::default();
+                receiver.flush(&io).await.unwrap();
+                clock.advance(10);
+                sender.input(io.segments()).unwrap();
+
+                let expected = if *negotiated { 20 } else { 110 };
+                assert_eq!(sender.srtt, expected);
+            });
+        }
+    }
+
+    #[test]
+    fn max_send_bps() {
+        let clock = Arc::new(ManualClock::default());
+        let mut config = KcpConfig::default();
+        config.clock = clock.clone();
+        config.max_send_bps = Some(0);
+        assert!(config.validate().is_err());
+        // 10000 bytes per second
+        config.max_send_bps = Some(80000);
+        let config = Arc::new(config);
+
+        smol::block_on(async {
+            let mut core = new_core(&config, None);
+            core.limit_rate(RateLimiter::shared(&config).unwrap());
+            let io = RecordIo::default();
+            let cx = Context::from_waker(noop_waker_ref());
+            for _ in 0..100 {
+                assert!(core.poll_send(&cx, &[0u8; 1000]).is_ready());
+            }
+            core.flush(&io).await.unwrap();
+            for _ in 0..100 {
+                clock.advance(10);
+                core.flush(&io).await.unwrap();
+            }
+
+            // One second at the cap, plus the burst and a segment sent on credit
+            let sent: usize = io
+                .segments()
+                .iter()
+                .filter(|segment| segment.command == CMD_PUSH)
+                .map(|segment| segment.encoded_len())
+                .sum();
+            assert!(sent <= 10000 + config.mtu + config.mtu);
+            assert!(sent >= 10000);
+        });
+    }
+
+    #[test]
+    fn max_segments_per_tick() {
+        let clock = Arc::new(ManualClock::default());
+        let mut config = KcpConfig::default();
+        config.clock = clock.clone();
+        config.congestion = Congestion::None;
+        config.max_segments_per_tick = Some(0);
+        assert!(config.validate().is_err());
+        config.max_segments_per_tick = Some(4);
+        let config = Arc::new(config);
+
+        smol::block_on(async {
+            let mut core = new_core(&config, None);
+            let cx = Context::from_waker(noop_waker_ref());
+            // Exactly the initial window of the peer
+            let data = vec![0u8; core.mss];
+            for _ in 0..16 {
+                assert!(core.poll_send(&cx, &data).is_ready());
+            }
+            // The window takes all of them, but they leave 4 at a time
+            let mut ticks = Vec::new();
+            for _ in 0..6 {
+                let io = RecordIo::default();
+                core.flush(&io).await.unwrap();
+                let pushed = io
+                    .segments()
+                    .iter()
+                    .filter(|segment| segment.command == CMD_PUSH)
+                    .count();
+                ticks.push(pushed);
+                clock.advance(10);
+            }
+            assert_eq!(core.send_window.len(), 16);
+            assert_eq!(ticks, vec![4, 4, 4, 4, 0, 0]);
+        });
+    }
+
+    #[test]
+    fn send_overflow_policy() {
+        let mut config = KcpConfig::default();
+        config.send_window_size = 0;
+        assert!(config.validate().is_err());
+        config.send_window_size = 4;
+        let written = |policy: SendOverflowPolicy| {
+            let mut config = config.clone();
+            config.send_overflow_policy = policy;
+            let mut core = new_core(&Arc::new(config), None);
+            let cx = Context::from_waker(noop_waker_ref());
+            // Every full segment is one write, the queue is full after 4 of them
+            let payloads: Vec<_> = (0..5u8).map(|i| vec![i; core.mss]).collect();
+            for payload in &payloads[..4] {
+                assert!(core.poll_send(&cx, payload).is_ready());
+            }
+            let result = core.poll_send(&cx, &payloads[4]);
+            let queued: Vec<u8> = core.send_queue.iter().map(|data| data[0]).collect();
+            (result, queued, core.get_stats().send_queue_dropped)
+        };
+
+        let (result, queued, dropped) = written(SendOverflowPolicy::Backpressure);
+        assert!(result.is_pending());
+        assert_eq!(queued, [0, 1, 2, 3]);
+        assert_eq!(dropped, 0);
+
+        let (result, queued, dropped) = written(SendOverflowPolicy::DropOldest);
+        assert!(matches!(result, Poll::Ready(Ok(()))));
+        assert_eq!(queued, [1, 2, 3, 4]);
+        assert_eq!(dropped, 1);
+
+        let (result, queued, dropped) = written(SendOverflowPolicy::Error);
+        assert!(matches!(result, Poll::Ready(Err(KcpError::SendQueueFull))));
+        assert_eq!(queued, [0, 1, 2, 3]);
+        assert_eq!(dropped, 0);
+    }
+
+    #[test]
+    fn max_inflight_bytes() {
+        let clock = Arc::new(ManualClock::default());
+        let mut config = KcpConfig::default();
+        config.clock = clock.clone();
+        config.congestion = Congestion::None;
+        config.max_inflight_bytes = Some(0);
+        assert!(config.validate().is_err());
+        // The windows allow 16 segments, the cap less than 3
+        let cap = 4000;
+        config.max_inflight_bytes = Some(cap);
+        let config = Arc::new(config);
+
+        smol::block_on(async {
+            let mut sender = new_core(&config, None);
+            let mut receiver = new_core(&config, None);
+            let cx = Context::from_waker(noop_waker_ref());
+            sender.open(Bytes::new());
+            for _ in 0..16 {
+                assert!(sender.poll_send(&cx, &[0u8; 1000]).is_ready());
+            }
+            let mut received = 0;
+            for _ in 0..100 {
+                let io = RecordIo::default();
+                sender.flush(&io).await.unwrap();
+                let inflight: usize = sender
+                    .send_window
+                    .iter()
+                    .map(|sending_segment| sending_segment.segment.data.len())
+                    .sum();
+                assert!(inflight <= cap);
+                assert!(sender.get_send_window() > 3);
+
+                receiver.input(io.segments()).unwrap();
+                received += receiver
+                    .take_recv_queue()
+                    .iter()
+                    .map(|data| data.len())
+                    .sum::<usize>();
+                let io = RecordIo::default();
+                receiver.flush(&io).await.unwrap();
+                clock.advance(10);
+                sender.input(io.segments()).unwrap();
+            }
+            assert_eq!(received, 16 * 1000);
+        });
+    }
+
+    #[test]
+    fn ecn_echo() {
+        let clock = Arc::new(ManualClock::default());
+        let mut config = KcpConfig::default();
+        config.clock = clock.clone();
+        config.congestion = Congestion::KcpReno;
+        config.ecn = true;
+        let config = Arc::new(config);
+
+        smol::block_on(async {
+            let mut sender = new_core(&config, None);
+            let mut receiver = new_core(&config, None);
+            let cx = Context::from_waker(noop_waker_ref());
+            sender.open(Bytes::new());
+            assert!(sender.poll_send(&cx, b"hello").is_ready());
+            let io = RecordIo::default();
+            sender.flush(&io).await.unwrap();
+
+            // Every packet arrives, but marked CE on the way
+            receiver.input(io.segments()).unwrap();
+            receiver.input_ce();
+            let io = RecordIo::default();
+            receiver.flush(&io).await.unwrap();
+            let segments = io.segments();
+            assert!(segments
+                .iter()
+                .any(|segment| segment.command == CMD_ECN_ECHO));
+
+            let window = sender.get_send_window();
+            clock.advance(10);
+            sender.input(segments).unwrap();
+            assert_eq!(sender.get_stats().ecn_echoes, 1);
+            assert_eq!(sender.get_stats().segments_retransmitted, 0);
+            assert_eq!(sender.get_send_window(), window / 2);
+        });
+    }
+
+    #[test]
+    fn ecn_echo_clock_wrap() {
+        let clock = Arc::new(ManualClock::default());
+        clock.advance(u32::MAX - 300);
+        let mut config = KcpConfig::default();
+        config.clock = clock.clone();
+        config.congestion = Congestion::KcpReno;
+        config.ecn = true;
+        let config = Arc::new(config);
+
+        smol::block_on(async {
+            let mut sender = new_core(&config, None);
+            let mut receiver = new_core(&config, None);
+            let cx = Context::from_waker(noop_waker_ref());
+            sender.open(Bytes::new());
+            assert!(sender.poll_send(&cx, b"hello").is_ready());
+            let io = RecordIo::default();
+            sender.flush(&io).await.unwrap();
+
+            receiver.input(io.segments()).unwrap();
+            receiver.input_ce();
+            let io = RecordIo::default();
+            receiver.flush(&io).await.unwrap();
+            let segments: Vec<_> = io
+                .segments()
+                .into_iter()
+                .filter(|segment| segment.command == CMD_ECN_ECHO)
+                .collect();
+
+            // The reaction deadline lands past the wrap of the u32 clock
+            let window = sender.get_send_window();
+            clock.advance(295);
+            sender.input(segments.clone()).unwrap();
+            assert_eq!(sender.get_send_window(), window / 2);
+
+            // A second echo before the deadline is ignored
+            clock.advance(2);
+            sender.input(segments).unwrap();
+            assert_eq!(sender.get_stats().ecn_echoes, 2);
+            assert_eq!(sender.get_send_window(), window / 2);
+        });
+    }
+
+    #[test]
+    fn scheduling_policy() {
+        let segment_len = 1000 + KCP_HEADER_LEN;
+        let served = |policy: SchedulingPolicy| {
+            let clock = Arc::new(ManualClock::default());
+            let mut config = KcpConfig::default();
+            config.clock = clock.clone();
+            config.max_send_bps = Some(80000);
+            config.scheduling = policy;
+            // Nothing is acked, only retransmitted
+            config.timeout = 10000;
+            let config = Arc::new(config);
+            let limiter = RateLimiter::shared(&config).unwrap();
+
+            smol::block_on(async {
+                let cx = Context::from_waker(noop_waker_ref());
+                let mut streams = Vec::new();
+                for priority in 0..3u8 {
+                    let (tx, _) = bounded(1);
+                    let mut core = KcpCore::new(priority as u16, config.clone(), tx, None, 0);
+                    core.limit_rate(limiter.clone());
+                    core.set_priority(priority);
+                    for _ in 0..50 {
+                        assert!(core.poll_send(&cx, &[0u8; 1000]).is_ready());
+                    }
+                    streams.push((core, RecordIo::default()));
+                }
+                for _ in 0..600 {
+                    clock.advance(10);
+                    for (core, io) in streams.iter_mut() {
+                        core.flush(io).await.unwrap();
+                    }
+                }
+                streams
+                    .iter()
+                    .map(|(_, io)| {
+                        io.segments()
+                            .iter()
+                            .filter(|segment| segment.command == CMD_PUSH)
+                            .map(|segment| segment.encoded_len())
+                            .sum::<usize>()
+                    })
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        let sent = served(SchedulingPolicy::RoundRobin);
+        let max = *sent.iter().max().unwrap();
+        let min = *sent.iter().min().unwrap();
+        assert!(min > 0);
+        // The first stream takes the initial burst before the others show up
+        assert!(max - min <= 3 * segment_len);
+
+        let sent = served(SchedulingPolicy::WeightedByPriority);
+        assert!(sent[0] < sent[1] && sent[1] < sent[2]);
+        assert!(sent[2] >= 2 * sent[0]);
+
+        // Only what went out before the higher priority streams showed up
+        let sent = served(SchedulingPolicy::StrictPriority);
+        assert!(sent[0] + sent[1] <= 3 * segment_len);
+        assert!(sent[2] > 10 * segment_len);
+    }
+
+    #[test]
+    fn window_limited_ratio() {
+        let clock = Arc::new(ManualClock::default());
+        let mut config = KcpConfig::default();
+        config.clock = clock.clone();
+        let config = Arc::new(config);
+
+        // Every round writes `payload`, or as much of it as fits when saturating, then exchanges
+        // a flush each way
+        let transfer = |payload: Vec<u8>, saturate: bool| {
+            let clock = clock.clone();
+            let config = config.clone();
+            smol::block_on(async move {
+                let cx = Context::from_waker(noop_waker_ref());
+                let mut sender = new_core(&config, None);
+                let mut receiver = new_core(&config, None);
+                for _ in 0..10 {
+                    if saturate {
+                        while sender.poll_send(&cx, &payload).is_ready() {}
+                    } else {
+                        assert!(sender.poll_send(&cx, &payload).is_ready());
+                    }
+                    clock.advance(10);
+                    let io = RecordIo::default();
+                    sender.flush(&io).await.unwrap();
+                    receiver.input(io.segments()).unwrap();
+                    let _ = receiver.poll_recv(&cx);
+                    let io = RecordIo::default();
+                    receiver.flush(&io).await.unwrap();
+                    sender.input(io.segments()).unwrap();
+                }
+                (sender.get_stats(), sender.is_window_limited())
+            })
+        };
+
+        // Far more queued than the congestion window lets out. Only the first flush, before
+        // the peer advertised its window, is limited by something else.
+        let (stats, limited) = transfer(vec![0u8; config.mss], true);
+        assert_eq!(stats.flushes, 10);
+        assert!(stats.window_limited_ratio() > 0.8);
+        assert!(limited);
+
+        // A trickle never fills the window
+        let (stats, limited) = transfer(b"hello".to_vec(), false);
+        assert_eq!(stats.flushes, 10);
+        assert_eq!(stats.window_limited_ratio(), 0.0);
+        assert!(!limited);
+    }
+
+    #[test]
+    fn max_acks_per_packet() {
+        let clock = Arc::new(ManualClock::default());
+        let mut config = KcpConfig::default();
+        config.clock = clock.clone();
+        config.mtu = 100;
+        config.mss = 100 - KCP_HEADER_LEN;
+        config.max_acks_per_packet = 4;
+        let config = Arc::new(config);
+
+        smol::block_on(async {
+            let cx = Context::from_waker(noop_waker_ref());
+            let mut sender = new_core(&config, None);
+            let mut receiver = new_core(&config, None);
+            let payload = vec![0u8; config.mss];
+            for _ in 0..50 {
+                assert!(sender.poll_send(&cx, &payload).is_ready());
+            }
+            // The peer has advertised a window taking the whole burst
+            sender.remote_window_size = config.recv_window_size;
+            sender.congestion_window_size = config.send_window_size;
+            let io = RecordIo::default();
+            sender.flush(&io).await.unwrap();
+            let segments = io.segments();
+            assert_eq!(
+                segments
+                    .iter()
+                    .filter(|segment| segment.command == CMD_PUSH)
+                    .count(),
+                50
+            );
+
+            receiver.input(segments).unwrap();
+            let io = RecordIo::default();
+            receiver.flush(&io).await.unwrap();
+            let mut acked = Vec::new();
+            for packet in io.packets.lock().unwrap().iter() {
+                assert!(packet.len() <= config.mtu);
+                let mut packet = &packet[..];
+                let mut entries = 0;
+                while packet.has_remaining() {
+                    let segment = KcpSegment::decode(packet).unwrap();
+                    packet.advance(segment.encoded_len());
+                    if segment.command == CMD_ACK || segment.command == CMD_ACK_DELAY {
+                        let entry_len = if segment.command == CMD_ACK { 8 } else { 12 };
+                        let mut data = &segment.data[..];
+                        while data.has_remaining() {
+                            data.get_u32_le();
+                            acked.push(data.get_u32_le());
+                            data.advance(entry_len - 8);
+                            entries += 1;
+                        }
+                    }
+                }
+                assert!(entries <= 4);
+            }
+            acked.sort();
+            assert_eq!(acked, (0..50).collect::<Vec<u32>>());
+        });
+    }
+
+    #[test]
+    fn piggyback_ack() {
+        // Packets sent by both sides, flushing in turns, until every segment is acked
+        fn count_packets(forward: usize, backward: usize) -> usize {
+            let clock = Arc::new(ManualClock::default());
+            let mut config = KcpConfig::default();
+            config.clock = clock.clone();
+            config.congestion = Congestion::None;
+            let config = Arc::new(config);
+
+            smol::block_on(async {
+                let cx = Context::from_waker(noop_waker_ref());
+                let mut a = new_core(&config, None);
+                let mut b = new_core(&config, None);
+                let payload = vec![0u8; config.mss];
+                for _ in 0..forward {
+                    assert!(a.poll_send(&cx, &payload).is_ready());
+                }
+                for _ in 0..backward {
+                    assert!(b.poll_send(&cx, &payload).is_ready());
+                }
+                let mut packets = 0;
+                for _ in 0..100 {
+                    if a.flush_ready() && b.flush_ready() {
+                        break;
+                    }
+                    clock.advance(10);
+                    let io = RecordIo::default();
+                    a.flush(&io).await.unwrap();
+                    packets += io.packets.lock().unwrap().len();
+                    b.input(io.segments()).unwrap();
+                    let io = RecordIo::default();
+                    b.flush(&io).await.unwrap();
+                    packets += io.packets.lock().unwrap().len();
+                    a.input(io.segments()).unwrap();
+                    a.take_recv_queue();
+                    b.take_recv_queue();
+                }
+                assert!(a.flush_ready() && b.flush_ready());
+                packets
+            })
+        }
+
+        let forward = count_packets(64, 0);
+        let backward = count_packets(0, 64);
+        let duplex = count_packets(64, 64);
+        // Acks ride on the data of the other direction
+        assert!(duplex < forward + backward);
+    }
+
+    #[test]
+    fn asymmetric_windows() {
+        let clock = Arc::new(ManualClock::default());
+        let mut config = KcpConfig::default();
+        config.clock = clock.clone();
+        config.congestion = Congestion::None;
+        // A sends little and takes little, B sends a lot
+        let mut config_a = config.clone();
+        config_a.send_window_size = 4;
+        config_a.recv_window_size = 8;
+        let mut config_b = config;
+        config_b.send_window_size = 64;
+        config_b.recv_window_size = 32;
+        let config_a = Arc::new(config_a);
+        let config_b = Arc::new(config_b);
+
+        async fn deliver(from: &mut KcpCore, to: &mut KcpCore) {
+            let io = RecordIo::default();
+            from.flush(&io).await.unwrap();
+            to.input(io.segments()).unwrap();
+        }
+
+        smol::block_on(async {
+            let cx = Context::from_waker(noop_waker_ref());
+            let mut a = new_core(&config_a, None);
+            let mut b = new_core(&config_b, None);
+            // Each learns the window of the other from an ack, with nothing left unread
+            assert!(a.poll_send(&cx, b"a").is_ready());
+            assert!(b.poll_send(&cx, b"b").is_ready());
+            deliver(&mut a, &mut b).await;
+            deliver(&mut b, &mut a).await;
+            a.take_recv_queue();
+            b.take_recv_queue();
+            clock.advance(10);
+            assert!(a.poll_send(&cx, b"a").is_ready());
+            deliver(&mut a, &mut b).await;
+            b.take_recv_queue();
+            deliver(&mut b, &mut a).await;
+            assert_eq!(a.get_remote_window(), 32);
+            assert_eq!(b.get_remote_window(), 8);
+
+            // Each direction is held by its own window: A by its send window though B
+            // takes more, B by the receive window of A though it may send more
+            let payload = vec![0u8; a.mss];
+            for _ in 0..2 {
+                for _ in 0..4 {
+                    assert!(a.poll_send(&cx, &payload).is_ready());
+                }
+                clock.advance(10);
+                deliver(&mut a, &mut b).await;
+            }
+            assert_eq!(a.send_window.len(), 4);
+            assert_eq!(a.send_queue.len(), 4);
+            for _ in 0..64 {
+                assert!(b.poll_send(&cx, &payload).is_ready());
+            }
+            deliver(&mut b, &mut a).await;
+            assert_eq!(b.send_window.len(), 8);
+            assert_eq!(a.recv_queue.len(), 8);
+
+            // A peer ignoring the advertised window gets nothing more in
+            b.remote_window_size = 64;
+            clock.advance(10);
+            deliver(&mut b, &mut a).await;
+            assert_eq!(b.send_window.len(), 64);
+            assert_eq!(a.recv_queue.len(), 8);
+            assert!(a.recv_window.is_empty());
+        });
+    }
+
+    #[test]
+    fn sequence_wraparound() {
+        assert_eq!(i32diff(0, u32::MAX), 1);
+        assert_eq!(i32diff(u32::MAX, 0), -1);
+        assert_eq!(i32diff(5, u32::MAX - 5), 11);
+        assert_eq!(i32diff(0x8000_0000, 0), i32::MIN);
+    }
+
+    #[test]
+    fn ack_and_reorder_across_wrap() {
+        let clock = Arc::new(ManualClock::default());
+        let mut config = KcpConfig::default();
+        config.clock = clock.clone();
+        config.congestion = Congestion::None;
+        config.initial_sequence = u32::MAX - 3;
+        let config = Arc::new(config);
+
+        smol::block_on(async {
+            let mut sender = new_core(&config, None);
+            let mut receiver = new_core(&config, None);
+            let cx = Context::from_waker(noop_waker_ref());
+            sender.open(Bytes::new());
+            for i in 0..6u8 {
+                assert!(sender.poll_send(&cx, &vec![i; sender.mss]).is_ready());
+            }
+            let io = RecordIo::default();
+            sender.flush(&io).await.unwrap();
+            // Without the keep-alive PING
+            let segments: Vec<_> = io
+                .segments()
+                .into_iter()
+                .filter(|segment| segment.command != CMD_PING)
+                .collect();
+            let sequences: Vec<u32> = segments.iter().map(|segment| segment.sequence).collect();
+            assert_eq!(
+                sequences,
+                [u32::MAX - 3, u32::MAX - 2, u32::MAX - 1, u32::MAX, 0, 1, 2]
+            );
+            clock.advance(10);
+
+            // Backwards, and the one numbered 0 lost
+            for segment in segments
+                .iter()
+                .rev()
+                .filter(|segment| segment.sequence != 0)
+            {
+                receiver.input(vec![segment.clone()]).unwrap();
+            }
+            let delivered: Vec<u8> = receiver
+                .take_recv_queue()
+                .iter()
+                .map(|data| data[0])
+                .collect();
+            assert_eq!(delivered, [0, 1, 2]);
+            assert_eq!(receiver.recv_next, 0);
+
+            // The una acks up to the wrap, the ACK entries what follows the gap
+            let io = RecordIo::default();
+            receiver.flush(&io).await.unwrap();
+            sender.input(io.segments()).unwrap();
+            let unacked: Vec<u32> = sender
+                .send_window
+                .iter()
+                .map(|sending_segment| sending_segment.segment.sequence)
+                .collect();
+            assert_eq!(unacked, [0]);
+            assert_eq!(sender.send_unack, 0);
+            assert!(sender.send_window[0].fast_rexmit_counter > 0);
+
+            receiver.input(vec![segments[4].clone()]).unwrap();
+            let delivered: Vec<u8> = receiver
+                .take_recv_queue()
+                .iter()
+                .map(|data| data[0])
+                .collect();
+            assert_eq!(delivered, [3, 4, 5]);
+            let io = RecordIo::default();
+            receiver.flush(&io).await.unwrap();
+            sender.input(io.segments()).unwrap();
+            assert!(sender.send_window.is_empty());
+            assert_eq!(sender.send_unack, 3);
+        });
+    }
+
+    #[test]
+    fn window_probe() {
+        let clock = Arc::new(ManualClock::default());
+        let mut config = KcpConfig::default();
+        config.clock = clock.clone();
+        config.recv_window_size = 4;
+        let config = Arc::new(config);
+
+        smol::block_on(async {
+            let cx = Context::from_waker(noop_waker_ref());
+            let mut sender = new_core(&config, None);
+            let mut receiver = new_core(&config, None);
+            let payload = vec![0u8; config.mss];
+            for _ in 0..4 {
+                assert!(sender.poll_send(&cx, &payload).is_ready());
+            }
+            let io = RecordIo::default();
+            sender.flush(&io).await.unwrap();
+
+            // The receiver's queue is full, it acks with a zero window
+            receiver.input(io.segments()).unwrap();
+            let io = RecordIo::default();
+            receiver.flush(&io).await.unwrap();
+            sender.input(io.segments()).unwrap();
+            assert_eq!(sender.get_remote_window(), 0);
+
+            for _ in 0..4 {
+                assert!(sender.poll_send(&cx, &payload).is_ready());
+            }
+            clock.advance(10);
+            sender.flush(&RecordIo::default()).await.unwrap();
+
+            // The window opens, but the receiver has nothing to tell it with
+            assert!(receiver.poll_recv(&cx).is_ready());
+            clock.advance(10);
+            receiver.flush(&RecordIo::default()).await.unwrap();
+
+            let mut probes = 0;
+            let mut elapsed = 0;
+            loop {
+                clock.advance(10);
+                elapsed += 10;
+                assert!(elapsed < config.keep_alive_interval);
+                let io = RecordIo::default();
+                sender.flush(&io).await.unwrap();
+                let segments = io.segments();
+                probes += segments
+                    .iter()
+                    .filter(|segment| segment.command == CMD_WINDOW_PROBE)
+                    .count();
+                if segments.iter().any(|segment| segment.command == CMD_PUSH) {
+                    break;
+                }
+                receiver.input(segments).unwrap();
+                let io = RecordIo::default();
+                receiver.flush(&io).await.unwrap();
+                sender.input(io.segments()).unwrap();
+            }
+            assert_eq!(probes, 1);
+        });
+    }
+
+    #[test]
+    fn window_scale() {
+        // How many segments one flush puts in flight on a path of a large bandwidth-delay product
+        async fn exchange(from: &mut KcpCore, to: &mut KcpCore) {
+            let io = RecordIo::default();
+            from.flush(&io).await.unwrap();
+            to.input(io.segments()).unwrap();
+        }
+
+        fn in_flight(features: Features) -> usize {
+            let mut config = KcpConfig::default();
+            config.clock = Arc::new(ManualClock::default());
+            config.mtu = KCP_HEADER_LEN + 8;
+            config.mss = 8;
+            config.send_window_size = 0x20000;
+            config.recv_window_size = 0x20000;
+            config.congestion = Congestion::None;
+            config.features = features;
+            let config = Arc::new(config);
+
+            smol::block_on(async {
+                let cx = Context::from_waker(noop_waker_ref());
+                let mut sender = new_core(&config, None);
+                let mut receiver = new_core(&config, None);
+                sender.open(Bytes::new());
+                exchange(&mut sender, &mut receiver).await;
+                receiver.open(Bytes::new());
+                exchange(&mut receiver, &mut sender).await;
+                exchange(&mut sender, &mut receiver).await;
+                // The first segment the receiver sends after its OPEN is acked
+                assert!(receiver.poll_send(&cx, b"ready").is_ready());
+                exchange(&mut receiver, &mut sender).await;
+
+                let payload = vec![0u8; 8 * 0x10010];
+                assert!(sender.poll_send(&cx, &payload).is_ready());
+                let io = RecordIo::default();
+                sender.flush(&io).await.unwrap();
+                io.segments()
+                    .iter()
+                    .filter(|segment| segment.command == CMD_PUSH)
+                    .count()
+            })
+        }
+
+        assert_eq!(in_flight(Features::all()), 0x10010);
+        assert_eq!(in_flight(Features::all() - Features::WINDOW_SCALE), 0xffff);
+        assert_eq!(window_shift(0x20000), 2);
+        assert_eq!(window_shift(0xffff), 0);
+    }
+
+    #[test]
+    fn request_retransmit() {
+        let clock = Arc::new(ManualClock::default());
+        let mut config = KcpConfig::default();
+        config.clock = clock.clone();
+        config.congestion = Congestion::KcpReno;
+        let config = Arc::new(config);
+
+        fn pushes(io: &RecordIo) -> Vec<KcpSegment> {
+            io.segments()
+                .into_iter()
+                .filter(|segment| segment.command == CMD_PUSH)
+                .collect()
+        }
+
+        smol::block_on(async {
+            let cx = Context::from_waker(noop_waker_ref());
+            let mut sender = new_core(&config, None);
+            assert!(!sender.request_retransmit());
+            assert!(sender.poll_send(&cx, b"critical").is_ready());
+            let io = RecordIo::default();
+            sender.flush(&io).await.unwrap();
+            assert_eq!(pushes(&io).len(), 1);
+
+            // No ACK comes back, and the RTO is far away
+            clock.advance(10);
+            let io = RecordIo::default();
+            sender.flush(&io).await.unwrap();
+            assert!(pushes(&io).is_empty());
+
+            let window = sender.get_send_window();
+            assert!(sender.request_retransmit());
+            let io = RecordIo::default();
+            sender.flush(&io).await.unwrap();
+            let segments = pushes(&io);
+            assert_eq!(segments.len(), 1);
+            assert_eq!(&segments[0].data[..], b"critical");
+            assert_eq!(sender.get_stats().segments_retransmitted, 1);
+            // Not a loss signal, the congestion window is untouched
+            assert_eq!(sender.get_send_window(), window);
+
+            // Sent once only
+            let io = RecordIo::default();
+            sender.flush(&io).await.unwrap();
+            assert!(pushes(&io).is_empty());
+
+            // Too soon for another one
+            clock.advance(1);
+            assert!(!sender.request_retransmit());
+            clock.advance(config.rto_min);
+            assert!(sender.request_retransmit());
+        });
+    }
+
+    #[test]
+    fn single_stream() {
+        // Bytes of the packet carrying the OPEN and a small write
+        fn packet_len(single_stream: bool) -> usize {
+            let mut config = KcpConfig::default();
+            config.clock = Arc::new(ManualClock::default());
+            config.single_stream = single_stream;
+            let config = Arc::new(config);
+            smol::block_on(async {
+                let cx = Context::from_waker(noop_waker_ref());
+                let mut core = new_core(&config, None);
+                core.open(Bytes::new());
+                assert!(core.poll_send(&cx, b"hello").is_ready());
+                let io = RecordIo::default();
+                core.flush(&io).await.unwrap();
+                let packets = io.packets.lock().unwrap();
+                assert_eq!(packets.len(), 1);
+                packets[0].len()
+            })
+        }
+
+        // The OPEN, the write and the first keep-alive PING, each without the stream id
+        assert_eq!(packet_len(false) - packet_len(true), 3 * 2);
+    }
+
+    #[test]
+    fn idle_interval() {
+        let clock = Arc::new(ManualClock::default());
+        let mut config = KcpConfig::default();
+        config.clock = clock.clone();
+        let config = Arc::new(config);
+
+        smol::block_on(async {
+            let cx = Context::from_waker(noop_waker_ref());
+            let mut core = new_core(&config, None);
+            // The first flush pings
+            core.flush(&RecordIo::default()).await.unwrap();
+            clock.advance(config.max_interval);
+            core.flush(&RecordIo::default()).await.unwrap();
+            // Nothing to do until the next keep-alive
+            assert_eq!(
+                core.get_interval(),
+                config.keep_alive_interval - config.max_interval
+            );
+
+            assert!(core.poll_send(&cx, b"hello").is_ready());
+            assert!(core.get_interval() <= config.max_interval);
+        });
+    }
+
+    #[test]
+    fn peer_auth() {
+        async fn exchange(from: &mut KcpCore, to: &mut KcpCore) {
+            let io = RecordIo::default();
+            let _ = from.flush(&io).await;
+            to.input(io.segments()).unwrap();
         }
+
+        let config = |key: &'static [u8]| {
+            let mut config = KcpConfig::default();
+            config.clock = Arc::new(ManualClock::default());
+            config.peer_auth_key = Some(Bytes::from_static(key));
+            Arc::new(config)
+        };
+
+        smol::block_on(async {
+            let cx = Context::from_waker(noop_waker_ref());
+            let mut sender = new_core(&config(b"key"), None);
+            let mut receiver = new_core(&config(b"key"), None);
+            sender.open(Bytes::from_static(b"label"));
+            exchange(&mut sender, &mut receiver).await;
+            assert!(!receiver.is_auth_failed());
+            assert_eq!(&receiver.get_label()[..], b"label");
+            receiver.open(receiver.get_label());
+            exchange(&mut receiver, &mut sender).await;
+            assert!(!sender.is_auth_failed());
+            assert!(sender.get_features().contains(Features::AUTH));
+
+            let mut sender = new_core(&config(b"key"), None);
+            let mut receiver = new_core(&config(b"other key"), None);
+            sender.open(Bytes::new());
+            exchange(&mut sender, &mut receiver).await;
+            assert!(receiver.is_auth_failed());
+            // The reset tells the sender
+            exchange(&mut receiver, &mut sender).await;
+            assert!(sender.is_auth_failed());
+            match sender.poll_recv(&cx) {
+                Poll::Ready(Err(KcpError::PeerAuthFailed)) => {}
+                _ => panic!("the stream should fail authentication"),
+            }
+        });
+    }
+
+    #[test]
+    fn refragment() {
+        let clock = Arc::new(ManualClock::default());
+        let mut config = KcpConfig::default();
+        config.clock = clock.clone();
+        config.congestion = Congestion::None;
+        let config = Arc::new(config);
+
+        smol::block_on(async {
+            let cx = Context::from_waker(noop_waker_ref());
+            let mut sender = new_core(&config, None);
+            sender.remote_features = Features::all();
+            // Lost what was sent at the old mtu
+            let mut lost = new_core(&config, None);
+            // Got it, but its ACKs were lost
+            let mut holding = new_core(&config, None);
+            let payload: Vec<u8> = (0..config.mss * 4).map(|i| i as u8).collect();
+            assert!(sender.poll_send(&cx, &payload[..config.mss * 2]).is_ready());
+            let io = RecordIo::default();
+            sender.flush(&io).await.unwrap();
+            holding.input(io.segments()).unwrap();
+            // Queued at the old mss
+            assert!(sender.poll_send(&cx, &payload[config.mss * 2..]).is_ready());
+
+            let mtu = sender.get_mtu() / 2;
+            sender.lower_mtu(mtu).unwrap();
+            assert_eq!(sender.get_mtu(), mtu);
+            clock.advance(10);
+            let io = RecordIo::default();
+            sender.flush(&io).await.unwrap();
+            let segments = io.segments();
+            assert!(segments.iter().all(|segment| segment.encoded_len() <= mtu));
+            // The sent segments keep their sequence numbers
+            let mut fragmented: Vec<u32> = segments
+                .iter()
+                .filter(|segment| segment.command == CMD_FRAGMENT)
+                .map(|segment| segment.sequence)
+                .collect();
+            fragmented.dedup();
+            assert_eq!(fragmented, vec![0, 1]);
+            for receiver in [&mut lost, &mut holding].iter_mut() {
+                receiver.input(segments.clone()).unwrap();
+                let received: Vec<u8> = receiver
+                    .recv_queue
+                    .iter()
+                    .flat_map(|data| data.to_vec())
+                    .collect();
+                assert_eq!(received, payload);
+            }
+            assert_eq!(sender.get_stats().bytes_sent, payload.len() as u64);
+            assert_eq!(sender.get_stats().mtu_reductions, 1);
+
+            assert!(sender.lower_mtu(KCP_HEADER_LEN).is_err());
+        });
+    }
+
+    #[test]
+    fn peer_reset_drops_send_window() {
+        async fn exchange(from: &mut KcpCore, to: &mut KcpCore) {
+            let io = RecordIo::default();
+            let _ = from.flush(&io).await;
+            to.input(io.segments()).unwrap();
+        }
+
+        let clock = Arc::new(ManualClock::default());
+        let mut config = KcpConfig::default();
+        config.clock = clock.clone();
+        let config = Arc::new(config);
+
+        smol::block_on(async {
+            let cx = Context::from_waker(noop_waker_ref());
+            let shared = CongestionState::shared(&config);
+            let mut sender = new_core(&config, Some(shared.clone()));
+            let mut receiver = new_core(&config, None);
+            sender.open(Bytes::new());
+            exchange(&mut sender, &mut receiver).await;
+            receiver.open(receiver.get_label());
+            exchange(&mut receiver, &mut sender).await;
+
+            // In flight and never acked, the peer aborts the stream
+            assert!(sender.poll_send(&cx, &[0u8; 4096]).is_ready());
+            sender.flush(&RecordIo::default()).await.unwrap();
+            let window = shared.lock().unwrap().window_size;
+            receiver.reset(1, "abort");
+            exchange(&mut receiver, &mut sender).await;
+            assert_eq!(sender.get_stats().send_queue_len, 0);
+
+            for _ in 0..8 {
+                clock.advance(RTO_INIT);
+                let io = RecordIo::default();
+                let _ = sender.flush(&io).await;
+                assert!(io
+                    .segments()
+                    .iter()
+                    .all(|segment| segment.command != CMD_PUSH));
+            }
+            // The other streams keep their window
+            assert_eq!(shared.lock().unwrap().window_size, window);
+        });
     }
 }
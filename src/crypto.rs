@@ -1,29 +1,103 @@
-use std::{num::NonZeroU32, sync::Arc};
+use std::{
+    net::SocketAddr,
+    num::NonZeroU32,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering},
+        Arc,
+    },
+};
 
 use bytes::{BufMut, Bytes, BytesMut};
 use ring::{
     aead::{self, BoundKey, Nonce, NonceSequence},
     error::Unspecified,
-    pbkdf2,
+    hkdf, pbkdf2,
     rand::SecureRandom,
     rand::SystemRandom,
 };
 
 use crate::core::KcpIo;
+use crate::error::KcpError;
 
 pub trait Crypto: Send + Sync {
     fn encrypt(&self, buf: &[u8]) -> Bytes;
     fn decrypt(&self, buf: &mut [u8]) -> usize;
+
+    /// Length of the authentication tag, packets are split into ciphertext and tag with it.
+    /// It differs between algorithms, so it's never assumed.
+    fn tag_len(&self) -> usize;
+
+    /// Bytes added to every packet, at least the tag
+    fn overhead(&self) -> usize {
+        self.tag_len()
+    }
+
+    /// Use a key per stream, derived from the stream id in the first 2 bytes of every
+    /// packet, see `KcpConfig::per_stream_keys`. Returns false when unsupported.
+    fn set_per_stream_keys(&self, _enabled: bool) -> bool {
+        false
+    }
+}
+
+/// Packets in a row failing to decrypt, see `CryptoLayer::with_failure_threshold`
+#[derive(Default)]
+struct FailureCounter {
+    failures: AtomicU32,
+    threshold: Option<u32>,
 }
 
+impl FailureCounter {
+    fn succeeded(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+    }
+
+    /// The packet is dropped, or the session torn down past the threshold
+    fn failed(&self) -> std::io::Result<usize> {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        match self.threshold {
+            Some(threshold) if failures >= threshold => {
+                log::error!("{} packets in a row failed to decrypt", failures);
+                Err(KcpError::DecryptFailureThreshold(failures).into())
+            }
+            _ => Ok(0),
+        }
+    }
+}
+
+/// Encrypts and authenticates every packet of a handle, the OPEN and RESET segments, ACKs
+/// and pings included. A packet failing to decrypt is dropped before the handle parses it, so
+/// a forged OPEN never creates a stream.
 pub struct CryptoLayer<IO, C> {
     io: IO,
     crypto: C,
+    failures: FailureCounter,
 }
 
 impl<IO: KcpIo + Send + Sync, C: Crypto> CryptoLayer<IO, C> {
     pub fn wrap(io: IO, crypto: C) -> Self {
-        Self { io, crypto }
+        Self {
+            io,
+            crypto,
+            failures: FailureCounter::default(),
+        }
+    }
+
+    /// Give up after `threshold` packets in a row fail to decrypt, most likely the peer
+    /// holds another key. Receiving fails with `KcpError::DecryptFailureThreshold` and the
+    /// handle closes all its streams. Off by default, since anyone able to send to the
+    /// socket can then tear the sessions down.
+    pub fn with_failure_threshold(mut self, threshold: u32) -> Self {
+        self.failures.threshold = Some(threshold.max(1));
+        self
+    }
+
+    fn open(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.crypto.decrypt(buf);
+        if size != 0 {
+            self.failures.succeeded();
+            return Ok(size);
+        }
+        self.failures.failed()
     }
 }
 
@@ -36,8 +110,181 @@ impl<IO: KcpIo + Send + Sync, C: Crypto> KcpIo for CryptoLayer<IO, C> {
 
     async fn recv_packet(&self, buf: &mut [u8]) -> std::io::Result<usize> {
         let len = self.io.recv_packet(buf).await?;
+        self.open(&mut buf[..len])
+    }
+
+    async fn recv_packet_ecn(&self, buf: &mut [u8]) -> std::io::Result<(usize, bool)> {
+        let (len, ce) = self.io.recv_packet_ecn(buf).await?;
+        let size = self.open(&mut buf[..len])?;
+        Ok((size, ce))
+    }
+
+    fn overhead(&self) -> usize {
+        self.io.overhead() + self.crypto.overhead()
+    }
+
+    fn set_per_stream_keys(&self, enabled: bool) -> bool {
+        self.crypto.set_per_stream_keys(enabled)
+    }
+
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        self.io.peer_addr()
+    }
+
+    async fn send_packet_to(&self, buf: &[u8], addr: SocketAddr) -> std::io::Result<()> {
+        let ciphertext = self.crypto.encrypt(buf);
+        self.io.send_packet_to(&ciphertext, addr).await
+    }
+
+    async fn recv_packet_from(
+        &self,
+        buf: &mut [u8],
+    ) -> std::io::Result<(usize, Option<SocketAddr>)> {
+        let (len, addr) = self.io.recv_packet_from(buf).await?;
+        let size = self.open(&mut buf[..len])?;
+        Ok((size, addr))
+    }
+}
+
+const UNDECIDED: u8 = 0;
+const PLAINTEXT: u8 = 1;
+const ENCRYPTED: u8 = 2;
+
+/// `CryptoLayer` for a server session which may also serve legacy peers sending plaintext.
+/// Every packet is tried with the key until one authenticates, from then on the session is
+/// encrypted like with `CryptoLayer` and plaintext is dropped. Until then packets failing to
+/// authenticate are taken as plaintext, and so are the replies. A stray or corrupted first
+/// datagram doesn't lock an encrypted peer out.
+pub struct FallbackCryptoLayer<IO, C> {
+    io: IO,
+    crypto: C,
+    mode: AtomicU8,
+    failures: FailureCounter,
+}
+
+impl<IO: KcpIo + Send + Sync, C: Crypto> FallbackCryptoLayer<IO, C> {
+    /// Without `allow_plaintext`, it's exactly `CryptoLayer`.
+    ///
+    /// Allowing plaintext gives up everything the encryption provides for those peers.
+    /// Their packets can be read and forged on the path, and the key no longer keeps
+    /// strangers out, anything failing to authenticate gets a plaintext session. Until a
+    /// session authenticates, even one of a peer holding the key accepts forged plaintext
+    /// and answers in plaintext. Only enable it while migrating clients, and turn it off
+    /// afterwards.
+    pub fn wrap(io: IO, crypto: C, allow_plaintext: bool) -> Self {
+        let mode = if allow_plaintext {
+            UNDECIDED
+        } else {
+            ENCRYPTED
+        };
+        Self {
+            io,
+            crypto,
+            mode: AtomicU8::new(mode),
+            failures: FailureCounter::default(),
+        }
+    }
+
+    /// As `CryptoLayer::with_failure_threshold`. Only the packets dropped count, those taken
+    /// as plaintext before the session authenticates don't.
+    pub fn with_failure_threshold(mut self, threshold: u32) -> Self {
+        self.failures.threshold = Some(threshold.max(1));
+        self
+    }
+
+    pub fn is_plaintext(&self) -> bool {
+        self.mode.load(Ordering::Acquire) == PLAINTEXT
+    }
+
+    fn open(&self, buf: &mut [u8], len: usize) -> std::io::Result<usize> {
+        if self.mode.load(Ordering::Acquire) == ENCRYPTED {
+            let size = self.crypto.decrypt(&mut buf[..len]);
+            if size == 0 {
+                return self.failures.failed();
+            }
+            self.failures.succeeded();
+            return Ok(size);
+        }
+        // Decryption works in place, keep a copy in case it's plaintext
+        let packet = buf[..len].to_vec();
         let size = self.crypto.decrypt(&mut buf[..len]);
-        Ok(size)
+        if size > 0 {
+            if self.mode.swap(ENCRYPTED, Ordering::AcqRel) == PLAINTEXT {
+                log::info!(
+                    "plaintext peer {:?} turned to encryption",
+                    self.io.peer_addr()
+                );
+            }
+            self.failures.succeeded();
+            Ok(size)
+        } else if len > 0 {
+            if self
+                .mode
+                .compare_exchange(UNDECIDED, PLAINTEXT, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                log::warn!("serving a plaintext peer {:?}", self.io.peer_addr());
+            } else if self.mode.load(Ordering::Acquire) == ENCRYPTED {
+                // Authenticated by another packet meanwhile
+                return self.failures.failed();
+            }
+            buf[..len].copy_from_slice(&packet);
+            Ok(len)
+        } else {
+            Ok(0)
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<IO: KcpIo + Send + Sync, C: Crypto> KcpIo for FallbackCryptoLayer<IO, C> {
+    async fn send_packet(&self, buf: &[u8]) -> std::io::Result<()> {
+        if self.is_plaintext() {
+            self.io.send_packet(buf).await
+        } else {
+            let ciphertext = self.crypto.encrypt(buf);
+            self.io.send_packet(&ciphertext).await
+        }
+    }
+
+    async fn recv_packet(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.io.recv_packet(buf).await?;
+        self.open(buf, len)
+    }
+
+    async fn recv_packet_ecn(&self, buf: &mut [u8]) -> std::io::Result<(usize, bool)> {
+        let (len, ce) = self.io.recv_packet_ecn(buf).await?;
+        Ok((self.open(buf, len)?, ce))
+    }
+
+    fn overhead(&self) -> usize {
+        // Streams may be set up before the mode is known, assume the worst
+        self.io.overhead() + self.crypto.overhead()
+    }
+
+    fn set_per_stream_keys(&self, enabled: bool) -> bool {
+        self.crypto.set_per_stream_keys(enabled)
+    }
+
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        self.io.peer_addr()
+    }
+
+    async fn send_packet_to(&self, buf: &[u8], addr: SocketAddr) -> std::io::Result<()> {
+        if self.is_plaintext() {
+            self.io.send_packet_to(buf, addr).await
+        } else {
+            let ciphertext = self.crypto.encrypt(buf);
+            self.io.send_packet_to(&ciphertext, addr).await
+        }
+    }
+
+    async fn recv_packet_from(
+        &self,
+        buf: &mut [u8],
+    ) -> std::io::Result<(usize, Option<SocketAddr>)> {
+        let (len, addr) = self.io.recv_packet_from(buf).await?;
+        Ok((self.open(buf, len)?, addr))
     }
 }
 
@@ -65,10 +312,16 @@ impl<'a> OneNonceSequence<'a> {
     }
 }
 
+/// The clear stream id in front of packets encrypted with per-stream keys
+const STREAM_ID_LEN: usize = 2;
+
 pub struct AeadCrypto {
     key_bytes: Bytes,
     algorithm: &'static aead::Algorithm,
     random: SystemRandom,
+    /// Extracted from `key_bytes`, the stream keys are expanded from it
+    stream_prk: hkdf::Prk,
+    per_stream_keys: AtomicBool,
 }
 
 impl AeadCrypto {
@@ -84,10 +337,41 @@ impl AeadCrypto {
             &mut key_bytes,
         );
         let key_bytes = key_bytes.freeze();
+        let stream_prk =
+            hkdf::Salt::new(hkdf::HKDF_SHA256, b"ap-kcp-stream-salt").extract(&key_bytes);
         Self {
             key_bytes,
             algorithm,
             random: SystemRandom::new(),
+            stream_prk,
+            per_stream_keys: AtomicBool::new(false),
+        }
+    }
+
+    fn stream_key(&self, stream_id: &[u8]) -> Bytes {
+        let mut key_bytes = BytesMut::with_capacity(self.algorithm.key_len());
+        key_bytes.resize(self.algorithm.key_len(), 0);
+        self.stream_prk
+            .expand(&[&b"ap-kcp-stream-key"[..], stream_id], self.algorithm)
+            .and_then(|okm| okm.fill(&mut key_bytes))
+            .unwrap();
+        key_bytes.freeze()
+    }
+
+    /// The key of the packets with the clear `stream_id`, the master key without one
+    fn unbound_key(&self, stream_id: &[u8]) -> aead::UnboundKey {
+        if stream_id.is_empty() {
+            aead::UnboundKey::new(&self.algorithm, &self.key_bytes).unwrap()
+        } else {
+            aead::UnboundKey::new(&self.algorithm, &self.stream_key(stream_id)).unwrap()
+        }
+    }
+
+    fn clear_len(&self) -> usize {
+        if self.per_stream_keys.load(Ordering::Relaxed) {
+            STREAM_ID_LEN
+        } else {
+            0
         }
     }
 }
@@ -100,48 +384,85 @@ impl<C: Crypto> Crypto for Arc<C> {
     fn decrypt(&self, buf: &mut [u8]) -> usize {
         C::decrypt(self, buf)
     }
+
+    fn tag_len(&self) -> usize {
+        C::tag_len(self)
+    }
+
+    fn overhead(&self) -> usize {
+        C::overhead(self)
+    }
+
+    fn set_per_stream_keys(&self, enabled: bool) -> bool {
+        C::set_per_stream_keys(self, enabled)
+    }
 }
 
 impl Crypto for AeadCrypto {
+    fn tag_len(&self) -> usize {
+        self.algorithm.tag_len()
+    }
+
+    fn overhead(&self) -> usize {
+        aead::NONCE_LEN + self.tag_len()
+    }
+
+    fn set_per_stream_keys(&self, enabled: bool) -> bool {
+        self.per_stream_keys.store(enabled, Ordering::Relaxed);
+        true
+    }
+
     fn encrypt(&self, buf: &[u8]) -> Bytes {
-        let unbound_key = aead::UnboundKey::new(&self.algorithm, &self.key_bytes).unwrap();
+        let (stream_id, buf) = buf.split_at(self.clear_len().min(buf.len()));
+        let unbound_key = self.unbound_key(stream_id);
 
         let mut nonce = [0u8; aead::NONCE_LEN];
         self.random.fill(&mut nonce).unwrap();
         let nonce_sequence = OneNonceSequence::new(&nonce);
 
         let mut sealing_key = aead::SealingKey::new(unbound_key, nonce_sequence);
-        let mut cipertext =
-            BytesMut::with_capacity(aead::NONCE_LEN + buf.len() + self.algorithm.tag_len());
+        let mut cipertext = BytesMut::with_capacity(stream_id.len() + buf.len() + self.overhead());
 
-        // | ENCRPYTED | TAG | NONCE |
+        // | STREAM ID | ENCRPYTED | TAG | NONCE |, the stream id only with per-stream keys
+        cipertext.put_slice(stream_id);
         cipertext.put_slice(buf);
 
-        sealing_key
-            .seal_in_place_append_tag(aead::Aad::empty(), &mut cipertext)
+        let tag = sealing_key
+            .seal_in_place_separate_tag(
+                aead::Aad::from(stream_id),
+                &mut cipertext[stream_id.len()..],
+            )
             .unwrap();
+        debug_assert_eq!(tag.as_ref().len(), self.tag_len());
+        cipertext.put_slice(tag.as_ref());
 
         cipertext.put_slice(&nonce);
         cipertext.freeze()
     }
 
     fn decrypt(&self, buf: &mut [u8]) -> usize {
-        if buf.len() < aead::NONCE_LEN + self.algorithm.tag_len() {
+        let clear_len = self.clear_len();
+        if buf.len() < clear_len + self.overhead() {
             return 0;
         }
         let len = buf.len();
-        let unbound_key = aead::UnboundKey::new(&self.algorithm, &self.key_bytes).unwrap();
+        let plaintext_len = len - self.overhead();
         let mut nonce = [0u8; aead::NONCE_LEN];
         nonce.copy_from_slice(&buf[len - aead::NONCE_LEN..]);
 
+        let (stream_id, buf) = buf.split_at_mut(clear_len);
+        let unbound_key = self.unbound_key(stream_id);
         let nonce_sequence = OneNonceSequence::new(&nonce);
         let mut opening_key = aead::OpeningKey::new(unbound_key, nonce_sequence);
-        if let Ok(plaintext) =
-            opening_key.open_in_place(aead::Aad::empty(), &mut buf[..len - aead::NONCE_LEN])
-        {
-            plaintext.len()
+        // The ciphertext and the tag right after it
+        let sealed = &mut buf[..plaintext_len - clear_len + self.tag_len()];
+        if let Ok(plaintext) = opening_key.open_in_place(aead::Aad::from(&*stream_id), sealed) {
+            debug_assert_eq!(plaintext.len(), plaintext_len - clear_len);
+            // The clear stream id stays in front of the plaintext
+            plaintext_len
         } else {
-            log::error!("failed to decrypt aead packet");
+            // Routine for the plaintext peers of `FallbackCryptoLayer`
+            log::debug!("failed to decrypt aead packet");
             0
         }
     }
@@ -168,4 +489,62 @@ mod test {
         let len = crypto.decrypt(&mut plaintext);
         assert!(len == 0);
     }
+
+    #[test]
+    fn tag_len() {
+        for algorithm in [
+            &aead::AES_128_GCM,
+            &aead::AES_256_GCM,
+            &aead::CHACHA20_POLY1305,
+        ]
+        .iter()
+        {
+            let crypto = AeadCrypto::new(b"secret_key!", algorithm);
+            assert_eq!(crypto.tag_len(), algorithm.tag_len());
+            assert_eq!(Arc::new(crypto).tag_len(), algorithm.tag_len());
+
+            let crypto = AeadCrypto::new(b"secret_key!", algorithm);
+            let plaintext = b"some plaintext";
+            let ciphertext = crypto.encrypt(plaintext);
+            assert_eq!(ciphertext.len(), plaintext.len() + crypto.overhead());
+            let mut buf = ciphertext.to_vec();
+            assert_eq!(crypto.decrypt(&mut buf), plaintext.len());
+
+            // Anything shorter than the tag and the nonce is no packet
+            let mut buf = ciphertext[plaintext.len()..].to_vec();
+            buf.pop();
+            assert_eq!(crypto.decrypt(&mut buf), 0);
+        }
+    }
+
+    #[test]
+    fn per_stream_keys() {
+        let crypto = AeadCrypto::new(b"secret_key!", &aead::AES_256_GCM);
+        assert!(crypto.set_per_stream_keys(true));
+        assert_ne!(crypto.stream_key(&[1, 0]), crypto.stream_key(&[2, 0]));
+        assert_ne!(crypto.stream_key(&[1, 0]), crypto.key_bytes);
+
+        let packet1 = b"\x01\x00some plaintext";
+        let packet2 = b"\x02\x00some plaintext";
+        let ciphertext1 = crypto.encrypt(packet1);
+        let ciphertext2 = crypto.encrypt(packet2);
+        // The stream id stays readable, the overhead is the same
+        assert_eq!(&ciphertext1[..2], &[1, 0]);
+        assert_eq!(ciphertext1.len(), packet1.len() + crypto.overhead());
+        for (ciphertext, packet) in [(&ciphertext1, packet1), (&ciphertext2, packet2)].iter() {
+            let mut buf = ciphertext.to_vec();
+            let len = crypto.decrypt(&mut buf);
+            assert_eq!(&buf[..len], &packet[..]);
+        }
+
+        // Under another stream id, the packet is opened with another key
+        let mut buf = ciphertext1.to_vec();
+        buf[0] = 2;
+        assert_eq!(crypto.decrypt(&mut buf), 0);
+
+        // The master key opens none of them
+        let master = AeadCrypto::new(b"secret_key!", &aead::AES_256_GCM);
+        let mut buf = ciphertext1.to_vec();
+        assert_eq!(master.decrypt(&mut buf), 0);
+    }
 }
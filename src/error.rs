@@ -6,11 +6,34 @@ use std::io::{self, ErrorKind};
 pub enum KcpError {
     TooManyStreams,
     InvalidSegmentDataSize(usize, usize),
+    MalformedSegment(String),
     IoError(io::Error),
     UnsupportCmd(u8),
     Timeout,
     NoResponse,
     Shutdown(String),
+    LabelTooLong(usize),
+    DatagramTooLong(usize),
+    InvalidConfig(String),
+    LifetimeExpired,
+    IdleTimeout,
+    ReasonTooLong(usize),
+    /// The peer aborted the stream with `KcpStream::reset_with`
+    PeerReset {
+        code: u32,
+        reason: String,
+    },
+    /// The peer doesn't hold the same `KcpConfig::peer_auth_key`
+    PeerAuthFailed,
+    /// Too many packets in a row failed to decrypt, see `CryptoLayer::with_failure_threshold`
+    DecryptFailureThreshold(u32),
+    /// The OPEN segment was not acked after `KcpConfig::handshake_retries` retransmissions
+    HandshakeTimeout,
+    /// The send queue is full, see `SendOverflowPolicy::Error`
+    SendQueueFull,
+    /// The peer abandoned this many segments here, see `KcpConfig::segment_ttl`. Reading
+    /// again goes on with the data past the gap.
+    DataSkipped(u32),
 }
 
 impl StdError for KcpError {}
@@ -32,6 +55,11 @@ impl From<KcpError> for io::Error {
     fn from(err: KcpError) -> io::Error {
         let kind = match err {
             KcpError::IoError(err) => return err,
+            KcpError::PeerReset { .. } => ErrorKind::ConnectionReset,
+            KcpError::PeerAuthFailed => ErrorKind::PermissionDenied,
+            KcpError::HandshakeTimeout => ErrorKind::TimedOut,
+            KcpError::SendQueueFull => ErrorKind::WouldBlock,
+            KcpError::DataSkipped(_) => ErrorKind::InvalidData,
             _ => ErrorKind::Other,
         };
 
@@ -41,6 +69,10 @@ impl From<KcpError> for io::Error {
 
 impl From<io::Error> for KcpError {
     fn from(err: io::Error) -> KcpError {
+        // Unwrap what `From<KcpError> for io::Error` wrapped
+        if err.get_ref().map_or(false, |inner| inner.is::<KcpError>()) {
+            return *err.into_inner().unwrap().downcast::<KcpError>().unwrap();
+        }
         KcpError::IoError(err)
     }
 }
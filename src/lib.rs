@@ -1,14 +1,68 @@
+#[cfg(feature = "sync_core")]
+extern crate alloc;
+
 mod async_kcp;
+#[cfg(feature = "tokio")]
+pub mod compat;
+pub mod compression;
 mod core;
 pub mod crypto;
 pub mod error;
+pub mod obfuscation;
+pub mod sans_io;
 mod segment;
+pub mod socket;
+mod spans;
+pub mod spsc;
+#[cfg(feature = "sync_core")]
+pub mod sync_core;
+
+/// Entry points for the fuzz targets in `fuzz/`, not a stable api
+#[cfg(feature = "fuzz")]
+#[doc(hidden)]
+pub mod fuzz {
+    use bytes::{Buf, BytesMut};
+
+    use crate::{error::KcpResult, segment::KcpSegment};
+
+    /// Decodes every segment in the packet, a decoded segment must encode back to the same bytes
+    pub fn decode_packet(mut packet: &[u8]) -> KcpResult<()> {
+        while packet.has_remaining() {
+            let segment = KcpSegment::decode(packet)?;
+            let mut buf = BytesMut::new();
+            segment.encode(&mut buf);
+            assert_eq!(&buf[..], &packet[..segment.encoded_len()]);
+            packet.advance(segment.encoded_len());
+        }
+        Ok(())
+    }
+}
 
+pub use crate::async_kcp::AcceptedStream;
+pub use crate::async_kcp::KcpChannel;
+pub use crate::async_kcp::KcpDatagram;
 pub use crate::async_kcp::KcpHandle;
+pub use crate::async_kcp::KcpReadHalf;
 pub use crate::async_kcp::KcpStream;
+pub use crate::async_kcp::KcpWriteHalf;
+pub use crate::async_kcp::SessionSummary;
+pub use crate::async_kcp::StreamKind;
+pub use crate::async_kcp::StreamParameters;
+pub use crate::core::Clock;
 pub use crate::core::Congestion;
+pub use crate::core::Features;
 pub use crate::core::KcpConfig;
 pub use crate::core::KcpIo;
+pub use crate::core::KcpStats;
+pub use crate::core::SchedulingPolicy;
+pub use crate::core::SegmentTrace;
+pub use crate::core::SegmentTracer;
+pub use crate::core::SendOverflowPolicy;
+pub use crate::core::SystemClock;
+pub use crate::core::TraceDirection;
+pub use crate::core::DEFAULT_MTU;
+pub use crate::core::MAX_DATAGRAM;
+pub use crate::segment::KCP_HEADER_LEN;
 
 pub use async_trait::async_trait;
 
@@ -16,20 +70,102 @@ pub mod prelude {
     #[async_trait::async_trait]
     impl crate::KcpIo for smol::net::UdpSocket {
         async fn send_packet(&self, buf: &[u8]) -> std::io::Result<()> {
-            self.send(buf).await?;
-            Ok(())
+            crate::socket::send_retrying(|| async {
+                self.send(buf).await?;
+                Ok(())
+            })
+            .await
+        }
+
+        async fn recv_packet(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let size = self.recv(buf).await?;
+            Ok(size)
+        }
+
+        async fn recv_packet_ecn(&self, buf: &mut [u8]) -> std::io::Result<(usize, bool)> {
+            let (size, _, ce) = crate::socket::recv_from_ecn(self, buf).await?;
+            Ok((size, ce))
+        }
+
+        fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+            smol::net::UdpSocket::peer_addr(self).ok()
+        }
+
+        async fn send_packet_to(
+            &self,
+            buf: &[u8],
+            addr: std::net::SocketAddr,
+        ) -> std::io::Result<()> {
+            crate::socket::send_retrying(|| async {
+                self.send_to(buf, addr).await?;
+                Ok(())
+            })
+            .await
+        }
+
+        async fn recv_packet_from(
+            &self,
+            buf: &mut [u8],
+        ) -> std::io::Result<(usize, Option<std::net::SocketAddr>)> {
+            let (size, addr) = self.recv_from(buf).await?;
+            Ok((size, Some(addr)))
+        }
+    }
+
+    /// A connected async-std socket, for applications built on async-std. The timers and
+    /// tasks of the handle still run on smol, next to the async-std runtime.
+    #[cfg(feature = "async-std")]
+    #[async_trait::async_trait]
+    impl crate::KcpIo for async_std::net::UdpSocket {
+        async fn send_packet(&self, buf: &[u8]) -> std::io::Result<()> {
+            crate::socket::send_retrying(|| async {
+                self.send(buf).await?;
+                Ok(())
+            })
+            .await
         }
 
         async fn recv_packet(&self, buf: &mut [u8]) -> std::io::Result<usize> {
             let size = self.recv(buf).await?;
             Ok(size)
         }
+
+        fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+            async_std::net::UdpSocket::peer_addr(self).ok()
+        }
+
+        async fn send_packet_to(
+            &self,
+            buf: &[u8],
+            addr: std::net::SocketAddr,
+        ) -> std::io::Result<()> {
+            crate::socket::send_retrying(|| async {
+                self.send_to(buf, addr).await?;
+                Ok(())
+            })
+            .await
+        }
+
+        async fn recv_packet_from(
+            &self,
+            buf: &mut [u8],
+        ) -> std::io::Result<(usize, Option<std::net::SocketAddr>)> {
+            let (size, addr) = self.recv_from(buf).await?;
+            Ok((size, Some(addr)))
+        }
     }
 }
 
 #[cfg(test)]
 pub mod test {
-    use std::{sync::Arc, time::Duration};
+    use std::{
+        io::IoSlice,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
 
     use crate::core::KcpConfig;
 
@@ -151,23 +287,31 @@ pub mod test {
     pub struct NetworkIoSimulator {
         packet_loss: f64,
         delay: u64,
+        // Up to this much more delay, picked for every packet, so packets overtake each other
+        jitter: u64,
         tx: Sender<Bytes>,
         rx: Receiver<Bytes>,
     }
 
     impl NetworkIoSimulator {
         fn new(packet_loss: f64, delay: u64) -> (Self, Self) {
+            Self::with_jitter(packet_loss, delay, 0)
+        }
+
+        fn with_jitter(packet_loss: f64, delay: u64, jitter: u64) -> (Self, Self) {
             let (tx1, rx1) = bounded(1);
             let (tx2, rx2) = bounded(1);
             let io1 = Self {
                 packet_loss,
                 delay,
+                jitter,
                 tx: tx1,
                 rx: rx2,
             };
             let io2 = Self {
                 packet_loss,
                 delay,
+                jitter,
                 tx: tx2,
                 rx: rx1,
             };
@@ -179,7 +323,7 @@ pub mod test {
     impl KcpIo for NetworkIoSimulator {
         async fn send_packet(&self, buf: &[u8]) -> std::io::Result<()> {
             let tx = self.tx.clone();
-            let delay = self.delay;
+            let delay = self.delay + rand::thread_rng().gen_range(0, self.jitter + 1);
             let loss = self.packet_loss;
             let packet = Bytes::copy_from_slice(buf);
             smol::spawn(async move {
@@ -205,6 +349,71 @@ pub mod test {
         }
     }
 
+    /// Sends `payload` from `io1` to `io2` over a fresh pair of handles, and asserts that
+    /// exactly the same bytes arrive, in order, before EOF
+    pub async fn assert_reliable_transfer<T: KcpIo + Send + Sync + 'static>(
+        io1: T,
+        io2: T,
+        payload: &[u8],
+    ) {
+        let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+        let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+        let mut stream1 = kcp1.connect().await.unwrap();
+        let data = payload.to_vec();
+        let writer = smol::spawn(async move {
+            // Writes don't line up with the segments
+            for chunk in data.chunks(777) {
+                stream1.write_all(chunk).await.unwrap();
+            }
+            stream1.close().await.unwrap();
+        });
+
+        let mut stream2 = kcp2.accept().await.unwrap();
+        let mut received = Vec::new();
+        stream2.read_to_end(&mut received).await.unwrap();
+        if let Some(offset) = received
+            .iter()
+            .zip(payload.iter())
+            .position(|(a, b)| a != b)
+        {
+            panic!("received data differs from offset {}", offset);
+        }
+        assert_eq!(received.len(), payload.len());
+        stream2.close().await.unwrap();
+        writer.await;
+    }
+
+    #[test]
+    fn reliable_transfer() {
+        init();
+        smol::block_on(async move {
+            let mut payload = vec![0u8; 0x10000];
+            rand::thread_rng().fill_bytes(&mut payload);
+            for loss in [0.0, 0.05, 0.3].iter() {
+                let (io1, io2) = NetworkIoSimulator::with_jitter(*loss, 10, 20);
+                assert_reliable_transfer(io1, io2, &payload).await;
+            }
+        });
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn async_std_udp() {
+        async_std::task::block_on(async {
+            let io1 = async_std::net::UdpSocket::bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let io2 = async_std::net::UdpSocket::bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            io1.connect(io2.local_addr().unwrap()).await.unwrap();
+            io2.connect(io1.local_addr().unwrap()).await.unwrap();
+            let mut payload = vec![0u8; 0x10000];
+            rand::thread_rng().fill_bytes(&mut payload);
+            assert_reliable_transfer(io1, io2, &payload).await;
+        });
+    }
+
     #[test]
     fn udp() {
         init();
@@ -368,4 +577,2070 @@ pub mod test {
             t.await;
         });
     }
+
+    #[test]
+    fn eof() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(b"hello").await.unwrap();
+            let t = smol::spawn(async move {
+                stream1.close().await.unwrap();
+            });
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = Vec::new();
+            buf.resize(100, 0u8);
+            let len = stream2.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"hello");
+            assert_eq!(stream2.read(&mut buf).await.unwrap(), 0);
+            assert_eq!(stream2.read(&mut buf).await.unwrap(), 0);
+            t.await;
+        });
+    }
+
+    #[test]
+    fn close_immediate() {
+        init();
+        smol::block_on(async move {
+            for immediate in [false, true].iter() {
+                let immediate = *immediate;
+                let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+                let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+                let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+                let data = random_data();
+                let mut stream1 = kcp1.connect().await.unwrap();
+                let mut expected = Vec::new();
+                for _ in 0..100 {
+                    stream1.write_all(&data).await.unwrap();
+                    expected.extend_from_slice(&data);
+                }
+                let t = smol::spawn(async move {
+                    if immediate {
+                        stream1.close_immediate().await.unwrap();
+                    } else {
+                        stream1.close().await.unwrap();
+                    }
+                });
+                let mut stream2 = kcp2.accept().await.unwrap();
+                let mut buf = Vec::new();
+                stream2.read_to_end(&mut buf).await.unwrap();
+                if immediate {
+                    assert!(buf.len() <= expected.len());
+                    assert_eq!(&buf[..], &expected[..buf.len()]);
+                } else {
+                    assert_eq!(buf, expected);
+                }
+                t.await;
+            }
+        });
+    }
+
+    #[test]
+    fn label() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            let stream1 = kcp1.connect_with_label(b"example.com:443").await.unwrap();
+            assert_eq!(stream1.label(), b"example.com:443");
+            let stream2 = kcp2.accept().await.unwrap();
+            assert_eq!(stream2.label(), b"example.com:443");
+
+            let stream1 = kcp1.connect().await.unwrap();
+            assert!(stream1.label().is_empty());
+            let stream2 = kcp2.accept().await.unwrap();
+            assert!(stream2.label().is_empty());
+
+            let label = [0u8; async_kcp::MAX_LABEL_LEN + 1];
+            assert!(kcp1.connect_with_label(&label).await.is_err());
+        });
+    }
+
+    #[test]
+    fn close_session() {
+        init();
+        smol::block_on(async move {
+            // One unconnected socket for all peers
+            let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let server_addr = udp.local_addr().unwrap();
+            let server = KcpHandle::new(udp, KcpConfig::default());
+
+            let mut clients = Vec::new();
+            for _ in 0..2 {
+                let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                udp.connect(server_addr).await.unwrap();
+                let addr = udp.local_addr().unwrap();
+                let client = KcpHandle::new(udp, KcpConfig::default());
+                let mut streams = Vec::new();
+                for _ in 0..2 {
+                    let mut stream1 = client.connect().await.unwrap();
+                    stream1.write_all(b"hello").await.unwrap();
+                    let mut stream2 = server.accept().await.unwrap();
+                    let mut buf = [0u8; 5];
+                    stream2.read_exact(&mut buf).await.unwrap();
+                    streams.push((stream1, stream2));
+                }
+                clients.push((addr, client, streams));
+            }
+            assert_eq!(server.get_stream_count().await, 4);
+
+            let (addr, _, banned_streams) = &mut clients[0];
+            let banned = Some(*addr);
+            assert!(server
+                .close_session(banned, 7, &"x".repeat(0x100))
+                .await
+                .is_err());
+            assert_eq!(server.close_session(banned, 7, "banned").await.unwrap(), 2);
+            assert_eq!(server.close_session(banned, 7, "banned").await.unwrap(), 0);
+            assert_eq!(server.get_stream_count().await, 2);
+            let mut buf = [0u8; 5];
+            for (stream1, stream2) in banned_streams.iter_mut() {
+                assert!(stream2.read(&mut buf).await.is_err());
+                let err = stream1
+                    .read(&mut buf)
+                    .await
+                    .unwrap_err()
+                    .into_inner()
+                    .unwrap()
+                    .downcast::<error::KcpError>()
+                    .unwrap();
+                match *err {
+                    error::KcpError::PeerReset { code, ref reason } => {
+                        assert_eq!(code, 7);
+                        assert_eq!(reason, "banned");
+                    }
+                    ref err => panic!("unexpected error {}", err),
+                }
+            }
+
+            // The other peer goes on
+            let (_, _, streams) = &mut clients[1];
+            for (stream1, stream2) in streams.iter_mut() {
+                stream2.write_all(b"world").await.unwrap();
+                stream1.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"world");
+            }
+        });
+    }
+
+    #[test]
+    fn peek() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(b"hello world").await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+
+            let mut peeked = [0u8; 5];
+            let len = stream2.peek(&mut peeked).await.unwrap();
+            assert_eq!(&peeked[..len], b"hello");
+            let len = stream2.peek(&mut peeked).await.unwrap();
+            assert_eq!(&peeked[..len], b"hello");
+
+            let mut buf = [0u8; 11];
+            stream2.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello world");
+        });
+    }
+
+    #[test]
+    fn effective_mtu() {
+        use crate::crypto::{AeadCrypto, CryptoLayer};
+        use ring::aead;
+
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let io1 = CryptoLayer::wrap(io1, AeadCrypto::new(b"key", &aead::AES_256_GCM));
+            let io2 = CryptoLayer::wrap(io2, AeadCrypto::new(b"key", &aead::AES_256_GCM));
+            let mut config = KcpConfig::default();
+            config.mtu = 200;
+            config.mss = 200 - KCP_HEADER_LEN;
+            let kcp1 = KcpHandle::new(io1, config.clone());
+            let kcp2 = KcpHandle::new(io2, config.clone());
+
+            let data = random_data();
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(&data).await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = vec![0u8; data.len()];
+            stream2.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf[..], &data[..]);
+
+            let overhead = aead::NONCE_LEN + aead::AES_256_GCM.tag_len();
+            assert_eq!(stream1.effective_mtu().await, 200 - overhead);
+            assert_eq!(stream2.effective_mtu().await, 200 - overhead);
+            let window = stream1.effective_send_window().await;
+            assert!(window > 0 && window <= config.send_window_size);
+        });
+    }
+
+    #[test]
+    fn datagram() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.3, 10);
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            let datagram1 = kcp1.open_datagram();
+            let datagram2 = kcp2.open_datagram();
+            assert!(datagram1
+                .send(&vec![0u8; datagram1.max_len() + 1])
+                .await
+                .is_err());
+
+            let data = random_data();
+            let mut stream1 = kcp1.connect().await.unwrap();
+            for i in 0..100u32 {
+                datagram1.send(&i.to_le_bytes()).await.unwrap();
+                stream1.write_all(&data[..0x10]).await.unwrap();
+            }
+            stream1.write_all(&data).await.unwrap();
+
+            // The reliable stream sees none of the datagrams
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = vec![0u8; 0x10];
+            for _ in 0..100 {
+                stream2.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf[..], &data[..0x10]);
+            }
+            let mut buf = vec![0u8; data.len()];
+            stream2.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf[..], &data[..]);
+
+            let mut received = Vec::new();
+            while let Some(datagram) = datagram2
+                .recv()
+                .or(async {
+                    Timer::after(Duration::from_millis(100)).await;
+                    Err(error::KcpError::Timeout)
+                })
+                .await
+                .ok()
+            {
+                let mut id = [0u8; 4];
+                id.copy_from_slice(&datagram);
+                received.push(u32::from_le_bytes(id));
+            }
+            assert!(!received.is_empty());
+            assert!(received.len() < 100);
+            assert!(received.windows(2).all(|ids| ids[0] != ids[1]));
+        });
+    }
+
+    #[test]
+    fn datagram_arrival_time() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::with_jitter(0.0, 20, 10);
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            let datagram1 = kcp1.open_datagram();
+            let datagram2 = kcp2.open_datagram();
+
+            let mut sent = Vec::new();
+            for i in 0..20u32 {
+                sent.push(Instant::now());
+                datagram1.send(&i.to_le_bytes()).await.unwrap();
+                Timer::after(Duration::from_millis(30)).await;
+            }
+
+            // Read late, the arrival times don't include the wait in the queue
+            let mut incoming = datagram2.incoming();
+            for _ in 0..20 {
+                let (datagram, arrived) = incoming.next().await.unwrap();
+                let mut id = [0u8; 4];
+                id.copy_from_slice(&datagram);
+                let transit = arrived - sent[u32::from_le_bytes(id) as usize];
+                assert!(transit >= Duration::from_millis(20));
+                assert!(transit < Duration::from_millis(20 + 10 + 20));
+            }
+        });
+    }
+
+    #[test]
+    fn accept_metadata() {
+        init();
+        smol::block_on(async move {
+            // AUTH is only advertised with a key
+            for features in [Features::empty(), Features::all() - Features::AUTH].iter() {
+                let (io1, io2) = get_udp_pair().await;
+                let client_addr = io1.local_addr().unwrap();
+                let mut config = KcpConfig::default();
+                config.features = *features;
+                let kcp1 = KcpHandle::new(io1, config);
+                let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+
+                let before = std::time::SystemTime::now();
+                let mut stream1 = kcp1.connect_with_label(b"telemetry").await.unwrap();
+                stream1.write_all(b"hello").await.unwrap();
+                let accepted = kcp2.accept_with_metadata().await.unwrap();
+                assert!(accepted.established_at >= before);
+                assert_eq!(accepted.peer_addr, Some(client_addr));
+                assert_eq!(&accepted.label[..], b"telemetry");
+                assert_eq!(accepted.stream.label(), b"telemetry");
+                assert_eq!(accepted.features, *features);
+            }
+        });
+    }
+
+    #[test]
+    fn local_recv_window() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let mut config = KcpConfig::default();
+            config.keep_alive_interval = 50;
+            let kcp1 = KcpHandle::new(io1, config.clone());
+            let kcp2 = KcpHandle::new(io2, config.clone());
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(b"hello").await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream2.read_exact(&mut buf).await.unwrap();
+            stream2.set_local_recv_window(1).await;
+            // Let a ping carry the new window
+            Timer::after(Duration::from_millis(200)).await;
+
+            let mut data = vec![0u8; 0x4000];
+            rand::thread_rng().fill_bytes(&mut data);
+            stream1.write_all(&data).await.unwrap();
+            Timer::after(Duration::from_millis(300)).await;
+
+            // Nothing is read, so the sender stalls after one segment
+            assert!(stream1.peer_recv_window().await <= 1);
+            assert!(stream2.get_stats().await.bytes_received < data.len() as u64 / 2);
+
+            let mut buf = vec![0u8; data.len()];
+            stream2.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, data);
+        });
+    }
+
+    #[test]
+    fn flush_and_wait_acked() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.2, 20);
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            let mut stream1 = kcp1.connect().await.unwrap();
+            let mut data = vec![0u8; 0x10000];
+            rand::thread_rng().fill_bytes(&mut data);
+            stream1.write_all(&data).await.unwrap();
+            let stream2 = kcp2.accept().await.unwrap();
+
+            stream1.flush_and_wait_acked().await.unwrap();
+            // Acked segments are already in the peer's hands
+            assert_eq!(stream2.get_stats().await.bytes_received, data.len() as u64);
+        });
+    }
+
+    #[test]
+    fn write_vectored() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.1, 10);
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            let mut stream1 = kcp1.connect().await.unwrap();
+
+            let mut parts = vec![
+                vec![0u8; 10],
+                vec![0u8; 0],
+                vec![0u8; 3000],
+                vec![0u8; 0x10000],
+            ];
+            for part in parts.iter_mut() {
+                rand::thread_rng().fill_bytes(part);
+            }
+            let slices: Vec<_> = parts.iter().map(|part| IoSlice::new(part)).collect();
+            stream1.write_all_vectored(&slices).await.unwrap();
+
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let expected = parts.concat();
+            let mut buf = vec![0u8; expected.len()];
+            stream2.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, expected);
+        });
+    }
+
+    #[test]
+    fn stream_config() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            let mut fast = KcpConfig::default();
+            fast.max_interval = 20;
+            let mut slow = KcpConfig::default();
+            slow.max_interval = 1000;
+            kcp2.set_accept_config(slow.clone()).await.unwrap();
+
+            let mut too_large = KcpConfig::default();
+            too_large.mtu += 1;
+            assert!(kcp1.connect_with_config(too_large).await.is_err());
+
+            let mut stream_fast = kcp1.connect_with_config(fast).await.unwrap();
+            let mut stream_slow = kcp1.connect_with_config(slow).await.unwrap();
+            for stream in [&mut stream_fast, &mut stream_slow].iter_mut() {
+                stream.write_all(b"hello").await.unwrap();
+                stream.flush().await.unwrap();
+            }
+
+            // The rto never drops below max_interval
+            assert!(stream_fast.get_stats().await.rto < 1000);
+            assert!(stream_slow.get_stats().await.rto >= 1000);
+
+            for _ in 0..2 {
+                let mut stream = kcp2.accept().await.unwrap();
+                stream.write_all(b"world").await.unwrap();
+                stream.flush().await.unwrap();
+                assert!(stream.get_stats().await.rto >= 1000);
+            }
+        });
+    }
+
+    #[test]
+    fn lifetime() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let mut config = KcpConfig::default();
+            config.max_stream_lifetime = Some(Duration::from_millis(300));
+            config.max_session_lifetime = Some(Duration::from_millis(600));
+            let kcp1 = KcpHandle::new(io1, config);
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            let start = Instant::now();
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(b"hello").await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+
+            // Busy all the time, but closed anyway
+            let err = loop {
+                if let Err(e) = stream1.write_all(b"hello").await {
+                    break e;
+                }
+                Timer::after(Duration::from_millis(20)).await;
+            };
+            assert!(start.elapsed() >= Duration::from_millis(300));
+            let err = err
+                .into_inner()
+                .unwrap()
+                .downcast::<error::KcpError>()
+                .unwrap();
+            assert!(matches!(*err, error::KcpError::LifetimeExpired));
+
+            // The peer still gets everything written before the deadline
+            let mut buf = Vec::new();
+            stream2.read_to_end(&mut buf).await.unwrap();
+            assert!(buf.len() >= 10);
+
+            Timer::after(Duration::from_millis(600).saturating_sub(start.elapsed())).await;
+            assert!(matches!(
+                kcp1.connect().await,
+                Err(error::KcpError::LifetimeExpired)
+            ));
+        });
+    }
+
+    #[test]
+    fn bytes_retransmitted() {
+        init();
+        smol::block_on(async move {
+            let mut data = vec![0u8; 0x40000];
+            rand::thread_rng().fill_bytes(&mut data);
+            let mut retransmitted = Vec::new();
+            for loss in [0.0, 0.3].iter() {
+                let (io1, io2) = NetworkIoSimulator::new(*loss, 10);
+                let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+                let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+                let mut stream1 = kcp1.connect().await.unwrap();
+                stream1.write_all(&data).await.unwrap();
+                stream1.flush().await.unwrap();
+                let mut stream2 = kcp2.accept().await.unwrap();
+                let mut buf = vec![0u8; data.len()];
+                stream2.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, data);
+                retransmitted.push(stream1.get_stats().await.bytes_retransmitted);
+            }
+            log::info!("bytes retransmitted: {:?}", retransmitted);
+            // Every lost segment is sent again
+            assert!(retransmitted[1] >= data.len() as u64 / 10);
+            assert!(retransmitted[0] < retransmitted[1]);
+        });
+    }
+
+    struct CountingIo<T> {
+        io: T,
+        packets: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl<T: KcpIo + Send + Sync> KcpIo for CountingIo<T> {
+        async fn send_packet(&self, buf: &[u8]) -> std::io::Result<()> {
+            self.packets.fetch_add(1, Ordering::Relaxed);
+            self.io.send_packet(buf).await
+        }
+
+        async fn recv_packet(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.io.recv_packet(buf).await
+        }
+    }
+
+    #[test]
+    fn wait_idle() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            // Nothing opened yet
+            kcp1.wait_idle(None).await;
+
+            let mut streams = Vec::new();
+            for _ in 0..3 {
+                let mut stream1 = kcp1.connect().await.unwrap();
+                stream1.write_all(b"hello").await.unwrap();
+                let stream2 = kcp2.accept().await.unwrap();
+                streams.push((stream1, stream2));
+            }
+            assert_eq!(kcp1.get_stream_count().await, 3);
+
+            let grace = Duration::from_millis(100);
+            let idle = smol::spawn(async move {
+                kcp1.wait_idle(Some(grace)).await;
+                (Instant::now(), kcp1.get_stream_count().await)
+            });
+            for (mut stream1, mut stream2) in streams {
+                stream1.close().await.unwrap();
+                stream2.close().await.unwrap();
+            }
+            let closed = Instant::now();
+            let (idle_at, count) = idle.await;
+            assert_eq!(count, 0);
+            assert!(idle_at >= closed);
+            assert!(idle_at - closed < Duration::from_secs(2));
+        });
+    }
+
+    #[cfg(feature = "trace_segments")]
+    #[test]
+    fn trace_segments() {
+        use crate::core::{SegmentTrace, TraceDirection};
+        use crate::segment::{CMD_ACK, CMD_ACK_DELAY, CMD_OPEN, CMD_PUSH};
+
+        init();
+        smol::block_on(async move {
+            let traces = Arc::new(std::sync::Mutex::new(Vec::<SegmentTrace>::new()));
+            let mut config = KcpConfig::default();
+            {
+                let traces = traces.clone();
+                config.segment_tracer = Some(Arc::new(move |trace: &SegmentTrace| {
+                    traces.lock().unwrap().push(trace.clone())
+                }));
+            }
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let kcp1 = KcpHandle::new(io1, config);
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(b"hello").await.unwrap();
+            stream1.flush().await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream2.read_exact(&mut buf).await.unwrap();
+            stream1.flush_and_wait_acked().await.unwrap();
+
+            let traces = traces.lock().unwrap().clone();
+            let sent: Vec<_> = traces
+                .iter()
+                .filter(|trace| {
+                    trace.direction == TraceDirection::Sent
+                        && (trace.command == CMD_OPEN || trace.command == CMD_PUSH)
+                })
+                .map(|trace| (trace.command, trace.sequence, trace.len))
+                .collect();
+            assert_eq!(sent[0].0, CMD_OPEN);
+            assert_eq!(sent[0].1, 0);
+            assert_eq!(sent[1], (CMD_PUSH, 1, 5));
+            assert!(traces.iter().any(|trace| {
+                trace.direction == TraceDirection::Received
+                    && (trace.command == CMD_ACK || trace.command == CMD_ACK_DELAY)
+            }));
+        });
+    }
+
+    #[test]
+    fn read_until() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            // Records longer than a segment, and short ones sharing a segment
+            let records: Vec<Vec<u8>> = (0..20)
+                .map(|i| vec![b'a' + i as u8; if i % 3 == 0 { 3000 } else { i * 7 }])
+                .collect();
+            let mut data = Vec::new();
+            for record in &records {
+                data.extend_from_slice(record);
+                data.push(b'\n');
+            }
+
+            let mut stream1 = kcp1.connect().await.unwrap();
+            // Writes cut across the records
+            for chunk in data.chunks(1000) {
+                stream1.write_all(chunk).await.unwrap();
+            }
+            stream1.close().await.unwrap();
+
+            let mut stream2 = kcp2.accept().await.unwrap();
+            for record in &records {
+                let mut buf = Vec::new();
+                let len = stream2.read_until(b'\n', &mut buf).await.unwrap();
+                assert_eq!(len, record.len() + 1);
+                assert_eq!(&buf[..record.len()], &record[..]);
+            }
+            let mut line = String::new();
+            assert_eq!(stream2.read_line(&mut line).await.unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn stream_idle_timeout() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let mut config = KcpConfig::default();
+            config.stream_idle_timeout = Some(Duration::from_millis(300));
+            let kcp1 = KcpHandle::new(io1, config);
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+
+            let mut idle1 = kcp1.connect().await.unwrap();
+            idle1.write_all(b"hello").await.unwrap();
+            let mut idle2 = kcp2.accept().await.unwrap();
+            let mut busy1 = kcp1.connect().await.unwrap();
+            busy1.write_all(b"hello").await.unwrap();
+            let mut busy2 = kcp2.accept().await.unwrap();
+
+            let mut buf = [0u8; 5];
+            for _ in 0..12 {
+                busy2.read_exact(&mut buf).await.unwrap();
+                Timer::after(Duration::from_millis(50)).await;
+                busy1.write_all(b"hello").await.unwrap();
+            }
+
+            // The peer sees the idle stream end, the writer gets the reason
+            let mut rest = Vec::new();
+            idle2.read_to_end(&mut rest).await.unwrap();
+            assert_eq!(&rest, b"hello");
+            let err = idle1
+                .write_all(b"hello")
+                .await
+                .unwrap_err()
+                .into_inner()
+                .unwrap()
+                .downcast::<error::KcpError>()
+                .unwrap();
+            assert!(matches!(*err, error::KcpError::IdleTimeout));
+
+            // The busy stream on the same session goes on
+            busy2.read_exact(&mut buf).await.unwrap();
+            busy1.write_all(b"world").await.unwrap();
+            busy2.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"world");
+        });
+    }
+
+    #[test]
+    fn half_close() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(b"request").await.unwrap();
+            let (mut reader1, mut writer1) = stream1.split();
+            writer1.close().await.unwrap();
+            assert!(writer1.write_all(b"more").await.is_err());
+
+            // The peer sees EOF, but its own side stays open
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut request = Vec::new();
+            stream2.read_to_end(&mut request).await.unwrap();
+            assert_eq!(&request, b"request");
+            stream2.write_all(b"response").await.unwrap();
+            stream2.close().await.unwrap();
+
+            let mut response = Vec::new();
+            reader1.read_to_end(&mut response).await.unwrap();
+            assert_eq!(&response, b"response");
+            let stream1 = reader1.reunite(writer1).unwrap();
+            assert_eq!(stream1.get_stream_id(), stream2.get_stream_id());
+        });
+    }
+
+    #[test]
+    fn reconfigure() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 5);
+            let mut config = KcpConfig::default();
+            // 50KB per second
+            config.max_send_bps = Some(400_000);
+            let kcp1 = KcpHandle::new(io1, config.clone());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(&[0u8; 1000]).await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let _writer =
+                smol::spawn(async move { while stream1.write_all(&[0u8; 1000]).await.is_ok() {} });
+
+            async fn received(stream: &mut KcpStream) -> usize {
+                let deadline = Instant::now() + Duration::from_millis(500);
+                let mut buf = vec![0u8; 0x10000];
+                let mut total = 0;
+                while Instant::now() < deadline {
+                    let read = stream.read(&mut buf).or(async {
+                        Timer::at(deadline).await;
+                        Ok(0)
+                    });
+                    total += read.await.unwrap();
+                }
+                total
+            }
+
+            let before = received(&mut stream2).await;
+
+            let mut mtu_changed = config.clone();
+            mtu_changed.mtu = 1000;
+            mtu_changed.mss = 1000 - segment::KCP_HEADER_LEN;
+            assert!(kcp1.reconfigure(mtu_changed).await.is_err());
+            // Ten times the cap
+            config.max_send_bps = Some(4_000_000);
+            kcp1.reconfigure(config).await.unwrap();
+            assert_eq!(kcp1.config().max_send_bps, Some(4_000_000));
+
+            let after = received(&mut stream2).await;
+            log::info!("received {} then {} bytes", before, after);
+            assert!(after > before * 3);
+            assert_eq!(kcp1.get_stream_count().await, 1);
+        });
+    }
+
+    #[test]
+    fn reset_with() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(b"hello").await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream2.read_exact(&mut buf).await.unwrap();
+
+            let reason = [b'x'; async_kcp::MAX_RESET_REASON_LEN + 1];
+            let reason = std::str::from_utf8(&reason).unwrap();
+            assert!(matches!(
+                stream1.reset_with(7, reason).await,
+                Err(error::KcpError::ReasonTooLong(_))
+            ));
+            stream1.reset_with(42, "upstream refused").await.unwrap();
+
+            let err = stream2.read(&mut buf).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+            let err = err
+                .into_inner()
+                .unwrap()
+                .downcast::<error::KcpError>()
+                .unwrap();
+            match *err {
+                error::KcpError::PeerReset { code, ref reason } => {
+                    assert_eq!(code, 42);
+                    assert_eq!(reason, "upstream refused");
+                }
+                ref err => panic!("unexpected error {}", err),
+            }
+            assert!(stream1.write_all(b"hello").await.is_err());
+        });
+    }
+
+    #[test]
+    fn pause_output() {
+        init();
+        smol::block_on(async move {
+            let packets = Arc::new(AtomicUsize::new(0));
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let io1 = CountingIo {
+                io: io1,
+                packets: packets.clone(),
+            };
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(b"hello").await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream2.read_exact(&mut buf).await.unwrap();
+
+            kcp1.pause_output();
+            // Let a flush already running finish
+            Timer::after(Duration::from_millis(50)).await;
+            let sent = packets.load(Ordering::Relaxed);
+            let data = random_data();
+            stream1.write_all(&data).await.unwrap();
+            Timer::after(Duration::from_millis(300)).await;
+            assert_eq!(packets.load(Ordering::Relaxed), sent);
+
+            kcp1.resume_output();
+            let mut received = vec![0u8; data.len()];
+            stream2.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received[..], &data[..]);
+            assert!(packets.load(Ordering::Relaxed) > sent);
+        });
+    }
+
+    #[test]
+    fn session_summary() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            let payload = random_data();
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(&payload).await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = vec![0u8; payload.len()];
+            stream2.read_exact(&mut buf).await.unwrap();
+            stream1.close().await.unwrap();
+            stream2.close().await.unwrap();
+            kcp1.shutdown().await;
+            kcp2.shutdown().await;
+
+            let sent = kcp1.session_summary().await;
+            let received = kcp2.session_summary().await;
+            // The OPEN carries the features and the window shift
+            assert_eq!(sent.stats.bytes_sent, payload.len() as u64 + 2);
+            assert_eq!(received.stats.bytes_received, payload.len() as u64);
+            assert!(sent.stats.peak_rtt >= 20);
+            assert!(sent.stats.peak_congestion_window > 0);
+            assert!(sent.duration >= Duration::from_millis(20));
+            assert!(sent
+                .to_string()
+                .contains(&format!("{} bytes out", payload.len() + 2)));
+        });
+    }
+
+    /// Drops every packet larger than `max`, like a path which black-holes large packets
+    struct BlackHoleIo<T> {
+        io: T,
+        max: usize,
+    }
+
+    #[async_trait::async_trait]
+    impl<T: KcpIo + Send + Sync> KcpIo for BlackHoleIo<T> {
+        async fn send_packet(&self, buf: &[u8]) -> std::io::Result<()> {
+            if buf.len() > self.max {
+                return Ok(());
+            }
+            self.io.send_packet(buf).await
+        }
+
+        async fn recv_packet(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.io.recv_packet(buf).await
+        }
+    }
+
+    #[test]
+    fn mtu_black_hole() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let io1 = BlackHoleIo { io: io1, max: 900 };
+            let io2 = BlackHoleIo { io: io2, max: 900 };
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+
+            // The handshake is small enough to get through
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(b"hello").await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream2.read_exact(&mut buf).await.unwrap();
+
+            let payload: Vec<u8> = (0..8).flat_map(|_| random_data().to_vec()).collect();
+            stream1.write_all(&payload).await.unwrap();
+            let mut buf = vec![0u8; payload.len()];
+            stream2.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, payload);
+
+            assert!(stream1.effective_mtu().await <= 900);
+            let stats = stream1.get_stats().await;
+            assert!(stats.mtu_reductions >= 1);
+            assert_eq!(stats.bytes_sent, payload.len() as u64 + 7);
+        });
+    }
+
+    #[test]
+    fn packet_sizes() {
+        use crate::crypto::{AeadCrypto, Crypto, CryptoLayer};
+        use ring::aead;
+
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let crypto = AeadCrypto::new(b"key", &aead::CHACHA20_POLY1305);
+            let overhead = crypto.overhead();
+            let io1 = CryptoLayer::wrap(io1, crypto);
+            let io2 = CryptoLayer::wrap(io2, AeadCrypto::new(b"key", &aead::CHACHA20_POLY1305));
+            let config = KcpConfig::default();
+            assert_eq!(config.mtu, DEFAULT_MTU);
+            assert_eq!(config.mss, DEFAULT_MTU - KCP_HEADER_LEN);
+            let kcp1 = KcpHandle::new(io1, config.clone());
+            let _kcp2 = KcpHandle::new(io2, config);
+
+            // The datagrams on the wire, crypto included, fill the receive buffers exactly
+            let stream = kcp1.connect().await.unwrap();
+            assert_eq!(stream.effective_mtu().await + overhead, DEFAULT_MTU);
+            assert!(DEFAULT_MTU <= MAX_DATAGRAM);
+
+            let mut config = KcpConfig::default();
+            config.mtu = MAX_DATAGRAM + 1;
+            config.mss = MAX_DATAGRAM + 1 - KCP_HEADER_LEN;
+            assert!(config.validate().is_err());
+        });
+    }
+
+    #[test]
+    fn take_buffered() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            let data = random_data();
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(&data).await.unwrap();
+            stream1.write_all(b"tail").await.unwrap();
+            stream1.flush().await.unwrap();
+
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            stream2.peek(&mut buf).await.unwrap();
+            // Acked means delivered, but nothing is read yet
+            assert_eq!(&buf, &data[..4]);
+            let buffered = stream2.take_buffered().await;
+            assert_eq!(&buffered[..data.len()], &data[..]);
+            assert_eq!(&buffered[data.len()..], b"tail");
+            assert!(stream2.take_buffered().await.is_empty());
+        });
+    }
+
+    #[test]
+    fn stream_kinds() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            let peer_datagram = kcp2.open_datagram();
+
+            let channel = kcp1.connect_with_kind(StreamKind::Datagram).await.unwrap();
+            assert_eq!(channel.kind(), StreamKind::Datagram);
+            let datagram = channel.into_datagram().unwrap();
+            let channel = kcp1.connect_with_kind(StreamKind::default()).await.unwrap();
+            assert_eq!(channel.kind(), StreamKind::Reliable);
+            let mut stream = channel.into_stream().unwrap();
+
+            datagram.send(b"datagram").await.unwrap();
+            stream.write_all(b"stream").await.unwrap();
+            assert_eq!(&peer_datagram.recv().await.unwrap()[..], b"datagram");
+            let mut peer_stream = kcp2.accept().await.unwrap();
+            let mut buf = [0u8; 6];
+            peer_stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"stream");
+            assert_eq!(kcp2.get_stream_count().await, 1);
+        });
+    }
+
+    #[test]
+    fn reliable_only() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let mut config = KcpConfig::default();
+            config.features.remove(Features::DATAGRAM);
+            let kcp1 = KcpHandle::new(io1, config.clone());
+            let kcp2 = KcpHandle::new(io2, config);
+            assert!(matches!(
+                kcp1.connect_with_kind(StreamKind::Datagram).await,
+                Err(error::KcpError::InvalidConfig(_))
+            ));
+
+            // Datagrams sent anyway are dropped, the streams carry on
+            kcp1.open_datagram().send(b"datagram").await.unwrap();
+            let mut stream = kcp1.connect().await.unwrap();
+            stream.write_all(b"stream").await.unwrap();
+            let mut peer_stream = kcp2.accept().await.unwrap();
+            let mut buf = [0u8; 6];
+            peer_stream.read_exact(&mut buf).await.unwrap();
+            let datagram = kcp2.open_datagram();
+            assert!(smol::future::poll_once(datagram.recv()).await.is_none());
+        });
+    }
+
+    #[test]
+    fn read_to_end_timeout() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+
+            // A responder which answers half and stalls
+            let responder = smol::spawn(async move {
+                let mut stream = kcp2.accept().await.unwrap();
+                let mut request = Vec::new();
+                stream.read_to_end(&mut request).await.unwrap();
+                assert_eq!(request, b"request");
+                stream.write_all(b"partial").await.unwrap();
+                Timer::after(Duration::from_secs(1)).await;
+                stream.write_all(b" response").await.unwrap();
+                stream.close().await.unwrap();
+                kcp2
+            });
+
+            let stream = kcp1.connect().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            writer.write_all(b"request").await.unwrap();
+            writer.close().await.unwrap();
+
+            let mut response = Vec::new();
+            let err = reader
+                .read_to_end_timeout(&mut response, Duration::from_millis(300))
+                .await
+                .unwrap_err();
+            assert!(matches!(err, error::KcpError::Timeout));
+            assert_eq!(response, b"partial");
+
+            let len = reader
+                .read_to_end_timeout(&mut response, Duration::from_secs(5))
+                .await
+                .unwrap();
+            assert_eq!(len, b" response".len());
+            assert_eq!(response, b"partial response");
+            let _kcp2 = responder.await;
+        });
+    }
+
+    #[test]
+    fn single_stream() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let mut config = KcpConfig::default();
+            config.single_stream = true;
+            let kcp1 = KcpHandle::new(io1, config.clone());
+            let kcp2 = KcpHandle::new(io2, config);
+            let data = random_data();
+            let mut stream1 = kcp1.connect().await.unwrap();
+            assert!(matches!(
+                kcp1.connect().await,
+                Err(error::KcpError::TooManyStreams)
+            ));
+            stream1.write_all(&data).await.unwrap();
+            stream1.flush().await.unwrap();
+
+            let mut stream2 = kcp2.accept().await.unwrap();
+            assert_eq!(stream2.get_stream_id(), 0);
+            let mut buf = vec![0u8; data.len()];
+            stream2.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf[..], &data[..]);
+
+            // A stream config can't leave the mode of the handle
+            assert!(kcp1
+                .connect_with_config(KcpConfig::default())
+                .await
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn concurrent_connects() {
+        init();
+        smol::block_on(async move {
+            let mut config = KcpConfig::default();
+            config.single_stream = true;
+            // The race is narrow, a few rounds catch it
+            for _ in 0..20 {
+                let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+                let kcp1 = Arc::new(KcpHandle::new(io1, config.clone()));
+                let kcp2 = KcpHandle::new(io2, config.clone());
+                // Only one takes stream 0, the others fail instead of replacing its session
+                let connects: Vec<_> = (0..8)
+                    .map(|_| {
+                        let kcp1 = kcp1.clone();
+                        smol::spawn(async move { kcp1.connect().await })
+                    })
+                    .collect();
+                let mut streams = Vec::new();
+                for connect in connects {
+                    match connect.await {
+                        Ok(stream) => streams.push(stream),
+                        Err(error::KcpError::TooManyStreams) => {}
+                        Err(e) => panic!("{:?}", e),
+                    }
+                }
+                assert_eq!(streams.len(), 1);
+
+                let mut stream1 = streams.pop().unwrap();
+                stream1.write_all(b"hello").await.unwrap();
+                stream1.flush().await.unwrap();
+                let mut stream2 = kcp2.accept().await.unwrap();
+                let mut buf = [0u8; 5];
+                stream2.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+            }
+        });
+    }
+
+    #[test]
+    fn obfuscation() {
+        use crate::obfuscation::{ObfuscationLayer, XorObfuscator};
+
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let io1 = ObfuscationLayer::wrap(io1, XorObfuscator::new(b"key"));
+            let io2 = ObfuscationLayer::wrap(io2, XorObfuscator::new(b"key"));
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            let data = random_data();
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(&data).await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = vec![0u8; data.len()];
+            stream2.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf[..], &data[..]);
+
+            // The peer can't make sense of the packets
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let io1 = ObfuscationLayer::wrap(io1, XorObfuscator::new(b"key"));
+            let io2 = ObfuscationLayer::wrap(io2, XorObfuscator::new(b"other key"));
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(b"hello").await.unwrap();
+            let accepted = async { Some(kcp2.accept().await) };
+            let timeout = async {
+                Timer::after(Duration::from_millis(500)).await;
+                None
+            };
+            assert!(accepted.or(timeout).await.is_none());
+        });
+    }
+
+    #[test]
+    fn idle_sessions() {
+        init();
+        smol::block_on(async move {
+            const STREAMS: u64 = 20;
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 5);
+            let config = KcpConfig::default();
+            let kcp1 = KcpHandle::new(io1, config.clone());
+            let kcp2 = KcpHandle::new(io2, config.clone());
+            let mut streams = Vec::new();
+            for _ in 0..STREAMS {
+                let mut stream1 = kcp1.connect().await.unwrap();
+                stream1.write_all(b"hello").await.unwrap();
+                let mut stream2 = kcp2.accept().await.unwrap();
+                let mut buf = [0u8; 5];
+                stream2.read_exact(&mut buf).await.unwrap();
+                streams.push((stream1, stream2));
+            }
+            // Let the acks settle
+            Timer::after(Duration::from_millis(500)).await;
+
+            let mut before = 0;
+            for (stream, _) in &streams {
+                before += stream.get_stats().await.flushes;
+            }
+            Timer::after(Duration::from_millis(1000)).await;
+            let mut after = 0;
+            for (stream, _) in &streams {
+                after += stream.get_stats().await.flushes;
+            }
+            let idle_flushes = after - before;
+            // Ticking every max_interval would take 10 flushes a second per stream, sleeping
+            // until the keep-alive takes a couple
+            assert!(idle_flushes < STREAMS * 1000 / config.max_interval as u64 / 3);
+
+            // Writing still wakes an idle stream at once
+            let (stream1, stream2) = &mut streams[0];
+            stream1.write_all(b"world").await.unwrap();
+            let mut buf = [0u8; 5];
+            let read = async {
+                stream2.read_exact(&mut buf).await.unwrap();
+                true
+            };
+            let timeout = async {
+                Timer::after(Duration::from_millis(config.max_interval as u64)).await;
+                false
+            };
+            assert!(read.or(timeout).await);
+            assert_eq!(&buf, b"world");
+        });
+    }
+
+    #[test]
+    fn peer_auth() {
+        init();
+        smol::block_on(async move {
+            let config = |key: Option<&'static [u8]>| KcpConfig {
+                peer_auth_key: key.map(Bytes::from_static),
+                ..Default::default()
+            };
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let kcp1 = KcpHandle::new(io1, config(Some(b"password")));
+            let kcp2 = KcpHandle::new(io2, config(Some(b"password")));
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(b"hello").await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream2.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            stream2.write_all(b"world").await.unwrap();
+            stream1.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"world");
+
+            let keys: [(Option<&'static [u8]>, Option<&'static [u8]>); 3] = [
+                (Some(b"password"), Some(b"other password")),
+                (None, Some(b"password")),
+                (Some(b"password"), None),
+            ];
+            for (key1, key2) in keys.iter() {
+                let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+                let kcp1 = KcpHandle::new(io1, config(*key1));
+                let kcp2 = KcpHandle::new(io2, config(*key2));
+                let mut stream1 = kcp1.connect().await.unwrap();
+                stream1.write_all(b"hello").await.unwrap();
+                let err = stream1.read(&mut buf).await.unwrap_err();
+                assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+
+                if key2.is_some() {
+                    // Refused before the application sees it
+                    let accepted = async { Some(kcp2.accept().await) };
+                    let timeout = async {
+                        Timer::after(Duration::from_millis(500)).await;
+                        None
+                    };
+                    assert!(accepted.or(timeout).await.is_none());
+                } else {
+                    // Accepted without a key, then reset by the connecting side
+                    let mut stream2 = kcp2.accept().await.unwrap();
+                    let err = loop {
+                        match stream2.read(&mut buf).await {
+                            Ok(len) => assert!(len > 0),
+                            Err(e) => break e,
+                        }
+                    };
+                    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn connect_to() {
+        init();
+        smol::block_on(async move {
+            // One unconnected socket for all peers
+            let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mesh_addr = udp.local_addr().unwrap();
+            let mesh = KcpHandle::new(udp, KcpConfig::default());
+
+            let mut servers = Vec::new();
+            for _ in 0..2 {
+                let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                let addr = udp.local_addr().unwrap();
+                servers.push((addr, KcpHandle::new(udp, KcpConfig::default())));
+            }
+
+            let mut streams = Vec::new();
+            for (addr, server) in servers.iter() {
+                let mut stream1 = mesh.connect_to(*addr).await.unwrap();
+                let greeting = addr.to_string();
+                stream1.write_all(greeting.as_bytes()).await.unwrap();
+                let accepted = server.accept_with_metadata().await.unwrap();
+                assert_eq!(accepted.peer_addr, Some(mesh_addr));
+                let mut stream2 = accepted.stream;
+                let mut buf = vec![0u8; greeting.len()];
+                stream2.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, greeting.as_bytes());
+
+                // The answer comes back to the stream of its peer
+                stream2.write_all(b"pong").await.unwrap();
+                let mut buf = [0u8; 4];
+                stream1.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"pong");
+                streams.push((stream1, stream2));
+            }
+            assert_eq!(mesh.get_stream_count().await, 2);
+        });
+    }
+
+    #[test]
+    fn queue_stats() {
+        init();
+        smol::block_on(async move {
+            let mut config = KcpConfig::default();
+            config.send_window_size = 32;
+            config.recv_window_size = 32;
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 10);
+            let kcp1 = KcpHandle::new(io1, config.clone());
+            let kcp2 = KcpHandle::new(io2, config.clone());
+            let mut stream1 = kcp1.connect().await.unwrap();
+            let payload = vec![0u8; config.mss * 100];
+            stream1.write_all(&payload).await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+
+            // A reader too slow to read anything yet fills its window, then the sender's
+            // queue waits for the window to open
+            Timer::after(Duration::from_millis(500)).await;
+            let receiver = stream2.get_stats().await;
+            assert_eq!(receiver.recv_queue_len, 32);
+            assert_eq!(receiver.recv_queue_bytes, 32 * config.mss as u64);
+            let sender = stream1.get_stats().await;
+            assert!(sender.send_queue_len > 0);
+            assert_eq!(
+                sender.send_queue_bytes,
+                sender.send_queue_len * config.mss as u64
+            );
+            assert_eq!(kcp2.get_stats().await.recv_queue_len, 32);
+
+            let mut buf = vec![0u8; payload.len()];
+            stream2.read_exact(&mut buf).await.unwrap();
+            stream1.flush_and_wait_acked().await.unwrap();
+            assert_eq!(stream1.get_stats().await.send_queue_len, 0);
+            assert_eq!(stream2.get_stats().await.recv_queue_len, 0);
+
+            // The queues of evicted streams are gone, only their counters are kept
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(&payload).await.unwrap();
+            let _stream2 = kcp2.accept().await.unwrap();
+            Timer::after(Duration::from_millis(500)).await;
+            assert_eq!(kcp2.get_stats().await.recv_queue_len, 32);
+            assert!(kcp1.get_stats().await.send_queue_len > 0);
+            let (evicted1, evicted2) = futures::join!(
+                kcp1.close_session(None, 7, "evicted"),
+                kcp2.close_session(None, 7, "evicted")
+            );
+            assert_eq!(evicted1.unwrap(), 2);
+            assert_eq!(evicted2.unwrap(), 2);
+            let sender = kcp1.get_stats().await;
+            assert_eq!((sender.send_queue_len, sender.send_queue_bytes), (0, 0));
+            let receiver = kcp2.get_stats().await;
+            assert_eq!((receiver.recv_queue_len, receiver.recv_queue_bytes), (0, 0));
+            assert_eq!(
+                receiver.bytes_received,
+                (payload.len() + 32 * config.mss) as u64
+            );
+        });
+    }
+
+    #[test]
+    fn fallback_crypto_per_packet() {
+        use crate::crypto::{AeadCrypto, CryptoLayer, FallbackCryptoLayer};
+        use ring::aead;
+
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = get_udp_pair().await;
+            let stray = io1.clone();
+            let io1 = CryptoLayer::wrap(io1, AeadCrypto::new(b"key", &aead::AES_256_GCM));
+            let io2 =
+                FallbackCryptoLayer::wrap(io2, AeadCrypto::new(b"key", &aead::AES_256_GCM), true);
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+
+            // Taken as plaintext, but it doesn't decide the session
+            stray.send(&[0xaa; 100]).await.unwrap();
+            Timer::after(Duration::from_millis(50)).await;
+
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(b"hello").await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream2.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            // Answered encrypted
+            stream2.write_all(b"world").await.unwrap();
+            stream1.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"world");
+        });
+    }
+
+    #[test]
+    fn fallback_decrypt_failure_threshold() {
+        use crate::crypto::{AeadCrypto, CryptoLayer, FallbackCryptoLayer};
+        use ring::aead;
+
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = get_udp_pair().await;
+            let garbage = io1.clone();
+            let io1 = CryptoLayer::wrap(io1, AeadCrypto::new(b"key", &aead::AES_256_GCM));
+            let io2 =
+                FallbackCryptoLayer::wrap(io2, AeadCrypto::new(b"key", &aead::AES_256_GCM), true)
+                    .with_failure_threshold(16);
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(b"hello").await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream2.read_exact(&mut buf).await.unwrap();
+
+            // Authenticated, so they're dropped rather than taken as plaintext
+            let flood = smol::spawn(async move {
+                for _ in 0..1000 {
+                    if garbage.send(&[0xaa; 100]).await.is_err() {
+                        break;
+                    }
+                    Timer::after(Duration::from_millis(1)).await;
+                }
+            });
+            let err = stream2.read(&mut buf).await.unwrap_err();
+            match error::KcpError::from(err) {
+                error::KcpError::DecryptFailureThreshold(failures) => assert!(failures >= 16),
+                err => panic!("unexpected error {:?}", err),
+            }
+            flood.cancel().await;
+        });
+    }
+
+    #[test]
+    fn decrypt_failure_threshold() {
+        use crate::crypto::{AeadCrypto, CryptoLayer};
+        use ring::aead;
+
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = get_udp_pair().await;
+            let garbage = io1.clone();
+            let io1 = CryptoLayer::wrap(io1, AeadCrypto::new(b"key", &aead::AES_256_GCM));
+            let io2 = CryptoLayer::wrap(io2, AeadCrypto::new(b"key", &aead::AES_256_GCM))
+                .with_failure_threshold(16);
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(b"hello").await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream2.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            // A few undecryptable packets are only dropped
+            for _ in 0..8 {
+                garbage.send(&[0xaa; 100]).await.unwrap();
+            }
+            stream1.write_all(b"world").await.unwrap();
+            stream2.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"world");
+
+            // A long run of them closes the session
+            let flood = smol::spawn(async move {
+                for _ in 0..1000 {
+                    if garbage.send(&[0xaa; 100]).await.is_err() {
+                        break;
+                    }
+                    Timer::after(Duration::from_millis(1)).await;
+                }
+            });
+            let err = stream2.read(&mut buf).await.unwrap_err();
+            match error::KcpError::from(err) {
+                error::KcpError::DecryptFailureThreshold(failures) => assert!(failures >= 16),
+                err => panic!("unexpected error {:?}", err),
+            }
+            flood.cancel().await;
+        });
+    }
+
+    #[test]
+    fn reset_one_of_many() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.1, 10);
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            let mut data = vec![0u8; 0x40000];
+            rand::thread_rng().fill_bytes(&mut data);
+            let data = Arc::new(data);
+
+            let mut tasks = Vec::new();
+            for i in 0..3u8 {
+                let mut stream1 = kcp1.connect().await.unwrap();
+                stream1.write_all(&[i]).await.unwrap();
+                let mut stream2 = kcp2.accept().await.unwrap();
+                let mut tag = [0u8; 1];
+                stream2.read_exact(&mut tag).await.unwrap();
+                assert_eq!(tag[0], i);
+
+                let data1 = data.clone();
+                let data2 = data.clone();
+                let abort = i == 1;
+                tasks.push(smol::spawn(async move {
+                    if abort {
+                        stream1.write_all(&data1[..data1.len() / 2]).await.unwrap();
+                        stream1.reset_with(1, "abort").await.unwrap();
+                    } else {
+                        stream1.write_all(&data1).await.unwrap();
+                        stream1.close().await.unwrap();
+                    }
+                }));
+                tasks.push(smol::spawn(async move {
+                    let mut buf = Vec::new();
+                    if abort {
+                        let err = stream2.read_to_end(&mut buf).await.unwrap_err();
+                        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+                        assert!(buf.len() < data2.len());
+                    } else {
+                        stream2.read_to_end(&mut buf).await.unwrap();
+                        assert_eq!(&buf[..], &data2[..]);
+                    }
+                }));
+            }
+            futures::future::join_all(tasks).await;
+        });
+    }
+
+    /// Keeps a copy of every packet sent
+    struct RecordingIo<T> {
+        io: T,
+        packets: Arc<std::sync::Mutex<Vec<Vec<u8>>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl<T: KcpIo + Send + Sync> KcpIo for RecordingIo<T> {
+        async fn send_packet(&self, buf: &[u8]) -> std::io::Result<()> {
+            self.packets.lock().unwrap().push(buf.to_vec());
+            self.io.send_packet(buf).await
+        }
+
+        async fn recv_packet(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.io.recv_packet(buf).await
+        }
+    }
+
+    #[test]
+    fn per_stream_keys() {
+        use crate::crypto::{AeadCrypto, Crypto, CryptoLayer};
+        use ring::aead;
+
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 5);
+            let packets = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let io1 = RecordingIo {
+                io: io1,
+                packets: packets.clone(),
+            };
+            let io1 = CryptoLayer::wrap(io1, AeadCrypto::new(b"key", &aead::AES_256_GCM));
+            let io2 = CryptoLayer::wrap(io2, AeadCrypto::new(b"key", &aead::AES_256_GCM));
+            let config = KcpConfig {
+                per_stream_keys: true,
+                ..Default::default()
+            };
+            let kcp1 = KcpHandle::new(io1, config.clone());
+            let kcp2 = KcpHandle::new(io2, config);
+
+            let data = random_data();
+            let mut stream_ids = Vec::new();
+            for _ in 0..2 {
+                let mut stream1 = kcp1.connect().await.unwrap();
+                stream1.write_all(&data).await.unwrap();
+                stream1.flush().await.unwrap();
+                let mut stream2 = kcp2.accept().await.unwrap();
+                let mut buf = vec![0u8; data.len()];
+                stream2.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf[..], &data[..]);
+                stream_ids.push(stream1.get_stream_id());
+            }
+
+            let crypto = AeadCrypto::new(b"key", &aead::AES_256_GCM);
+            crypto.set_per_stream_keys(true);
+            let packets = packets.lock().unwrap();
+            for (i, &stream_id) in stream_ids.iter().enumerate() {
+                let other = stream_ids[1 - i];
+                let sent: Vec<_> = packets
+                    .iter()
+                    .filter(|packet| packet[..2] == stream_id.to_le_bytes())
+                    .collect();
+                assert!(!sent.is_empty());
+                for packet in sent {
+                    let mut buf = packet.clone();
+                    assert!(crypto.decrypt(&mut buf) > 0);
+                    // The key of the other stream doesn't open it
+                    let mut buf = packet.clone();
+                    buf[..2].copy_from_slice(&other.to_le_bytes());
+                    assert_eq!(crypto.decrypt(&mut buf), 0);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn per_stream_keys_compression() {
+        use crate::compression::{Codec, CompressionLayer};
+        use crate::crypto::{AeadCrypto, CryptoLayer};
+        use ring::aead;
+
+        init();
+        smol::block_on(async move {
+            let wrap = |io, codec| {
+                let crypto = AeadCrypto::new(b"key", &aead::AES_256_GCM);
+                CompressionLayer::wrap(CryptoLayer::wrap(io, crypto), codec)
+            };
+            // A codec header and compressed data would stand where the stream id is read
+            for codec in [Codec::Deflate, Codec::Zstd].iter() {
+                let (io, _) = NetworkIoSimulator::new(0.0, 5);
+                assert!(!wrap(io, *codec).set_per_stream_keys(true));
+            }
+
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 5);
+            let config = KcpConfig {
+                per_stream_keys: true,
+                ..Default::default()
+            };
+            let kcp1 = KcpHandle::new(wrap(io1, Codec::None), config.clone());
+            let kcp2 = KcpHandle::new(wrap(io2, Codec::None), config);
+            let data = random_data();
+            for _ in 0..2 {
+                let mut stream1 = kcp1.connect().await.unwrap();
+                stream1.write_all(&data).await.unwrap();
+                let mut stream2 = kcp2.accept().await.unwrap();
+                let mut buf = vec![0u8; data.len()];
+                stream2.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf[..], &data[..]);
+            }
+        });
+    }
+
+    #[test]
+    fn handshake_rtt() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 50);
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(b"hello").await.unwrap();
+            // Everything acked, the OPEN included
+            stream1.flush().await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream2.read_exact(&mut buf).await.unwrap();
+
+            let stats = stream1.get_stats().await;
+            log::info!("handshake rtt {}, srtt {}", stats.handshake_rtt, stats.srtt);
+            // Twice the one-way delay, plus the wait of the peer before acking
+            assert!(stats.handshake_rtt >= 100);
+            assert!(stats.handshake_rtt < 100 + KcpConfig::default().max_interval * 2);
+            assert!(stats.srtt >= 100);
+        });
+    }
+
+    #[test]
+    fn unknown_stream() {
+        use crate::segment::{KcpSegment, CMD_PUSH};
+
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = get_udp_pair().await;
+            let stray = io1.clone();
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let config = KcpConfig {
+                reset_unknown_streams: true,
+                ..Default::default()
+            };
+            let kcp2 = KcpHandle::new(io2, config);
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(b"hello").await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream2.read_exact(&mut buf).await.unwrap();
+
+            // A late retransmit of a stream long gone
+            let segment = KcpSegment {
+                stream_id: stream1.get_stream_id().wrapping_add(0x100),
+                command: CMD_PUSH,
+                recv_window_size: 0x100,
+                timestamp: 0,
+                sequence: 42,
+                recv_next: 0,
+                data: Bytes::from_static(b"stale"),
+            };
+            let mut packet = bytes::BytesMut::new();
+            segment.encode_framed(&mut packet, false);
+            stray.send(&packet).await.unwrap();
+
+            stream1.write_all(b"world").await.unwrap();
+            stream2.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"world");
+            Timer::after(Duration::from_millis(100)).await;
+            assert_eq!(kcp2.get_stats().await.unknown_stream_segments, 1);
+            // The reset came back to the sender, which knows no such stream either, and
+            // never answers a reset
+            assert_eq!(kcp1.get_stats().await.unknown_stream_segments, 1);
+
+            // The existing stream is undisturbed
+            stream2.write_all(b"again").await.unwrap();
+            stream1.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"again");
+        });
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_spans() {
+        use std::{cell::RefCell, collections::HashSet, sync::Mutex};
+        use tracing::{
+            field::{Field, Visit},
+            span, Event, Metadata, Subscriber,
+        };
+        use tracing_core::span::Current;
+
+        thread_local! {
+            // The spans entered on this thread, for `Span::current`
+            static ENTERED: RefCell<Vec<span::Id>> = RefCell::new(Vec::new());
+        }
+
+        #[derive(Default)]
+        struct SpanFields(Vec<(&'static str, String)>);
+
+        impl Visit for SpanFields {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                self.0.push((field.name(), format!("{:?}", value)));
+            }
+        }
+
+        struct RecordedSpan {
+            metadata: &'static Metadata<'static>,
+            name: &'static str,
+            parent: Option<u64>,
+            fields: SpanFields,
+        }
+
+        /// Keeps every span, none is ever closed
+        #[derive(Clone, Default)]
+        struct SpanRecorder {
+            spans: Arc<Mutex<Vec<RecordedSpan>>>,
+        }
+
+        impl Subscriber for SpanRecorder {
+            fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, attrs: &span::Attributes<'_>) -> span::Id {
+                let mut fields = SpanFields::default();
+                attrs.record(&mut fields);
+                let mut spans = self.spans.lock().unwrap();
+                spans.push(RecordedSpan {
+                    metadata: attrs.metadata(),
+                    name: attrs.metadata().name(),
+                    parent: attrs.parent().map(|parent| parent.into_u64()),
+                    fields,
+                });
+                span::Id::from_u64(spans.len() as u64)
+            }
+
+            fn record(&self, id: &span::Id, values: &span::Record<'_>) {
+                let mut spans = self.spans.lock().unwrap();
+                values.record(&mut spans[id.into_u64() as usize - 1].fields);
+            }
+
+            fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+            fn event(&self, _event: &Event<'_>) {}
+
+            fn enter(&self, span: &span::Id) {
+                ENTERED.with(|entered| entered.borrow_mut().push(span.clone()));
+            }
+
+            fn exit(&self, _span: &span::Id) {
+                ENTERED.with(|entered| entered.borrow_mut().pop());
+            }
+
+            fn current_span(&self) -> Current {
+                match ENTERED.with(|entered| entered.borrow().last().cloned()) {
+                    Some(id) => {
+                        let metadata =
+                            self.spans.lock().unwrap()[id.into_u64() as usize - 1].metadata;
+                        Current::new(id, metadata)
+                    }
+                    None => Current::none(),
+                }
+            }
+        }
+
+        init();
+        let recorder = SpanRecorder::default();
+        tracing::subscriber::set_global_default(recorder.clone()).unwrap();
+        let stream_id = smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 5);
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+            let data = random_data();
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(&data).await.unwrap();
+            stream1.flush().await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = vec![0u8; data.len()];
+            stream2.read_exact(&mut buf).await.unwrap();
+            stream1.get_stream_id()
+        });
+
+        let spans = recorder.spans.lock().unwrap();
+        let sessions: Vec<_> = (1..=spans.len() as u64)
+            .filter(|&id| spans[id as usize - 1].name == "session")
+            .collect();
+        assert!(sessions.len() >= 2);
+        let conn_id = |span: &RecordedSpan| {
+            span.fields
+                .0
+                .iter()
+                .find(|(name, _)| *name == "conn_id")
+                .map(|(_, value)| value.clone())
+                .unwrap()
+        };
+        // Told apart even without a peer address
+        let conn_ids: HashSet<_> = sessions
+            .iter()
+            .map(|&id| conn_id(&spans[id as usize - 1]))
+            .collect();
+        assert_eq!(conn_ids.len(), sessions.len());
+        // Both ends of the stream, each inside the session of its handle
+        let streams: Vec<_> = spans
+            .iter()
+            .filter(|span| span.name == "stream")
+            .filter(|span| {
+                span.fields
+                    .0
+                    .contains(&("stream_id", stream_id.to_string()))
+            })
+            .collect();
+        assert!(streams.len() >= 2);
+        for stream in streams {
+            let session = stream.parent.unwrap();
+            assert!(sessions.contains(&session));
+            assert_eq!(conn_id(stream), conn_id(&spans[session as usize - 1]));
+            assert!(stream.fields.0.iter().any(|(name, _)| *name == "send_next"));
+        }
+    }
+
+    /// Drops the first `drop` packets sent
+    struct DropFirstIo<T> {
+        io: T,
+        drop: usize,
+        sent: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl<T: KcpIo + Send + Sync> KcpIo for DropFirstIo<T> {
+        async fn send_packet(&self, buf: &[u8]) -> std::io::Result<()> {
+            if self.sent.fetch_add(1, Ordering::Relaxed) < self.drop {
+                return Ok(());
+            }
+            self.io.send_packet(buf).await
+        }
+
+        async fn recv_packet(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.io.recv_packet(buf).await
+        }
+    }
+
+    #[test]
+    fn handshake_retries() {
+        init();
+        smol::block_on(async move {
+            let config = KcpConfig {
+                handshake_interval: 50,
+                handshake_retries: 4,
+                ..Default::default()
+            };
+
+            // The OPEN goes through on its last retry
+            let (io1, io2) = NetworkIoSimulator::new(0.0, 5);
+            let io1 = DropFirstIo {
+                io: io1,
+                drop: 4,
+                sent: AtomicUsize::new(0),
+            };
+            let kcp1 = KcpHandle::new(io1, config.clone());
+            let kcp2 = KcpHandle::new(io2, config.clone());
+            let start = Instant::now();
+            // Nothing else is sent before the OPEN is acked
+            let mut stream1 = kcp1.connect().await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+            // At the pace of the handshake, not of the backed off RTO
+            assert!(start.elapsed() >= Duration::from_millis(200));
+            assert!(start.elapsed() < Duration::from_millis(1000));
+            stream1.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            stream2.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            // One packet more and the handshake gives up
+            let (io1, _io2) = NetworkIoSimulator::new(0.0, 5);
+            let io1 = DropFirstIo {
+                io: io1,
+                drop: 5,
+                sent: AtomicUsize::new(0),
+            };
+            let kcp1 = KcpHandle::new(io1, config);
+            let mut stream1 = kcp1.connect().await.unwrap();
+            let err = stream1.read(&mut buf).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+            assert!(matches!(
+                error::KcpError::from(err),
+                error::KcpError::HandshakeTimeout
+            ));
+        });
+    }
+
+    #[test]
+    fn forged_handshake() {
+        use crate::crypto::{AeadCrypto, Crypto, CryptoLayer};
+        use crate::segment::{KcpSegment, CMD_OPEN};
+        use ring::aead;
+
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = get_udp_pair().await;
+            let attacker = io1.clone();
+            let crypto = AeadCrypto::new(b"key", &aead::AES_256_GCM);
+            let overhead = crypto.overhead();
+            let io2 = CryptoLayer::wrap(io2, crypto);
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+
+            // An OPEN in the clear, then the same with a made up tag and nonce
+            let segment = KcpSegment {
+                stream_id: 0x4242,
+                command: CMD_OPEN,
+                recv_window_size: 0x100,
+                timestamp: 0,
+                sequence: 0,
+                recv_next: 0,
+                data: Bytes::from_static(&[0]),
+            };
+            let mut packet = bytes::BytesMut::new();
+            segment.encode_framed(&mut packet, false);
+            attacker.send(&packet).await.unwrap();
+            let mut sealed = packet.to_vec();
+            sealed.extend((0..overhead).map(|_| rand::random::<u8>()));
+            attacker.send(&sealed).await.unwrap();
+
+            Timer::after(Duration::from_millis(300)).await;
+            assert_eq!(kcp2.get_stream_count().await, 0);
+
+            // The same socket with the key gets through
+            let io1 = CryptoLayer::wrap(io1, AeadCrypto::new(b"key", &aead::AES_256_GCM));
+            let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(b"hello").await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream2.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            assert_eq!(kcp2.get_stream_count().await, 1);
+        });
+    }
+
+    #[test]
+    fn boxed_io() {
+        use crate::crypto::{AeadCrypto, CryptoLayer};
+        use ring::aead;
+
+        type BoxedIo = Box<dyn KcpIo + Send + Sync>;
+
+        async fn transport(kind: &str) -> (BoxedIo, BoxedIo) {
+            match kind {
+                "udp" => {
+                    let (io1, io2) = get_udp_pair().await;
+                    (Box::new(io1), Box::new(io2))
+                }
+                _ => {
+                    let (io1, io2) = NetworkIoSimulator::new(0.1, 10);
+                    (Box::new(io1), Box::new(io2))
+                }
+            }
+        }
+
+        init();
+        smol::block_on(async move {
+            for kind in &["udp", "memory"] {
+                let (io1, io2) = transport(kind).await;
+                let io1 = CryptoLayer::wrap(io1, AeadCrypto::new(b"key", &aead::AES_256_GCM));
+                let io2 = CryptoLayer::wrap(io2, AeadCrypto::new(b"key", &aead::AES_256_GCM));
+                let kcp1 = KcpHandle::new(io1, KcpConfig::default());
+                let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+
+                let data = random_data();
+                let mut stream1 = kcp1.connect().await.unwrap();
+                stream1.write_all(&data).await.unwrap();
+                let mut stream2 = kcp2.accept().await.unwrap();
+                let mut buf = vec![0u8; data.len()];
+                stream2.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf[..], &data[..]);
+            }
+        });
+    }
+
+    #[test]
+    fn initial_sequence() {
+        init();
+        smol::block_on(async move {
+            let (io1, io2) = NetworkIoSimulator::new(0.1, 10);
+            let mut config = KcpConfig::default();
+            // The OPEN and a few segments before the wrap, most of the data after it
+            config.initial_sequence = u32::MAX - 0x10;
+            let kcp1 = KcpHandle::new(io1, config.clone());
+            let kcp2 = KcpHandle::new(io2, config.clone());
+            assert!(kcp1
+                .connect_with_config(KcpConfig::default())
+                .await
+                .is_err());
+
+            let mut data = vec![0u8; 0x100 * config.mss];
+            rand::thread_rng().fill_bytes(&mut data);
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(&data).await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = vec![0u8; data.len()];
+            stream2.read_exact(&mut buf).await.unwrap();
+            assert!(buf == data);
+
+            // And back, the other direction wraps on its own
+            stream2.write_all(&data).await.unwrap();
+            stream1.read_exact(&mut buf).await.unwrap();
+            assert!(buf == data);
+            stream1.close().await.unwrap();
+            stream2.close().await.unwrap();
+        });
+    }
 }
@@ -1,111 +1,208 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use bytes::Bytes;
-use clap::{App, Arg};
-use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use clap::{App, Arg, ArgMatches};
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Future};
 use log::LevelFilter;
 use ring::aead;
 use smol::{
     channel::bounded,
     channel::Receiver,
-    channel::Sender,
     future::FutureExt,
+    lock::Mutex,
     net::{TcpListener, TcpStream, UdpSocket},
-    Task,
+    Task, Timer,
 };
 
 mod async_kcp;
+mod compression;
 mod core;
 mod crypto;
 mod error;
+mod metrics;
 mod segment;
+mod socket;
+mod spans;
+mod spsc;
+mod upstream;
 
 use crate::{
-    async_kcp::KcpHandle,
-    core::{KcpConfig, KcpIo},
-    crypto::{AeadCrypto, Crypto, CryptoLayer},
+    async_kcp::{KcpHandle, KcpStream, StreamParameters, MAX_RESET_REASON_LEN},
+    compression::{Codec, CompressionLayer},
+    core::{KcpConfig, KcpIo, MAX_DATAGRAM, MAX_WINDOW_SHIFT},
+    crypto::{AeadCrypto, Crypto, CryptoLayer, FallbackCryptoLayer},
     error::KcpResult,
+    metrics::Metrics,
+    socket::{bind_udp, recv_from_ecn, send_retrying, UdpOptions},
+    spsc::TrySendError,
+    upstream::{connect_tcp, Balance, UpstreamGuard, UpstreamPool},
 };
 
 #[async_trait::async_trait]
 impl KcpIo for smol::net::UdpSocket {
     async fn send_packet(&self, buf: &[u8]) -> std::io::Result<()> {
-        self.send(buf).await?;
-        Ok(())
+        send_retrying(|| async {
+            self.send(buf).await?;
+            Ok(())
+        })
+        .await
     }
 
     async fn recv_packet(&self, buf: &mut [u8]) -> std::io::Result<usize> {
         let size = self.recv(buf).await?;
         Ok(size)
     }
+
+    async fn recv_packet_ecn(&self, buf: &mut [u8]) -> std::io::Result<(usize, bool)> {
+        let (size, _, ce) = recv_from_ecn(self, buf).await?;
+        Ok((size, ce))
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        UdpSocket::peer_addr(self).ok()
+    }
 }
 
 struct UdpListener {
     accept_rx: Receiver<UdpSession>,
+    sessions: Arc<Mutex<HashMap<SocketAddr, spsc::Sender<(Bytes, bool)>>>>,
+    accepting: Arc<AtomicBool>,
     _task: Task<KcpResult<()>>,
 }
 
 impl UdpListener {
-    async fn accept(&self) -> UdpSession {
-        self.accept_rx.recv().await.unwrap()
+    /// None if the listener is shut down
+    async fn accept(&self) -> Option<UdpSession> {
+        self.accept_rx.recv().await.ok()
     }
 
-    fn new(udp: UdpSocket) -> Self {
+    /// Drop the packets of new peers, the sessions already accepted go on
+    fn stop_accepting(&self) {
+        self.accepting.store(false, Ordering::Release);
+    }
+
+    /// Stop accepting sessions and close all of them
+    async fn shutdown(&self) {
+        self.accept_rx.close();
+        for (_, tx) in self.sessions.lock().await.drain() {
+            tx.close();
+        }
+    }
+
+    /// Close the session of `addr`, its KcpHandle stops receiving packets.
+    /// New packets from `addr` start a new session.
+    async fn evict(&self, addr: &SocketAddr) -> bool {
+        match self.sessions.lock().await.remove(addr) {
+            Some(tx) => {
+                tx.close();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// With `ecn`, the CE marks of the packets are passed to the sessions
+    fn new(udp: UdpSocket, ecn: bool) -> Self {
         let udp = Arc::new(udp);
         let (accept_tx, accept_rx) = bounded(0x10);
+        let sessions = Arc::new(Mutex::new(
+            HashMap::<SocketAddr, spsc::Sender<(Bytes, bool)>>::new(),
+        ));
+        let accepting = Arc::new(AtomicBool::new(true));
         let _task = {
-            let mut sessions = HashMap::<String, Sender<Bytes>>::new();
+            let sessions = sessions.clone();
+            let accepting = accepting.clone();
             let udp = udp.clone();
             smol::spawn(async move {
+                let mut buf = Vec::new();
+                buf.resize(MAX_DATAGRAM, 0u8);
                 loop {
-                    let mut buf = Vec::new();
-                    buf.resize(0x1000, 0u8);
-                    let (size, addr) = udp.recv_from(&mut buf).await?;
-                    let payload = Bytes::copy_from_slice(&buf[..size]);
-                    if let Some(tx) = sessions.get(&addr.to_string()) {
-                        tx.send(payload).await.unwrap();
+                    let (size, addr, ce) = if ecn {
+                        recv_from_ecn(&udp, &mut buf).await?
                     } else {
-                        let (tx, rx) = bounded(0x100);
-                        sessions.insert(addr.to_string(), tx.clone());
-                        let session = UdpSession {
-                            udp: udp.clone(),
-                            rx,
-                            remote: addr,
-                        };
-                        accept_tx.send(session).await.unwrap();
-                        tx.send(payload).await.unwrap();
-                        sessions.retain(|_, tx| !tx.is_closed());
+                        let (size, addr) = udp.recv_from(&mut buf).await?;
+                        (size, addr, false)
+                    };
+                    let payload = (Bytes::copy_from_slice(&buf[..size]), ce);
+                    let mut sessions = sessions.lock().await;
+                    let payload = match sessions.get_mut(&addr) {
+                        Some(tx) => match tx.try_send(payload) {
+                            Ok(()) => continue,
+                            Err(TrySendError::Full(_)) => {
+                                // Like a full socket buffer, KCP retransmits it
+                                log::trace!("session queue of {} is full, dropping", addr);
+                                continue;
+                            }
+                            // The session is gone, start a new one
+                            Err(TrySendError::Closed(payload)) => payload,
+                        },
+                        None => payload,
+                    };
+                    if !accepting.load(Ordering::Acquire) {
+                        log::trace!("draining, dropping the packet of {}", addr);
+                        continue;
+                    }
+                    let (mut tx, rx) = spsc::bounded(0x100);
+                    let _ = tx.try_send(payload);
+                    sessions.retain(|_, tx| !tx.is_closed());
+                    sessions.insert(addr, tx);
+                    drop(sessions);
+
+                    let session = UdpSession {
+                        udp: udp.clone(),
+                        rx: Mutex::new(rx),
+                        remote: addr,
+                    };
+                    if accept_tx.send(session).await.is_err() {
+                        log::info!("udp listener shut down");
+                        return Ok(());
                     }
                 }
             })
         };
-        Self { _task, accept_rx }
+        Self {
+            _task,
+            sessions,
+            accepting,
+            accept_rx,
+        }
     }
 }
 
 struct UdpSession {
     remote: SocketAddr,
-    rx: Receiver<Bytes>,
+    // Only the feed task of the handle receives, the lock is never contended
+    rx: Mutex<spsc::Receiver<(Bytes, bool)>>,
     udp: Arc<UdpSocket>,
 }
 
-impl Drop for UdpSession {
-    fn drop(&mut self) {
-        self.rx.close();
-    }
-}
-
 #[async_trait::async_trait]
 impl core::KcpIo for UdpSession {
     async fn send_packet(&self, buf: &[u8]) -> std::io::Result<()> {
-        self.udp.send_to(buf, self.remote).await?;
-        Ok(())
+        send_retrying(|| async {
+            self.udp.send_to(buf, self.remote).await?;
+            Ok(())
+        })
+        .await
     }
 
     async fn recv_packet(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let (len, _) = self.recv_packet_ecn(buf).await?;
+        Ok(len)
+    }
+
+    async fn recv_packet_ecn(&self, buf: &mut [u8]) -> std::io::Result<(usize, bool)> {
+        let mut rx = self.rx.lock().await;
         loop {
-            let payload = self
-                .rx
+            let (payload, ce) = rx
                 .recv()
                 .await
                 .map_err(|_| std::io::ErrorKind::ConnectionReset)?;
@@ -115,90 +212,341 @@ impl core::KcpIo for UdpSession {
             }
             let len = payload.len();
             buf[..len].copy_from_slice(&payload);
-            return Ok(len);
+            return Ok((len, ce));
         }
     }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        Some(self.remote)
+    }
+}
+
+/// Which side of a relay failed
+enum RelayError {
+    Read(std::io::Error),
+    Write(std::io::Error),
 }
 
 async fn relay<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
     reader: &mut R,
     writer: &mut W,
-) -> std::io::Result<()> {
+) -> Result<(), RelayError> {
     let mut buf = Vec::new();
     buf.resize(0x1000, 0u8);
     loop {
-        let len = reader.read(&mut buf).await?;
+        let len = reader.read(&mut buf).await.map_err(RelayError::Read)?;
         if len == 0 {
             return Ok(());
         }
-        writer.write_all(&buf[..len]).await?;
+        writer
+            .write_all(&buf[..len])
+            .await
+            .map_err(RelayError::Write)?;
+    }
+}
+
+/// The reset code of the streams whose TCP side failed, the reason tells how
+const TCP_ERROR_RESET_CODE: u32 = 2;
+
+fn tcp_error_reason(side: &str, err: &std::io::Error) -> String {
+    let mut reason = match err.kind() {
+        std::io::ErrorKind::ConnectionReset => format!("{} reset", side),
+        std::io::ErrorKind::ConnectionRefused => format!("{} refused", side),
+        std::io::ErrorKind::TimedOut => format!("{} timed out", side),
+        _ => format!("{} error: {}", side, err),
+    };
+    if reason.len() > MAX_RESET_REASON_LEN {
+        let mut len = MAX_RESET_REASON_LEN;
+        while !reason.is_char_boundary(len) {
+            len -= 1;
+        }
+        reason.truncate(len);
+    }
+    reason
+}
+
+/// Relays both ways until either ends. A failing TCP side resets the KCP stream, so that
+/// the peer learns why, otherwise it's closed. The halves are reunited first, so the stream
+/// is closed as a whole, never only half-closed by dropping its write half.
+async fn relay_stream(tcp_stream: TcpStream, kcp_stream: KcpStream, side: &str) -> KcpResult<()> {
+    let mut tcp_reader = tcp_stream;
+    let mut tcp_writer = tcp_reader.clone();
+    let (mut kcp_reader, mut kcp_writer) = kcp_stream.split();
+    let t1 = async {
+        match relay(&mut tcp_reader, &mut kcp_writer).await {
+            Err(RelayError::Read(e)) => Some(e),
+            _ => None,
+        }
+    };
+    let t2 = async {
+        match relay(&mut kcp_reader, &mut tcp_writer).await {
+            Err(RelayError::Write(e)) => Some(e),
+            _ => None,
+        }
+    };
+    let tcp_error = t1.race(t2).await;
+    let mut kcp_stream = kcp_reader.reunite(kcp_writer).unwrap();
+    match tcp_error {
+        Some(e) => {
+            log::warn!("{} failed, resetting the stream: {}", side, e);
+            kcp_stream
+                .reset_with(TCP_ERROR_RESET_CODE, &tcp_error_reason(side, &e))
+                .await?;
+        }
+        None => {
+            kcp_stream.close().await?;
+            tcp_writer.close().await?;
+        }
+    }
+    Ok(())
+}
+
+/// Receives once on SIGINT or SIGTERM
+fn shutdown_signal() -> Receiver<()> {
+    let (tx, rx) = bounded(1);
+    if let Err(e) = ctrlc::set_handler(move || {
+        let _ = tx.try_send(());
+    }) {
+        log::error!("failed to set signal handler: {}", e);
+    }
+    rx
+}
+
+/// None if the shutdown signal comes first. A closed signal channel also means shutdown.
+async fn until_shutdown<T>(future: impl Future<Output = T>, shutdown: &Receiver<()>) -> Option<T> {
+    async { Some(future.await) }
+        .race(async {
+            let _ = shutdown.recv().await;
+            None
+        })
+        .await
+}
+
+/// Upstream targets of the server, picked by the label of each stream
+struct Routes {
+    default: Arc<UpstreamPool>,
+    labeled: HashMap<Vec<u8>, String>,
+    /// Bounds every connection attempt, see `--connect-timeout`
+    connect_timeout: Option<Duration>,
+}
+
+impl Routes {
+    fn new(default: String) -> Self {
+        Self::with_upstreams(UpstreamPool::single(default))
+    }
+
+    /// Balance the streams without a labeled route across several targets
+    fn with_upstreams(default: UpstreamPool) -> Self {
+        Self {
+            default: Arc::new(default),
+            labeled: HashMap::new(),
+            connect_timeout: None,
+        }
+    }
+
+    fn insert(&mut self, label: &[u8], target: String) {
+        self.labeled.insert(label.to_vec(), target);
+    }
+
+    /// Connects the target of a stream, the guard counts it against a pooled upstream
+    async fn connect(
+        &self,
+        label: &[u8],
+    ) -> std::io::Result<(TcpStream, String, Option<UpstreamGuard>)> {
+        match self.labeled.get(label) {
+            Some(target) => {
+                let tcp_stream = connect_tcp(target, self.connect_timeout).await?;
+                Ok((tcp_stream, target.clone(), None))
+            }
+            None => {
+                let (tcp_stream, guard) = self.default.connect(self.connect_timeout).await?;
+                Ok((tcp_stream, guard.addr().to_string(), Some(guard)))
+            }
+        }
+    }
+}
+
+/// What `--log-session` reports of every new stream
+#[derive(Clone)]
+struct SessionLog {
+    algorithm: String,
+    codec: Codec,
+}
+
+impl SessionLog {
+    /// The features are only negotiated once the peer's OPEN has arrived
+    fn summary(&self, parameters: &StreamParameters) -> String {
+        format!(
+            "stream {}: mtu {}, send window {}, peer window {}, algorithm {}, compression {:?}, features {:?}",
+            parameters.stream_id,
+            parameters.mtu,
+            parameters.send_window,
+            parameters.peer_window,
+            self.algorithm,
+            self.codec,
+            parameters.features,
+        )
     }
 }
 
 async fn client<T: crate::core::KcpIo + Send + Sync + 'static>(
     listener: TcpListener,
-    kcp: KcpHandle<T>,
+    kcp: Arc<KcpHandle<T>>,
+    label: Vec<u8>,
+    session_log: Option<SessionLog>,
+    shutdown: Receiver<()>,
 ) -> std::io::Result<()> {
     loop {
-        let (tcp_stream, _) = listener.accept().await?;
+        let (tcp_stream, _) = match until_shutdown(listener.accept(), &shutdown).await {
+            Some(accepted) => accepted?,
+            None => break,
+        };
         log::info!("tcp accepted");
-        let kcp_stream = kcp.connect().await?;
+        let kcp_stream = kcp.connect_with_label(&label).await?;
         log::info!("kcp connected");
+        let session_log = session_log.clone();
         let t: Task<KcpResult<()>> = smol::spawn(async move {
-            let mut tcp_reader = tcp_stream;
-            let mut tcp_writer = tcp_reader.clone();
-            let (mut kcp_reader, mut kcp_writer) = kcp_stream.split();
-            let t1 = relay(&mut tcp_reader, &mut kcp_writer);
-            let t2 = relay(&mut kcp_reader, &mut tcp_writer);
-            let _ = t1.race(t2).await;
-            let mut kcp_stream = kcp_reader.reunite(kcp_writer).unwrap();
-            kcp_stream.close().await?;
-            tcp_writer.close().await?;
+            let mut kcp_stream = kcp_stream;
+            if let Some(session_log) = session_log {
+                // The peer's OPEN acks ours
+                kcp_stream.flush_and_wait_acked().await?;
+                log::info!("{}", session_log.summary(&kcp_stream.parameters().await));
+            }
+            relay_stream(tcp_stream, kcp_stream, "client").await?;
             log::info!("client relay ends");
             Ok(())
         });
         t.detach();
     }
+    log::info!("shutting down");
+    kcp.shutdown().await;
+    Ok(())
+}
+
+/// How long a udp session is kept without any stream before it is removed, by default
+const SESSION_IDLE_GRACE: Duration = Duration::from_secs(5);
+/// The reset code of the streams still open when the drain deadline passes
+const DRAIN_RESET_CODE: u32 = 1;
+
+/// How the server sets up each udp session
+struct SessionOptions {
+    codec: Codec,
+    config: KcpConfig,
+    /// Also serve clients which don't encrypt, see the caveats of `FallbackCryptoLayer::wrap`
+    allow_plaintext: bool,
+    /// See `FallbackCryptoLayer::with_failure_threshold`
+    decrypt_failure_threshold: Option<u32>,
+    log_session: Option<SessionLog>,
+    /// How long the relays may take to finish after the shutdown signal, the streams
+    /// still open then are reset
+    drain_timeout: Duration,
+    /// How long a session without streams is kept, a client opening a stream meanwhile
+    /// goes on with the same session
+    session_grace: Duration,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            codec: Codec::None,
+            config: KcpConfig::default(),
+            allow_plaintext: false,
+            decrypt_failure_threshold: None,
+            log_session: None,
+            drain_timeout: Duration::from_secs(30),
+            session_grace: SESSION_IDLE_GRACE,
+        }
+    }
+}
+
+enum ServerEvent {
+    Accepted(UdpSession),
+    Idle(u64),
 }
 
 async fn server<C: Crypto + 'static>(
-    addr: String,
+    routes: Arc<Routes>,
     udp: UdpSocket,
     crypto: C,
+    options: SessionOptions,
+    metrics: Arc<Metrics>,
+    shutdown: Receiver<()>,
 ) -> std::io::Result<()> {
-    let listener = UdpListener::new(udp);
+    // KcpHandle::new panics on an invalid config, fail once here rather than on a session
+    options.config.validate()?;
+    let listener = UdpListener::new(udp, options.config.ecn);
     let crypto = Arc::new(crypto);
+    // Skip the upstreams already down from the first stream on
+    routes.default.check().await;
+    let _health_checks = smol::spawn(routes.default.clone().run_health_checks());
     let mut sessions: Vec<(
-        Arc<KcpHandle<CryptoLayer<UdpSession, Arc<C>>>>,
+        Arc<KcpHandle<CompressionLayer<FallbackCryptoLayer<UdpSession, Arc<C>>>>>,
         Task<KcpResult<()>>,
+        Task<()>,
+        SocketAddr,
+        u64,
     )> = Vec::new();
+    let (idle_tx, idle_rx) = bounded(0x10);
+    let mut next_id = 0u64;
 
     loop {
-        let udp_session = listener.accept().await;
-        log::info!("new udp session: {}", udp_session.remote);
-        let udp_session = CryptoLayer::wrap(udp_session, crypto.clone());
+        let accept = async { listener.accept().await.map(ServerEvent::Accepted) };
+        let idle = async { idle_rx.recv().await.ok().map(ServerEvent::Idle) };
+        let udp_session = match until_shutdown(accept.or(idle), &shutdown).await {
+            Some(Some(ServerEvent::Accepted(udp_session))) => udp_session,
+            Some(Some(ServerEvent::Idle(id))) => {
+                if let Some(i) = sessions.iter().position(|session| session.4 == id) {
+                    let (handle, _, _, remote, _) = sessions.swap_remove(i);
+                    log::info!(
+                        "removing idle kcp handle of {}: {}",
+                        remote,
+                        handle.session_summary().await
+                    );
+                    metrics.retire(&*handle).await;
+                    listener.evict(&remote).await;
+                }
+                continue;
+            }
+            _ => break,
+        };
+        let remote = udp_session.remote;
+        log::info!("new udp session: {}", remote);
+        let mut udp_session =
+            FallbackCryptoLayer::wrap(udp_session, crypto.clone(), options.allow_plaintext);
+        if let Some(threshold) = options.decrypt_failure_threshold {
+            udp_session = udp_session.with_failure_threshold(threshold);
+        }
+        let udp_session = CompressionLayer::wrap(udp_session, options.codec);
         log::trace!("udp session accepted");
-        let kcp = Arc::new(KcpHandle::new(udp_session, KcpConfig::default()));
+        let kcp = Arc::new(KcpHandle::new(udp_session, options.config.clone()));
+        metrics.register(kcp.clone()).await;
         let t: Task<KcpResult<()>> = {
-            let addr = addr.clone();
+            let routes = routes.clone();
             let kcp = kcp.clone();
+            let session_log = options.log_session.clone();
             smol::spawn(async move {
                 let mut relay_task = Vec::new();
                 loop {
                     let kcp_stream = kcp.accept().await?;
                     log::info!("kcp accepted");
-                    let tcp_stream = TcpStream::connect(addr.clone()).await?;
-                    log::info!("tcp connected");
+                    if let Some(session_log) = &session_log {
+                        log::info!("{}", session_log.summary(&kcp_stream.parameters().await));
+                    }
+                    let (tcp_stream, target, upstream) =
+                        match routes.connect(kcp_stream.label()).await {
+                            Ok(connected) => connected,
+                            Err(e) => {
+                                log::error!("failed to connect the target: {}", e);
+                                let reason = tcp_error_reason("upstream", &e);
+                                let mut kcp_stream = kcp_stream;
+                                let _ = kcp_stream.reset_with(TCP_ERROR_RESET_CODE, &reason).await;
+                                continue;
+                            }
+                        };
+                    log::info!("tcp connected to {}", target);
                     let t: Task<KcpResult<()>> = smol::spawn(async move {
-                        let mut tcp_reader = tcp_stream;
-                        let mut tcp_writer = tcp_reader.clone();
-                        let (mut kcp_reader, mut kcp_writer) = kcp_stream.split();
-                        let t1 = relay(&mut tcp_reader, &mut kcp_writer);
-                        let t2 = relay(&mut kcp_reader, &mut tcp_writer);
-                        let _ = t1.race(t2).await;
-                        let mut kcp_stream = kcp_reader.reunite(kcp_writer).unwrap();
-                        kcp_stream.close().await?;
-                        tcp_writer.close().await?;
+                        let _upstream = upstream;
+                        relay_stream(tcp_stream, kcp_stream, "upstream").await?;
                         log::info!("server relay ends");
                         Ok(())
                     });
@@ -206,19 +554,49 @@ async fn server<C: Crypto + 'static>(
                 }
             })
         };
-        sessions.retain(|(handle, _)| {
-            let ok = smol::block_on(async {
-                let count = handle.get_stream_count().await;
-                log::debug!("count = {}", count);
-                count > 0
-            });
-            if !ok {
-                log::info!("removing kcp handle");
-            }
-            ok
-        });
-        sessions.push((kcp, t));
+        let id = next_id;
+        next_id += 1;
+        let reaper = {
+            let kcp = kcp.clone();
+            let idle_tx = idle_tx.clone();
+            let grace = options.session_grace;
+            smol::spawn(async move {
+                kcp.wait_idle(Some(grace)).await;
+                let _ = idle_tx.send(id).await;
+            })
+        };
+        sessions.push((kcp, t, reaper, remote, id));
+    }
+
+    log::info!(
+        "draining {} sessions for up to {:?}",
+        sessions.len(),
+        options.drain_timeout
+    );
+    listener.stop_accepting();
+    for session in &sessions {
+        session.0.stop_accepting();
     }
+    let drained = async {
+        futures::future::join_all(sessions.iter().map(|session| session.0.wait_idle(None))).await;
+        true
+    };
+    let deadline = async {
+        Timer::after(options.drain_timeout).await;
+        false
+    };
+    if !drained.or(deadline).await {
+        log::warn!("drain deadline passed, resetting the remaining streams");
+        for session in &sessions {
+            let _ = session
+                .0
+                .reset_all(DRAIN_RESET_CODE, "server shutdown")
+                .await;
+        }
+    }
+    futures::future::join_all(sessions.iter().map(|session| session.0.shutdown())).await;
+    listener.shutdown().await;
+    Ok(())
 }
 
 fn get_algorithm(name: &str) -> &'static aead::Algorithm {
@@ -232,8 +610,215 @@ fn get_algorithm(name: &str) -> &'static aead::Algorithm {
     }
 }
 
-fn main() {
-    let matches = App::new("ap_kcp")
+fn get_codec(name: &str) -> Codec {
+    match name {
+        "none" => Codec::None,
+        "deflate" => Codec::Deflate,
+        "zstd" => Codec::Zstd,
+        _ => {
+            panic!("no codec named {}", name)
+        }
+    }
+}
+
+fn validate_size(size: String) -> Result<(), String> {
+    size.parse::<usize>()
+        .map(|_| ())
+        .map_err(|e| format!("invalid size {}: {}", size, e))
+}
+
+fn get_udp_options(matches: &ArgMatches) -> UdpOptions {
+    UdpOptions {
+        recv_buffer_size: matches
+            .value_of("udp-rcvbuf")
+            .map(|size| size.parse().unwrap()),
+        send_buffer_size: matches
+            .value_of("udp-sndbuf")
+            .map(|size| size.parse().unwrap()),
+        dscp: matches.value_of("dscp").map(|dscp| dscp.parse().unwrap()),
+        ecn: matches.is_present("ecn"),
+        interface: matches.value_of("interface").map(str::to_string),
+    }
+}
+
+fn get_kcp_config(matches: &ArgMatches) -> KcpConfig {
+    let peer_auth_key = if matches.is_present("verify-peer") {
+        let password = matches.value_of("password").unwrap();
+        Some(Bytes::copy_from_slice(password.as_bytes()))
+    } else {
+        None
+    };
+    let defaults = KcpConfig::default();
+    KcpConfig {
+        ecn: matches.is_present("ecn"),
+        peer_auth_key,
+        send_window_size: matches
+            .value_of("send-window")
+            .map_or(defaults.send_window_size, |window| window.parse().unwrap()),
+        recv_window_size: matches
+            .value_of("recv-window")
+            .map_or(defaults.recv_window_size, |window| window.parse().unwrap()),
+        ..defaults
+    }
+}
+
+#[cfg(unix)]
+fn get_inherited_udp(
+    matches: &ArgMatches,
+    options: &UdpOptions,
+) -> std::io::Result<Option<UdpSocket>> {
+    matches
+        .value_of("fd")
+        .map(|fd| socket::udp_from_fd(fd.parse().unwrap(), options))
+        .transpose()
+}
+
+#[cfg(not(unix))]
+fn get_inherited_udp(
+    matches: &ArgMatches,
+    _options: &UdpOptions,
+) -> std::io::Result<Option<UdpSocket>> {
+    if matches.is_present("fd") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "--fd is only supported on unix",
+        ));
+    }
+    Ok(None)
+}
+
+/// Performs the setup of the tunnel without running it, everything is torn down on return
+async fn check(matches: &ArgMatches<'_>) -> Result<(), String> {
+    let local = matches.value_of("local").unwrap();
+    let password = matches.value_of("password").unwrap();
+    let algorithm = get_algorithm(matches.value_of("algorithm").unwrap());
+    let _aead = AeadCrypto::new(password.as_bytes(), algorithm);
+    let _codec = get_codec(matches.value_of("compression").unwrap());
+    let udp_options = get_udp_options(matches);
+    get_kcp_config(matches)
+        .validate()
+        .map_err(|e| format!("invalid kcp config: {}", e))?;
+
+    if let Some(metrics_addr) = matches.value_of("metrics-addr") {
+        TcpListener::bind(metrics_addr)
+            .await
+            .map_err(|e| format!("failed to bind metrics on {}: {}", metrics_addr, e))?;
+    }
+
+    let inherited = get_inherited_udp(matches, &udp_options)
+        .map_err(|e| format!("failed to take over the inherited udp socket: {}", e))?;
+    if matches.is_present("client") {
+        let remote = matches.value_of("remote").unwrap();
+        let udp = match inherited {
+            Some(udp) => udp,
+            None => bind_udp(":::0", &udp_options)
+                .await
+                .map_err(|e| format!("failed to bind udp: {}", e))?,
+        };
+        connect_udp(&udp, remote, get_connect_timeout(matches))
+            .await
+            .map_err(|e| format!("failed to connect udp to {}: {}", remote, e))?;
+        TcpListener::bind(local)
+            .await
+            .map_err(|e| format!("failed to bind tcp on {}: {}", local, e))?;
+    } else if matches.is_present("server") {
+        if inherited.is_none() {
+            bind_udp(local, &udp_options)
+                .await
+                .map_err(|e| format!("failed to bind udp on {}: {}", local, e))?;
+        }
+        let routes = get_routes(matches);
+        let targets = routes
+            .labeled
+            .values()
+            .map(String::as_str)
+            .chain(routes.default.addrs());
+        for target in targets {
+            smol::net::resolve(target)
+                .await
+                .map_err(|e| format!("failed to resolve target {}: {}", target, e))?;
+        }
+    } else {
+        return Err("either --client or --server is needed".to_string());
+    }
+    Ok(())
+}
+
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+fn get_routes(matches: &ArgMatches) -> Routes {
+    let mut routes = match matches.values_of("upstream") {
+        Some(upstreams) => {
+            // Neither has a default_value, clap would then require --upstream for every command
+            let balance = match matches.value_of("balance") {
+                Some("least-connections") => Balance::LeastConnections,
+                _ => Balance::RoundRobin,
+            };
+            let interval = matches
+                .value_of("health-check-interval")
+                .map_or(HEALTH_CHECK_INTERVAL, |interval| {
+                    Duration::from_secs(interval.parse().unwrap())
+                });
+            Routes::with_upstreams(UpstreamPool::new(
+                upstreams.map(str::to_string).collect(),
+                balance,
+                interval,
+            ))
+        }
+        None => Routes::new(matches.value_of("remote").unwrap().to_string()),
+    };
+    for route in matches.values_of("route").into_iter().flatten() {
+        let (label, target) = route.split_at(route.find('=').unwrap());
+        routes.insert(label.as_bytes(), target[1..].to_string());
+    }
+    routes.connect_timeout = get_connect_timeout(matches);
+    routes
+}
+
+fn get_connect_timeout(matches: &ArgMatches) -> Option<Duration> {
+    matches
+        .value_of("connect-timeout")
+        .map(|timeout| Duration::from_secs(timeout.parse().unwrap()))
+}
+
+fn get_decrypt_failure_threshold(matches: &ArgMatches) -> Option<u32> {
+    matches
+        .value_of("decrypt-failure-threshold")
+        .map(|threshold| threshold.parse().unwrap())
+}
+
+/// Connects the udp socket of the client to `--remote`, resolving it may hang as well
+async fn connect_udp(
+    udp: &UdpSocket,
+    remote: &str,
+    timeout: Option<Duration>,
+) -> std::io::Result<()> {
+    let connect = udp.connect(remote);
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return connect.await,
+    };
+    let expired = async {
+        Timer::after(timeout).await;
+        Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("connecting {} timed out after {:?}", remote, timeout),
+        ))
+    };
+    connect.or(expired).await
+}
+
+fn set_threads(matches: &ArgMatches) -> usize {
+    let threads = matches
+        .value_of("threads")
+        .map(|threads| threads.parse().unwrap())
+        .unwrap_or(num_cpus::get() + 2);
+    std::env::set_var("SMOL_THREADS", threads.to_string());
+    threads
+}
+
+fn app() -> App<'static, 'static> {
+    App::new("ap_kcp")
         .arg(
             Arg::with_name("local")
                 .long("local")
@@ -246,7 +831,7 @@ fn main() {
                 .long("remote")
                 .short("r")
                 .takes_value(true)
-                .required(true),
+                .required_unless("upstream"),
         )
         .arg(
             Arg::with_name("client")
@@ -284,6 +869,16 @@ fn main() {
                 })
                 .default_value("aes-256-gcm"),
         )
+        .arg(
+            Arg::with_name("compression")
+                .long("compression")
+                .takes_value(true)
+                .validator(|name| match name.as_str() {
+                    "none" | "deflate" | "zstd" => Ok(()),
+                    _ => Err("Valid compression codec: none, deflate, zstd".to_string()),
+                })
+                .default_value("none"),
+        )
         .arg(
             Arg::with_name("congestion")
                 .long("congestion")
@@ -291,35 +886,323 @@ fn main() {
                 .takes_value(true)
                 .required(false),
         )
+        .arg(
+            Arg::with_name("metrics-addr")
+                .long("metrics-addr")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("udp-rcvbuf")
+                .long("udp-rcvbuf")
+                .takes_value(true)
+                .required(false)
+                .validator(validate_size),
+        )
+        .arg(
+            Arg::with_name("udp-sndbuf")
+                .long("udp-sndbuf")
+                .takes_value(true)
+                .required(false)
+                .validator(validate_size),
+        )
+        .arg(
+            Arg::with_name("dscp")
+                .long("dscp")
+                .takes_value(true)
+                .required(false)
+                .help("DSCP of outgoing udp packets, e.g. 46 (EF) for interactive tunnels")
+                .validator(|dscp| match dscp.parse::<u8>() {
+                    Ok(dscp) if dscp <= socket::MAX_DSCP => Ok(()),
+                    _ => Err(format!("DSCP should be 0 to {}", socket::MAX_DSCP)),
+                }),
+        )
+        .arg(
+            Arg::with_name("ecn")
+                .long("ecn")
+                .help("Mark packets ECN-capable and back off on congestion marks, unix only"),
+        )
+        .arg(
+            Arg::with_name("interface")
+                .long("interface")
+                .takes_value(true)
+                .help("Bind the udp socket to this network interface with SO_BINDTODEVICE, e.g. for policy routing. Linux only, needs CAP_NET_RAW"),
+        )
+        .arg(
+            Arg::with_name("send-window")
+                .long("send-window")
+                .takes_value(true)
+                .help("Segments sent and not acked yet at most, e.g. large on the fast direction of an asymmetric link")
+                .validator(|window| match window.parse::<u32>() {
+                    Ok(window) if window > 0 => Ok(()),
+                    _ => Err("Send window should be a positive number of segments".to_string()),
+                }),
+        )
+        .arg(
+            Arg::with_name("recv-window")
+                .long("recv-window")
+                .takes_value(true)
+                .help("Segments received and not forwarded yet at most, advertised to the peer")
+                .validator(|window| match window.parse::<u32>() {
+                    Ok(window) if window > 0 && window <= 0xffff << MAX_WINDOW_SHIFT => Ok(()),
+                    _ => Err(format!(
+                        "Receive window should be a number of segments from 1 to {}",
+                        0xffff << MAX_WINDOW_SHIFT
+                    )),
+                }),
+        )
+        .arg(
+            Arg::with_name("log-session")
+                .long("log-session")
+                .help("Log the negotiated mtu, windows, algorithm and features of every new stream"),
+        )
+        .arg(
+            Arg::with_name("allow-plaintext")
+                .long("allow-plaintext")
+                .requires("server")
+                .help("Also serve clients which don't encrypt, only for migrating legacy clients"),
+        )
+        .arg(
+            Arg::with_name("decrypt-failure-threshold")
+                .long("decrypt-failure-threshold")
+                .takes_value(true)
+                .help("Close a session after this many packets in a row fail to decrypt, off by default")
+                .validator(|threshold| match threshold.parse::<u32>() {
+                    Ok(threshold) if threshold > 0 => Ok(()),
+                    _ => Err("Decrypt failure threshold should be a positive number".to_string()),
+                }),
+        )
+        .arg(
+            Arg::with_name("verify-peer")
+                .long("verify-peer")
+                .help("Check that the peer holds the same password in every stream handshake, both sides need it"),
+        )
+        .arg(
+            Arg::with_name("check")
+                .long("check")
+                .help("Bind the sockets and validate the configuration, then exit"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .takes_value(true)
+                .required(false)
+                .validator(|threads| match threads.parse::<usize>() {
+                    Ok(threads) if threads >= 1 => Ok(()),
+                    _ => Err("Thread number should be at least 1".to_string()),
+                }),
+        )
+        .arg(
+            Arg::with_name("fd")
+                .long("fd")
+                .takes_value(true)
+                .required(false)
+                .help("Use an inherited udp socket instead of binding one, e.g. 3 for systemd socket activation")
+                .validator(|fd| match fd.parse::<i32>() {
+                    Ok(fd) if fd >= 0 => Ok(()),
+                    _ => Err("Invalid file descriptor".to_string()),
+                }),
+        )
+        .arg(
+            Arg::with_name("label")
+                .long("label")
+                .takes_value(true)
+                .required(false)
+                .conflicts_with("server")
+                .help("Label of the streams opened by the client, the server routes by it"),
+        )
+        .arg(
+            Arg::with_name("route")
+                .long("route")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required(false)
+                .conflicts_with("client")
+                .help("Route streams labeled <label> to <target> instead of --remote")
+                .value_name("label=target")
+                .validator(|route| match route.find('=') {
+                    Some(_) => Ok(()),
+                    None => Err("Route should be label=target".to_string()),
+                }),
+        )
+        .arg(
+            Arg::with_name("upstream")
+                .long("upstream")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required(false)
+                .conflicts_with("client")
+                .help("Balance the streams without a --route across these targets instead of --remote, skipping the ones failing health checks"),
+        )
+        .arg(
+            Arg::with_name("balance")
+                .long("balance")
+                .takes_value(true)
+                .requires("upstream")
+                .validator(|balance| match balance.as_str() {
+                    "round-robin" | "least-connections" => Ok(()),
+                    _ => Err("Valid balance: round-robin, least-connections".to_string()),
+                }),
+        )
+        .arg(
+            Arg::with_name("health-check-interval")
+                .long("health-check-interval")
+                .takes_value(true)
+                .requires("upstream")
+                .help("Seconds between the health checks of the upstreams, 10 by default")
+                .validator(|interval| match interval.parse::<u64>() {
+                    Ok(interval) if interval >= 1 => Ok(()),
+                    _ => Err("Health check interval should be at least 1 second".to_string()),
+                }),
+        )
+        .arg(
+            Arg::with_name("drain-timeout")
+                .long("drain-timeout")
+                .takes_value(true)
+                .help("Seconds the server waits for open streams to finish on shutdown, before resetting them")
+                .validator(|timeout| match timeout.parse::<u64>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err("Drain timeout should be a number of seconds".to_string()),
+                })
+                .default_value("30"),
+        )
+        .arg(
+            Arg::with_name("session-grace")
+                .long("session-grace")
+                .takes_value(true)
+                .conflicts_with("client")
+                .help("Seconds the server keeps a session without streams, so that the client may reopen streams on it, 5 by default")
+                .validator(|grace| match grace.parse::<u64>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err("Session grace should be a number of seconds".to_string()),
+                }),
+        )
+        .arg(
+            Arg::with_name("connect-timeout")
+                .long("connect-timeout")
+                .takes_value(true)
+                .required(false)
+                .help("Seconds to wait for the server's tcp connection to a target, or the client's udp connection to --remote")
+                .validator(|timeout| match timeout.parse::<u64>() {
+                    Ok(timeout) if timeout >= 1 => Ok(()),
+                    _ => Err("Connect timeout should be at least 1 second".to_string()),
+                }),
+        )
         .author("black-binary")
         .version("0.1.0")
-        .get_matches();
+}
+
+fn main() {
+    let matches = app().get_matches();
 
-    let thread = num_cpus::get() + 2;
-    std::env::set_var("SMOL_THREADS", thread.to_string());
+    set_threads(&matches);
 
     let _ = env_logger::builder()
         .filter_module("ap_kcp", LevelFilter::Info)
         .try_init();
 
+    if matches.is_present("check") {
+        match smol::block_on(check(&matches)) {
+            Ok(()) => {
+                println!("configuration ok");
+                return;
+            }
+            Err(e) => {
+                eprintln!("configuration check failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
     smol::block_on(async move {
         let local = matches.value_of("local").unwrap();
-        let remote = matches.value_of("remote").unwrap();
         let password = matches.value_of("password").unwrap();
         let algorithm_name = matches.value_of("algorithm").unwrap();
+        let udp_options = get_udp_options(&matches);
+        let codec = get_codec(matches.value_of("compression").unwrap());
 
         let aead = AeadCrypto::new(password.as_bytes(), get_algorithm(algorithm_name));
+        let session_log = if matches.is_present("log-session") {
+            Some(SessionLog {
+                algorithm: algorithm_name.to_string(),
+                codec,
+            })
+        } else {
+            None
+        };
+
+        let shutdown = shutdown_signal();
+        let metrics = Arc::new(Metrics::default());
+        if let Some(metrics_addr) = matches.value_of("metrics-addr") {
+            let listener = TcpListener::bind(metrics_addr).await.unwrap();
+            log::info!("serving metrics on {}", metrics_addr);
+            smol::spawn(metrics.clone().serve(listener)).detach();
+        }
 
         if matches.is_present("client") {
-            let udp = UdpSocket::bind(":::0").await.unwrap();
-            udp.connect(remote).await.unwrap();
-            let udp = crypto::CryptoLayer::wrap(udp, aead);
-            let kcp_handle = KcpHandle::new(udp, KcpConfig::default());
+            let udp = match get_inherited_udp(&matches, &udp_options).unwrap() {
+                Some(udp) => udp,
+                None => match bind_udp(":::0", &udp_options).await {
+                    Ok(udp) => udp,
+                    Err(e) => {
+                        log::error!("failed to bind udp: {}", e);
+                        return;
+                    }
+                },
+            };
+            let remote = matches.value_of("remote").unwrap();
+            if let Err(e) = connect_udp(&udp, remote, get_connect_timeout(&matches)).await {
+                log::error!("failed to connect udp to {}: {}", remote, e);
+                return;
+            }
+            let mut udp = CryptoLayer::wrap(udp, aead);
+            if let Some(threshold) = get_decrypt_failure_threshold(&matches) {
+                udp = udp.with_failure_threshold(threshold);
+            }
+            let udp = CompressionLayer::wrap(udp, codec);
+            let kcp_handle = Arc::new(KcpHandle::new(udp, get_kcp_config(&matches)));
+            metrics.register(kcp_handle.clone()).await;
             let listener = TcpListener::bind(local).await.unwrap();
-            client(listener, kcp_handle).await.unwrap();
+            let label = matches.value_of("label").unwrap_or("").as_bytes().to_vec();
+            if let Err(e) = client(listener, kcp_handle, label, session_log, shutdown).await {
+                log::error!("client error: {}", e);
+            }
         } else if matches.is_present("server") {
-            let udp = UdpSocket::bind(local).await.unwrap();
-            server(remote.to_string(), udp, aead).await.unwrap();
+            let udp = match get_inherited_udp(&matches, &udp_options).unwrap() {
+                Some(udp) => udp,
+                None => match bind_udp(local, &udp_options).await {
+                    Ok(udp) => udp,
+                    Err(e) => {
+                        log::error!("failed to bind udp on {}: {}", local, e);
+                        return;
+                    }
+                },
+            };
+            let routes = Arc::new(get_routes(&matches));
+            let options = SessionOptions {
+                codec,
+                config: get_kcp_config(&matches),
+                allow_plaintext: matches.is_present("allow-plaintext"),
+                decrypt_failure_threshold: get_decrypt_failure_threshold(&matches),
+                log_session: session_log,
+                drain_timeout: Duration::from_secs(
+                    matches.value_of("drain-timeout").unwrap().parse().unwrap(),
+                ),
+                // No default_value, clap would count a defaulted arg as conflicting with --client
+                session_grace: matches
+                    .value_of("session-grace")
+                    .map_or(SESSION_IDLE_GRACE, |grace| {
+                        Duration::from_secs(grace.parse().unwrap())
+                    }),
+            };
+            if options.allow_plaintext {
+                log::warn!("plaintext clients are allowed, their traffic is not protected");
+            }
+            if let Err(e) = server(routes, udp, aead, options, metrics, shutdown).await {
+                log::error!("server error: {}", e);
+            }
         }
     })
 }
@@ -331,16 +1214,20 @@ fn simple_iperf() {
         .filter_module("ap_kcp", LevelFilter::Info)
         .try_init();
     let password = "password";
+    let (_shutdown_tx, shutdown_rx) = bounded(1);
+    let client_shutdown = shutdown_rx.clone();
     let t1 = smol::spawn(async move {
         let local = "127.0.0.1:5000";
         let remote = "127.0.0.1:6000";
         let udp = UdpSocket::bind(":::0").await.unwrap();
         udp.connect(remote).await.unwrap();
         let aead = AeadCrypto::new(password.as_bytes(), &aead::AES_256_GCM);
-        let udp = crypto::CryptoLayer::wrap(udp, aead);
-        let kcp_handle = KcpHandle::new(udp, KcpConfig::default());
+        let udp = CompressionLayer::wrap(CryptoLayer::wrap(udp, aead), Codec::None);
+        let kcp_handle = Arc::new(KcpHandle::new(udp, KcpConfig::default()));
         let listener = TcpListener::bind(local).await.unwrap();
-        client(listener, kcp_handle).await.unwrap();
+        client(listener, kcp_handle, Vec::new(), None, client_shutdown)
+            .await
+            .unwrap();
     });
 
     let t2 = smol::spawn(async move {
@@ -348,9 +1235,721 @@ fn simple_iperf() {
         let remote = "127.0.0.1:5201";
         let udp = UdpSocket::bind(local).await.unwrap();
         let aead = AeadCrypto::new(password.as_bytes(), &aead::AES_256_GCM);
-        server(remote.to_string(), udp, aead).await.unwrap();
+        server(
+            Arc::new(Routes::new(remote.to_string())),
+            udp,
+            aead,
+            SessionOptions::default(),
+            Arc::new(Metrics::default()),
+            shutdown_rx,
+        )
+        .await
+        .unwrap();
     });
     smol::block_on(async {
         t1.race(t2).await;
     });
 }
+
+#[test]
+fn graceful_shutdown() {
+    smol::block_on(async {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = udp.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = bounded(1);
+        let aead = AeadCrypto::new(b"password", &aead::AES_256_GCM);
+        let server_task = smol::spawn(server(
+            Arc::new(Routes::new(target_addr.to_string())),
+            udp,
+            aead,
+            SessionOptions::default(),
+            Arc::new(Metrics::default()),
+            shutdown_rx,
+        ));
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        udp.connect(server_addr).await.unwrap();
+        let aead = AeadCrypto::new(b"password", &aead::AES_256_GCM);
+        let udp = CompressionLayer::wrap(CryptoLayer::wrap(udp, aead), Codec::None);
+        let kcp = KcpHandle::new(udp, KcpConfig::default());
+        let mut stream = kcp.connect().await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let (mut tcp_stream, _) = target.accept().await.unwrap();
+        let mut buf = [0u8; 5];
+        tcp_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // What the signal handler does on SIGINT/SIGTERM
+        shutdown_tx.send(()).await.unwrap();
+
+        // The relay goes on until our side closes
+        stream.write_all(b"world").await.unwrap();
+        tcp_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+        stream.close().await.unwrap();
+        server_task.await.unwrap();
+    });
+}
+
+#[test]
+fn session_grace() {
+    async fn relay_once<T: crate::core::KcpIo + Send + Sync + 'static>(
+        kcp: &KcpHandle<T>,
+        target: &TcpListener,
+    ) {
+        let mut stream = kcp.connect().await.unwrap();
+        stream.write_all(b"x").await.unwrap();
+        let (mut tcp_stream, _) = target.accept().await.unwrap();
+        let mut buf = [0u8; 1];
+        tcp_stream.read_exact(&mut buf).await.unwrap();
+        stream.close().await.unwrap();
+    }
+
+    smol::block_on(async {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = udp.local_addr().unwrap();
+        let (_shutdown_tx, shutdown_rx) = bounded(1);
+        let metrics = Arc::new(Metrics::default());
+        let aead = AeadCrypto::new(b"password", &aead::AES_256_GCM);
+        let options = SessionOptions {
+            session_grace: Duration::from_secs(1),
+            ..Default::default()
+        };
+        let _server_task = smol::spawn(server(
+            Arc::new(Routes::new(target_addr.to_string())),
+            udp,
+            aead,
+            options,
+            metrics.clone(),
+            shutdown_rx,
+        ));
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        udp.connect(server_addr).await.unwrap();
+        let aead = AeadCrypto::new(b"password", &aead::AES_256_GCM);
+        let udp = CompressionLayer::wrap(CryptoLayer::wrap(udp, aead), Codec::None);
+        let kcp = KcpHandle::new(udp, KcpConfig::default());
+
+        relay_once(&kcp, &target).await;
+        let id = metrics.sessions().await[0].id;
+        // Reopened within the grace, the session goes on
+        Timer::after(Duration::from_millis(300)).await;
+        relay_once(&kcp, &target).await;
+        let sessions = metrics.sessions().await;
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].id, id);
+
+        // Idle past the grace, the session is removed and the next stream starts another
+        let removed = async {
+            while !metrics.sessions().await.is_empty() {
+                Timer::after(Duration::from_millis(100)).await;
+            }
+            true
+        };
+        let timeout = async {
+            Timer::after(Duration::from_secs(10)).await;
+            false
+        };
+        assert!(removed.or(timeout).await);
+        relay_once(&kcp, &target).await;
+        let sessions = metrics.sessions().await;
+        assert_eq!(sessions.len(), 1);
+        assert_ne!(sessions[0].id, id);
+    });
+}
+
+#[test]
+fn server_invalid_config() {
+    smol::block_on(async {
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (_shutdown_tx, shutdown_rx) = bounded(1);
+        let aead = AeadCrypto::new(b"password", &aead::AES_256_GCM);
+        let mut options = SessionOptions::default();
+        options.config.mtu = MAX_DATAGRAM + 1;
+        // Fails right away, not once a client shows up
+        assert!(server(
+            Arc::new(Routes::new("127.0.0.1:1".to_string())),
+            udp,
+            aead,
+            options,
+            Arc::new(Metrics::default()),
+            shutdown_rx,
+        )
+        .await
+        .is_err());
+    });
+}
+
+#[test]
+fn session_grace_args() {
+    let args = |mode: &'static str, grace: Option<&'static str>| {
+        let mut args = vec![
+            "ap_kcp",
+            mode,
+            "--local",
+            "127.0.0.1:3000",
+            "--remote",
+            "127.0.0.1:4000",
+            "--password",
+            "password",
+        ];
+        if let Some(grace) = grace {
+            args.extend(&["--session-grace", grace]);
+        }
+        args
+    };
+    assert!(app().get_matches_from_safe(args("--client", None)).is_ok());
+    assert!(app()
+        .get_matches_from_safe(args("--client", Some("1")))
+        .is_err());
+    let matches = app().get_matches_from(args("--server", None));
+    assert_eq!(matches.value_of("session-grace"), None);
+    let matches = app().get_matches_from(args("--server", Some("1")));
+    assert_eq!(matches.value_of("session-grace"), Some("1"));
+}
+
+#[test]
+fn decrypt_failure_threshold_args() {
+    let args = |threshold: &'static str| {
+        vec![
+            "ap_kcp",
+            "--server",
+            "--local",
+            "127.0.0.1:3000",
+            "--remote",
+            "127.0.0.1:4000",
+            "--password",
+            "password",
+            "--decrypt-failure-threshold",
+            threshold,
+        ]
+    };
+    let matches = app().get_matches_from(args("16"));
+    assert_eq!(get_decrypt_failure_threshold(&matches), Some(16));
+    assert!(app().get_matches_from_safe(args("0")).is_err());
+    let matches = app().get_matches_from(&args("16")[..8]);
+    assert_eq!(get_decrypt_failure_threshold(&matches), None);
+}
+
+#[test]
+fn upstream_reset() {
+    use socket2::SockRef;
+
+    smol::block_on(async {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = udp.local_addr().unwrap();
+        let (_shutdown_tx, shutdown_rx) = bounded(1);
+        let aead = AeadCrypto::new(b"password", &aead::AES_256_GCM);
+        let _server_task = smol::spawn(server(
+            Arc::new(Routes::new(target_addr.to_string())),
+            udp,
+            aead,
+            SessionOptions::default(),
+            Arc::new(Metrics::default()),
+            shutdown_rx,
+        ));
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        udp.connect(server_addr).await.unwrap();
+        let aead = AeadCrypto::new(b"password", &aead::AES_256_GCM);
+        let udp = CompressionLayer::wrap(CryptoLayer::wrap(udp, aead), Codec::None);
+        let kcp = KcpHandle::new(udp, KcpConfig::default());
+        let mut stream = kcp.connect().await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let (mut tcp_stream, _) = target.accept().await.unwrap();
+        let mut buf = [0u8; 5];
+        tcp_stream.read_exact(&mut buf).await.unwrap();
+
+        // Closing with a zero linger sends a RST
+        SockRef::from(&tcp_stream)
+            .set_linger(Some(Duration::from_secs(0)))
+            .unwrap();
+        drop(tcp_stream);
+
+        let err = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+        match crate::error::KcpError::from(err) {
+            crate::error::KcpError::PeerReset { code, reason } => {
+                assert_eq!(code, TCP_ERROR_RESET_CODE);
+                assert_eq!(reason, "upstream reset");
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+    });
+}
+
+#[test]
+fn windows() {
+    let args = |extra: &[&'static str]| {
+        let mut args = vec![
+            "ap_kcp",
+            "--client",
+            "--local",
+            "127.0.0.1:3000",
+            "--remote",
+            "127.0.0.1:4000",
+            "--password",
+            "password",
+        ];
+        args.extend_from_slice(extra);
+        args
+    };
+    let defaults = KcpConfig::default();
+    let config = get_kcp_config(&app().get_matches_from(args(&[])));
+    assert_eq!(config.send_window_size, defaults.send_window_size);
+    assert_eq!(config.recv_window_size, defaults.recv_window_size);
+
+    // An ADSL client uploads little and downloads a lot
+    let matches = app().get_matches_from(args(&["--send-window", "64", "--recv-window", "4096"]));
+    let config = get_kcp_config(&matches);
+    assert_eq!(config.send_window_size, 64);
+    assert_eq!(config.recv_window_size, 4096);
+    assert!(config.validate().is_ok());
+
+    assert!(app()
+        .get_matches_from_safe(args(&["--send-window", "0"]))
+        .is_err());
+    assert!(app()
+        .get_matches_from_safe(args(&["--recv-window", "2000000000"]))
+        .is_err());
+}
+
+#[test]
+fn threads() {
+    let matches = app().get_matches_from(vec![
+        "ap_kcp",
+        "--client",
+        "--local",
+        "127.0.0.1:3000",
+        "--remote",
+        "127.0.0.1:4000",
+        "--password",
+        "password",
+        "--threads",
+        "4",
+    ]);
+    assert_eq!(set_threads(&matches), 4);
+    assert_eq!(std::env::var("SMOL_THREADS").unwrap(), "4");
+    assert!(app()
+        .get_matches_from_safe(vec![
+            "ap_kcp",
+            "--client",
+            "--local",
+            "127.0.0.1:3000",
+            "--remote",
+            "127.0.0.1:4000",
+            "--password",
+            "password",
+            "--threads",
+            "0",
+        ])
+        .is_err());
+}
+
+#[test]
+fn label_routes() {
+    smol::block_on(async {
+        let default_target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = udp.local_addr().unwrap();
+
+        let default_addr = default_target.local_addr().unwrap().to_string();
+        let target_addr = target.local_addr().unwrap().to_string();
+        let route = format!("ssh={}", target_addr);
+        let matches = app().get_matches_from(vec![
+            "ap_kcp",
+            "--server",
+            "--local",
+            "127.0.0.1:0",
+            "--remote",
+            &default_addr,
+            "--password",
+            "password",
+            "--route",
+            &route,
+        ]);
+        let routes = Arc::new(get_routes(&matches));
+        assert_eq!(routes.labeled[&b"ssh"[..]], target_addr);
+        assert!(!routes.labeled.contains_key(&b"web"[..]));
+        assert_eq!(
+            routes.default.addrs().collect::<Vec<_>>(),
+            vec![default_addr.as_str()]
+        );
+
+        let (_shutdown_tx, shutdown_rx) = bounded(1);
+        let aead = AeadCrypto::new(b"password", &aead::AES_256_GCM);
+        let _server = smol::spawn(server(
+            routes,
+            udp,
+            aead,
+            SessionOptions::default(),
+            Arc::new(Metrics::default()),
+            shutdown_rx,
+        ));
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        udp.connect(server_addr).await.unwrap();
+        let aead = AeadCrypto::new(b"password", &aead::AES_256_GCM);
+        let udp = CompressionLayer::wrap(CryptoLayer::wrap(udp, aead), Codec::None);
+        let kcp = KcpHandle::new(udp, KcpConfig::default());
+
+        for (label, listener) in [(&b"ssh"[..], &target), (&b"web"[..], &default_target)].iter() {
+            let mut stream = kcp.connect_with_label(label).await.unwrap();
+            stream.write_all(label).await.unwrap();
+            let (mut tcp_stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 3];
+            tcp_stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf[..], *label);
+        }
+    });
+}
+
+#[test]
+fn check_mode() {
+    smol::block_on(async {
+        let args = |algorithm: &'static str, local: &'static str| {
+            vec![
+                "ap_kcp",
+                "--server",
+                "--local",
+                local,
+                "--remote",
+                "127.0.0.1:5201",
+                "--password",
+                "password",
+                "--algorithm",
+                algorithm,
+                "--check",
+            ]
+        };
+        assert!(app()
+            .get_matches_from_safe(args("rot13", "127.0.0.1:0"))
+            .is_err());
+
+        let matches = app().get_matches_from(args("aes-256-gcm", "127.0.0.1:0"));
+        assert!(check(&matches).await.is_ok());
+
+        let matches = app().get_matches_from(args("aes-256-gcm", "127.0.0.1:99999"));
+        assert!(check(&matches).await.is_err());
+    });
+}
+
+#[test]
+fn plaintext_and_encrypted_clients() {
+    smol::block_on(async {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = udp.local_addr().unwrap();
+        let (_shutdown_tx, shutdown_rx) = bounded(1);
+        let aead = AeadCrypto::new(b"password", &aead::AES_256_GCM);
+        let _server = smol::spawn(server(
+            Arc::new(Routes::new(target_addr.to_string())),
+            udp,
+            aead,
+            SessionOptions {
+                allow_plaintext: true,
+                ..Default::default()
+            },
+            Arc::new(Metrics::default()),
+            shutdown_rx,
+        ));
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        udp.connect(server_addr).await.unwrap();
+        let aead = AeadCrypto::new(b"password", &aead::AES_256_GCM);
+        let encrypted = KcpHandle::new(
+            CompressionLayer::wrap(CryptoLayer::wrap(udp, aead), Codec::None),
+            KcpConfig::default(),
+        );
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        udp.connect(server_addr).await.unwrap();
+        let plaintext = KcpHandle::new(
+            CompressionLayer::wrap(udp, Codec::None),
+            KcpConfig::default(),
+        );
+
+        let mut encrypted_stream = encrypted.connect().await.unwrap();
+        encrypted_stream.write_all(b"encrypted").await.unwrap();
+        let mut plaintext_stream = plaintext.connect().await.unwrap();
+        plaintext_stream.write_all(b"plaintext").await.unwrap();
+
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            let (mut tcp_stream, _) = target.accept().await.unwrap();
+            let mut buf = [0u8; 9];
+            tcp_stream.read_exact(&mut buf).await.unwrap();
+            tcp_stream.write_all(&buf).await.unwrap();
+            received.push(buf);
+        }
+        received.sort();
+        assert_eq!(&received[0], b"encrypted");
+        assert_eq!(&received[1], b"plaintext");
+
+        // Both get the replies in their own format
+        let mut buf = [0u8; 9];
+        encrypted_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"encrypted");
+        plaintext_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"plaintext");
+    });
+}
+
+#[test]
+fn log_session() {
+    smol::block_on(async {
+        let matches = app().get_matches_from(vec![
+            "ap_kcp",
+            "--client",
+            "--local",
+            "127.0.0.1:0",
+            "--remote",
+            "127.0.0.1:1",
+            "--password",
+            "password",
+            "--log-session",
+        ]);
+        assert!(matches.is_present("log-session"));
+
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = udp.local_addr().unwrap();
+        let session_log = SessionLog {
+            algorithm: "aes-256-gcm".to_string(),
+            codec: Codec::None,
+        };
+        let (_shutdown_tx, shutdown_rx) = bounded(1);
+        let aead = AeadCrypto::new(b"password", &aead::AES_256_GCM);
+        let _server = smol::spawn(server(
+            Arc::new(Routes::new(target_addr.to_string())),
+            udp,
+            aead,
+            SessionOptions {
+                log_session: Some(session_log.clone()),
+                ..Default::default()
+            },
+            Arc::new(Metrics::default()),
+            shutdown_rx,
+        ));
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        udp.connect(server_addr).await.unwrap();
+        let aead = AeadCrypto::new(b"password", &aead::AES_256_GCM);
+        let udp = CompressionLayer::wrap(CryptoLayer::wrap(udp, aead), Codec::None);
+        let kcp = KcpHandle::new(udp, KcpConfig::default());
+        let mut stream = kcp.connect().await.unwrap();
+        stream.flush_and_wait_acked().await.unwrap();
+
+        // What the client logs once the server's OPEN has arrived
+        let summary = session_log.summary(&stream.parameters().await);
+        assert!(summary.starts_with(&format!("stream {}: ", stream.get_stream_id())));
+        assert!(summary.contains(&format!("mtu {}, ", stream.effective_mtu().await)));
+        assert!(summary.contains("algorithm aes-256-gcm, compression None"));
+        assert!(summary.ends_with(&format!(
+            "features {:?}",
+            crate::core::Features::all() - crate::core::Features::AUTH
+        )));
+    });
+}
+
+#[test]
+fn upstream_health_checks() {
+    smol::block_on(async {
+        let healthy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let healthy_addr = healthy.local_addr().unwrap().to_string();
+        // Nothing listens there any more
+        let down_addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = udp.local_addr().unwrap();
+
+        let matches = app().get_matches_from(vec![
+            "ap_kcp",
+            "--server",
+            "--local",
+            "127.0.0.1:0",
+            "--password",
+            "password",
+            "--upstream",
+            &down_addr,
+            "--upstream",
+            &healthy_addr,
+            "--balance",
+            "least-connections",
+        ]);
+        let routes = Arc::new(get_routes(&matches));
+        assert_eq!(
+            routes.default.addrs().collect::<Vec<_>>(),
+            vec![down_addr.as_str(), healthy_addr.as_str()]
+        );
+
+        let (_shutdown_tx, shutdown_rx) = bounded(1);
+        let aead = AeadCrypto::new(b"password", &aead::AES_256_GCM);
+        let _server = smol::spawn(server(
+            routes.clone(),
+            udp,
+            aead,
+            SessionOptions::default(),
+            Arc::new(Metrics::default()),
+            shutdown_rx,
+        ));
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        udp.connect(server_addr).await.unwrap();
+        let aead = AeadCrypto::new(b"password", &aead::AES_256_GCM);
+        let udp = CompressionLayer::wrap(CryptoLayer::wrap(udp, aead), Codec::None);
+        let kcp = KcpHandle::new(udp, KcpConfig::default());
+
+        let mut streams = Vec::new();
+        for i in 0..4u8 {
+            let mut stream = kcp.connect().await.unwrap();
+            stream.write_all(&[i]).await.unwrap();
+            // Skip the connections of the health checks, they carry nothing
+            loop {
+                let (mut tcp_stream, _) = healthy.accept().await.unwrap();
+                let mut buf = [0u8; 1];
+                if tcp_stream.read_exact(&mut buf).await.is_ok() {
+                    assert_eq!(buf[0], i);
+                    break;
+                }
+            }
+            streams.push(stream);
+        }
+        assert!(!routes.default.is_healthy(&down_addr));
+        assert!(routes.default.is_healthy(&healthy_addr));
+    });
+}
+
+#[test]
+fn drain_deadline() {
+    smol::block_on(async {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = udp.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = bounded(1);
+        let aead = AeadCrypto::new(b"password", &aead::AES_256_GCM);
+        let drain_timeout = Duration::from_secs(1);
+        let server_task = smol::spawn(server(
+            Arc::new(Routes::new(target_addr.to_string())),
+            udp,
+            aead,
+            SessionOptions {
+                drain_timeout,
+                ..Default::default()
+            },
+            Arc::new(Metrics::default()),
+            shutdown_rx,
+        ));
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        udp.connect(server_addr).await.unwrap();
+        let aead = AeadCrypto::new(b"password", &aead::AES_256_GCM);
+        let udp = CompressionLayer::wrap(CryptoLayer::wrap(udp, aead), Codec::None);
+        let kcp = KcpHandle::new(udp, KcpConfig::default());
+        let mut short = kcp.connect().await.unwrap();
+        short.write_all(b"short").await.unwrap();
+        let (mut short_target, _) = target.accept().await.unwrap();
+        // The target of this one never answers, nor does our side close it
+        let mut stuck = kcp.connect().await.unwrap();
+        stuck.write_all(b"stuck").await.unwrap();
+        let (_stuck_target, _) = target.accept().await.unwrap();
+
+        let start = std::time::Instant::now();
+        shutdown_tx.send(()).await.unwrap();
+
+        // The short transfer completes within the deadline
+        short.write_all(b" done").await.unwrap();
+        let mut buf = [0u8; 10];
+        short_target.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"short done");
+        short.close().await.unwrap();
+
+        // The stuck one is reset at the deadline
+        let mut rest = Vec::new();
+        assert!(stuck.read_to_end(&mut rest).await.is_err());
+        server_task.await.unwrap();
+        assert!(start.elapsed() >= drain_timeout);
+    });
+}
+
+#[test]
+fn enumerate_sessions() {
+    smol::block_on(async {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let _target = smol::spawn(async move {
+            let mut connections = Vec::new();
+            loop {
+                let (connection, _) = target.accept().await.unwrap();
+                connections.push(connection);
+            }
+        });
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = udp.local_addr().unwrap();
+        let (_shutdown_tx, shutdown_rx) = bounded(1);
+        let metrics = Arc::new(Metrics::default());
+        let _server = smol::spawn(server(
+            Arc::new(Routes::new(target_addr.to_string())),
+            udp,
+            AeadCrypto::new(b"password", &aead::AES_256_GCM),
+            SessionOptions::default(),
+            metrics.clone(),
+            shutdown_rx,
+        ));
+
+        // Client i opens i + 1 streams
+        let mut clients = Vec::new();
+        let mut expected = HashMap::new();
+        for i in 0..3 {
+            let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            udp.connect(server_addr).await.unwrap();
+            let local_addr = udp.local_addr().unwrap();
+            let aead = AeadCrypto::new(b"password", &aead::AES_256_GCM);
+            let udp = CompressionLayer::wrap(CryptoLayer::wrap(udp, aead), Codec::None);
+            let kcp = KcpHandle::new(udp, KcpConfig::default());
+            let mut streams = Vec::new();
+            for _ in 0..=i {
+                let mut stream = kcp.connect().await.unwrap();
+                stream.write_all(b"hello").await.unwrap();
+                streams.push(stream);
+            }
+            expected.insert(local_addr, i + 1);
+            clients.push((kcp, streams));
+        }
+
+        let mut sessions = Vec::new();
+        for _ in 0..50 {
+            sessions = metrics.sessions().await;
+            let found: HashMap<_, _> = sessions
+                .iter()
+                .map(|session| (session.peer_addr.unwrap(), session.streams))
+                .collect();
+            if found == expected {
+                break;
+            }
+            Timer::after(Duration::from_millis(100)).await;
+        }
+        assert_eq!(sessions.len(), 3);
+        for session in &sessions {
+            assert_eq!(expected[&session.peer_addr.unwrap()], session.streams);
+            assert!(session.stats.bytes_received >= 5 * session.streams as u64);
+        }
+        let mut ids: Vec<_> = sessions.iter().map(|session| session.id).collect();
+        ids.dedup();
+        assert_eq!(ids.len(), 3);
+    });
+}
@@ -0,0 +1,361 @@
+use std::{
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::SystemTime,
+};
+
+use futures::{AsyncReadExt, AsyncWriteExt};
+use smol::{
+    lock::Mutex,
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{async_kcp::KcpHandle, core::KcpIo, core::KcpStats};
+
+#[async_trait::async_trait]
+pub trait StatsSource: Send + Sync {
+    fn peer_addr(&self) -> Option<SocketAddr>;
+    async fn get_stream_count(&self) -> usize;
+    async fn get_stats(&self) -> KcpStats;
+}
+
+#[async_trait::async_trait]
+impl<T: KcpIo + Send + Sync + 'static> StatsSource for KcpHandle<T> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        KcpHandle::peer_addr(self)
+    }
+
+    async fn get_stream_count(&self) -> usize {
+        KcpHandle::get_stream_count(self).await
+    }
+
+    async fn get_stats(&self) -> KcpStats {
+        KcpHandle::get_stats(self).await
+    }
+}
+
+/// A live session, see `Metrics::sessions`
+#[derive(Clone, Debug)]
+pub struct SessionSnapshot {
+    /// In the order of registration, never reused
+    pub id: u64,
+    pub peer_addr: Option<SocketAddr>,
+    pub streams: usize,
+    pub stats: KcpStats,
+}
+
+/// Every session at one point in time, see `Metrics::snapshot`
+#[derive(Clone, Debug)]
+pub struct MetricsSnapshot {
+    pub taken_at: SystemTime,
+    pub sessions: Vec<SessionSnapshot>,
+    /// Of all the sessions, also the retired ones
+    pub total: KcpStats,
+    pub streams: usize,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    handles: Mutex<Vec<(u64, Weak<dyn StatsSource>)>>,
+    next_id: AtomicU64,
+    retired: Mutex<KcpStats>,
+}
+
+impl Metrics {
+    pub async fn register(&self, handle: Arc<dyn StatsSource>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.handles
+            .lock()
+            .await
+            .push((id, Arc::downgrade(&handle)));
+    }
+
+    /// The stats of all sessions. The live ones are taken together with the retired ones, so
+    /// that each session is counted once, then read concurrently without holding up the
+    /// registration and retirement of others.
+    pub async fn snapshot(&self) -> MetricsSnapshot {
+        let (live, mut total) = {
+            let mut handles = self.handles.lock().await;
+            handles.retain(|(_, handle)| handle.strong_count() > 0);
+            let live: Vec<_> = handles
+                .iter()
+                .filter_map(|(id, handle)| handle.upgrade().map(|handle| (*id, handle)))
+                .collect();
+            let retired = self.retired.lock().await.clone();
+            (live, retired)
+        };
+        let taken_at = SystemTime::now();
+        let sessions = futures::future::join_all(live.iter().map(|(id, handle)| async move {
+            SessionSnapshot {
+                id: *id,
+                peer_addr: handle.peer_addr(),
+                streams: handle.get_stream_count().await,
+                stats: handle.get_stats().await,
+            }
+        }))
+        .await;
+        let mut streams = 0;
+        for session in &sessions {
+            streams += session.streams;
+            total.accumulate(&session.stats);
+        }
+        MetricsSnapshot {
+            taken_at,
+            sessions,
+            total,
+            streams,
+        }
+    }
+
+    /// The registered sessions still alive, for an admin view
+    pub async fn sessions(&self) -> Vec<SessionSnapshot> {
+        self.snapshot().await.sessions
+    }
+
+    /// One line per session
+    pub async fn render_sessions(&self) -> String {
+        let mut body = String::new();
+        for session in self.sessions().await {
+            let peer_addr = match session.peer_addr {
+                Some(addr) => addr.to_string(),
+                None => "-".to_string(),
+            };
+            let _ = writeln!(
+                body,
+                "{} {} streams {} sent {} received {} retransmitted {}",
+                session.id,
+                peer_addr,
+                session.streams,
+                session.stats.bytes_sent,
+                session.stats.bytes_received,
+                session.stats.segments_retransmitted
+            );
+        }
+        body
+    }
+
+    /// Keep the counters of a handle which is about to be dropped. It's no longer a live
+    /// session from then on, even while it's still around.
+    pub async fn retire(&self, handle: &dyn StatsSource) {
+        let stats = handle.get_stats().await;
+        // An address, not a pointer, so that the future stays Send
+        let address = handle as *const dyn StatsSource as *const () as usize;
+        let mut handles = self.handles.lock().await;
+        handles.retain(|(_, registered)| {
+            registered.upgrade().map_or(false, |registered| {
+                Arc::as_ptr(&registered) as *const () as usize != address
+            })
+        });
+        self.retired.lock().await.accumulate(&stats.retired());
+    }
+
+    pub async fn render(&self) -> String {
+        let snapshot = self.snapshot().await;
+        let sessions = snapshot.sessions.len();
+        let streams = snapshot.streams;
+        let stats = snapshot.total;
+
+        let mut body = String::new();
+        let metrics = [
+            ("ap_kcp_sessions", "gauge", sessions as u64),
+            ("ap_kcp_streams", "gauge", streams as u64),
+            ("ap_kcp_bytes_sent_total", "counter", stats.bytes_sent),
+            (
+                "ap_kcp_bytes_received_total",
+                "counter",
+                stats.bytes_received,
+            ),
+            ("ap_kcp_segments_sent_total", "counter", stats.segments_sent),
+            (
+                "ap_kcp_segments_retransmitted_total",
+                "counter",
+                stats.segments_retransmitted,
+            ),
+            (
+                "ap_kcp_bytes_retransmitted_total",
+                "counter",
+                stats.bytes_retransmitted,
+            ),
+            ("ap_kcp_ecn_echoes_total", "counter", stats.ecn_echoes),
+            ("ap_kcp_flushes_total", "counter", stats.flushes),
+            (
+                "ap_kcp_window_limited_flushes_total",
+                "counter",
+                stats.window_limited_flushes,
+            ),
+            (
+                "ap_kcp_mtu_reductions_total",
+                "counter",
+                stats.mtu_reductions,
+            ),
+            ("ap_kcp_send_queue_segments", "gauge", stats.send_queue_len),
+            ("ap_kcp_send_queue_bytes", "gauge", stats.send_queue_bytes),
+            ("ap_kcp_recv_queue_segments", "gauge", stats.recv_queue_len),
+            ("ap_kcp_recv_queue_bytes", "gauge", stats.recv_queue_bytes),
+        ];
+        for (name, kind, value) in metrics.iter() {
+            let _ = writeln!(body, "# TYPE {} {}", name, kind);
+            let _ = writeln!(body, "{} {}", name, value);
+        }
+        body
+    }
+
+    async fn respond(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        let mut buf = Vec::new();
+        buf.resize(0x400, 0u8);
+        let len = stream.read(&mut buf).await?;
+        let request = String::from_utf8_lossy(&buf[..len]);
+        let body = if request.starts_with("GET /metrics ") {
+            Some(self.render().await)
+        } else if request.starts_with("GET /sessions ") {
+            Some(self.render_sessions().await)
+        } else {
+            None
+        };
+        let response = if let Some(body) = body {
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        } else {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        };
+        stream.write_all(response.as_bytes()).await?;
+        stream.close().await
+    }
+
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> std::io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let metrics = self.clone();
+            smol::spawn(async move {
+                if let Err(e) = metrics.respond(stream).await {
+                    log::error!("metrics error: {}", e);
+                }
+            })
+            .detach();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use smol::net::UdpSocket;
+
+    use super::*;
+    use crate::core::KcpConfig;
+
+    #[test]
+    fn metrics_endpoint() {
+        smol::block_on(async {
+            let io1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let io2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            io1.connect(io2.local_addr().unwrap()).await.unwrap();
+            io2.connect(io1.local_addr().unwrap()).await.unwrap();
+            let kcp1 = Arc::new(KcpHandle::new(io1, KcpConfig::default()));
+            let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+
+            let metrics = Arc::new(Metrics::default());
+            metrics.register(kcp1.clone()).await;
+
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(b"hello").await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream2.read_exact(&mut buf).await.unwrap();
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let _server = smol::spawn(metrics.clone().serve(listener));
+
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client
+                .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+            let body = response.split("\r\n\r\n").nth(1).unwrap();
+            let mut values = std::collections::HashMap::new();
+            for line in body.lines().filter(|line| !line.starts_with('#')) {
+                let mut fields = line.split(' ');
+                let name = fields.next().unwrap();
+                let value: u64 = fields.next().unwrap().parse().unwrap();
+                assert!(fields.next().is_none());
+                values.insert(name.to_string(), value);
+            }
+            assert_eq!(values["ap_kcp_sessions"], 1);
+            assert_eq!(values["ap_kcp_streams"], 1);
+            // The OPEN carries the features and the window shift
+            assert_eq!(values["ap_kcp_bytes_sent_total"], 7);
+        });
+    }
+
+    #[test]
+    fn snapshot() {
+        smol::block_on(async {
+            let metrics = Metrics::default();
+            let mut handles = Vec::new();
+            let mut streams = Vec::new();
+            for _ in 0..3 {
+                let io1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                let io2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                io1.connect(io2.local_addr().unwrap()).await.unwrap();
+                io2.connect(io1.local_addr().unwrap()).await.unwrap();
+                let kcp1 = Arc::new(KcpHandle::new(io1, KcpConfig::default()));
+                let kcp2 = KcpHandle::new(io2, KcpConfig::default());
+                metrics.register(kcp1.clone()).await;
+
+                let mut stream1 = kcp1.connect().await.unwrap();
+                stream1.write_all(&[0u8; 0x1000]).await.unwrap();
+                stream1.flush().await.unwrap();
+                let stream2 = kcp2.accept().await.unwrap();
+                handles.push((kcp1, kcp2));
+                streams.push((stream1, stream2));
+            }
+
+            // Still around, but no longer live
+            metrics.retire(&*handles[0].0).await;
+            let retired = metrics.retired.lock().await.clone();
+
+            // Keep the others busy meanwhile
+            let writers: Vec<_> = streams
+                .drain(1..)
+                .map(|(mut stream1, mut stream2)| {
+                    smol::spawn(async move {
+                        let write = async {
+                            for _ in 0..0x10 {
+                                stream1.write_all(&[0u8; 0x1000]).await.unwrap();
+                            }
+                        };
+                        let read = async {
+                            let mut buf = vec![0u8; 0x11000];
+                            stream2.read_exact(&mut buf).await.unwrap();
+                        };
+                        futures::future::join(write, read).await;
+                    })
+                })
+                .collect();
+
+            let snapshot = metrics.snapshot().await;
+            assert_eq!(snapshot.sessions.len(), 2);
+            assert!(snapshot.sessions.iter().all(|session| session.id != 0));
+            assert_eq!(snapshot.streams, 2);
+            let mut total = retired;
+            for session in &snapshot.sessions {
+                total.accumulate(&session.stats);
+            }
+            assert_eq!(snapshot.total.bytes_sent, total.bytes_sent);
+            assert_eq!(snapshot.total.segments_sent, total.segments_sent);
+            assert!(snapshot.total.bytes_sent >= 3 * 0x1000);
+            futures::future::join_all(writers).await;
+        });
+    }
+}
@@ -0,0 +1,173 @@
+//! Disguises the packets for middleboxes which throttle recognizable UDP traffic.
+//!
+//! Obfuscation hides nothing from anyone who knows the transform, the contents are
+//! protected by `CryptoLayer` only.
+
+use std::net::SocketAddr;
+
+use ring::digest;
+
+use crate::core::KcpIo;
+
+/// The transform applied to every datagram, and its inverse
+pub trait Obfuscator: Send + Sync {
+    fn obfuscate(&self, buf: &[u8]) -> Vec<u8>;
+
+    /// Restores the packet of `len` bytes in place, returns its new length, 0 if it's malformed
+    fn deobfuscate(&self, buf: &mut [u8], len: usize) -> usize;
+
+    /// Bytes added to every packet
+    fn overhead(&self) -> usize {
+        0
+    }
+}
+
+const SALT_LEN: usize = 4;
+
+/// XORs every packet with a keystream of the key and a random salt, so the packets look
+/// like noise and the same packet never looks the same twice.
+///
+/// | SALT | MASKED PACKET |
+pub struct XorObfuscator {
+    seed: u64,
+}
+
+impl XorObfuscator {
+    pub fn new(key: &[u8]) -> Self {
+        let mut context = digest::Context::new(&digest::SHA256);
+        context.update(b"ap-kcp-obfuscation");
+        context.update(key);
+        let mut seed = [0u8; 8];
+        seed.copy_from_slice(&context.finish().as_ref()[..8]);
+        Self {
+            seed: u64::from_le_bytes(seed),
+        }
+    }
+
+    /// SplitMix64, cheap and good enough to look random
+    fn mask(&self, salt: &[u8], data: &mut [u8]) {
+        let mut salt_bytes = [0u8; 8];
+        salt_bytes[..SALT_LEN].copy_from_slice(salt);
+        let mut state = self.seed ^ u64::from_le_bytes(salt_bytes);
+        for chunk in data.chunks_mut(8) {
+            state = state.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            z ^= z >> 31;
+            for (byte, key) in chunk.iter_mut().zip(z.to_le_bytes().iter()) {
+                *byte ^= key;
+            }
+        }
+    }
+}
+
+impl Obfuscator for XorObfuscator {
+    fn obfuscate(&self, buf: &[u8]) -> Vec<u8> {
+        let salt: [u8; SALT_LEN] = rand::random();
+        let mut packet = Vec::with_capacity(SALT_LEN + buf.len());
+        packet.extend_from_slice(&salt);
+        packet.extend_from_slice(buf);
+        self.mask(&salt, &mut packet[SALT_LEN..]);
+        packet
+    }
+
+    fn deobfuscate(&self, buf: &mut [u8], len: usize) -> usize {
+        if len <= SALT_LEN {
+            return 0;
+        }
+        let mut salt = [0u8; SALT_LEN];
+        salt.copy_from_slice(&buf[..SALT_LEN]);
+        buf.copy_within(SALT_LEN..len, 0);
+        self.mask(&salt, &mut buf[..len - SALT_LEN]);
+        len - SALT_LEN
+    }
+
+    fn overhead(&self) -> usize {
+        SALT_LEN
+    }
+}
+
+/// Applies the obfuscator to every datagram. Wrap the socket with it, inside `CryptoLayer`,
+/// so the nonce and tag of the crypto are disguised as well. Both ends need the same one.
+pub struct ObfuscationLayer<IO, O> {
+    io: IO,
+    obfuscator: O,
+}
+
+impl<IO: KcpIo + Send + Sync, O: Obfuscator> ObfuscationLayer<IO, O> {
+    pub fn wrap(io: IO, obfuscator: O) -> Self {
+        Self { io, obfuscator }
+    }
+}
+
+#[async_trait::async_trait]
+impl<IO: KcpIo + Send + Sync, O: Obfuscator> KcpIo for ObfuscationLayer<IO, O> {
+    async fn send_packet(&self, buf: &[u8]) -> std::io::Result<()> {
+        let packet = self.obfuscator.obfuscate(buf);
+        self.io.send_packet(&packet).await
+    }
+
+    async fn recv_packet(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.io.recv_packet(buf).await?;
+        Ok(self.obfuscator.deobfuscate(buf, len))
+    }
+
+    async fn recv_packet_ecn(&self, buf: &mut [u8]) -> std::io::Result<(usize, bool)> {
+        let (len, ce) = self.io.recv_packet_ecn(buf).await?;
+        Ok((self.obfuscator.deobfuscate(buf, len), ce))
+    }
+
+    fn overhead(&self) -> usize {
+        self.io.overhead() + self.obfuscator.overhead()
+    }
+
+    fn set_per_stream_keys(&self, enabled: bool) -> bool {
+        self.io.set_per_stream_keys(enabled)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.io.peer_addr()
+    }
+
+    async fn send_packet_to(&self, buf: &[u8], addr: SocketAddr) -> std::io::Result<()> {
+        let packet = self.obfuscator.obfuscate(buf);
+        self.io.send_packet_to(&packet, addr).await
+    }
+
+    async fn recv_packet_from(
+        &self,
+        buf: &mut [u8],
+    ) -> std::io::Result<(usize, Option<SocketAddr>)> {
+        let (len, addr) = self.io.recv_packet_from(buf).await?;
+        Ok((self.obfuscator.deobfuscate(buf, len), addr))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn xor() {
+        let obfuscator = XorObfuscator::new(b"key");
+        let packet = b"some packet with a recognizable header".to_vec();
+        let obfuscated = obfuscator.obfuscate(&packet);
+        assert_eq!(obfuscated.len(), packet.len() + obfuscator.overhead());
+        assert!(!obfuscated
+            .windows(packet.len())
+            .any(|window| window == &packet[..]));
+        // A new salt every time
+        assert_ne!(obfuscated, obfuscator.obfuscate(&packet));
+
+        let mut buf = obfuscated.clone();
+        let len = obfuscator.deobfuscate(&mut buf, obfuscated.len());
+        assert_eq!(&buf[..len], &packet[..]);
+
+        let mut buf = obfuscated.clone();
+        let len = XorObfuscator::new(b"other key").deobfuscate(&mut buf, obfuscated.len());
+        assert_ne!(&buf[..len], &packet[..]);
+
+        assert_eq!(obfuscator.deobfuscate(&mut buf, SALT_LEN), 0);
+    }
+}
@@ -0,0 +1,396 @@
+//! The stream state machine of `KcpHandle`, without io or an async runtime, for custom event
+//! loops like glommio, monoio or io_uring.
+//!
+//! `Kcp` wraps the same core the async streams run on, the caller owns the socket and the
+//! clock. Each received packet goes to `input`, `update` runs the timers and flushes, and the
+//! packets it produces are taken with `output`. `check` tells when `update` is due next.
+//! Times are milliseconds from any fixed point, passed with every call, and may wrap.
+//!
+//! A `Kcp` is one stream. The streams of a socket are told apart with `peek_stream_id`, a
+//! packet opening a new one starts an accepted `Kcp`. Datagrams are not supported.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::{Buf, Bytes};
+use futures::task::noop_waker_ref;
+use smol::channel::{bounded, Receiver};
+
+use crate::{
+    core::{i32diff, Clock, KcpConfig, KcpCore, KcpIo},
+    error::{KcpError, KcpResult},
+    segment::{KcpSegment, CMD_DATAGRAM, CMD_OPEN},
+};
+
+/// The time of the caller, as of the last call
+#[derive(Default)]
+struct CallerClock {
+    now: AtomicU32,
+}
+
+#[async_trait::async_trait]
+impl Clock for CallerClock {
+    fn now_millis(&self) -> u32 {
+        self.now.load(Ordering::Relaxed)
+    }
+
+    async fn sleep(&self, _duration: Duration) {
+        // The caller runs the timers, see `Kcp::check`
+        futures::future::pending().await
+    }
+}
+
+/// Collects the packets of a flush
+#[derive(Default)]
+struct OutputIo {
+    packets: Mutex<VecDeque<Bytes>>,
+}
+
+#[async_trait::async_trait]
+impl KcpIo for OutputIo {
+    async fn send_packet(&self, buf: &[u8]) -> std::io::Result<()> {
+        self.packets
+            .lock()
+            .unwrap()
+            .push_back(Bytes::copy_from_slice(buf));
+        Ok(())
+    }
+
+    async fn recv_packet(&self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        futures::future::pending().await
+    }
+}
+
+/// The stream id of a packet, to find its `Kcp` among the streams of a socket
+pub fn peek_stream_id(packet: &[u8], config: &KcpConfig) -> Option<u16> {
+    if config.single_stream {
+        Some(0)
+    } else if packet.len() >= KcpSegment::header_len(false) {
+        Some(KcpSegment::peek_stream_id(packet))
+    } else {
+        None
+    }
+}
+
+/// One stream driven by the caller, see the module docs
+pub struct Kcp {
+    core: KcpCore,
+    config: Arc<KcpConfig>,
+    clock: Arc<CallerClock>,
+    io: OutputIo,
+    // The core asks to be flushed at once, e.g. when its send window is full
+    flush_notify_rx: Receiver<()>,
+    next_update: u32,
+    read_buffer: VecDeque<Bytes>,
+    finished: bool,
+}
+
+impl Kcp {
+    fn new(stream_id: u16, mut config: KcpConfig, now: u32) -> KcpResult<Self> {
+        config.validate()?;
+        let clock = Arc::new(CallerClock::default());
+        clock.now.store(now, Ordering::Relaxed);
+        config.clock = clock.clone();
+        let config = Arc::new(config);
+        let (tx, rx) = bounded(1);
+        Ok(Self {
+            core: KcpCore::new(stream_id, config.clone(), tx, None, 0),
+            config,
+            clock,
+            io: OutputIo::default(),
+            flush_notify_rx: rx,
+            next_update: now,
+            read_buffer: VecDeque::new(),
+            finished: false,
+        })
+    }
+
+    /// Opens a stream, its OPEN goes out with the first `update`. `KcpConfig::clock` is
+    /// replaced by the times passed in, and `KcpConfig::mtu` has to leave room for whatever
+    /// the caller adds to the packets.
+    pub fn connect(stream_id: u16, label: &[u8], config: KcpConfig, now: u32) -> KcpResult<Self> {
+        let mut kcp = Self::new(stream_id, config, now)?;
+        kcp.core.open(Bytes::copy_from_slice(label));
+        Ok(kcp)
+    }
+
+    /// The stream the peer opens with `packet`, answered with our OPEN on the next `update`
+    pub fn accept(packet: &[u8], config: KcpConfig, now: u32) -> KcpResult<Self> {
+        let segments = Self::decode(packet, config.single_stream)?;
+        // The OPEN may share the packet with a keep-alive PING flushed before it
+        let opening = segments.iter().find(|segment| {
+            segment.command == CMD_OPEN && segment.recv_next == config.initial_sequence
+        });
+        let stream_id = match opening {
+            Some(segment) => segment.stream_id,
+            None => {
+                return Err(KcpError::MalformedSegment(
+                    "the packet doesn't open a stream".to_string(),
+                ))
+            }
+        };
+        let mut kcp = Self::new(stream_id, config, now)?;
+        kcp.core.input(segments)?;
+        // One failing authentication is reset by the next update
+        if !kcp.core.is_auth_failed() {
+            let label = kcp.core.get_label();
+            kcp.core.open(label);
+        }
+        Ok(kcp)
+    }
+
+    fn decode(mut packet: &[u8], single_stream: bool) -> KcpResult<Vec<KcpSegment>> {
+        let mut segments = Vec::new();
+        while packet.has_remaining() {
+            let segment = KcpSegment::decode_framed(packet, single_stream)?;
+            packet.advance(segment.framed_len(single_stream));
+            if segment.command != CMD_DATAGRAM {
+                segments.push(segment);
+            }
+        }
+        Ok(segments)
+    }
+
+    #[inline]
+    pub fn stream_id(&self) -> u16 {
+        self.core.get_stream_id()
+    }
+
+    /// The label of the stream, the peer's once its OPEN has arrived
+    pub fn label(&self) -> Bytes {
+        self.core.get_label()
+    }
+
+    fn set_now(&self, now: u32) {
+        self.clock.now.store(now, Ordering::Relaxed);
+    }
+
+    /// Handles a packet from the peer. Packets of other streams are ignored.
+    pub fn input(&mut self, packet: &[u8], now: u32) -> KcpResult<()> {
+        self.set_now(now);
+        let segments = Self::decode(packet, self.config.single_stream)?;
+        if segments
+            .iter()
+            .any(|segment| segment.stream_id != self.stream_id())
+        {
+            return Ok(());
+        }
+        if segments.is_empty() {
+            return Ok(());
+        }
+        self.core.input(segments)
+    }
+
+    /// Runs the timers and flushes, the packets are then ready in `output`. It fails once the
+    /// stream is over, closed, reset or timed out, and nothing is left to do.
+    pub fn update(&mut self, now: u32) -> KcpResult<()> {
+        if self.finished {
+            return Err(KcpError::Shutdown("the stream is over".to_string()));
+        }
+        self.set_now(now);
+        while self.flush_notify_rx.try_recv().is_ok() {}
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let flushed = {
+            let mut flush = Box::pin(self.core.flush(&self.io));
+            match flush.as_mut().poll(&mut cx) {
+                Poll::Ready(flushed) => flushed,
+                Poll::Pending => unreachable!("flushing into memory never waits"),
+            }
+        };
+        if let Err(e) = flushed {
+            self.finished = true;
+            return Err(e);
+        }
+        self.next_update = now.wrapping_add(self.core.get_interval());
+        Ok(())
+    }
+
+    /// When `update` is due. It's at once, a time not after the last call, when the stream
+    /// asks for a flush, e.g. after `send` or `input`.
+    pub fn check(&self, now: u32) -> u32 {
+        if !self.flush_notify_rx.is_empty() || i32diff(self.next_update, now) < 0 {
+            now
+        } else {
+            self.next_update
+        }
+    }
+
+    /// The next packet to send to the peer
+    pub fn output(&mut self) -> Option<Bytes> {
+        self.io.packets.lock().unwrap().pop_front()
+    }
+
+    /// Queues `data` whole, false when the send window is full. `update` makes room as the
+    /// peer acks.
+    pub fn send(&mut self, data: &[u8], now: u32) -> KcpResult<bool> {
+        self.set_now(now);
+        let cx = Context::from_waker(noop_waker_ref());
+        match self.core.poll_send(&cx, data) {
+            Poll::Ready(sent) => sent.map(|_| true),
+            Poll::Pending => Ok(false),
+        }
+    }
+
+    /// Reads what arrived in order. None when nothing did, `Some(0)` at the end of the stream.
+    pub fn recv(&mut self, buf: &mut [u8], now: u32) -> KcpResult<Option<usize>> {
+        self.set_now(now);
+        if self.read_buffer.is_empty() {
+            let cx = Context::from_waker(noop_waker_ref());
+            match self.core.poll_recv(&cx) {
+                Poll::Ready(Ok(payloads)) if payloads.is_empty() => return Ok(Some(0)),
+                Poll::Ready(Ok(payloads)) => self.read_buffer = payloads,
+                Poll::Ready(Err(e)) => return Err(e),
+                Poll::Pending => return Ok(None),
+            }
+        }
+        let mut len = 0;
+        while len < buf.len() {
+            let payload = match self.read_buffer.front_mut() {
+                Some(payload) => payload,
+                None => break,
+            };
+            let size = std::cmp::min(payload.remaining(), buf.len() - len);
+            payload.copy_to_slice(&mut buf[len..len + size]);
+            len += size;
+            if !payload.has_remaining() {
+                self.read_buffer.pop_front();
+            }
+        }
+        Ok(Some(len))
+    }
+
+    /// Closes the stream once everything sent is acked, `update` then fails
+    pub fn close(&mut self, now: u32) -> KcpResult<()> {
+        self.set_now(now);
+        self.core.try_close()
+    }
+
+    /// Aborts the stream, the peer fails with `KcpError::PeerReset`
+    pub fn reset(&mut self, code: u32, reason: &str, now: u32) {
+        self.set_now(now);
+        self.core.reset(code, reason);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::KcpHandle;
+    use smol::{future::FutureExt, io::AsyncReadExt, io::AsyncWriteExt, net::UdpSocket, Timer};
+
+    /// Delivers the output of `from`, dropping every `drop_every`th packet
+    fn deliver(from: &mut Kcp, to: &mut Kcp, now: u32, packets: &mut usize, drop_every: usize) {
+        while let Some(packet) = from.output() {
+            *packets += 1;
+            if *packets % drop_every != 0 {
+                to.input(&packet, now).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn manual_clock() {
+        let data: Vec<u8> = (0..0x20000u32).map(|i| (i * 31 % 251) as u8).collect();
+        let mut now = 0u32;
+        let mut client = Kcp::connect(1, b"label", KcpConfig::default(), now).unwrap();
+        client.update(now).unwrap();
+        let open = client.output().unwrap();
+        assert_eq!(peek_stream_id(&open, &KcpConfig::default()), Some(1));
+        let mut server = Kcp::accept(&open, KcpConfig::default(), now).unwrap();
+        assert_eq!(&server.label()[..], b"label");
+
+        let mut sent = 0;
+        let mut closed = false;
+        let mut received = Vec::new();
+        let mut packets = 0;
+        let mut buf = [0u8; 0x1000];
+        loop {
+            while sent < data.len() {
+                let end = std::cmp::min(sent + 0x400, data.len());
+                if !client.send(&data[sent..end], now).unwrap() {
+                    break;
+                }
+                sent = end;
+            }
+            if sent == data.len() && !closed {
+                client.close(now).unwrap();
+                closed = true;
+            }
+            // Both fail once the stream is closed
+            let _ = client.update(now);
+            let _ = server.update(now);
+            deliver(&mut client, &mut server, now, &mut packets, 7);
+            deliver(&mut server, &mut client, now, &mut packets, 7);
+            match server.recv(&mut buf, now).unwrap() {
+                Some(0) => break,
+                Some(len) => received.extend_from_slice(&buf[..len]),
+                None => {}
+            }
+            let next = std::cmp::min(client.check(now), server.check(now));
+            now = std::cmp::max(next, now + 1);
+            assert!(now < 600_000, "the transfer stalled");
+        }
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn with_kcp_handle() {
+        smol::block_on(async {
+            let udp1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let udp2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            udp1.connect(udp2.local_addr().unwrap()).await.unwrap();
+            udp2.connect(udp1.local_addr().unwrap()).await.unwrap();
+            let handle = KcpHandle::new(udp2, KcpConfig::default());
+            let echo = smol::spawn(async move {
+                let mut stream = handle.accept().await.unwrap();
+                let mut buf = [0u8; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                stream.write_all(&buf).await.unwrap();
+                stream.flush().await.unwrap();
+                handle
+            });
+
+            let clock = crate::SystemClock;
+            let mut kcp = Kcp::connect(7, b"", KcpConfig::default(), clock.now_millis()).unwrap();
+            assert!(kcp.send(b"hello", clock.now_millis()).unwrap());
+            let mut received = Vec::new();
+            let mut buf = [0u8; 0x1000];
+            while received.len() < 5 {
+                let now = clock.now_millis();
+                kcp.update(now).unwrap();
+                while let Some(packet) = kcp.output() {
+                    udp1.send(&packet).await.unwrap();
+                }
+                let wait = kcp.check(now).wrapping_sub(now);
+                let packet = async { Some(udp1.recv(&mut buf).await.unwrap()) };
+                let timeout = async {
+                    Timer::after(Duration::from_millis(wait as u64)).await;
+                    None
+                };
+                if let Some(len) = packet.or(timeout).await {
+                    let packet = buf[..len].to_vec();
+                    kcp.input(&packet, clock.now_millis()).unwrap();
+                }
+                let mut read = [0u8; 5];
+                if let Some(len) = kcp.recv(&mut read, clock.now_millis()).unwrap() {
+                    received.extend_from_slice(&read[..len]);
+                }
+            }
+            assert_eq!(&received[..], b"hello");
+            // The ACKs of the echo, the handle's flush waits for them
+            kcp.update(clock.now_millis()).unwrap();
+            while let Some(packet) = kcp.output() {
+                udp1.send(&packet).await.unwrap();
+            }
+            drop(echo.await);
+        });
+    }
+}
@@ -2,10 +2,30 @@ use bytes::{Buf, BufMut, Bytes, BytesMut};
 
 use crate::error::{KcpError, KcpResult};
 
-pub const HEADER_SIZE: usize = 2 + 1 + 2 + 4 + 4 + 4 + 2;
+/// Every segment starts with a header of this size, the rest of the mtu is left for data
+pub const KCP_HEADER_LEN: usize = 2 + 1 + 2 + 4 + 4 + 4 + 2;
+/// The header without the stream id, see `KcpConfig::single_stream`
+pub const SINGLE_STREAM_HEADER_LEN: usize = KCP_HEADER_LEN - 2;
 pub const CMD_PUSH: u8 = 1;
 pub const CMD_ACK: u8 = 2;
 pub const CMD_PING: u8 = 3;
+pub const CMD_OPEN: u8 = 4;
+pub const CMD_DATAGRAM: u8 = 5;
+pub const CMD_SKIP: u8 = 6;
+pub const CMD_ACK_DELAY: u8 = 7;
+/// The receiver saw packets marked Congestion Experienced
+pub const CMD_ECN_ECHO: u8 = 8;
+/// FIN of the sender's write side only, the receiver keeps writing until it closes too
+pub const CMD_HALF_CLOSE: u8 = 9;
+/// Aborts the stream, carrying an error code and a reason. It's not retransmitted.
+pub const CMD_RESET: u8 = 10;
+/// Asks the receiver to report its window, which it does with a PING at once
+pub const CMD_WINDOW_PROBE: u8 = 11;
+/// A piece of a sent segment which exceeds the mtu lowered since, under the same sequence
+/// number. The payload starts with the length of the whole segment and the piece's offset.
+pub const CMD_FRAGMENT: u8 = 12;
+/// | TOTAL LENGTH | OFFSET |, both u16 le
+pub const FRAGMENT_HEADER_LEN: usize = 2 + 2;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub(crate) struct KcpSegment {
@@ -21,7 +41,8 @@ pub(crate) struct KcpSegment {
 impl KcpSegment {
     fn check_command(commmand: u8) -> KcpResult<()> {
         match commmand {
-            CMD_ACK | CMD_PUSH | CMD_PING => Ok(()),
+            CMD_ACK | CMD_PUSH | CMD_PING | CMD_OPEN | CMD_DATAGRAM | CMD_SKIP | CMD_ACK_DELAY
+            | CMD_ECN_ECHO | CMD_HALF_CLOSE | CMD_RESET | CMD_WINDOW_PROBE | CMD_FRAGMENT => Ok(()),
             _ => Err(KcpError::UnsupportCmd(commmand)),
         }
     }
@@ -31,8 +52,33 @@ impl KcpSegment {
         packet.get_u16_le()
     }
 
-    pub fn decode(mut packet: &[u8]) -> KcpResult<Self> {
-        let stream_id = packet.get_u16_le();
+    #[inline]
+    pub fn header_len(single_stream: bool) -> usize {
+        if single_stream {
+            SINGLE_STREAM_HEADER_LEN
+        } else {
+            KCP_HEADER_LEN
+        }
+    }
+
+    #[cfg(any(test, feature = "fuzz"))]
+    pub fn decode(packet: &[u8]) -> KcpResult<Self> {
+        Self::decode_framed(packet, false)
+    }
+
+    /// Without the stream id in `single_stream` mode, the segment then belongs to stream 0
+    pub fn decode_framed(mut packet: &[u8], single_stream: bool) -> KcpResult<Self> {
+        if packet.len() < Self::header_len(single_stream) {
+            return Err(KcpError::MalformedSegment(format!(
+                "truncated header of {} bytes",
+                packet.len()
+            )));
+        }
+        let stream_id = if single_stream {
+            0
+        } else {
+            packet.get_u16_le()
+        };
         let command = packet.get_u8();
         Self::check_command(command)?;
         let recv_window_size = packet.get_u16_le();
@@ -41,10 +87,11 @@ impl KcpSegment {
         let recv_next = packet.get_u32_le();
         let len = packet.get_u16_le();
         if packet.remaining() < len as usize {
-            return Err(KcpError::InvalidSegmentDataSize(
-                len as usize,
-                packet.remaining(),
-            ));
+            return Err(KcpError::MalformedSegment(format!(
+                "payload length {} exceeds the remaining {} bytes",
+                len,
+                packet.remaining()
+            )));
         }
 
         let data = packet.copy_to_bytes(len as usize);
@@ -53,6 +100,10 @@ impl KcpSegment {
             return Err(KcpError::InvalidSegmentDataSize(8, len as usize));
         }
 
+        if command == CMD_ACK_DELAY && (len == 0 || len % 12 != 0) {
+            return Err(KcpError::InvalidSegmentDataSize(12, len as usize));
+        }
+
         let segment = Self {
             stream_id,
             command,
@@ -65,8 +116,15 @@ impl KcpSegment {
         Ok(segment)
     }
 
+    #[cfg(any(test, feature = "fuzz"))]
     pub fn encode(&self, buf: &mut BytesMut) {
-        buf.put_u16_le(self.stream_id);
+        self.encode_framed(buf, false)
+    }
+
+    pub fn encode_framed(&self, buf: &mut BytesMut, single_stream: bool) {
+        if !single_stream {
+            buf.put_u16_le(self.stream_id);
+        }
         buf.put_u8(self.command);
         buf.put_u16_le(self.recv_window_size);
         buf.put_u32_le(self.timestamp);
@@ -78,7 +136,12 @@ impl KcpSegment {
 
     #[inline]
     pub fn encoded_len(&self) -> usize {
-        HEADER_SIZE + self.data.len()
+        KCP_HEADER_LEN + self.data.len()
+    }
+
+    #[inline]
+    pub fn framed_len(&self, single_stream: bool) -> usize {
+        Self::header_len(single_stream) + self.data.len()
     }
 }
 
@@ -105,4 +168,78 @@ mod test {
 
         assert_eq!(segment1, segment2);
     }
+
+    fn encoded_ack() -> BytesMut {
+        let segment = KcpSegment {
+            stream_id: 1234,
+            command: CMD_ACK,
+            recv_window_size: 100,
+            timestamp: 1,
+            recv_next: 123,
+            sequence: 2,
+            data: Bytes::copy_from_slice(&[0u8; 8]),
+        };
+        let mut buf = BytesMut::new();
+        segment.encode(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn truncated() {
+        let buf = encoded_ack();
+        for len in 0..KCP_HEADER_LEN {
+            match KcpSegment::decode(&buf[..len]) {
+                Err(KcpError::MalformedSegment(_)) => {}
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        // A complete header with a partial payload
+        for len in KCP_HEADER_LEN..buf.len() {
+            assert!(KcpSegment::decode(&buf[..len]).is_err());
+        }
+        assert!(KcpSegment::decode(&buf).is_ok());
+    }
+
+    #[test]
+    fn lying_length() {
+        let mut buf = encoded_ack();
+        for len in [9u16, 0x100, u16::MAX].iter() {
+            buf[KCP_HEADER_LEN - 2..KCP_HEADER_LEN].copy_from_slice(&len.to_le_bytes());
+            match KcpSegment::decode(&buf) {
+                Err(KcpError::MalformedSegment(_)) => {}
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_command() {
+        let mut buf = encoded_ack();
+        buf[2] = 0xff;
+        match KcpSegment::decode(&buf) {
+            Err(KcpError::UnsupportCmd(0xff)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn single_stream() {
+        let segment = KcpSegment {
+            stream_id: 0,
+            command: CMD_PUSH,
+            recv_window_size: 100,
+            timestamp: 1,
+            recv_next: 123,
+            sequence: 2,
+            data: Bytes::copy_from_slice(b"hello_world!"),
+        };
+        let mut multiplexed = BytesMut::new();
+        segment.encode(&mut multiplexed);
+        let mut single = BytesMut::new();
+        segment.encode_framed(&mut single, true);
+        assert_eq!(single.len(), segment.framed_len(true));
+        assert_eq!(single.len() + 2, multiplexed.len());
+        assert_eq!(&single[..], &multiplexed[2..]);
+        assert_eq!(KcpSegment::decode_framed(&single, true).unwrap(), segment);
+    }
 }
@@ -0,0 +1,570 @@
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::{
+    convert::TryFrom,
+    future::Future,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use smol::{
+    net::{resolve, AsyncToSocketAddrs, UdpSocket},
+    Timer,
+};
+#[cfg(unix)]
+use socket2::SockAddr;
+use socket2::{Domain, Protocol, Socket, Type};
+
+#[derive(Clone, Default)]
+pub struct UdpOptions {
+    /// SO_RCVBUF, the OS may clamp or round it
+    pub recv_buffer_size: Option<usize>,
+    /// SO_SNDBUF, the OS may clamp or round it
+    pub send_buffer_size: Option<usize>,
+    /// DSCP of outgoing packets, set through IP_TOS or IPV6_TCLASS. It's 6 bits, 0 to 63.
+    pub dscp: Option<u8>,
+    /// Mark outgoing packets ECN-capable, and receive the ECN bits of incoming ones with
+    /// `recv_from_ecn`. Only supported on unix.
+    pub ecn: bool,
+    /// SO_BINDTODEVICE, only send and receive through this network interface whatever the
+    /// routes say. Only supported on linux, and it needs CAP_NET_RAW.
+    pub interface: Option<String>,
+}
+
+pub const MAX_DSCP: u8 = 0x3f;
+
+// The lower 2 bits of the TOS or traffic class
+const ECN_MASK: u8 = 0b11;
+const ECN_ECT0: u8 = 0b10;
+const ECN_CE: u8 = 0b11;
+
+#[cfg(unix)]
+fn set_tclass_v6(socket: &Socket, tclass: u8) -> io::Result<()> {
+    let tclass = tclass as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_TCLASS,
+            &tclass as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_tclass_v6(_socket: &Socket, _tclass: u8) -> io::Result<()> {
+    Err(io::Error::new(
+        ErrorKind::Other,
+        "IPV6_TCLASS is only supported on unix",
+    ))
+}
+
+#[cfg(unix)]
+fn set_int_option(socket: &Socket, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+    let value: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Deliver the TOS or traffic class of every packet, read by `recv_from_ecn`
+#[cfg(unix)]
+fn enable_recv_tos(socket: &Socket, ipv6: bool) -> io::Result<()> {
+    if ipv6 {
+        set_int_option(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS)?;
+        // For the ipv4-mapped peers of a dual stack socket, not supported everywhere
+        let _ = set_int_option(socket, libc::IPPROTO_IP, libc::IP_RECVTOS);
+        Ok(())
+    } else {
+        set_int_option(socket, libc::IPPROTO_IP, libc::IP_RECVTOS)
+    }
+}
+
+#[cfg(not(unix))]
+fn enable_recv_tos(_socket: &Socket, _ipv6: bool) -> io::Result<()> {
+    Err(io::Error::new(
+        ErrorKind::Other,
+        "receiving the ECN bits is only supported on unix",
+    ))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_device(socket: &Socket, interface: &str) -> io::Result<()> {
+    socket
+        .bind_device(Some(interface.as_bytes()))
+        .map_err(|e| {
+            let reason = match e.raw_os_error() {
+                Some(libc::EPERM) => "it needs CAP_NET_RAW".to_string(),
+                Some(libc::ENODEV) => "no such interface".to_string(),
+                _ => e.to_string(),
+            };
+            io::Error::new(
+                e.kind(),
+                format!("failed to bind to interface {}: {}", interface, reason),
+            )
+        })?;
+    log::info!("udp socket bound to interface {}", interface);
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_device(_socket: &Socket, interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        ErrorKind::Other,
+        format!(
+            "failed to bind to interface {}: SO_BINDTODEVICE is only supported on linux",
+            interface
+        ),
+    ))
+}
+
+fn apply_tos(socket: &Socket, dscp: u8, ecn: bool, ipv6: bool) -> io::Result<()> {
+    if dscp > MAX_DSCP {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("dscp {} is out of range 0 to {}", dscp, MAX_DSCP),
+        ));
+    }
+    // The lower 2 bits are ECN
+    let tos = dscp << 2 | if ecn { ECN_ECT0 } else { 0 };
+    if ipv6 {
+        set_tclass_v6(socket, tos)?;
+    } else {
+        socket.set_tos(tos as u32)?;
+    }
+    log::info!("udp socket dscp = {}, ecn = {}", dscp, ecn);
+    Ok(())
+}
+
+fn apply_options(socket: &Socket, options: &UdpOptions, ipv6: bool) -> io::Result<()> {
+    if let Some(interface) = &options.interface {
+        bind_device(socket, interface)?;
+    }
+    if options.dscp.is_some() || options.ecn {
+        apply_tos(socket, options.dscp.unwrap_or(0), options.ecn, ipv6)?;
+    }
+    if options.ecn {
+        enable_recv_tos(socket, ipv6)?;
+    }
+    if let Some(size) = options.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = options.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    log::info!(
+        "udp socket buffer: recv = {}, send = {}",
+        socket.recv_buffer_size()?,
+        socket.send_buffer_size()?
+    );
+    Ok(())
+}
+
+pub async fn bind_udp<A: AsyncToSocketAddrs>(
+    addr: A,
+    options: &UdpOptions,
+) -> io::Result<UdpSocket> {
+    let addr = resolve(addr)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no address to bind"))?;
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    apply_options(&socket, options, addr.is_ipv6())?;
+    socket.bind(&addr.into())?;
+    UdpSocket::try_from(std::net::UdpSocket::from(socket))
+}
+
+/// Take over an inherited udp socket, e.g. from systemd socket activation.
+/// It's used as is, without binding.
+///
+/// The ownership of `fd` moves to the returned socket.
+#[cfg(unix)]
+pub fn udp_from_fd(fd: RawFd, options: &UdpOptions) -> io::Result<UdpSocket> {
+    let socket = unsafe { Socket::from_raw_fd(fd) };
+    if socket.r#type()? != Type::DGRAM {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("fd {} is not a udp socket", fd),
+        ));
+    }
+    let ipv6 = socket
+        .local_addr()?
+        .as_socket()
+        .map_or(false, |addr| addr.is_ipv6());
+    apply_options(&socket, options, ipv6)?;
+    UdpSocket::try_from(std::net::UdpSocket::from(socket))
+}
+
+#[cfg(unix)]
+fn recvmsg_ecn(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, bool)> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // Room for one TOS or traffic class message
+    let mut control = [0u8; 64];
+    let ((size, tos), addr) = unsafe {
+        SockAddr::init(|storage, len| {
+            let mut msg: libc::msghdr = std::mem::zeroed();
+            msg.msg_name = storage as *mut libc::c_void;
+            msg.msg_namelen = *len;
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = control.len() as _;
+            let size = libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_DONTWAIT);
+            if size < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            *len = msg.msg_namelen;
+
+            let mut tos = 0u8;
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                let level = (*cmsg).cmsg_level;
+                let kind = (*cmsg).cmsg_type;
+                let data = libc::CMSG_DATA(cmsg);
+                if level == libc::IPPROTO_IP && (kind == libc::IP_TOS || kind == libc::IP_RECVTOS) {
+                    tos = *data;
+                } else if level == libc::IPPROTO_IPV6 && kind == libc::IPV6_TCLASS {
+                    tos = (data as *const libc::c_int).read_unaligned() as u8;
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+            Ok((size as usize, tos))
+        })?
+    };
+    let addr = addr
+        .as_socket()
+        .ok_or_else(|| io::Error::new(ErrorKind::Other, "packet from a non-ip address"))?;
+    Ok((size, addr, tos & ECN_MASK == ECN_CE))
+}
+
+/// Receives a packet like `recv_from`, also telling whether it's marked Congestion
+/// Experienced. The ECN bits are only delivered on sockets set up with `UdpOptions::ecn`.
+#[cfg(unix)]
+pub async fn recv_from_ecn(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, bool)> {
+    loop {
+        // Wait for a packet without taking it, smol has no recvmsg
+        socket.peek_from(&mut [0u8; 1]).await?;
+        match recvmsg_ecn(socket, buf) {
+            // Taken by another task in between
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            result => return result,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn recv_from_ecn(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, bool)> {
+    let (size, addr) = socket.recv_from(buf).await?;
+    Ok((size, addr, false))
+}
+
+// A send failing with a transient error is retried this many times, the backoff doubling
+// from SEND_RETRY_BACKOFF
+const SEND_RETRIES: usize = 4;
+const SEND_RETRY_BACKOFF: Duration = Duration::from_millis(1);
+
+/// The socket is momentarily out of buffers, the same send is likely to succeed shortly
+pub fn is_transient_send_error(e: &io::Error) -> bool {
+    match e.kind() {
+        ErrorKind::WouldBlock | ErrorKind::Interrupted => true,
+        #[cfg(unix)]
+        _ => matches!(
+            e.raw_os_error(),
+            Some(libc::ENOBUFS) | Some(libc::ENOMEM) | Some(libc::EAGAIN)
+        ),
+        #[cfg(not(unix))]
+        _ => false,
+    }
+}
+
+/// Sends through `send`, retrying transient errors a few times with a short backoff. Other
+/// errors and a transient one lasting through all retries are returned.
+pub async fn send_retrying<F, Fut>(mut send: F) -> io::Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<()>>,
+{
+    let mut backoff = SEND_RETRY_BACKOFF;
+    for _ in 0..SEND_RETRIES {
+        match send().await {
+            Err(e) if is_transient_send_error(&e) => {
+                log::debug!("transient send error, retrying: {}", e);
+                Timer::after(backoff).await;
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+    send().await
+}
+
+#[cfg(test)]
+mod test {
+    use socket2::SockRef;
+
+    use super::*;
+
+    #[test]
+    fn buffer_size() {
+        smol::block_on(async {
+            let options = UdpOptions {
+                recv_buffer_size: Some(0x10000),
+                send_buffer_size: Some(0x10000),
+                ..Default::default()
+            };
+            let udp = bind_udp("127.0.0.1:0", &options).await.unwrap();
+            let socket = SockRef::from(&udp);
+            assert!(socket.recv_buffer_size().unwrap() >= 0x10000);
+            assert!(socket.send_buffer_size().unwrap() >= 0x10000);
+        });
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn interface() {
+        smol::block_on(async {
+            // Unprivileged, it fails with a clear error rather than binding somewhere else
+            let options = UdpOptions {
+                interface: Some("lo".to_string()),
+                ..Default::default()
+            };
+            match bind_udp("127.0.0.1:0", &options).await {
+                Ok(udp) => {
+                    assert_eq!(
+                        SockRef::from(&udp).device().unwrap().as_deref(),
+                        Some(&b"lo"[..])
+                    );
+                }
+                Err(e) => {
+                    assert!(e.to_string().contains("interface lo"));
+                    assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+                }
+            }
+
+            let options = UdpOptions {
+                interface: Some("ap-kcp-none0".to_string()),
+                ..Default::default()
+            };
+            let err = bind_udp("127.0.0.1:0", &options).await.unwrap_err();
+            assert!(err.to_string().contains("interface ap-kcp-none0"));
+        });
+    }
+
+    #[test]
+    fn dscp() {
+        smol::block_on(async {
+            let options = UdpOptions {
+                dscp: Some(46),
+                ..Default::default()
+            };
+            let udp = bind_udp("127.0.0.1:0", &options).await.unwrap();
+            let socket = SockRef::from(&udp);
+            assert_eq!(socket.tos().unwrap(), 46 << 2);
+
+            #[cfg(unix)]
+            {
+                let udp = bind_udp("[::1]:0", &options).await.unwrap();
+                let mut tclass: libc::c_int = 0;
+                let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+                let ret = unsafe {
+                    libc::getsockopt(
+                        udp.as_raw_fd(),
+                        libc::IPPROTO_IPV6,
+                        libc::IPV6_TCLASS,
+                        &mut tclass as *mut libc::c_int as *mut libc::c_void,
+                        &mut len,
+                    )
+                };
+                assert_eq!(ret, 0);
+                assert_eq!(tclass, 46 << 2);
+            }
+
+            let options = UdpOptions {
+                dscp: Some(64),
+                ..Default::default()
+            };
+            let err = bind_udp("127.0.0.1:0", &options).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ecn() {
+        smol::block_on(async {
+            let options = UdpOptions {
+                dscp: Some(46),
+                ecn: true,
+                ..Default::default()
+            };
+            let receiver = bind_udp("127.0.0.1:0", &options).await.unwrap();
+            assert_eq!(SockRef::from(&receiver).tos().unwrap(), 46 << 2 | 0b10);
+
+            // What a congested router does to an ECN-capable packet
+            let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = receiver.local_addr().unwrap();
+            SockRef::from(&sender).set_tos(0b11).unwrap();
+            sender.send_to(b"marked", addr).await.unwrap();
+            SockRef::from(&sender).set_tos(0b10).unwrap();
+            sender.send_to(b"capable", addr).await.unwrap();
+
+            let mut buf = [0u8; 16];
+            let (size, from, ce) = recv_from_ecn(&receiver, &mut buf).await.unwrap();
+            assert_eq!(&buf[..size], b"marked");
+            assert_eq!(from, sender.local_addr().unwrap());
+            assert!(ce);
+            let (size, _, ce) = recv_from_ecn(&receiver, &mut buf).await.unwrap();
+            assert_eq!(&buf[..size], b"capable");
+            assert!(!ce);
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn inherited_fd() {
+        use std::os::unix::io::IntoRawFd;
+
+        use futures::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::{async_kcp::KcpHandle, core::KcpConfig};
+
+        smol::block_on(async {
+            let inherited = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            inherited.connect(udp.local_addr().unwrap()).unwrap();
+            udp.connect(inherited.local_addr().unwrap()).await.unwrap();
+            let addr = inherited.local_addr().unwrap();
+
+            let inherited = udp_from_fd(inherited.into_raw_fd(), &UdpOptions::default()).unwrap();
+            assert_eq!(inherited.local_addr().unwrap(), addr);
+
+            let kcp1 = KcpHandle::new(inherited, KcpConfig::default());
+            let kcp2 = KcpHandle::new(udp, KcpConfig::default());
+            let mut stream1 = kcp1.connect().await.unwrap();
+            stream1.write_all(b"hello").await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream2.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn send_retry() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use futures::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::{
+            async_kcp::KcpHandle,
+            core::{KcpConfig, KcpIo},
+        };
+
+        // Two of every three sends fail as if the socket ran out of buffers
+        struct FlakyIo {
+            udp: UdpSocket,
+            attempts: AtomicUsize,
+        }
+
+        impl FlakyIo {
+            async fn try_send(&self, buf: &[u8]) -> io::Result<()> {
+                if self.attempts.fetch_add(1, Ordering::Relaxed) % 3 != 0 {
+                    return Err(io::Error::from_raw_os_error(libc::ENOBUFS));
+                }
+                self.udp.send(buf).await?;
+                Ok(())
+            }
+        }
+
+        #[async_trait::async_trait]
+        impl KcpIo for FlakyIo {
+            async fn send_packet(&self, buf: &[u8]) -> io::Result<()> {
+                send_retrying(|| self.try_send(buf)).await
+            }
+
+            async fn recv_packet(&self, buf: &mut [u8]) -> io::Result<usize> {
+                self.udp.recv(buf).await
+            }
+
+            fn peer_addr(&self) -> Option<SocketAddr> {
+                self.udp.peer_addr().ok()
+            }
+        }
+
+        smol::block_on(async {
+            let udp1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let udp2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            udp1.connect(udp2.local_addr().unwrap()).await.unwrap();
+            udp2.connect(udp1.local_addr().unwrap()).await.unwrap();
+            let flaky1 = FlakyIo {
+                udp: udp1,
+                attempts: AtomicUsize::new(0),
+            };
+            let flaky2 = FlakyIo {
+                udp: udp2,
+                attempts: AtomicUsize::new(0),
+            };
+
+            let kcp1 = KcpHandle::new(flaky1, KcpConfig::default());
+            let kcp2 = KcpHandle::new(flaky2, KcpConfig::default());
+            let mut stream1 = kcp1.connect().await.unwrap();
+            let data = vec![0x42u8; 0x10000];
+            stream1.write_all(&data).await.unwrap();
+            let mut stream2 = kcp2.accept().await.unwrap();
+            let mut buf = vec![0u8; data.len()];
+            stream2.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, data);
+            stream2.write_all(b"hello").await.unwrap();
+            stream1.read_exact(&mut buf[..5]).await.unwrap();
+            assert_eq!(&buf[..5], b"hello");
+
+            // Fatal errors are not retried
+            let attempts = AtomicUsize::new(0);
+            let err = send_retrying(|| async {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                Err(io::Error::from_raw_os_error(libc::EINVAL))
+            })
+            .await
+            .unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+            assert_eq!(attempts.load(Ordering::Relaxed), 1);
+
+            // Nor is a transient error forever
+            attempts.store(0, Ordering::Relaxed);
+            send_retrying(|| async {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                Err(io::Error::from_raw_os_error(libc::ENOBUFS))
+            })
+            .await
+            .unwrap_err();
+            assert_eq!(attempts.load(Ordering::Relaxed), SEND_RETRIES + 1);
+        });
+    }
+}
@@ -0,0 +1,79 @@
+//! Spans of the `tracing` feature: a handle runs in a `session` span, and each of its streams
+//! in a `stream` span inside it, which records how far the sequence numbers went. Both carry
+//! the `conn_id` of the handle, unique in the process, which tells apart the sessions without
+//! a peer address. Without the feature the spans are empty and cost nothing.
+
+use std::net::SocketAddr;
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::{Instrument, Span};
+
+#[cfg(feature = "tracing")]
+pub(crate) fn session(conn_id: u64, peer: Option<SocketAddr>) -> Span {
+    tracing::info_span!("session", conn_id, peer = ?peer)
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn stream(
+    session: &Span,
+    conn_id: u64,
+    stream_id: u16,
+    peer: Option<SocketAddr>,
+) -> Span {
+    tracing::info_span!(
+        parent: session,
+        "stream",
+        conn_id,
+        stream_id,
+        peer = ?peer,
+        send_una = tracing::field::Empty,
+        send_next = tracing::field::Empty,
+        recv_next = tracing::field::Empty
+    )
+}
+
+/// Records the sequence numbers of a stream in the current span, the stream's own when
+/// flushed by its update task
+#[cfg(feature = "tracing")]
+pub(crate) fn record_sequences(send_una: u32, send_next: u32, recv_next: u32) {
+    let span = Span::current();
+    span.record("send_una", &send_una);
+    span.record("send_next", &send_next);
+    span.record("recv_next", &recv_next);
+}
+
+#[cfg(not(feature = "tracing"))]
+#[derive(Clone)]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) trait Instrument: Sized {
+    #[inline(always)]
+    fn instrument(self, _span: Span) -> Self {
+        self
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl<T> Instrument for T {}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub(crate) fn session(_conn_id: u64, _peer: Option<SocketAddr>) -> Span {
+    Span
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub(crate) fn stream(
+    _session: &Span,
+    _conn_id: u64,
+    _stream_id: u16,
+    _peer: Option<SocketAddr>,
+) -> Span {
+    Span
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub(crate) fn record_sequences(_send_una: u32, _send_next: u32, _recv_next: u32) {}
@@ -0,0 +1,241 @@
+//! A bounded single-producer single-consumer queue.
+//!
+//! It hands packets from the task reading a shared udp socket to the sessions. There is
+//! exactly one producer and one consumer, so a push or a pop is a couple of atomic
+//! operations, without the locks and the waiter lists of a multi-producer channel.
+
+use std::{
+    cell::UnsafeCell,
+    fmt,
+    mem::MaybeUninit,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use futures::task::AtomicWaker;
+
+struct Ring<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    mask: usize,
+    // Both only grow and wrap around, the slot is the index masked
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    closed: AtomicBool,
+    recv_waker: AtomicWaker,
+}
+
+unsafe impl<T: Send> Send for Ring<T> {}
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Ring<T> {
+    #[inline]
+    fn slot(&self, index: usize) -> *mut MaybeUninit<T> {
+        self.slots[index & self.mask].get()
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.recv_waker.wake();
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        let mut head = *self.head.get_mut();
+        let tail = *self.tail.get_mut();
+        while head != tail {
+            unsafe { ptr::drop_in_place((*self.slot(head)).as_mut_ptr()) };
+            head = head.wrapping_add(1);
+        }
+    }
+}
+
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "Full(..)"),
+            TrySendError::Closed(_) => write!(f, "Closed(..)"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct RecvError;
+
+/// Creates a queue holding at least `capacity` items, rounded up to a power of two
+pub fn bounded<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "capacity should be at least 1");
+    let capacity = capacity.next_power_of_two();
+    let slots = (0..capacity)
+        .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+        .collect();
+    let ring = Arc::new(Ring {
+        slots,
+        mask: capacity - 1,
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+        closed: AtomicBool::new(false),
+        recv_waker: AtomicWaker::new(),
+    });
+    (Sender { ring: ring.clone() }, Receiver { ring })
+}
+
+/// The producing end, pushing never waits. Dropping it closes the queue.
+pub struct Sender<T> {
+    ring: Arc<Ring<T>>,
+}
+
+impl<T> Sender<T> {
+    pub fn try_send(&mut self, value: T) -> Result<(), TrySendError<T>> {
+        let ring = &self.ring;
+        if ring.closed.load(Ordering::Acquire) {
+            return Err(TrySendError::Closed(value));
+        }
+        let tail = ring.tail.load(Ordering::Relaxed);
+        let head = ring.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) > ring.mask {
+            return Err(TrySendError::Full(value));
+        }
+        // The consumer never reads the slot before the tail moves past it
+        unsafe { (*ring.slot(tail)).as_mut_ptr().write(value) };
+        ring.tail.store(tail.wrapping_add(1), Ordering::Release);
+        ring.recv_waker.wake();
+        Ok(())
+    }
+
+    /// The receiver gets the items already queued, then `RecvError`
+    pub fn close(&self) {
+        self.ring.close();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.ring.closed.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.ring.close();
+    }
+}
+
+/// The consuming end. Dropping it closes the queue.
+pub struct Receiver<T> {
+    ring: Arc<Ring<T>>,
+}
+
+impl<T> Receiver<T> {
+    fn try_pop(&mut self) -> Option<T> {
+        let ring = &self.ring;
+        let head = ring.head.load(Ordering::Relaxed);
+        let tail = ring.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        // The producer never writes the slot before the head moves past it
+        let value = unsafe { (*ring.slot(head)).as_ptr().read() };
+        ring.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<T, RecvError>> {
+        if let Some(value) = self.try_pop() {
+            return Poll::Ready(Ok(value));
+        }
+        self.ring.recv_waker.register(cx.waker());
+        // The producer may have pushed or closed before the waker was registered
+        let closed = self.ring.closed.load(Ordering::Acquire);
+        if let Some(value) = self.try_pop() {
+            return Poll::Ready(Ok(value));
+        }
+        if closed {
+            Poll::Ready(Err(RecvError))
+        } else {
+            Poll::Pending
+        }
+    }
+
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        futures::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn close(&self) {
+        self.ring.close();
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.ring.close();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn send_recv() {
+        smol::block_on(async {
+            let (mut tx, mut rx) = bounded(3);
+            for i in 0..4 {
+                tx.try_send(i).unwrap();
+            }
+            assert!(matches!(tx.try_send(4), Err(TrySendError::Full(4))));
+            assert_eq!(rx.recv().await, Ok(0));
+            tx.try_send(4).unwrap();
+            drop(tx);
+            // Queued items are still delivered after closing
+            for i in 1..5 {
+                assert_eq!(rx.recv().await, Ok(i));
+            }
+            assert_eq!(rx.recv().await, Err(RecvError));
+        });
+    }
+
+    #[test]
+    fn wrap_around() {
+        smol::block_on(async {
+            let (mut tx, mut rx) = bounded(4);
+            let consumer = smol::spawn(async move {
+                let mut expected = 0usize;
+                while let Ok(value) = rx.recv().await {
+                    assert_eq!(value, expected);
+                    expected += 1;
+                }
+                expected
+            });
+            let mut i = 0usize;
+            while i < 0x10000 {
+                match tx.try_send(i) {
+                    Ok(()) => i += 1,
+                    Err(TrySendError::Full(_)) => smol::future::yield_now().await,
+                    Err(TrySendError::Closed(_)) => unreachable!(),
+                }
+            }
+            drop(tx);
+            assert_eq!(consumer.await, 0x10000);
+        });
+    }
+
+    #[test]
+    fn drop_queued() {
+        let value = Arc::new(());
+        let (mut tx, rx) = bounded(4);
+        tx.try_send(value.clone()).unwrap();
+        tx.try_send(value.clone()).unwrap();
+        drop(rx);
+        assert!(tx.is_closed());
+        drop(tx);
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+}
@@ -0,0 +1,845 @@
+//! A synchronous KCP state machine for targets without `std` or an async runtime, e.g.
+//! microcontrollers. Only `core` and `alloc` are used.
+//!
+//! Nothing happens on its own: the received packets are fed to `input`, `update` is called
+//! with the current time in milliseconds, and whatever `output` returns is sent. `check` tells
+//! when `update` is due next.
+//!
+//! A `Kcp` is one stream speaking the protocol of `KcpStream`, so its peer may be a
+//! `KcpHandle`, a `sans_io::Kcp` or another `Kcp`. It opens with an OPEN that advertises no
+//! features, the peer then sends plain ACKs, unscaled windows and no fragments. There's no
+//! congestion control beyond the peer's window, no datagrams and no half-close, and the
+//! peer has to keep `KcpConfig::single_stream` off, `initial_sequence` at 0 and
+//! `peer_auth_key` unset.
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    vec::Vec,
+};
+use core::cmp;
+
+use crate::segment::{
+    CMD_ACK, CMD_ACK_DELAY, CMD_DATAGRAM, CMD_ECN_ECHO, CMD_FRAGMENT, CMD_HALF_CLOSE, CMD_OPEN,
+    CMD_PING, CMD_PUSH, CMD_RESET, CMD_SKIP, CMD_WINDOW_PROBE, KCP_HEADER_LEN,
+};
+
+/// | STREAM ID | CMD | WND | TS | SN | UNA | LEN |
+pub const HEADER_LEN: usize = KCP_HEADER_LEN;
+/// | TIMESTAMP | SEQUENCE |
+const ACK_LEN: usize = 8;
+/// | TIMESTAMP | SEQUENCE | DELAY |
+const ACK_DELAY_LEN: usize = 12;
+
+const RTO_MIN: u32 = 100;
+const RTO_INIT: u32 = 200;
+const RTO_MAX: u32 = 60000;
+const INTERVAL: u32 = 10;
+/// How long `check` lets an idle `Kcp` wait, `send` and `input` make an update due earlier
+const IDLE_INTERVAL: u32 = 1000;
+/// The default `KcpConfig::keep_alive_interval`, well below the peer's `timeout`
+const KEEP_ALIVE: u32 = 1500;
+/// Later segments acked before a segment is resent without waiting for its RTO
+const FAST_REXMIT_THRESH: u32 = 2;
+/// Transmissions of a segment without an ACK before the link is taken for dead
+const DEAD_LINK: u32 = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Shorter than a header, or than the payload it declares
+    Malformed,
+    /// Not a command of the protocol
+    UnsupportedCommand(u8),
+    /// `accept` got a packet without the OPEN of a new stream
+    NotOpening,
+    /// The send queue is full, `update` makes room once the peer acks
+    BufferFull,
+    /// A segment was sent `DEAD_LINK` times without being acked
+    DeadLink,
+    /// The peer aborted the stream with this code
+    Reset(u32),
+    /// Sending after `close`, or reading after everything the peer sent before its FIN
+    Closed,
+}
+
+#[inline]
+fn i32diff(later: u32, earlier: u32) -> i32 {
+    later.wrapping_sub(earlier) as i32
+}
+
+#[inline]
+fn u16_at(buf: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([buf[i], buf[i + 1]])
+}
+
+#[inline]
+fn u32_at(buf: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]])
+}
+
+struct Segment {
+    command: u8,
+    sequence: u32,
+    timestamp: u32,
+    resend_ts: u32,
+    rto: u32,
+    transmits: u32,
+    skips: u32,
+    data: Vec<u8>,
+}
+
+struct Header {
+    command: u8,
+    window: u16,
+    timestamp: u32,
+    sequence: u32,
+}
+
+/// Splits a packet into its segments, checking the framing only
+struct Segments<'a> {
+    packet: &'a [u8],
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = Result<(Header, u16, u32, &'a [u8]), Error>;
+
+    /// The header, stream id, recv_next and payload of the next segment
+    fn next(&mut self) -> Option<Self::Item> {
+        let packet = self.packet;
+        if packet.is_empty() {
+            return None;
+        }
+        if packet.len() < HEADER_LEN {
+            self.packet = &[];
+            return Some(Err(Error::Malformed));
+        }
+        let len = u16_at(packet, 17) as usize;
+        if packet.len() < HEADER_LEN + len {
+            self.packet = &[];
+            return Some(Err(Error::Malformed));
+        }
+        let header = Header {
+            command: packet[2],
+            window: u16_at(packet, 3),
+            timestamp: u32_at(packet, 5),
+            sequence: u32_at(packet, 9),
+        };
+        self.packet = &packet[HEADER_LEN + len..];
+        Some(Ok((
+            header,
+            u16_at(packet, 0),
+            u32_at(packet, 13),
+            &packet[HEADER_LEN..HEADER_LEN + len],
+        )))
+    }
+}
+
+pub struct Kcp {
+    stream_id: u16,
+    mtu: usize,
+    now: u32,
+    flush_ts: u32,
+    ping_ts: u32,
+    send_window_size: u32,
+    recv_window_size: u32,
+    remote_window: u32,
+    send_next: u32,
+    send_unack: u32,
+    recv_next: u32,
+    srtt: u32,
+    rttval: u32,
+    rto: u32,
+    dead: bool,
+    reset: Option<u32>,
+    // Our FIN is queued
+    closing: bool,
+    // The peer's FIN is delivered
+    eof: bool,
+    // | COMMAND | PAYLOAD |, the OPEN and then PUSHes
+    send_queue: VecDeque<(u8, Vec<u8>)>,
+    send_window: VecDeque<Segment>,
+    recv_window: BTreeMap<u32, (u8, Vec<u8>)>,
+    // In order, the first one may be partially read
+    recv_queue: VecDeque<Vec<u8>>,
+    recv_offset: usize,
+    // | TIMESTAMP | SEQUENCE | of the segments to ack
+    ack_list: Vec<(u32, u32)>,
+    output: VecDeque<Vec<u8>>,
+}
+
+impl Kcp {
+    /// Opens stream `stream_id`, the OPEN goes out with the first `update`. The `mtu` must
+    /// not exceed the peer's `KcpConfig::mtu`.
+    pub fn connect(stream_id: u16, mtu: usize) -> Self {
+        assert!(mtu > HEADER_LEN + ACK_DELAY_LEN, "mtu {} is too small", mtu);
+        let mut send_queue = VecDeque::new();
+        // | FEATURES |, none and no label
+        send_queue.push_back((CMD_OPEN, alloc::vec![0]));
+        Self {
+            stream_id,
+            mtu,
+            now: 0,
+            flush_ts: 0,
+            ping_ts: KEEP_ALIVE,
+            send_window_size: 32,
+            recv_window_size: 128,
+            remote_window: 128,
+            send_next: 0,
+            send_unack: 0,
+            recv_next: 0,
+            srtt: 0,
+            rttval: 0,
+            rto: RTO_INIT,
+            dead: false,
+            reset: None,
+            closing: false,
+            eof: false,
+            send_queue,
+            send_window: VecDeque::new(),
+            recv_window: BTreeMap::new(),
+            recv_queue: VecDeque::new(),
+            recv_offset: 0,
+            ack_list: Vec::new(),
+            output: VecDeque::new(),
+        }
+    }
+
+    /// The stream the peer opens with `packet`, answered with our OPEN on the next `update`
+    pub fn accept(packet: &[u8], mtu: usize) -> Result<Self, Error> {
+        let mut opening = None;
+        for segment in (Segments { packet }) {
+            let (header, stream_id, recv_next, _) = segment?;
+            // The OPEN may share the packet with a keep-alive PING flushed before it
+            if header.command == CMD_OPEN && recv_next == 0 {
+                opening = Some(stream_id);
+            }
+        }
+        let mut kcp = Self::connect(opening.ok_or(Error::NotOpening)?, mtu);
+        kcp.input(packet)?;
+        Ok(kcp)
+    }
+
+    /// Windows in segments. The send window is also bounded by the window the peer
+    /// advertises.
+    pub fn with_window(mut self, send: u16, recv: u16) -> Self {
+        self.send_window_size = cmp::max(send, 1) as u32;
+        self.recv_window_size = cmp::max(recv, 1) as u32;
+        self
+    }
+
+    #[inline]
+    pub fn stream_id(&self) -> u16 {
+        self.stream_id
+    }
+
+    #[inline]
+    fn mss(&self) -> usize {
+        self.mtu - HEADER_LEN
+    }
+
+    fn check_open(&self) -> Result<(), Error> {
+        if let Some(code) = self.reset {
+            Err(Error::Reset(code))
+        } else if self.dead {
+            Err(Error::DeadLink)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Queues as much of `data` as the send queue takes, split into segments. It holds
+    /// twice the send window.
+    pub fn send(&mut self, data: &[u8]) -> Result<usize, Error> {
+        self.check_open()?;
+        if self.closing {
+            return Err(Error::Closed);
+        }
+        let limit = 2 * self.send_window_size as usize;
+        let mut sent = 0;
+        for chunk in data.chunks(self.mss()) {
+            if self.send_queue.len() >= limit {
+                break;
+            }
+            self.send_queue.push_back((CMD_PUSH, chunk.to_vec()));
+            sent += chunk.len();
+        }
+        if sent == 0 && !data.is_empty() {
+            return Err(Error::BufferFull);
+        }
+        Ok(sent)
+    }
+
+    /// Reads what arrived in order, 0 when nothing did. `Error::Closed` once the peer's FIN
+    /// is reached.
+    pub fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if let Some(code) = self.reset {
+            return Err(Error::Reset(code));
+        }
+        let mut read = 0;
+        while read < buf.len() {
+            let data = match self.recv_queue.front() {
+                Some(data) => data,
+                None => break,
+            };
+            let len = cmp::min(buf.len() - read, data.len() - self.recv_offset);
+            buf[read..read + len].copy_from_slice(&data[self.recv_offset..self.recv_offset + len]);
+            read += len;
+            self.recv_offset += len;
+            if self.recv_offset == data.len() {
+                self.recv_queue.pop_front();
+                self.recv_offset = 0;
+            }
+        }
+        self.move_to_recv_queue();
+        if read == 0 && !buf.is_empty() {
+            if self.eof && self.recv_queue.is_empty() {
+                return Err(Error::Closed);
+            }
+            if self.dead {
+                return Err(Error::DeadLink);
+            }
+        }
+        Ok(read)
+    }
+
+    /// Sends a FIN after what's queued. The peer answers with its own once it has read
+    /// everything, `recv` then fails with `Error::Closed`.
+    pub fn close(&mut self) {
+        if !self.closing {
+            self.closing = true;
+            self.send_queue.push_back((CMD_PUSH, Vec::new()));
+        }
+    }
+
+    /// Handles a received packet, one or more segments. Packets of other streams are
+    /// ignored.
+    pub fn input(&mut self, packet: &[u8]) -> Result<(), Error> {
+        if self.reset.is_some() {
+            return Ok(());
+        }
+        let mut max_ack = None;
+        for segment in (Segments { packet }) {
+            let (header, stream_id, recv_next, data) = segment?;
+            if stream_id != self.stream_id {
+                return Ok(());
+            }
+            self.remote_window = header.window as u32;
+            self.remove_acked_until(recv_next);
+            match header.command {
+                CMD_ACK | CMD_ACK_DELAY => {
+                    let entry_len = if header.command == CMD_ACK {
+                        ACK_LEN
+                    } else {
+                        ACK_DELAY_LEN
+                    };
+                    if data.len() % entry_len != 0 {
+                        return Err(Error::Malformed);
+                    }
+                    for ack in data.chunks(entry_len) {
+                        let timestamp = u32_at(ack, 0);
+                        let sequence = u32_at(ack, 4);
+                        // The peer held the ACK back this long
+                        let delay = if entry_len == ACK_DELAY_LEN {
+                            u32_at(ack, 8)
+                        } else {
+                            0
+                        };
+                        if i32diff(self.now, timestamp) >= 0 {
+                            let rtt = self.now.wrapping_sub(timestamp).saturating_sub(delay);
+                            self.update_rtt(cmp::max(rtt, 1));
+                        }
+                        self.remove_acked(sequence);
+                        max_ack = Some(match max_ack {
+                            Some(max) if i32diff(sequence, max) <= 0 => max,
+                            _ => sequence,
+                        });
+                    }
+                }
+                CMD_PUSH | CMD_OPEN | CMD_SKIP | CMD_HALF_CLOSE => {
+                    let window_end = self.recv_next.wrapping_add(self.recv_window_size);
+                    if i32diff(header.sequence, window_end) < 0 {
+                        self.ack_list.push((header.timestamp, header.sequence));
+                        if i32diff(header.sequence, self.recv_next) >= 0 {
+                            self.recv_window
+                                .entry(header.sequence)
+                                .or_insert_with(|| (header.command, data.to_vec()));
+                        }
+                    }
+                }
+                CMD_WINDOW_PROBE => {
+                    // The next flush pings, telling our window
+                    self.ping_ts = self.now;
+                }
+                CMD_RESET => {
+                    if data.len() >= 4 {
+                        self.handle_reset(u32_at(data, 0));
+                        return Ok(());
+                    }
+                }
+                // Datagrams are not supported, ECN and fragments are features we don't
+                // advertise
+                CMD_PING | CMD_DATAGRAM | CMD_ECN_ECHO | CMD_FRAGMENT => {}
+                command => return Err(Error::UnsupportedCommand(command)),
+            }
+        }
+
+        if let Some(max_ack) = max_ack {
+            for segment in &mut self.send_window {
+                if i32diff(max_ack, segment.sequence) > 0 {
+                    segment.skips += 1;
+                }
+            }
+        }
+        self.move_to_recv_queue();
+        Ok(())
+    }
+
+    /// Sends the ACKs, new segments, retransmissions and keep-alives once `check` says
+    /// it's due
+    pub fn update(&mut self, now: u32) {
+        self.now = now;
+        if self.reset.is_some() {
+            return;
+        }
+        let late = i32diff(now, self.flush_ts);
+        if late < 0 && i32diff(now, self.ping_ts) < 0 {
+            return;
+        }
+        self.flush_ts = if late > 10 * INTERVAL as i32 {
+            // Not called for a long while, don't try to catch up
+            now.wrapping_add(INTERVAL)
+        } else {
+            self.flush_ts.wrapping_add(INTERVAL)
+        };
+        self.flush();
+    }
+
+    /// When `update` has to be called next, at the latest
+    pub fn check(&self, now: u32) -> u32 {
+        if !self.ack_list.is_empty() || !self.send_queue.is_empty() {
+            return if i32diff(self.flush_ts, now) > 0 {
+                self.flush_ts
+            } else {
+                now
+            };
+        }
+        let mut next = now.wrapping_add(IDLE_INTERVAL);
+        for segment in &self.send_window {
+            if i32diff(segment.resend_ts, next) < 0 {
+                next = segment.resend_ts;
+            }
+        }
+        if i32diff(next, self.flush_ts) < 0 {
+            next = self.flush_ts;
+        }
+        if i32diff(self.ping_ts, next) < 0 {
+            next = self.ping_ts;
+        }
+        if i32diff(next, now) < 0 {
+            next = now;
+        }
+        next
+    }
+
+    /// The next packet to send
+    pub fn output(&mut self) -> Option<Vec<u8>> {
+        self.output.pop_front()
+    }
+
+    /// Segments queued or in flight
+    pub fn waiting_send(&self) -> usize {
+        self.send_queue.len() + self.send_window.len()
+    }
+
+    #[inline]
+    pub fn is_dead(&self) -> bool {
+        self.dead
+    }
+
+    fn handle_reset(&mut self, code: u32) {
+        self.reset = Some(code);
+        // Aborted, nothing is read or sent any more
+        self.recv_queue.clear();
+        self.recv_window.clear();
+        self.send_queue.clear();
+        self.send_window.clear();
+        self.ack_list.clear();
+        self.update_unack();
+    }
+
+    fn update_rtt(&mut self, rtt: u32) {
+        if self.srtt == 0 {
+            self.srtt = rtt;
+            self.rttval = rtt / 2;
+        } else {
+            let delta = cmp::max(rtt, self.srtt) - cmp::min(rtt, self.srtt);
+            self.rttval = (3 * self.rttval + delta) / 4;
+            self.srtt = cmp::max((7 * self.srtt + rtt) / 8, 1);
+        }
+        let rto = self.srtt + cmp::max(INTERVAL, 4 * self.rttval);
+        self.rto = rto.clamp(RTO_MIN, RTO_MAX);
+    }
+
+    fn remove_acked(&mut self, sequence: u32) {
+        if let Some(index) = self
+            .send_window
+            .iter()
+            .position(|segment| segment.sequence == sequence)
+        {
+            self.send_window.remove(index);
+        }
+        self.update_unack();
+    }
+
+    fn remove_acked_until(&mut self, recv_next: u32) {
+        while let Some(segment) = self.send_window.front() {
+            if i32diff(recv_next, segment.sequence) > 0 {
+                self.send_window.pop_front();
+            } else {
+                break;
+            }
+        }
+        self.update_unack();
+    }
+
+    fn update_unack(&mut self) {
+        self.send_unack = match self.send_window.front() {
+            Some(segment) => segment.sequence,
+            None => self.send_next,
+        };
+    }
+
+    fn move_to_recv_queue(&mut self) {
+        while let Some((command, data)) = self.recv_window.get(&self.recv_next) {
+            if *command == CMD_PUSH
+                && !data.is_empty()
+                && self.recv_queue.len() as u32 >= self.recv_window_size
+            {
+                break;
+            }
+            let (command, data) = self.recv_window.remove(&self.recv_next).unwrap();
+            self.recv_next = self.recv_next.wrapping_add(1);
+            match command {
+                // The features and label of the peer, or data it abandoned
+                CMD_OPEN | CMD_SKIP => {}
+                _ if data.is_empty() => {
+                    // FIN, answered with ours like `KcpStream` does
+                    self.eof = true;
+                    self.close();
+                    break;
+                }
+                _ => self.recv_queue.push_back(data),
+            }
+        }
+    }
+
+    fn advertised_window(&self) -> u16 {
+        let unused = self
+            .recv_window_size
+            .saturating_sub(self.recv_queue.len() as u32);
+        cmp::min(unused, 0xffff) as u16
+    }
+
+    /// Appends a segment to the packet in `buf`, which goes to the output once full
+    fn emit(&mut self, buf: &mut Vec<u8>, header: Header, data: &[u8]) {
+        if buf.len() + HEADER_LEN + data.len() > self.mtu {
+            self.output.push_back(core::mem::take(buf));
+        }
+        buf.extend_from_slice(&self.stream_id.to_le_bytes());
+        buf.push(header.command);
+        buf.extend_from_slice(&header.window.to_le_bytes());
+        buf.extend_from_slice(&header.timestamp.to_le_bytes());
+        buf.extend_from_slice(&header.sequence.to_le_bytes());
+        buf.extend_from_slice(&self.recv_next.to_le_bytes());
+        buf.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        buf.extend_from_slice(data);
+    }
+
+    fn flush(&mut self) {
+        let window = self.advertised_window();
+        let mut buf = Vec::with_capacity(self.mtu);
+
+        let acks_per_segment = self.mss() / ACK_LEN;
+        let ack_list = core::mem::take(&mut self.ack_list);
+        for acks in ack_list.chunks(acks_per_segment) {
+            let mut data = Vec::with_capacity(acks.len() * ACK_LEN);
+            for (timestamp, sequence) in acks {
+                data.extend_from_slice(&timestamp.to_le_bytes());
+                data.extend_from_slice(&sequence.to_le_bytes());
+            }
+            let header = Header {
+                command: CMD_ACK,
+                window,
+                timestamp: self.now,
+                sequence: 0,
+            };
+            self.emit(&mut buf, header, &data);
+        }
+
+        if i32diff(self.now, self.ping_ts) >= 0 {
+            // Keeps the peer from timing out an idle stream
+            self.ping_ts = self.now.wrapping_add(KEEP_ALIVE);
+            let header = Header {
+                command: CMD_PING,
+                window,
+                timestamp: self.now,
+                sequence: self.send_next,
+            };
+            self.emit(&mut buf, header, &[]);
+        }
+
+        // A zero window is probed with one segment, which the peer drops if it's still full
+        let window_size = cmp::min(self.send_window_size, cmp::max(self.remote_window, 1));
+        while i32diff(self.send_next, self.send_unack.wrapping_add(window_size)) < 0 {
+            let (command, data) = match self.send_queue.pop_front() {
+                Some(segment) => segment,
+                None => break,
+            };
+            self.send_window.push_back(Segment {
+                command,
+                sequence: self.send_next,
+                timestamp: 0,
+                resend_ts: 0,
+                rto: self.rto,
+                transmits: 0,
+                skips: 0,
+                data,
+            });
+            self.send_next = self.send_next.wrapping_add(1);
+        }
+
+        let now = self.now;
+        let mut send_window = core::mem::take(&mut self.send_window);
+        for segment in &mut send_window {
+            let due = if segment.transmits == 0 {
+                segment.rto = self.rto;
+                true
+            } else if i32diff(now, segment.resend_ts) >= 0 {
+                // Timed out, back off
+                segment.rto = cmp::min(segment.rto + segment.rto / 2, RTO_MAX);
+                true
+            } else if segment.skips >= FAST_REXMIT_THRESH {
+                segment.skips = 0;
+                true
+            } else {
+                false
+            };
+            if !due {
+                continue;
+            }
+            segment.transmits += 1;
+            segment.timestamp = now;
+            segment.resend_ts = now.wrapping_add(segment.rto);
+            if segment.transmits > DEAD_LINK {
+                self.dead = true;
+            }
+            let header = Header {
+                command: segment.command,
+                window,
+                timestamp: segment.timestamp,
+                sequence: segment.sequence,
+            };
+            self.emit(&mut buf, header, &segment.data);
+        }
+        self.send_window = send_window;
+
+        if !buf.is_empty() {
+            self.output.push_back(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{core::KcpConfig, sans_io};
+
+    /// Delivers the output of `from`, dropping every `drop_every`th packet
+    fn deliver(from: &mut Kcp, to: &mut Kcp, packets: &mut usize, drop_every: usize) {
+        while let Some(packet) = from.output() {
+            *packets += 1;
+            if *packets == drop_every {
+                *packets = 0;
+            } else {
+                to.input(&packet).unwrap();
+            }
+        }
+    }
+
+    fn transfer(data: &[u8], drop_every: usize) -> Vec<u8> {
+        let mut sender = Kcp::connect(7, 200);
+        let mut now = 0u32;
+        sender.update(now);
+        let open = sender.output().unwrap();
+        let mut receiver = Kcp::accept(&open, 200).unwrap();
+        assert_eq!(receiver.stream_id(), 7);
+        let mut sent = 0;
+        let mut received = Vec::new();
+        let mut packets = 0;
+        let mut buf = [0u8; 0x400];
+        loop {
+            if sent < data.len() {
+                if let Ok(len) = sender.send(&data[sent..]) {
+                    sent += len;
+                }
+            } else {
+                sender.close();
+            }
+            sender.update(now);
+            receiver.update(now);
+            deliver(&mut sender, &mut receiver, &mut packets, drop_every);
+            deliver(&mut receiver, &mut sender, &mut packets, drop_every);
+            loop {
+                match receiver.recv(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => received.extend_from_slice(&buf[..len]),
+                    Err(Error::Closed) => return received,
+                    Err(e) => panic!("{:?}", e),
+                }
+            }
+            let next = cmp::min(sender.check(now), receiver.check(now));
+            now = cmp::max(next, now + 1);
+            assert!(now < 600_000, "the transfer stalled");
+        }
+    }
+
+    #[test]
+    fn transfer_data() {
+        let data: Vec<u8> = (0..0x10000u32).map(|i| (i * 31 % 251) as u8).collect();
+        assert_eq!(transfer(&data, 0), data);
+        assert_eq!(transfer(&data, 5), data);
+    }
+
+    /// Both ways with a `sans_io::Kcp`, which runs the core of `KcpStream`
+    #[test]
+    fn with_kcp_core() {
+        let data: Vec<u8> = (0..0x8000u32).map(|i| (i * 31 % 251) as u8).collect();
+        let mut now = 0u32;
+        let mut kcp = Kcp::connect(3, crate::DEFAULT_MTU);
+        kcp.update(now);
+        let open = kcp.output().unwrap();
+        let mut peer = sans_io::Kcp::accept(&open, KcpConfig::default(), now).unwrap();
+        assert_eq!(peer.stream_id(), 3);
+
+        let mut sent = 0;
+        let mut echoed = 0;
+        let mut closed = false;
+        let mut peer_closed = false;
+        let mut peer_received = Vec::new();
+        let mut received = Vec::new();
+        let mut packets = 0;
+        let mut buf = [0u8; 0x1000];
+        loop {
+            if sent < data.len() {
+                if let Ok(len) = kcp.send(&data[sent..]) {
+                    sent += len;
+                }
+            } else if !closed && received.len() == data.len() {
+                // After the echo, the peer can't write once it reads the FIN
+                kcp.close();
+                closed = true;
+            }
+            // The peer echoes what it reads
+            while echoed < peer_received.len() {
+                let end = cmp::min(echoed + 0x400, peer_received.len());
+                if !peer.send(&peer_received[echoed..end], now).unwrap() {
+                    break;
+                }
+                echoed = end;
+            }
+            kcp.update(now);
+            // It fails once the stream is closed
+            let _ = peer.update(now);
+            while let Some(packet) = kcp.output() {
+                packets += 1;
+                if packets == 7 {
+                    packets = 0;
+                } else {
+                    peer.input(&packet, now).unwrap();
+                }
+            }
+            while let Some(packet) = peer.output() {
+                packets += 1;
+                if packets == 7 {
+                    packets = 0;
+                } else {
+                    kcp.input(&packet).unwrap();
+                }
+            }
+            if !peer_closed {
+                match peer.recv(&mut buf, now).unwrap() {
+                    Some(0) => peer_closed = true,
+                    Some(len) => peer_received.extend_from_slice(&buf[..len]),
+                    None => {}
+                }
+            }
+            loop {
+                match kcp.recv(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => received.extend_from_slice(&buf[..len]),
+                    Err(Error::Closed) => {
+                        assert!(peer_closed);
+                        assert_eq!(peer_received, data);
+                        assert_eq!(received, data);
+                        return;
+                    }
+                    Err(e) => panic!("{:?}", e),
+                }
+            }
+            let next = cmp::min(kcp.check(now), peer.check(now));
+            now = cmp::max(next, now + 1);
+            assert!(now < 600_000, "the transfer stalled");
+        }
+    }
+
+    #[test]
+    fn reset_by_peer() {
+        let mut now = 0u32;
+        let mut kcp = Kcp::connect(3, crate::DEFAULT_MTU);
+        kcp.update(now);
+        let open = kcp.output().unwrap();
+        let mut peer = sans_io::Kcp::accept(&open, KcpConfig::default(), now).unwrap();
+        now += 10;
+        peer.reset(42, "bye", now);
+        let _ = peer.update(now);
+        while let Some(packet) = peer.output() {
+            kcp.input(&packet).unwrap();
+        }
+        assert_eq!(kcp.send(b"hello"), Err(Error::Reset(42)));
+        assert_eq!(kcp.recv(&mut [0u8; 5]), Err(Error::Reset(42)));
+    }
+
+    #[test]
+    fn malformed() {
+        let mut kcp = Kcp::connect(7, 200);
+        assert_eq!(kcp.input(&[7, 0, CMD_PUSH]), Err(Error::Malformed));
+        let mut packet = [0u8; HEADER_LEN];
+        packet[0] = 7;
+        packet[2] = 0xff;
+        assert_eq!(kcp.input(&packet), Err(Error::UnsupportedCommand(0xff)));
+        // Another stream
+        packet[0] = 8;
+        assert_eq!(kcp.input(&packet), Ok(()));
+        packet[0] = 7;
+        packet[2] = CMD_PUSH;
+        packet[HEADER_LEN - 2] = 1;
+        assert_eq!(kcp.input(&packet), Err(Error::Malformed));
+        // A PUSH, not an OPEN
+        packet[HEADER_LEN - 2] = 0;
+        assert!(matches!(Kcp::accept(&packet, 200), Err(Error::NotOpening)));
+    }
+
+    #[test]
+    fn dead_link() {
+        let mut kcp = Kcp::connect(7, 200);
+        assert_eq!(kcp.send(b"hello"), Ok(5));
+        let mut now = 0;
+        while !kcp.is_dead() && now < 10_000_000 {
+            kcp.update(now);
+            while kcp.output().is_some() {}
+            now = cmp::max(kcp.check(now), now + 1);
+        }
+        assert!(kcp.is_dead());
+        assert_eq!(kcp.send(b"hello"), Err(Error::DeadLink));
+        assert_eq!(kcp.recv(&mut [0u8; 5]), Err(Error::DeadLink));
+    }
+}
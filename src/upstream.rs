@@ -0,0 +1,244 @@
+//! Several equivalent targets behind the default route of the server.
+//!
+//! Streams are balanced across the targets passing the health checks, a periodic TCP
+//! connect to each of them. A target refusing a stream is skipped until it passes again.
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use smol::{future::FutureExt, net::TcpStream, Timer};
+
+/// How long a health check waits for the connection
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Connects a TCP target, giving up with `TimedOut` after `timeout` if any. A target
+/// dropping the SYNs would otherwise keep the caller waiting for minutes.
+pub async fn connect_tcp(addr: &str, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let connect = TcpStream::connect(addr);
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return connect.await,
+    };
+    let expired = async {
+        Timer::after(timeout).await;
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("connecting {} timed out after {:?}", addr, timeout),
+        ))
+    };
+    connect.or(expired).await
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Balance {
+    RoundRobin,
+    /// The target with the fewest relayed streams, the first one on ties
+    LeastConnections,
+}
+
+struct Upstream {
+    addr: String,
+    healthy: AtomicBool,
+    connections: AtomicUsize,
+}
+
+pub struct UpstreamPool {
+    upstreams: Vec<Upstream>,
+    balance: Balance,
+    // None for a single target, which is never skipped
+    check_interval: Option<Duration>,
+    next: AtomicUsize,
+}
+
+/// Counts a stream relayed to an upstream, until dropped
+pub struct UpstreamGuard {
+    pool: Arc<UpstreamPool>,
+    index: usize,
+}
+
+impl UpstreamGuard {
+    pub fn addr(&self) -> &str {
+        &self.pool.upstreams[self.index].addr
+    }
+}
+
+impl Drop for UpstreamGuard {
+    fn drop(&mut self) {
+        self.pool.upstreams[self.index]
+            .connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl UpstreamPool {
+    /// A pool checked every `check_interval`, the targets are taken for healthy until checked
+    pub fn new(addrs: Vec<String>, balance: Balance, check_interval: Duration) -> Self {
+        Self {
+            upstreams: addrs
+                .into_iter()
+                .map(|addr| Upstream {
+                    addr,
+                    healthy: AtomicBool::new(true),
+                    connections: AtomicUsize::new(0),
+                })
+                .collect(),
+            balance,
+            check_interval: Some(check_interval),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// The single `--remote`, without health checks
+    pub fn single(addr: String) -> Self {
+        Self {
+            check_interval: None,
+            ..Self::new(vec![addr], Balance::RoundRobin, Duration::default())
+        }
+    }
+
+    pub fn addrs(&self) -> impl Iterator<Item = &str> {
+        self.upstreams.iter().map(|upstream| upstream.addr.as_str())
+    }
+
+    #[cfg(test)]
+    pub fn is_healthy(&self, addr: &str) -> bool {
+        self.upstreams
+            .iter()
+            .any(|upstream| upstream.addr == addr && upstream.healthy.load(Ordering::Relaxed))
+    }
+
+    fn pick(self: &Arc<Self>) -> Option<UpstreamGuard> {
+        let count = self.upstreams.len();
+        let healthy = |index: &usize| self.upstreams[*index].healthy.load(Ordering::Relaxed);
+        let index = match self.balance {
+            Balance::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..count).map(|i| (start + i) % count).find(healthy)
+            }
+            Balance::LeastConnections => (0..count)
+                .filter(healthy)
+                .min_by_key(|index| self.upstreams[*index].connections.load(Ordering::Relaxed)),
+        }?;
+        self.upstreams[index]
+            .connections
+            .fetch_add(1, Ordering::Relaxed);
+        Some(UpstreamGuard {
+            pool: self.clone(),
+            index,
+        })
+    }
+
+    /// Connects a healthy upstream. One refusing the connection or not answering within
+    /// `timeout` is marked down and the next is tried.
+    pub async fn connect(
+        self: &Arc<Self>,
+        timeout: Option<Duration>,
+    ) -> io::Result<(TcpStream, UpstreamGuard)> {
+        for _ in 0..self.upstreams.len() {
+            let guard = match self.pick() {
+                Some(guard) => guard,
+                None => break,
+            };
+            match connect_tcp(guard.addr(), timeout).await {
+                Ok(stream) => return Ok((stream, guard)),
+                Err(e) if self.check_interval.is_some() => {
+                    log::warn!("upstream {} is down: {}", guard.addr(), e);
+                    self.upstreams[guard.index]
+                        .healthy
+                        .store(false, Ordering::Relaxed);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "no healthy upstream",
+        ))
+    }
+
+    /// Probes every upstream once, a pool of the single `--remote` is never checked
+    pub async fn check(&self) {
+        if self.check_interval.is_none() {
+            return;
+        }
+        for upstream in &self.upstreams {
+            let healthy = connect_tcp(&upstream.addr, Some(HEALTH_CHECK_TIMEOUT))
+                .await
+                .is_ok();
+            if upstream.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+                if healthy {
+                    log::info!("upstream {} is up", upstream.addr);
+                } else {
+                    log::warn!("upstream {} failed the health check", upstream.addr);
+                }
+            }
+        }
+    }
+
+    /// Checks the upstreams periodically
+    pub async fn run_health_checks(self: Arc<Self>) {
+        let interval = match self.check_interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        loop {
+            Timer::after(interval).await;
+            self.check().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pool(balance: Balance) -> Arc<UpstreamPool> {
+        let addrs = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        Arc::new(UpstreamPool::new(addrs, balance, Duration::from_secs(1)))
+    }
+
+    #[test]
+    fn round_robin() {
+        let pool = pool(Balance::RoundRobin);
+        pool.upstreams[1].healthy.store(false, Ordering::Relaxed);
+        let picked: Vec<_> = (0..4)
+            .map(|_| pool.pick().unwrap().addr().to_string())
+            .collect();
+        assert_eq!(picked, vec!["a", "c", "c", "a"]);
+    }
+
+    #[test]
+    fn least_connections() {
+        let pool = pool(Balance::LeastConnections);
+        let a = pool.pick().unwrap();
+        let b = pool.pick().unwrap();
+        assert_eq!((a.addr(), b.addr()), ("a", "b"));
+        drop(a);
+        assert_eq!(pool.pick().unwrap().addr(), "a");
+        for upstream in &pool.upstreams {
+            upstream.healthy.store(false, Ordering::Relaxed);
+        }
+        assert!(pool.pick().is_none());
+    }
+
+    #[test]
+    fn connect_timeout() {
+        smol::block_on(async {
+            // Reserved for documentation, nothing answers there
+            let timeout = Duration::from_millis(500);
+            let start = std::time::Instant::now();
+            assert!(connect_tcp("192.0.2.1:80", Some(timeout)).await.is_err());
+            assert!(start.elapsed() < timeout + Duration::from_millis(500));
+
+            let listener = smol::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            connect_tcp(&addr, Some(timeout)).await.unwrap();
+        });
+    }
+}